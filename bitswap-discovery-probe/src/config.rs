@@ -1,4 +1,6 @@
 use failure::ResultExt;
+use ipfs_resolver_common::cid_normalization::CidNormalization;
+use ipfs_resolver_common::output::OutputConfig;
 use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::path::Path;
@@ -14,11 +16,30 @@ pub(crate) struct Config {
     /// Specifies a list of CIDs to probe for.
     pub(crate) cids: Vec<String>,
 
+    /// How CIDs of responses are normalized before comparing them to the probed CIDs.
+    /// Defaults to `codec_and_multihash`, which matches responses to CIDv0 and CIDv1 equivalents.
+    #[serde(default = "default_cid_normalization")]
+    pub(crate) cid_normalization: CidNormalization,
+
     /// Specifies the duration between WANT and CANCEL in seconds.
     pub(crate) cancel_after_seconds: u32,
 
     /// Specifies how long to wait after the CANCEL broadcast for late responses.
     pub(crate) wait_after_cancel_seconds: u32,
+
+    /// The path of the file to write results to.
+    /// If this is not set, results are written to stdout as CSV.
+    #[serde(default)]
+    pub(crate) output_file: Option<String>,
+
+    /// The format to write results to `output_file` in.
+    /// Defaults to gzipped CSV.
+    #[serde(default)]
+    pub(crate) output: OutputConfig,
+}
+
+fn default_cid_normalization() -> CidNormalization {
+    CidNormalization::CodecAndMultihash
 }
 
 /// Configuration for a single monitor to connect to.
@@ -17,8 +17,12 @@ use crate::config::Config;
 use ipfs_monitoring_plugin_client::http::{APIClient, BroadcastBitswapWantCancelEntry};
 use ipfs_monitoring_plugin_client::monitoring;
 use ipfs_monitoring_plugin_client::monitoring::{
-    BlockPresenceType, EventType, MonitoringClient, PushedEvent, RoutingKeyInformation,
+    BlockPresenceType, EventType, MonitoringClient, MonitoringClientOptions, MonitoringItem,
+    PushedEvent, ReconnectConfig, RoutingKeyInformation,
 };
+use ipfs_monitoring_plugin_client::source::MonitoringSource;
+use ipfs_resolver_common::cid_normalization::CidNormalization;
+use ipfs_resolver_common::output::{Column, ColumnType, OutputSink, Record};
 use ipfs_resolver_common::{logging, Result};
 
 mod config;
@@ -64,36 +68,37 @@ async fn main() -> Result<()> {
         .iter()
         .map(|c| {
             cid::Cid::from_str(c)
-                //.map(|c| Cid::new_v1(c.codec(), c.hash().clone()))
                 .map_err(|e| err_msg(format!("unable to parse CID {}: {:?}", c, e)))
         })
         .collect::<std::result::Result<Vec<_>, _>>()
         .context("unable to parse CID")?;
     debug!("parsed CIDs {:?}", cids);
 
-    // Deduplicate CIDs.
-    let mut cids = cids;
-    cids.sort();
-    cids.windows(2)
-        .try_for_each(|cs| {
-            let c1 = cs[0];
-            let c2 = cs[1];
-            if c1 == c2 {
-                Err(err_msg(format!("duplicate CID {} in input", c1)))
-            } else {
+    // Check for duplicate CIDs, after normalization.
+    let cid_normalization = cfg.cid_normalization;
+    let mut keys = HashSet::new();
+    cids.iter()
+        .try_for_each(|c| {
+            if keys.insert(cid_key(cid_normalization, &c.to_string())) {
                 Ok(())
+            } else {
+                Err(err_msg(format!("duplicate CID {} in input", c)))
             }
         })
         .context("input contains duplicates")?;
-    cids.dedup();
-    let cids = cids;
 
     // Connect to monitors.
     info!("connecting to monitors");
     let probes = try_join_all(cfg.monitors.iter().map(|c| {
         let name = c.name.clone();
-        Probe::connect(&c.amqp_server_address, &c.api_base_url, &cids, &c.name)
-            .and_then(|p| async move { futures::future::ok((name, p)).await })
+        Probe::connect(
+            &c.amqp_server_address,
+            &c.api_base_url,
+            &cids,
+            cid_normalization,
+            &c.name,
+        )
+        .and_then(|p| async move { futures::future::ok((name, p)).await })
     }))
     .await
     .context("unable to set up probes")?;
@@ -245,9 +250,7 @@ async fn main() -> Result<()> {
     });
     debug!("constructed complete result set: {:?}", results);
 
-    debug!("writing CSV output...");
-    let mut output_writer = csv::Writer::from_writer(io::BufWriter::new(stdout()));
-    results
+    let rows = results
         .into_iter()
         .flat_map(|((monitor_name, peer_id), peer_entry)| {
             let connected_addrs = format!(
@@ -270,6 +273,7 @@ async fn main() -> Result<()> {
                         measurement_id,
                         peer_id,
                         connected_addrs,
+                        normalized_cid: cid_normalization.normalize(&c),
                         cid: c,
                         want_before_send_ts_seconds: entry
                             .want_broadcast_status
@@ -309,10 +313,28 @@ async fn main() -> Result<()> {
                             .send_duration_millis,
                     },
                 )
-        })
-        .try_for_each(|row| output_writer.serialize(row))
-        .context("unable to write CSV output")?;
-    info!("done writing CSV output");
+        });
+
+    match cfg.output_file.as_ref() {
+        Some(path) => {
+            debug!("writing output to {}...", path);
+            let mut output_sink =
+                OutputSink::create(&cfg.output, path).context("unable to create output file")?;
+            rows.into_iter()
+                .try_for_each(|row| output_sink.write(&row))
+                .context("unable to write output")?;
+            output_sink.finish().context("unable to write output")?;
+            info!("done writing output to {}", path);
+        }
+        None => {
+            debug!("writing CSV output...");
+            let mut output_writer = csv::Writer::from_writer(io::BufWriter::new(stdout()));
+            rows.into_iter()
+                .try_for_each(|row| output_writer.serialize(row))
+                .context("unable to write CSV output")?;
+            info!("done writing CSV output");
+        }
+    }
 
     Ok(())
 }
@@ -344,6 +366,45 @@ pub struct OutputCSVRow {
     pub cancel_before_send_ts_subsec_milliseconds: u32,
     pub cancel_send_error: Option<String>,
     pub cancel_send_duration_millis: i64,
+
+    /// The normalized CID, if CID normalization is enabled.
+    pub normalized_cid: Option<String>,
+}
+
+impl Record for OutputCSVRow {
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::new("monitor", ColumnType::Dictionary),
+            Column::new("measurement_id", ColumnType::Int64),
+            Column::new("peer_id", ColumnType::Dictionary),
+            Column::new("connected_addrs", ColumnType::Utf8),
+            Column::new("cid", ColumnType::Dictionary),
+            Column::new("want_before_send_ts_seconds", ColumnType::Int64),
+            Column::new(
+                "want_before_send_ts_subsec_milliseconds",
+                ColumnType::UInt32,
+            ),
+            Column::nullable("want_send_error", ColumnType::Utf8),
+            Column::new("want_send_duration_millis", ColumnType::Int64),
+            Column::nullable("have_received_ts_seconds", ColumnType::Int64),
+            Column::nullable("have_received_ts_subsec_milliseconds", ColumnType::UInt32),
+            Column::nullable("dont_have_received_ts_seconds", ColumnType::Int64),
+            Column::nullable(
+                "dont_have_received_ts_subsec_milliseconds",
+                ColumnType::UInt32,
+            ),
+            Column::nullable("block_received_ts_seconds", ColumnType::Int64),
+            Column::nullable("block_received_ts_subsec_milliseconds", ColumnType::UInt32),
+            Column::new("cancel_before_send_ts_seconds", ColumnType::Int64),
+            Column::new(
+                "cancel_before_send_ts_subsec_milliseconds",
+                ColumnType::UInt32,
+            ),
+            Column::nullable("cancel_send_error", ColumnType::Utf8),
+            Column::new("cancel_send_duration_millis", ColumnType::Int64),
+            Column::nullable("normalized_cid", ColumnType::Dictionary),
+        ]
+    }
 }
 
 #[derive(Debug, Default, Clone, Serialize)]
@@ -373,6 +434,7 @@ struct BroadcastResponse {
     peer: String,
     connected_addrs: Vec<String>,
     timestamp: chrono::DateTime<chrono::Utc>,
+    /// The probed CID this responds to, which may be encoded differently in the response.
     cid: cid::Cid,
     response: BroadcastResponseType,
 }
@@ -395,6 +457,7 @@ impl Probe {
         amqp_address: &str,
         api_base_url: &str,
         cids_of_interest: &[cid::Cid],
+        cid_normalization: CidNormalization,
         monitor_name: &str,
     ) -> Result<Probe> {
         // Connect to node's plugin API.
@@ -413,11 +476,16 @@ impl Probe {
 
         let monitoring_client = tokio::time::timeout(
             time::Duration::from_secs(30),
-            MonitoringClient::new(
+            MonitoringClient::new_with_options(
                 amqp_address,
                 &vec![RoutingKeyInformation::BitswapMessages {
                     monitor_name: monitor_name.to_string(),
                 }],
+                MonitoringClientOptions {
+                    reconnect: Some(ReconnectConfig::default()),
+                    skip_undecodable: true,
+                    ..Default::default()
+                },
             ),
         )
         .await
@@ -425,19 +493,42 @@ impl Probe {
         .context("unable to initiate monitoring client")?;
         info!("connected to monitor {} at {}", monitor_name, amqp_address);
 
+        Self::with_source(
+            client,
+            monitoring_client,
+            cids_of_interest,
+            cid_normalization,
+            monitor_name,
+        )
+        .await
+    }
+
+    /// Sets up a probe using the given API client and source of monitoring events.
+    /// This returns once the first event has been received from the source.
+    async fn with_source<S: MonitoringSource + 'static>(
+        client: APIClient,
+        monitoring_client: S,
+        cids_of_interest: &[cid::Cid],
+        cid_normalization: CidNormalization,
+        monitor_name: &str,
+    ) -> Result<Probe> {
         // Set up some plumbing.
         let (res_tx, res_rx) = tokio::sync::oneshot::channel();
         let (ready_tx, ready_rx) = tokio::sync::oneshot::channel();
         let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
 
         // Fire off a worker to handle the monitoring.
-        let cids = cids_of_interest.to_vec();
+        let cids = cids_of_interest
+            .iter()
+            .map(|c| (cid_key(cid_normalization, &c.to_string()), *c))
+            .collect();
         let monitor_name = monitor_name.to_string();
         tokio::spawn(Self::receive_messages(
             monitor_name,
             monitoring_client,
             shutdown_rx,
-            cids.into_iter().collect(),
+            cid_normalization,
+            cids,
             res_tx,
             ready_tx,
         ));
@@ -464,6 +555,7 @@ impl Probe {
                 cancel_after_seconds,
             )
             .await
+            .map_err(|err| err.into())
     }
 
     async fn close(self) -> Result<Vec<BroadcastResponse>> {
@@ -486,11 +578,12 @@ impl Probe {
         messages
     }
 
-    async fn receive_messages(
+    async fn receive_messages<S: MonitoringSource>(
         monitor_name: String,
-        mut monitoring_client: MonitoringClient,
+        mut monitoring_client: S,
         mut shutdown_rx: tokio::sync::oneshot::Receiver<()>,
-        cids_of_interest: HashSet<cid::Cid>,
+        cid_normalization: CidNormalization,
+        cids_of_interest: HashMap<String, cid::Cid>,
         res_chan: tokio::sync::oneshot::Sender<Result<Vec<BroadcastResponse>>>,
         ready_chan: tokio::sync::oneshot::Sender<()>,
     ) {
@@ -514,9 +607,13 @@ impl Probe {
                                     error!("{}: unable to receive messages: {:?}",monitor_name,e);
                                     break
                                 }
-                                Ok((_,events)) => {
+                                Ok(MonitoringItem::Gap(gap)) => {
+                                    warn!("{}: reconnected, missed messages from {} to {}",monitor_name,gap.disconnected_at,gap.reconnected_at);
+                                }
+                                Ok(MonitoringItem::Events(_,events)) => {
                                     for event in events.into_iter() {
                                         if let Err(e) = Self::handle_message(&monitor_name,
+                                            cid_normalization,
                                             &cids_of_interest,
                                             event,
                                             &mut first,
@@ -546,7 +643,8 @@ impl Probe {
 
     fn handle_message(
         monitor_name: &str,
-        cids_of_interest: &HashSet<cid::Cid>,
+        cid_normalization: CidNormalization,
+        cids_of_interest: &HashMap<String, cid::Cid>,
         event: PushedEvent,
         first: &mut bool,
         responses: &mut Vec<BroadcastResponse>,
@@ -577,66 +675,45 @@ impl Probe {
                 // We only care for blocks and block presences.
                 if !msg.blocks.is_empty() {
                     for entry in msg.blocks.iter() {
-                        let c = cid::Cid::from_str(&entry.path);
-                        match c {
-                            Ok(c) => {
-                                //let c1 = Cid::new_v1(c.codec(), c.hash().clone());
-                                if cids_of_interest.contains(&c) {
-                                    debug!("{} {:9} {}", ident, "BLOCK", entry.path);
-                                    responses.push(BroadcastResponse {
-                                        peer: event.peer.clone(),
-                                        connected_addrs: msg.connected_addresses.clone(),
-                                        timestamp: event.timestamp,
-                                        cid: c,
-                                        response: BroadcastResponseType::Block,
-                                    });
-                                }
-                            }
-                            Err(e) => {
-                                error!(
-                                    "{}: unable to decode incoming CID {}: {:?}",
-                                    monitor_name, entry.path, e
-                                )
-                            }
+                        if let Some(c) =
+                            cids_of_interest.get(&cid_key(cid_normalization, &entry.path))
+                        {
+                            debug!("{} {:9} {}", ident, "BLOCK", entry.path);
+                            responses.push(BroadcastResponse {
+                                peer: event.peer.clone(),
+                                connected_addrs: msg.connected_addresses.clone(),
+                                timestamp: event.timestamp,
+                                cid: *c,
+                                response: BroadcastResponseType::Block,
+                            });
                         }
                     }
                 }
 
                 if !msg.block_presences.is_empty() {
                     for entry in msg.block_presences.iter() {
-                        let c = cid::Cid::from_str(&entry.cid.path);
-                        match c {
-                            Ok(c) => {
-                                //let c = Cid::new_v1(c.codec(), c.hash().clone());
-                                if cids_of_interest.contains(&c) {
-                                    debug!(
-                                        "{} {:9} {}",
-                                        ident,
-                                        match entry.block_presence_type {
-                                            monitoring::BlockPresenceType::Have =>
-                                                "HAVE".to_string(),
-                                            monitoring::BlockPresenceType::DontHave =>
-                                                "DONT_HAVE".to_string(),
-                                        },
-                                        entry.cid.path
-                                    );
-                                    responses.push(BroadcastResponse {
-                                        peer: event.peer.clone(),
-                                        connected_addrs: msg.connected_addresses.clone(),
-                                        timestamp: event.timestamp,
-                                        cid: c,
-                                        response: BroadcastResponseType::BlockPresence {
-                                            presence_type: entry.block_presence_type,
-                                        },
-                                    });
-                                }
-                            }
-                            Err(e) => {
-                                error!(
-                                    "{}: unable to decode incoming CID {}: {:?}",
-                                    monitor_name, entry.cid.path, e
-                                )
-                            }
+                        if let Some(c) =
+                            cids_of_interest.get(&cid_key(cid_normalization, &entry.cid.path))
+                        {
+                            debug!(
+                                "{} {:9} {}",
+                                ident,
+                                match entry.block_presence_type {
+                                    monitoring::BlockPresenceType::Have => "HAVE".to_string(),
+                                    monitoring::BlockPresenceType::DontHave =>
+                                        "DONT_HAVE".to_string(),
+                                },
+                                entry.cid.path
+                            );
+                            responses.push(BroadcastResponse {
+                                peer: event.peer.clone(),
+                                connected_addrs: msg.connected_addresses.clone(),
+                                timestamp: event.timestamp,
+                                cid: *c,
+                                response: BroadcastResponseType::BlockPresence {
+                                    presence_type: entry.block_presence_type,
+                                },
+                            });
                         }
                     }
                 }
@@ -645,3 +722,118 @@ impl Probe {
         Ok(())
     }
 }
+
+/// Returns the key by which the given CID is compared to the probed CIDs.
+/// This is the normalized CID, or the CID as given if normalization is disabled.
+fn cid_key(cid_normalization: CidNormalization, cid: &str) -> String {
+    cid_normalization
+        .normalize(cid)
+        .unwrap_or_else(|| cid.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipfs_monitoring_plugin_client::http::{
+        BroadcastBitswapWantCancelCancelEntry, BroadcastBitswapWantCancelResponse,
+        BroadcastBitswapWantCancelWantEntry,
+    };
+    use ipfs_monitoring_plugin_client::tcp::TCPMonitoringClient;
+    use ipfs_monitoring_plugin_mock::api::{Endpoint, MockPluginAPI, ScriptedResponse};
+    use ipfs_monitoring_plugin_mock::events;
+    use ipfs_monitoring_plugin_mock::publisher::{MockPublisher, ScriptStep};
+
+    const CID: &str = "QmPZ9gcCEpqKTo6aq61g2nXGUhM4iCL3ewB6LDXZCtioEB";
+
+    /// Probes for `probed` against a mock monitor, which responds with a HAVE for `responded`.
+    async fn probe_with_response(
+        probed: &str,
+        responded: &str,
+        cid_normalization: CidNormalization,
+    ) -> (cid::Cid, Vec<BroadcastResponse>) {
+        let api = MockPluginAPI::start().unwrap();
+        let ts = chrono::Utc::now();
+        api.push_response(
+            Endpoint::BroadcastWantCancel,
+            ScriptedResponse::result(&BroadcastBitswapWantCancelResponse {
+                peers: vec![BroadcastBitswapWantCancelEntry {
+                    peer: "peer".to_string(),
+                    want_status: BroadcastBitswapWantCancelWantEntry {
+                        timestamp_before_send: ts,
+                        send_duration_millis: 1,
+                        error: None,
+                        request_type_sent: None,
+                    },
+                    cancel_status: BroadcastBitswapWantCancelCancelEntry {
+                        timestamp_before_send: ts,
+                        send_duration_millis: 1,
+                        error: None,
+                    },
+                }],
+            }),
+        );
+
+        let publisher = MockPublisher::start().await.unwrap();
+        let source = TCPMonitoringClient::new(&publisher.address(), "mock")
+            .await
+            .unwrap();
+        let script = publisher.play(vec![
+            ScriptStep::WaitForConsumers(1),
+            // The probe is ready once it receives the first event, so we publish the response
+            // right away.
+            ScriptStep::Publish(vec![events::block_presence(
+                "peer",
+                responded,
+                BlockPresenceType::Have,
+            )]),
+        ]);
+
+        let cids = vec![cid::Cid::from_str(probed).unwrap()];
+        let api_client = APIClient::new(&api.base_url()).unwrap();
+        let (probe, script_res) = tokio::join!(
+            Probe::with_source(api_client, source, &cids, cid_normalization, "mock"),
+            script
+        );
+        script_res.unwrap();
+        let probe = probe.unwrap();
+
+        let broadcast = probe.broadcast(&cids, 5).await.unwrap();
+        assert_eq!(broadcast.len(), 1);
+        assert_eq!(broadcast[0].peer, "peer");
+        let requests = api.requests_to(Endpoint::BroadcastWantCancel);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].body["seconds_before_cancel"], 5);
+
+        (cids[0], probe.close().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn probe_against_mock_monitor() {
+        let (cid, responses) = probe_with_response(CID, CID, CidNormalization::None).await;
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].peer, "peer");
+        assert_eq!(responses[0].cid, cid);
+        assert!(matches!(
+            responses[0].response,
+            BroadcastResponseType::BlockPresence {
+                presence_type: BlockPresenceType::Have
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn matches_responses_for_other_cid_versions() {
+        let cid_v1 = CidNormalization::CodecAndMultihash.normalize(CID).unwrap();
+        assert_ne!(cid_v1, CID);
+
+        let (cid, responses) =
+            probe_with_response(CID, &cid_v1, CidNormalization::CodecAndMultihash).await;
+        assert_eq!(responses.len(), 1);
+        // The response is attributed to the CID as probed.
+        assert_eq!(responses[0].cid, cid);
+        assert_eq!(responses[0].cid.to_string(), CID);
+
+        let (_, responses) = probe_with_response(CID, &cid_v1, CidNormalization::None).await;
+        assert!(responses.is_empty());
+    }
+}
@@ -0,0 +1,432 @@
+use crate::engine::EngineRegistry;
+use crate::heavy_hitters::{HeavyHitters, SpaceSaving, WindowHeavyHitters};
+use failure::ResultExt;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use ipfs_monitoring_plugin_client::http::JSONResponse;
+use ipfs_resolver_common::wantlist::{
+    WantlistEntry, CSV_ENTRY_TYPE_WANT_BLOCK, CSV_ENTRY_TYPE_WANT_BLOCK_SEND_DONT_HAVE,
+    CSV_ENTRY_TYPE_WANT_HAVE,
+};
+use ipfs_resolver_common::Result;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+pub(crate) const API_BASE_PATH: &str = "/wantlist_api/v1";
+pub(crate) const API_PATH_PEERS_WANTING: &str = "/peers_wanting";
+pub(crate) const API_PATH_WANTLIST: &str = "/wantlist";
+pub(crate) const API_PATH_TOP_CIDS: &str = "/top_cids";
+pub(crate) const API_PATH_HEAVY_HITTERS: &str = "/heavy_hitters";
+
+/// The number of top requested CIDs returned if no limit is given.
+const DEFAULT_TOP_CIDS_LIMIT: usize = 10;
+
+/// An entry currently wanted by a peer, as seen by a monitor.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct CurrentWant {
+    pub(crate) monitor: String,
+    pub(crate) peer: String,
+    /// The CID as was sent in the last request.
+    pub(crate) cid: String,
+    /// The normalized CID, if CID normalization is enabled.
+    pub(crate) normalized_cid: Option<String>,
+    /// Either `want_block` or `want_have`.
+    pub(crate) want_type: &'static str,
+    pub(crate) send_dont_have: bool,
+    /// When the peer started to want the entry.
+    pub(crate) first_requested_at: chrono::DateTime<chrono::Utc>,
+    /// When the peer last requested the entry.
+    pub(crate) last_requested_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl CurrentWant {
+    fn new(monitor: &str, peer: &str, entry: &WantlistEntry) -> CurrentWant {
+        let entry_type = entry.csv_entry_type();
+        CurrentWant {
+            monitor: monitor.to_string(),
+            peer: peer.to_string(),
+            cid: entry.sent_cid().to_string(),
+            normalized_cid: entry.normalized_cid().map(|c| c.to_string()),
+            want_type: match entry_type {
+                CSV_ENTRY_TYPE_WANT_BLOCK | CSV_ENTRY_TYPE_WANT_BLOCK_SEND_DONT_HAVE => {
+                    "want_block"
+                }
+                _ => "want_have",
+            },
+            send_dont_have: entry_type != CSV_ENTRY_TYPE_WANT_BLOCK
+                && entry_type != CSV_ENTRY_TYPE_WANT_HAVE,
+            first_requested_at: entry.first_requested_at(),
+            last_requested_at: entry.last_requested_at(),
+        }
+    }
+}
+
+/// The response to a query for the peers currently wanting a CID, across all monitors.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct PeersWantingResponse {
+    pub(crate) cid: String,
+    pub(crate) wants: Vec<CurrentWant>,
+}
+
+/// The response to a query for the current wantlist of a peer, across all monitors.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct WantlistResponse {
+    pub(crate) peer: String,
+    pub(crate) wants: Vec<CurrentWant>,
+}
+
+/// A CID with the estimated number of non-duplicate requests for it, across all monitors.
+/// The estimate exceeds the true count by at most `error`.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct TopCID {
+    pub(crate) cid: String,
+    pub(crate) count: u64,
+    pub(crate) error: u64,
+}
+
+/// The response to a query for the top requested CIDs of the last minutes.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct TopCIDsResponse {
+    pub(crate) minutes: u32,
+    pub(crate) cids: Vec<TopCID>,
+}
+
+/// The response to a query for the heavy hitters of a monitor, or of all monitors.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct HeavyHittersResponse {
+    /// The monitor queried, or `None` for all monitors.
+    pub(crate) monitor: Option<String>,
+    pub(crate) windows: Vec<WindowHeavyHitters>,
+}
+
+/// An HTTP API to query the current wantlists of the engine simulations of all monitors, and the
+/// heavy hitters.
+/// Responses are JSON, in the same envelope the monitoring plugin uses.
+/// Queries lock the simulations, so they are answered on the blocking thread pool, to not stall
+/// the tasks processing events.
+pub(crate) struct WantlistAPI {
+    registry: EngineRegistry,
+    top_cids_max_minutes: u32,
+    heavy_hitters: Option<Arc<HeavyHitters>>,
+}
+
+impl WantlistAPI {
+    pub(crate) fn new(
+        registry: EngineRegistry,
+        top_cids_max_minutes: u32,
+        heavy_hitters: Option<Arc<HeavyHitters>>,
+    ) -> WantlistAPI {
+        WantlistAPI {
+            registry,
+            top_cids_max_minutes,
+            heavy_hitters,
+        }
+    }
+
+    /// Starts serving the API on the given address.
+    /// This must be called from within a Tokio runtime.
+    pub(crate) fn run(self, addr: SocketAddr) -> Result<()> {
+        let api = Arc::new(self);
+        let make_svc = make_service_fn(move |_| {
+            let api = api.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let api = api.clone();
+                    async move { Ok::<_, Infallible>(api.handle(req).await) }
+                }))
+            }
+        });
+
+        let server = Server::try_bind(&addr)
+            .context("unable to bind wantlist API")?
+            .serve(make_svc);
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                error!("wantlist API failed: {}", err)
+            }
+        });
+
+        Ok(())
+    }
+
+    async fn handle(self: Arc<Self>, req: Request<Body>) -> Response<Body> {
+        debug!("wantlist API: {} {}", req.method(), req.uri());
+        let (status, body) = if req.method() != Method::GET {
+            Self::error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+        } else {
+            let path = req.uri().path().to_string();
+            let params: HashMap<String, String> = req
+                .uri()
+                .query()
+                .map(|q| form_urlencoded::parse(q.as_bytes()).into_owned().collect())
+                .unwrap_or_default();
+            match tokio::task::spawn_blocking(move || self.query(&path, &params)).await {
+                Ok(res) => res,
+                Err(err) => {
+                    error!("wantlist API: query failed: {}", err);
+                    Self::error(StatusCode::INTERNAL_SERVER_ERROR, "query failed")
+                }
+            }
+        };
+
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    /// Answers a query for the given path and query parameters.
+    /// Returns the HTTP status and the JSON body of the response.
+    fn query(&self, path: &str, params: &HashMap<String, String>) -> (StatusCode, String) {
+        let path = path.strip_prefix(API_BASE_PATH);
+        if let Some(API_PATH_HEAVY_HITTERS) = path {
+            return self.query_heavy_hitters(params);
+        }
+        if path.is_some() && !self.registry.is_enabled() {
+            return Self::error(StatusCode::NOT_FOUND, "engine simulation not configured");
+        }
+
+        match path {
+            Some(API_PATH_PEERS_WANTING) => match params.get("cid") {
+                Some(cid) => Self::result(&self.peers_wanting(cid)),
+                None => Self::error(StatusCode::BAD_REQUEST, "missing cid"),
+            },
+            Some(API_PATH_WANTLIST) => match params.get("peer") {
+                Some(peer) => Self::result(&self.wantlist(peer)),
+                None => Self::error(StatusCode::BAD_REQUEST, "missing peer"),
+            },
+            Some(API_PATH_TOP_CIDS) => {
+                let minutes = match params.get("minutes").map(|m| m.parse::<u32>()) {
+                    None => self.top_cids_max_minutes,
+                    Some(Ok(m)) if m > 0 && m <= self.top_cids_max_minutes => m,
+                    Some(_) => {
+                        return Self::error(
+                            StatusCode::BAD_REQUEST,
+                            &format!(
+                                "minutes must be between 1 and {}",
+                                self.top_cids_max_minutes
+                            ),
+                        )
+                    }
+                };
+                let limit = match params.get("limit").map(|l| l.parse::<usize>()) {
+                    None => DEFAULT_TOP_CIDS_LIMIT,
+                    Some(Ok(l)) => l,
+                    Some(Err(_)) => return Self::error(StatusCode::BAD_REQUEST, "invalid limit"),
+                };
+                Self::result(&self.top_cids(minutes, limit))
+            }
+            _ => Self::error(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+
+    fn query_heavy_hitters(&self, params: &HashMap<String, String>) -> (StatusCode, String) {
+        let heavy_hitters = match &self.heavy_hitters {
+            Some(hh) => hh,
+            None => return Self::error(StatusCode::NOT_FOUND, "heavy hitters not configured"),
+        };
+        let n = match params.get("n").map(|n| n.parse::<usize>()) {
+            None => heavy_hitters.top_n(),
+            Some(Ok(n)) => n,
+            Some(Err(_)) => return Self::error(StatusCode::BAD_REQUEST, "invalid n"),
+        };
+        let monitor = params.get("monitor").map(|m| m.as_str());
+
+        match heavy_hitters.query(monitor, params.get("window").map(|w| w.as_str()), n) {
+            Some(windows) => Self::result(&HeavyHittersResponse {
+                monitor: monitor.map(|m| m.to_string()),
+                windows,
+            }),
+            None => Self::error(StatusCode::NOT_FOUND, "unknown monitor"),
+        }
+    }
+
+    fn peers_wanting(&self, cid: &str) -> PeersWantingResponse {
+        let mut wants = Vec::new();
+        for (monitor, engine) in self.registry.engines() {
+            let engine = engine.lock().unwrap();
+            wants.extend(
+                engine
+                    .simulation()
+                    .peers_wanting(cid)
+                    .into_iter()
+                    .map(|(peer, entry)| CurrentWant::new(&monitor, peer, entry)),
+            );
+        }
+        // Sort by monitor and then peer, to make the response deterministic.
+        wants.sort_by(|w1, w2| (&w1.monitor, &w1.peer).cmp(&(&w2.monitor, &w2.peer)));
+
+        PeersWantingResponse {
+            cid: cid.to_string(),
+            wants,
+        }
+    }
+
+    fn wantlist(&self, peer: &str) -> WantlistResponse {
+        let mut wants = Vec::new();
+        for (monitor, engine) in self.registry.engines() {
+            let engine = engine.lock().unwrap();
+            wants.extend(
+                engine
+                    .simulation()
+                    .wanted_entries(peer)
+                    .into_iter()
+                    .flatten()
+                    .map(|entry| CurrentWant::new(&monitor, peer, entry)),
+            );
+        }
+
+        WantlistResponse {
+            peer: peer.to_string(),
+            wants,
+        }
+    }
+
+    fn top_cids(&self, minutes: u32, limit: usize) -> TopCIDsResponse {
+        let sketches = self
+            .registry
+            .engines()
+            .into_iter()
+            .flat_map(|(_, engine)| engine.lock().unwrap().recent_request_counts(minutes))
+            .collect::<Vec<_>>();
+        let cids = SpaceSaving::merge(&sketches, self.registry.recent_requests_capacity())
+            .top(limit)
+            .into_iter()
+            .map(|hh| TopCID {
+                cid: hh.item,
+                count: hh.count,
+                error: hh.error,
+            })
+            .collect();
+
+        TopCIDsResponse { minutes, cids }
+    }
+
+    fn result<T: Serialize>(result: &T) -> (StatusCode, String) {
+        (
+            StatusCode::OK,
+            serde_json::to_string(&JSONResponse {
+                status: 200,
+                result: Some(result),
+                error: None,
+            })
+            .unwrap(),
+        )
+    }
+
+    fn error(status: StatusCode, error: &str) -> (StatusCode, String) {
+        (
+            status,
+            serde_json::to_string(&JSONResponse::<()> {
+                status: status.as_u16() as i32,
+                result: None,
+                error: Some(error.to_string()),
+            })
+            .unwrap(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LiveEngineSimulationConfig;
+    use ipfs_monitoring_plugin_client::monitoring::PushedEvent;
+    use ipfs_resolver_common::wantlist::EngineSimulationConfig;
+
+    const EVENTS: &str = r#"{"timestamp":"2024-01-01T00:00:00Z","peer":"QmPeer1","bitswap_message":{"wantlist_entries":[{"priority":1,"cancel":false,"send_dont_have":true,"cid":{"/":"QmA"},"want_type":1},{"priority":1,"cancel":false,"send_dont_have":false,"cid":{"/":"QmB"},"want_type":0}],"full_wantlist":false,"blocks":[],"block_presences":[],"connected_addresses":[]}}
+{"timestamp":"2024-01-01T00:01:00Z","peer":"QmPeer2","bitswap_message":{"wantlist_entries":[{"priority":1,"cancel":false,"send_dont_have":false,"cid":{"/":"QmA"},"want_type":0}],"full_wantlist":false,"blocks":[],"block_presences":[],"connected_addresses":[]}}
+{"timestamp":"2024-01-01T00:02:00Z","peer":"QmPeer1","bitswap_message":{"wantlist_entries":[{"priority":1,"cancel":true,"send_dont_have":false,"cid":{"/":"QmB"},"want_type":0}],"full_wantlist":false,"blocks":[],"block_presences":[],"connected_addresses":[]}}"#;
+
+    fn api_with_events() -> WantlistAPI {
+        let registry = EngineRegistry::new(
+            Some(LiveEngineSimulationConfig {
+                simulation_config: EngineSimulationConfig::default(),
+                evict_disconnected_after_secs: 3600,
+            }),
+            60,
+            100,
+        );
+        let engine = registry.create("test-api").unwrap().unwrap();
+        for l in EVENTS.lines() {
+            let event: PushedEvent = serde_json::from_str(l).unwrap();
+            engine.lock().unwrap().handle_event(&event).unwrap();
+        }
+
+        WantlistAPI::new(registry, 60, None)
+    }
+
+    fn query(api: &WantlistAPI, path: &str, params: &[(&str, &str)]) -> serde_json::Value {
+        let params = params
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let (status, body) = api.query(&format!("{}{}", API_BASE_PATH, path), &params);
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        serde_json::from_str::<serde_json::Value>(&body).unwrap()["result"].clone()
+    }
+
+    #[test]
+    fn answers_queries() {
+        let api = api_with_events();
+
+        let wants = query(&api, API_PATH_PEERS_WANTING, &[("cid", "QmA")])["wants"].clone();
+        assert_eq!(wants.as_array().unwrap().len(), 2);
+        assert_eq!(wants[0]["monitor"], "test-api");
+        assert_eq!(wants[0]["peer"], "QmPeer1");
+        assert_eq!(wants[0]["want_type"], "want_have");
+        assert_eq!(wants[0]["send_dont_have"], true);
+        assert_eq!(wants[0]["first_requested_at"], "2024-01-01T00:00:00Z");
+        assert_eq!(wants[1]["peer"], "QmPeer2");
+        assert_eq!(wants[1]["want_type"], "want_block");
+
+        let wants = query(&api, API_PATH_WANTLIST, &[("peer", "QmPeer1")])["wants"].clone();
+        assert_eq!(wants.as_array().unwrap().len(), 1);
+        assert_eq!(wants[0]["cid"], "QmA");
+
+        let top = query(&api, API_PATH_TOP_CIDS, &[("minutes", "2")]);
+        assert_eq!(top["cids"][0]["cid"], "QmA");
+        assert_eq!(top["cids"][0]["count"], 1);
+        assert_eq!(top["cids"].as_array().unwrap().len(), 1);
+        let top = query(&api, API_PATH_TOP_CIDS, &[("limit", "1")]);
+        assert_eq!(top["cids"][0]["cid"], "QmA");
+        assert_eq!(top["cids"][0]["count"], 2);
+        assert_eq!(top["cids"][0]["error"], 0);
+
+        let (status, _) = api.query(
+            &format!("{}{}", API_BASE_PATH, API_PATH_TOP_CIDS),
+            &[("minutes".to_string(), "61".to_string())].into(),
+        );
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = api.query(
+            &format!("{}{}", API_BASE_PATH, API_PATH_HEAVY_HITTERS),
+            &HashMap::new(),
+        );
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn answers_requests_off_the_runtime() {
+        let api = Arc::new(api_with_events());
+        let uri = format!("{}{}?cid=QmA", API_BASE_PATH, API_PATH_PEERS_WANTING);
+
+        let resp = api
+            .clone()
+            .handle(Request::get(&uri).body(Body::empty()).unwrap())
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(body["result"]["wants"].as_array().unwrap().len(), 2);
+
+        let resp = api
+            .handle(Request::post(&uri).body(Body::empty()).unwrap())
+            .await;
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
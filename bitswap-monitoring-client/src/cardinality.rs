@@ -0,0 +1,599 @@
+use crate::config::{CardinalityConfig, WindowConfig};
+use crate::heavy_hitters::GLOBAL_LABEL;
+use crate::prom::{MetricsKey, COUNTRY_NAME_ERROR, DISTINCT_CIDS, DISTINCT_PEERS};
+use failure::{ensure, ResultExt};
+use ipfs_resolver_common::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use twox_hash::XxHash64;
+
+/// The interval in which the gauges are updated, in wall-clock time.
+const GAUGE_UPDATE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// A HyperLogLog sketch, which estimates the number of distinct items inserted.
+///
+/// Sketches of the same precision can be merged, which estimates the number of distinct items of
+/// all their inputs.
+/// Registers are kept sparsely until enough of them are set, since most sketches, e.g., those of
+/// rare countries, only ever see a few items.
+/// See Flajolet et al., "HyperLogLog: the analysis of a near-optimal cardinality estimation
+/// algorithm".
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct HyperLogLog {
+    precision: u8,
+    registers: Registers,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Registers {
+    /// The registers which are not zero, by index.
+    Sparse(BTreeMap<u16, u8>),
+    Dense(Vec<u8>),
+}
+
+impl HyperLogLog {
+    /// Creates an empty sketch with 2^precision registers.
+    /// The precision must be between 4 and 16.
+    pub(crate) fn new(precision: u8) -> HyperLogLog {
+        HyperLogLog {
+            precision,
+            registers: Registers::Sparse(BTreeMap::new()),
+        }
+    }
+
+    fn num_registers(&self) -> usize {
+        1 << self.precision
+    }
+
+    /// Inserts an item.
+    /// Items are hashed with a fixed seed, so sketches remain valid across restarts.
+    pub(crate) fn insert(&mut self, item: &str) {
+        let mut hasher = XxHash64::with_seed(0);
+        hasher.write(item.as_bytes());
+        let hash = hasher.finish();
+
+        let index = (hash >> (64 - self.precision)) as u16;
+        // The position of the first set bit of the remaining bits, counting from one.
+        let rank = (hash << self.precision)
+            .leading_zeros()
+            .min(64 - self.precision as u32)
+            + 1;
+        self.update(index, rank as u8)
+    }
+
+    fn update(&mut self, index: u16, rank: u8) {
+        let num_registers = self.num_registers();
+        match &mut self.registers {
+            Registers::Sparse(registers) => {
+                let register = registers.entry(index).or_default();
+                *register = (*register).max(rank);
+                // A sparse register takes about eight bytes, a dense one takes one.
+                if registers.len() * 8 > num_registers {
+                    self.densify()
+                }
+            }
+            Registers::Dense(registers) => {
+                let register = &mut registers[index as usize];
+                *register = (*register).max(rank);
+            }
+        }
+    }
+
+    fn densify(&mut self) {
+        let dense = match &self.registers {
+            Registers::Sparse(registers) => {
+                let mut dense = vec![0; self.num_registers()];
+                for (index, rank) in registers.iter() {
+                    dense[*index as usize] = *rank;
+                }
+                dense
+            }
+            Registers::Dense(_) => return,
+        };
+        self.registers = Registers::Dense(dense);
+    }
+
+    /// Merges the given sketch, which must be of the same precision, into this one.
+    pub(crate) fn merge(&mut self, other: &HyperLogLog) {
+        debug_assert_eq!(self.precision, other.precision);
+        match &other.registers {
+            Registers::Sparse(registers) => registers
+                .iter()
+                .for_each(|(index, rank)| self.update(*index, *rank)),
+            Registers::Dense(other_registers) => {
+                self.densify();
+                if let Registers::Dense(registers) = &mut self.registers {
+                    registers
+                        .iter_mut()
+                        .zip(other_registers.iter())
+                        .for_each(|(r, o)| *r = (*r).max(*o));
+                }
+            }
+        }
+    }
+
+    /// Estimates the number of distinct items inserted.
+    pub(crate) fn estimate(&self) -> f64 {
+        let m = self.num_registers() as f64;
+        let (sum, zeros) = match &self.registers {
+            Registers::Sparse(registers) => {
+                let zeros = self.num_registers() - registers.len();
+                let sum = registers
+                    .values()
+                    .map(|r| 2_f64.powi(-(*r as i32)))
+                    .sum::<f64>();
+                (sum + zeros as f64, zeros)
+            }
+            Registers::Dense(registers) => (
+                registers.iter().map(|r| 2_f64.powi(-(*r as i32))).sum(),
+                registers.iter().filter(|r| **r == 0).count(),
+            ),
+        };
+
+        let alpha = match self.precision {
+            4 => 0.673,
+            5 => 0.697,
+            6 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let raw = alpha * m * m / sum;
+        // The raw estimate is biased for small cardinalities, for which we use linear counting.
+        if raw <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        }
+    }
+}
+
+/// Sketches of the distinct peers sending wantlists and the distinct CIDs requested.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct DistinctSketches {
+    peers: HyperLogLog,
+    cids: HyperLogLog,
+}
+
+impl DistinctSketches {
+    fn new(precision: u8) -> DistinctSketches {
+        DistinctSketches {
+            peers: HyperLogLog::new(precision),
+            cids: HyperLogLog::new(precision),
+        }
+    }
+
+    fn merge(&mut self, other: &DistinctSketches) {
+        self.peers.merge(&other.peers);
+        self.cids.merge(&other.cids);
+    }
+}
+
+/// The origin of wantlists, by the values of the `origin_country` and `origin_is_gateway` labels.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+struct Origin {
+    origin_country: String,
+    origin_is_gateway: String,
+}
+
+/// Sketches by origin over a window of event time, in panes.
+/// A tumbling window consists of a single pane.
+struct PanedWindow {
+    name: String,
+    pane_secs: i64,
+    num_panes: i64,
+    /// Panes by index, oldest first.
+    panes: VecDeque<(i64, HashMap<Origin, DistinctSketches>)>,
+}
+
+impl PanedWindow {
+    fn new(cfg: &WindowConfig) -> PanedWindow {
+        let (pane_secs, num_panes) = cfg.panes();
+        PanedWindow {
+            name: cfg.name(),
+            pane_secs: pane_secs as i64,
+            num_panes: num_panes as i64,
+            panes: VecDeque::new(),
+        }
+    }
+
+    /// Advances the window to the given timestamp and returns the current pane.
+    /// Events of earlier panes, e.g., due to reordering, are counted in the current pane.
+    fn advance(
+        &mut self,
+        ts: chrono::DateTime<chrono::Utc>,
+    ) -> &mut HashMap<Origin, DistinctSketches> {
+        let pane = ts.timestamp().div_euclid(self.pane_secs);
+        if self.panes.back().is_none_or(|(p, _)| *p < pane) {
+            self.panes.push_back((pane, HashMap::new()));
+            while self
+                .panes
+                .front()
+                .is_some_and(|(p, _)| *p <= pane - self.num_panes)
+            {
+                self.panes.pop_front();
+            }
+        }
+        // We know this is safe since we just made sure there is a pane.
+        &mut self.panes.back_mut().unwrap().1
+    }
+}
+
+/// Estimates the number of distinct peers sending wantlists and distinct CIDs requested, per
+/// monitor, origin country, and gateway status, over the configured windows of event time.
+/// Estimates are merged over all monitors, all countries, and all origins, and exported as gauges.
+pub(crate) struct Cardinalities {
+    cfg: CardinalityConfig,
+    /// Windows by monitor.
+    monitors: Mutex<BTreeMap<String, Vec<PanedWindow>>>,
+    /// The label values of the gauges currently exported, to remove them once their windows
+    /// are empty.
+    exported: Mutex<HashSet<[String; 4]>>,
+}
+
+impl Cardinalities {
+    /// Creates sketches for the given configuration, restoring them from the state file, if it
+    /// exists.
+    pub(crate) fn new(cfg: CardinalityConfig) -> Result<Cardinalities> {
+        ensure!(
+            (4..=16).contains(&cfg.precision),
+            "precision must be between 4 and 16"
+        );
+        WindowConfig::validate_all(&cfg.windows).context("invalid windows")?;
+
+        let monitors = match &cfg.state_file {
+            Some(path) if Path::new(path).exists() => {
+                let f = File::open(path).context("unable to open state file")?;
+                let state = serde_json::from_reader(BufReader::new(f))
+                    .context("unable to read state file")?;
+                let monitors = Self::restore(&cfg, state);
+                info!(
+                    "restored distinct count sketches of {} monitors from {}",
+                    monitors.len(),
+                    path
+                );
+                monitors
+            }
+            _ => BTreeMap::new(),
+        };
+
+        Ok(Cardinalities {
+            cfg,
+            monitors: Mutex::new(monitors),
+            exported: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Counts a wantlist sent by the given peer, requesting the given CIDs, received by the given
+    /// monitor.
+    pub(crate) fn record<'a>(
+        &self,
+        monitor_name: &str,
+        ts: chrono::DateTime<chrono::Utc>,
+        key: &MetricsKey,
+        peer: &str,
+        cids: impl Iterator<Item = &'a str> + Clone,
+    ) {
+        let origin = Origin {
+            origin_country: key
+                .geo_origin
+                .country_name()
+                .unwrap_or(COUNTRY_NAME_ERROR)
+                .to_string(),
+            origin_is_gateway: key.overlay_origin.is_gateway_str().to_string(),
+        };
+
+        let mut monitors = self.monitors.lock().unwrap();
+        let windows = monitors
+            .entry(monitor_name.to_string())
+            .or_insert_with(|| self.cfg.windows.iter().map(PanedWindow::new).collect());
+        for window in windows.iter_mut() {
+            let sketches = window
+                .advance(ts)
+                .entry(origin.clone())
+                .or_insert_with(|| DistinctSketches::new(self.cfg.precision));
+            sketches.peers.insert(peer);
+            for cid in cids.clone() {
+                sketches.cids.insert(cid);
+            }
+        }
+    }
+
+    /// Merges the sketches of each window, keyed by the values of the `monitor`,
+    /// `origin_country`, `origin_is_gateway`, and `window` labels.
+    /// Each sketch is merged into those of its monitor and of all monitors, each for its origin,
+    /// its gateway status over all countries, and over all origins.
+    fn merged(&self) -> HashMap<[String; 4], DistinctSketches> {
+        let monitors = self.monitors.lock().unwrap();
+        let mut merged: HashMap<[String; 4], DistinctSketches> = HashMap::new();
+
+        for (monitor, windows) in monitors.iter() {
+            for window in windows.iter() {
+                for (origin, sketches) in window.panes.iter().flat_map(|(_, pane)| pane.iter()) {
+                    for monitor in [monitor.as_str(), GLOBAL_LABEL] {
+                        for (country, gateway) in [
+                            (
+                                origin.origin_country.as_str(),
+                                origin.origin_is_gateway.as_str(),
+                            ),
+                            (GLOBAL_LABEL, origin.origin_is_gateway.as_str()),
+                            (GLOBAL_LABEL, GLOBAL_LABEL),
+                        ] {
+                            merged
+                                .entry([monitor, country, gateway, &window.name].map(String::from))
+                                .or_insert_with(|| DistinctSketches::new(self.cfg.precision))
+                                .merge(sketches)
+                        }
+                    }
+                }
+            }
+        }
+
+        merged
+    }
+
+    /// Updates the gauges with the current estimates.
+    pub(crate) fn update_gauges(&self) {
+        let merged = self.merged();
+        let mut exported = self.exported.lock().unwrap();
+
+        for labels in exported.drain().filter(|l| !merged.contains_key(l)) {
+            let labels = labels.each_ref().map(|l| l.as_str());
+            // This fails if the gauge was never set, which is fine.
+            let _ = DISTINCT_PEERS.remove_label_values(&labels);
+            let _ = DISTINCT_CIDS.remove_label_values(&labels);
+        }
+        for (labels, sketches) in merged.into_iter() {
+            let label_values = labels.each_ref().map(|l| l.as_str());
+            DISTINCT_PEERS
+                .with_label_values(&label_values)
+                .set(sketches.peers.estimate().round() as i64);
+            DISTINCT_CIDS
+                .with_label_values(&label_values)
+                .set(sketches.cids.estimate().round() as i64);
+            exported.insert(labels);
+        }
+    }
+
+    /// Updates the gauges periodically, in wall-clock time, until the process exits.
+    pub(crate) fn spawn_gauge_updates(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(GAUGE_UPDATE_INTERVAL);
+            loop {
+                interval.tick().await;
+                self.update_gauges()
+            }
+        });
+    }
+
+    /// Writes the sketches to the state file, if configured.
+    pub(crate) fn save(&self) -> Result<()> {
+        let path = match &self.cfg.state_file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let state = self.persisted_state();
+
+        // Write to a temporary file first, to keep the previous state if writing fails.
+        let tmp_path = format!("{}.tmp", path);
+        let f = File::create(&tmp_path).context("unable to create state file")?;
+        let mut w = BufWriter::new(f);
+        serde_json::to_writer(&mut w, &state).context("unable to write state")?;
+        w.flush().context("unable to write state")?;
+        std::fs::rename(&tmp_path, path).context("unable to replace state file")?;
+
+        info!(
+            "persisted distinct count sketches of {} monitors to {}",
+            state.monitors.len(),
+            path
+        );
+        Ok(())
+    }
+
+    fn persisted_state(&self) -> PersistedState {
+        let monitors = self.monitors.lock().unwrap();
+        PersistedState {
+            precision: self.cfg.precision,
+            monitors: monitors
+                .iter()
+                .map(|(monitor, windows)| {
+                    let windows = windows
+                        .iter()
+                        .map(|w| PersistedWindow {
+                            name: w.name.clone(),
+                            pane_secs: w.pane_secs,
+                            panes: w
+                                .panes
+                                .iter()
+                                .map(|(index, pane)| PersistedPane {
+                                    index: *index,
+                                    sketches: pane
+                                        .iter()
+                                        .map(|(o, s)| (o.clone(), s.clone()))
+                                        .collect(),
+                                })
+                                .collect(),
+                        })
+                        .collect();
+                    (monitor.clone(), windows)
+                })
+                .collect(),
+        }
+    }
+
+    /// Restores the windows of each monitor from persisted state.
+    /// Persisted windows which are no longer configured are discarded, as are all sketches if the
+    /// precision changed.
+    fn restore(
+        cfg: &CardinalityConfig,
+        state: PersistedState,
+    ) -> BTreeMap<String, Vec<PanedWindow>> {
+        if state.precision != cfg.precision {
+            warn!(
+                "discarding persisted distinct count sketches of precision {}, configured precision is {}",
+                state.precision, cfg.precision
+            );
+            return BTreeMap::new();
+        }
+
+        state
+            .monitors
+            .into_iter()
+            .map(|(monitor, persisted)| {
+                let mut persisted = persisted
+                    .into_iter()
+                    .map(|w| ((w.name, w.pane_secs), w.panes))
+                    .collect::<HashMap<_, _>>();
+                let windows = cfg
+                    .windows
+                    .iter()
+                    .map(|w| {
+                        let mut window = PanedWindow::new(w);
+                        if let Some(panes) =
+                            persisted.remove(&(window.name.clone(), window.pane_secs))
+                        {
+                            window.panes = panes
+                                .into_iter()
+                                .map(|p| (p.index, p.sketches.into_iter().collect()))
+                                .collect();
+                        }
+                        window
+                    })
+                    .collect();
+                (monitor, windows)
+            })
+            .collect()
+    }
+}
+
+/// The sketches of all monitors, as persisted to the state file.
+#[derive(Serialize, Deserialize)]
+struct PersistedState {
+    precision: u8,
+    monitors: BTreeMap<String, Vec<PersistedWindow>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedWindow {
+    name: String,
+    pane_secs: i64,
+    panes: Vec<PersistedPane>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedPane {
+    index: i64,
+    sketches: Vec<(Origin, DistinctSketches)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prom::{Geolocation, PublicGatewayStatus};
+
+    fn assert_close(estimate: f64, expected: f64) {
+        assert!(
+            (estimate - expected).abs() <= expected * 0.05,
+            "estimate {} too far from {}",
+            estimate,
+            expected
+        );
+    }
+
+    #[test]
+    fn hyperloglog_estimates_distinct_items() {
+        let mut small = HyperLogLog::new(12);
+        for i in 0..10 {
+            small.insert(&format!("item-{}", i));
+            small.insert(&format!("item-{}", i));
+        }
+        assert!(matches!(small.registers, Registers::Sparse(_)));
+        // Small cardinalities are estimated almost exactly, up to collisions of registers.
+        assert!((small.estimate() - 10.0).abs() <= 1.0);
+
+        let mut a = HyperLogLog::new(12);
+        let mut b = HyperLogLog::new(12);
+        for i in 0..6000 {
+            a.insert(&format!("item-{}", i));
+            b.insert(&format!("item-{}", i + 4000));
+        }
+        assert!(matches!(a.registers, Registers::Dense(_)));
+        assert_close(a.estimate(), 6000.0);
+
+        a.merge(&b);
+        assert_close(a.estimate(), 10000.0);
+
+        // Merging a sparse sketch is the same as inserting its items.
+        let mut merged = small.clone();
+        merged.merge(&b);
+        for i in 0..10 {
+            b.insert(&format!("item-{}", i));
+        }
+        assert_eq!(merged, b);
+    }
+
+    #[test]
+    fn merges_windows_and_persists() {
+        let cfg = CardinalityConfig {
+            precision: 10,
+            windows: vec![
+                WindowConfig::Tumbling { length_secs: 86400 },
+                WindowConfig::Sliding {
+                    length_secs: 120,
+                    num_panes: 2,
+                },
+            ],
+            state_file: None,
+        };
+        let cardinalities = Cardinalities::new(cfg.clone()).unwrap();
+        let ts = |secs| chrono::DateTime::from_timestamp(secs, 0).unwrap();
+        let germany = MetricsKey {
+            geo_origin: Geolocation::Alpha2("DE".to_string()),
+            overlay_origin: PublicGatewayStatus::NonGateway,
+            as_origin: String::new(),
+        };
+        let gateway = MetricsKey {
+            geo_origin: Geolocation::Unknown,
+            overlay_origin: PublicGatewayStatus::Gateway,
+            as_origin: String::new(),
+        };
+
+        cardinalities.record("m1", ts(0), &germany, "p1", ["a", "b"].into_iter());
+        cardinalities.record("m2", ts(10), &gateway, "p2", ["a"].into_iter());
+        cardinalities.record("m1", ts(200), &germany, "p1", ["c"].into_iter());
+
+        let estimates = |c: &Cardinalities| {
+            c.merged()
+                .into_iter()
+                .map(|(labels, s)| {
+                    let estimates = (s.peers.estimate().round(), s.cids.estimate().round());
+                    (labels.join("/"), estimates)
+                })
+                .collect::<HashMap<_, _>>()
+        };
+        let before = estimates(&cardinalities);
+        assert_eq!(before["m1/Germany/false/tumbling_86400s"], (1.0, 3.0));
+        assert_eq!(before["all/all/all/tumbling_86400s"], (2.0, 3.0));
+        assert_eq!(before["all/all/true/tumbling_86400s"], (1.0, 1.0));
+        // The sliding window of m1 has moved past the first request, and that of m2 is not
+        // advanced by events of m1.
+        assert_eq!(before["m1/all/all/sliding_120s"], (1.0, 1.0));
+        assert_eq!(before["all/all/all/sliding_120s"], (2.0, 2.0));
+
+        let state = serde_json::to_string(&cardinalities.persisted_state()).unwrap();
+        let restored = Cardinalities {
+            monitors: Mutex::new(Cardinalities::restore(
+                &cfg,
+                serde_json::from_str(&state).unwrap(),
+            )),
+            cfg,
+            exported: Mutex::new(HashSet::new()),
+        };
+        assert_eq!(estimates(&restored), before);
+    }
+}
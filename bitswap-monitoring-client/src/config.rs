@@ -1,5 +1,11 @@
-use failure::ResultExt;
+use failure::{ensure, ResultExt};
+use ipfs_monitoring_plugin_client::monitoring::{
+    MonitoringClientOptions, QueueMode, ReconnectConfig,
+};
+use ipfs_monitoring_plugin_client::replay::ReplaySpeed;
+use ipfs_resolver_common::wantlist::EngineSimulationConfig;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::fs::File;
 use std::path::Path;
 
@@ -9,8 +15,21 @@ use crate::Result;
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct Config {
     /// Configures the AMQP servers to connect to.
+    #[serde(default)]
     pub(crate) amqp_servers: Vec<AMQPServerConfig>,
 
+    /// Configures monitors pushing events over plain TCP.
+    #[serde(default)]
+    pub(crate) tcp_sources: Vec<TCPSourceConfig>,
+
+    /// Configures files or stdin to read events from.
+    #[serde(default)]
+    pub(crate) file_sources: Vec<FileSourceConfig>,
+
+    /// Configures recorded traces to replay, in addition to or instead of live AMQP sources.
+    #[serde(default)]
+    pub(crate) replay_sources: Vec<ReplaySourceConfig>,
+
     /// Specifies on what address a prometheus endpoint will be created.
     pub(crate) prometheus_address: String,
 
@@ -19,6 +38,12 @@ pub(crate) struct Config {
     #[serde(default = "default_geoip_database_path")]
     pub(crate) geoip_database_path: String,
 
+    /// Configures enrichment of events with the autonomous system (AS) they originate from, using
+    /// the GeoLite2 ASN database in `geoip_database_path`.
+    /// If not provided, the `origin_as` label of all metrics is empty.
+    #[serde(default)]
+    pub(crate) asn: Option<ASNConfig>,
+
     /// Specifies the location of the public gateway ID file.
     /// Each line in the file should contain one peer ID.
     /// If not provided, all traffic will be logged as non-gateway traffic.
@@ -28,6 +53,27 @@ pub(crate) struct Config {
     /// A subdirectory per monitor will be created.
     /// If not provided, logging to disk will be disabled.
     pub(crate) disk_logging_directory: Option<String>,
+
+    /// Configures an engine simulation per monitor, to classify wantlist entries in real time.
+    /// If not provided, no simulation is run.
+    #[serde(default)]
+    pub(crate) engine_simulation: Option<LiveEngineSimulationConfig>,
+
+    /// Configures an HTTP API to query the current wantlists of the engine simulations and the
+    /// heavy hitters.
+    /// If not provided, no API is served.
+    #[serde(default)]
+    pub(crate) wantlist_api: Option<WantlistAPIConfig>,
+
+    /// Configures tracking of the most requested CIDs and the peers sending the most requests.
+    /// If not provided, no heavy hitters are tracked.
+    #[serde(default)]
+    pub(crate) heavy_hitters: Option<HeavyHittersConfig>,
+
+    /// Configures estimation of the number of distinct peers sending wantlists and CIDs requested.
+    /// If not provided, distinct peers and CIDs are not counted.
+    #[serde(default)]
+    pub(crate) cardinality: Option<CardinalityConfig>,
 }
 
 /// Configuration for a single data source.
@@ -38,6 +84,298 @@ pub(crate) struct AMQPServerConfig {
 
     /// A list of monitor names to subscribe to.
     pub(crate) monitor_names: Vec<String>,
+
+    /// Configures backoff for re-establishing lost connections.
+    /// Defaults to retrying indefinitely, starting at 500ms and backing off to at most one minute.
+    #[serde(default)]
+    pub(crate) reconnect: ReconnectConfig,
+
+    /// Whether to reject and skip events that cannot be decoded, instead of reconnecting.
+    /// Defaults to true.
+    #[serde(default = "default_skip_undecodable")]
+    pub(crate) skip_undecodable: bool,
+
+    /// The kind of queue to consume from.
+    /// For shared queues, the monitor name is appended to the configured queue name, such that
+    /// each monitor is consumed from its own queue.
+    /// Defaults to an exclusive queue.
+    #[serde(default)]
+    pub(crate) queue: QueueMode,
+
+    /// The maximum number of unacknowledged deliveries to receive at once.
+    pub(crate) prefetch_count: Option<u16>,
+
+    /// The time after which events in the queue expire if not consumed, in milliseconds.
+    pub(crate) message_ttl_millis: Option<u32>,
+}
+
+fn default_skip_undecodable() -> bool {
+    true
+}
+
+impl AMQPServerConfig {
+    /// Builds client options for the given monitor.
+    pub(crate) fn client_options(&self, monitor_name: &str) -> MonitoringClientOptions {
+        let queue = match &self.queue {
+            QueueMode::Exclusive => QueueMode::Exclusive,
+            QueueMode::Shared { name, durable } => QueueMode::Shared {
+                name: format!("{}.{}", name, monitor_name),
+                durable: *durable,
+            },
+        };
+
+        MonitoringClientOptions {
+            reconnect: Some(self.reconnect.clone()),
+            skip_undecodable: self.skip_undecodable,
+            queue,
+            prefetch_count: self.prefetch_count,
+            message_ttl_millis: self.message_ttl_millis,
+            ..Default::default()
+        }
+    }
+}
+
+/// Configuration for a monitor pushing events over TCP.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct TCPSourceConfig {
+    /// The address of the monitor, as host:port.
+    pub(crate) address: String,
+
+    /// The name of the monitor to attribute events to.
+    pub(crate) monitor_name: String,
+
+    /// Configures backoff for re-establishing lost connections.
+    #[serde(default)]
+    pub(crate) reconnect: ReconnectConfig,
+}
+
+/// Configuration for reading events from a file or stdin.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct FileSourceConfig {
+    /// The path to read newline-delimited JSON events from, optionally gzipped.
+    /// Use `-` to read from stdin.
+    pub(crate) path: String,
+
+    /// The name of the monitor to attribute events to.
+    pub(crate) monitor_name: String,
+}
+
+/// Configuration for replaying traces previously written to disk.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct ReplaySourceConfig {
+    /// The name of the monitor to attribute replayed events to.
+    pub(crate) monitor_name: String,
+
+    /// A list of globs matching the gzipped JSON trace files to replay.
+    /// Files are replayed glob by glob, in alphabetical order within each glob.
+    pub(crate) input_globs: Vec<String>,
+
+    /// The speed at which to replay events.
+    /// Defaults to real-time.
+    #[serde(default)]
+    pub(crate) speed: ReplaySpeed,
+}
+
+/// Configuration for the real-time engine simulation of each monitor.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct LiveEngineSimulationConfig {
+    /// Configuration of the simulation, as used by `ipfs-json-to-csv` and `unify-bitswap-traces`.
+    /// Request lifetimes are not tracked.
+    pub(crate) simulation_config: EngineSimulationConfig,
+
+    /// The time after which the ledgers of disconnected peers are evicted, in seconds.
+    /// This bounds memory usage, but requests re-sent by peers reconnecting after their ledger was
+    /// evicted are not marked as reconnect duplicates.
+    /// Defaults to one hour.
+    #[serde(default = "default_evict_disconnected_after_secs")]
+    pub(crate) evict_disconnected_after_secs: u32,
+}
+
+fn default_evict_disconnected_after_secs() -> u32 {
+    3600
+}
+
+/// Configuration for the HTTP API to query the current wantlists.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct WantlistAPIConfig {
+    /// The address to listen on, as host:port.
+    pub(crate) listen_address: String,
+
+    /// The maximum number of minutes for which the top requested CIDs can be queried.
+    /// Requests are counted per CID and minute for this long.
+    /// Defaults to 60.
+    #[serde(default = "default_top_cids_max_minutes")]
+    pub(crate) top_cids_max_minutes: u32,
+
+    /// The number of CIDs counted per monitor and minute for the top requested CIDs.
+    /// Counts are estimates if more distinct CIDs are requested within a minute.
+    /// CIDs requested more often than the number of requests divided by this are guaranteed to be
+    /// counted.
+    /// Defaults to 1000.
+    #[serde(default = "default_top_cids_capacity")]
+    pub(crate) top_cids_capacity: usize,
+}
+
+fn default_top_cids_max_minutes() -> u32 {
+    60
+}
+
+fn default_top_cids_capacity() -> usize {
+    1000
+}
+
+/// Configuration for tracking heavy hitters, i.e., the most requested CIDs and the peers sending
+/// the most requests, per monitor and over all monitors.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct HeavyHittersConfig {
+    /// The number of items counted by each sketch.
+    /// Items requested more often than the number of requests divided by this are guaranteed to
+    /// be tracked.
+    /// Defaults to 1000.
+    #[serde(default = "default_heavy_hitters_capacity")]
+    pub(crate) capacity: usize,
+
+    /// The number of top items exported as gauges, per monitor and window.
+    /// Defaults to 10.
+    #[serde(default = "default_heavy_hitters_top_n")]
+    pub(crate) top_n: usize,
+
+    /// The windows of event time over which to track heavy hitters.
+    /// For tumbling windows, the last completed window is reported.
+    pub(crate) windows: Vec<WindowConfig>,
+}
+
+fn default_heavy_hitters_capacity() -> usize {
+    1000
+}
+
+fn default_heavy_hitters_top_n() -> usize {
+    10
+}
+
+/// Configuration for estimating the number of distinct peers sending wantlists and CIDs requested,
+/// per monitor, origin country, and gateway status.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct CardinalityConfig {
+    /// The base-2 logarithm of the number of registers of each HyperLogLog sketch, between 4 and
+    /// 16.
+    /// The standard error of estimates is about 1.04/sqrt(2^precision).
+    /// Defaults to 12, i.e., a standard error of 1.6% and at most 4KiB per sketch.
+    #[serde(default = "default_cardinality_precision")]
+    pub(crate) precision: u8,
+
+    /// The windows of event time over which to count distinct peers and CIDs.
+    /// For tumbling windows, the current window is reported, e.g., the current day (UTC) for a
+    /// length of 86400 seconds.
+    pub(crate) windows: Vec<WindowConfig>,
+
+    /// A path to persist the sketches to on shutdown, and to restore them from on startup.
+    /// If not provided, the sketches are not persisted.
+    #[serde(default)]
+    pub(crate) state_file: Option<String>,
+}
+
+fn default_cardinality_precision() -> u8 {
+    12
+}
+
+/// A window of event time over which events are aggregated.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum WindowConfig {
+    /// Consecutive windows of the given length, aligned to the Unix epoch.
+    Tumbling { length_secs: u32 },
+
+    /// A window of the given length, which advances in panes of the length divided by the number
+    /// of panes.
+    /// The window up to and including the current pane is reported.
+    Sliding { length_secs: u32, num_panes: u32 },
+}
+
+impl WindowConfig {
+    /// Returns the name of this window, as used in labels and queries.
+    pub(crate) fn name(&self) -> String {
+        match self {
+            WindowConfig::Tumbling { length_secs } => format!("tumbling_{}s", length_secs),
+            WindowConfig::Sliding { length_secs, .. } => format!("sliding_{}s", length_secs),
+        }
+    }
+
+    /// Returns the length of a pane, in seconds, and the number of panes of this window.
+    /// A tumbling window consists of a single pane.
+    pub(crate) fn panes(&self) -> (u32, u32) {
+        match *self {
+            WindowConfig::Tumbling { length_secs } => (length_secs, 1),
+            WindowConfig::Sliding {
+                length_secs,
+                num_panes,
+            } => (length_secs / num_panes, num_panes),
+        }
+    }
+
+    /// Checks that the given windows have positive lengths evenly divided into panes, and distinct
+    /// names.
+    pub(crate) fn validate_all(windows: &[WindowConfig]) -> Result<()> {
+        for w in windows.iter() {
+            match *w {
+                WindowConfig::Tumbling { length_secs } => {
+                    ensure!(length_secs > 0, "window length must be >0")
+                }
+                WindowConfig::Sliding {
+                    length_secs,
+                    num_panes,
+                } => {
+                    ensure!(num_panes > 0, "number of panes must be >0");
+                    ensure!(
+                        length_secs > 0 && length_secs % num_panes == 0,
+                        "window length must be a positive multiple of the number of panes"
+                    )
+                }
+            }
+        }
+
+        let names = windows.iter().map(|w| w.name()).collect::<HashSet<_>>();
+        ensure!(
+            names.len() == windows.len(),
+            "windows must be of different kinds or lengths"
+        );
+
+        Ok(())
+    }
+}
+
+/// Configuration for labeling metrics by the autonomous system (AS) events originate from.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct ASNConfig {
+    /// What to label ASes by, either their `number`, e.g., "AS13335", or the `organization`
+    /// operating them, e.g., "CLOUDFLARENET".
+    /// ASes without a known organization are labeled by number.
+    /// Defaults to number.
+    #[serde(default)]
+    pub(crate) label: ASLabelKind,
+
+    /// The number of labels, i.e., ASes or organizations, with the most events, which are used
+    /// as-is.
+    /// Events from all others are labeled "Other", to keep the number of series bounded.
+    /// Defaults to 20.
+    #[serde(default = "default_asn_top_n")]
+    pub(crate) top_n: usize,
+}
+
+fn default_asn_top_n() -> usize {
+    20
+}
+
+/// What to label autonomous systems by.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ASLabelKind {
+    /// The AS number, prefixed with "AS".
+    #[default]
+    Number,
+
+    /// The organization operating the AS.
+    Organization,
 }
 
 fn default_geoip_database_path() -> String {
@@ -0,0 +1,288 @@
+use crate::config::LiveEngineSimulationConfig;
+use crate::heavy_hitters::SpaceSaving;
+use crate::prom::EngineMetrics;
+use failure::ResultExt;
+use ipfs_monitoring_plugin_client::monitoring::PushedEvent;
+use ipfs_resolver_common::wantlist::{
+    EngineSimulation, IngestResult, JSONMessage, CSV_DUPLICATE_STATUS_DUP_FULL_WANTLIST,
+    CSV_DUPLICATE_STATUS_DUP_REBROADCAST, CSV_DUPLICATE_STATUS_DUP_RECONNECT,
+    CSV_DUPLICATE_STATUS_DUP_SLIDING_WINDOW, CSV_DUPLICATE_STATUS_NO_DUP, CSV_ENTRY_TYPE_CANCEL,
+    CSV_ENTRY_TYPE_SYNTHETIC_CANCEL_DISCONNECT, CSV_ENTRY_TYPE_SYNTHETIC_CANCEL_FULL_WANTLIST,
+};
+use ipfs_resolver_common::Result;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// The interval, in event time, in which disconnected peers are evicted and gauges are updated.
+const MAINTENANCE_INTERVAL_SECS: i64 = 10;
+
+/// Creates the engine simulations of all monitors, if configured, and keeps them by monitor name,
+/// to be queried via the wantlist API.
+#[derive(Clone)]
+pub(crate) struct EngineRegistry {
+    cfg: Option<LiveEngineSimulationConfig>,
+    recent_requests_window_minutes: u32,
+    recent_requests_capacity: usize,
+    engines: Arc<Mutex<BTreeMap<String, Arc<Mutex<LiveEngineSimulation>>>>>,
+}
+
+impl EngineRegistry {
+    /// Creates a registry for simulations with the given configuration, or no simulations.
+    /// Requests are counted per CID for the given number of minutes, by sketches with the given
+    /// capacity.
+    pub(crate) fn new(
+        cfg: Option<LiveEngineSimulationConfig>,
+        recent_requests_window_minutes: u32,
+        recent_requests_capacity: usize,
+    ) -> EngineRegistry {
+        EngineRegistry {
+            cfg,
+            recent_requests_window_minutes,
+            recent_requests_capacity,
+            engines: Default::default(),
+        }
+    }
+
+    /// Returns the capacity of the sketches counting recent requests.
+    pub(crate) fn recent_requests_capacity(&self) -> usize {
+        self.recent_requests_capacity
+    }
+
+    /// Returns whether engine simulations are configured.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.cfg.is_some()
+    }
+
+    /// Creates and registers the engine simulation of the given monitor, if configured,
+    /// replacing any earlier one.
+    /// Returns a handle to the simulation, to feed it with events.
+    pub(crate) fn create(
+        &self,
+        monitor_name: &str,
+    ) -> Result<Option<Arc<Mutex<LiveEngineSimulation>>>> {
+        let cfg = match &self.cfg {
+            Some(cfg) => cfg.clone(),
+            None => return Ok(None),
+        };
+        let engine = Arc::new(Mutex::new(LiveEngineSimulation::new(
+            monitor_name,
+            cfg,
+            self.recent_requests_window_minutes,
+            self.recent_requests_capacity,
+        )?));
+        self.engines
+            .lock()
+            .unwrap()
+            .insert(monitor_name.to_string(), engine.clone());
+
+        Ok(Some(engine))
+    }
+
+    /// Returns the engine simulations of all monitors, sorted by monitor name.
+    pub(crate) fn engines(&self) -> Vec<(String, Arc<Mutex<LiveEngineSimulation>>)> {
+        self.engines
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, engine)| (name.clone(), engine.clone()))
+            .collect()
+    }
+}
+
+/// Counts non-duplicate requests per CID, in buckets of one minute of event time.
+/// Each bucket is a Space-Saving sketch, so the memory used is bounded by the window and the
+/// capacity of the sketches.
+/// Buckets older than the window are dropped.
+struct RecentRequests {
+    window_minutes: u32,
+    capacity: usize,
+    /// Buckets by minute since the Unix epoch, oldest first.
+    buckets: VecDeque<(i64, SpaceSaving)>,
+}
+
+impl RecentRequests {
+    fn new(window_minutes: u32, capacity: usize) -> RecentRequests {
+        RecentRequests {
+            window_minutes,
+            capacity,
+            buckets: VecDeque::new(),
+        }
+    }
+
+    /// Advances to the minute of the given timestamp, dropping buckets older than the window.
+    fn advance(&mut self, ts: chrono::DateTime<chrono::Utc>) {
+        if self.window_minutes == 0 {
+            return;
+        }
+        let minute = ts.timestamp().div_euclid(60);
+        if self.buckets.back().is_none_or(|(m, _)| *m < minute) {
+            self.buckets
+                .push_back((minute, SpaceSaving::new(self.capacity)));
+            while self
+                .buckets
+                .front()
+                .is_some_and(|(m, _)| *m <= minute - self.window_minutes as i64)
+            {
+                self.buckets.pop_front();
+            }
+        }
+    }
+
+    /// Counts a request in the bucket of the newest minute advanced to.
+    /// Requests of earlier minutes, e.g., due to reordering, are counted in that bucket as well.
+    fn record(&mut self, cid: &str) {
+        if let Some((_, counts)) = self.buckets.back_mut() {
+            counts.insert(cid);
+        }
+    }
+
+    /// Returns the buckets of the last given number of minutes, up to the newest minute advanced
+    /// to.
+    fn buckets(&self, minutes: u32) -> impl Iterator<Item = &SpaceSaving> {
+        let newest = self.buckets.back().map(|(m, _)| *m).unwrap_or_default();
+        self.buckets
+            .iter()
+            .filter(move |(m, _)| *m > newest - minutes as i64)
+            .map(|(_, c)| c)
+    }
+}
+
+/// An engine simulation of a single monitor, fed with events as they are received.
+/// The simulation advances by the timestamps of the events, so replays are simulated the same way
+/// as live events.
+pub(crate) struct LiveEngineSimulation {
+    sim: EngineSimulation,
+    metrics: EngineMetrics,
+    evict_disconnected_after: chrono::Duration,
+    next_maintenance: Option<chrono::DateTime<chrono::Utc>>,
+    message_id: i64,
+    recent_requests: RecentRequests,
+}
+
+impl LiveEngineSimulation {
+    /// Creates a new simulation for the given monitor.
+    /// Requests are counted per CID for the given number of minutes, by sketches with the given
+    /// capacity.
+    fn new(
+        monitor_name: &str,
+        cfg: LiveEngineSimulationConfig,
+        recent_requests_window_minutes: u32,
+        recent_requests_capacity: usize,
+    ) -> Result<LiveEngineSimulation> {
+        let mut simulation_config = cfg.simulation_config;
+        simulation_config.emit_request_lifetimes = false;
+        let sim = EngineSimulation::new(simulation_config)
+            .context("unable to set up engine simulation")?;
+
+        Ok(LiveEngineSimulation {
+            sim,
+            metrics: EngineMetrics::new(monitor_name),
+            evict_disconnected_after: chrono::Duration::seconds(
+                cfg.evict_disconnected_after_secs as i64,
+            ),
+            next_maintenance: None,
+            message_id: 0,
+            recent_requests: RecentRequests::new(
+                recent_requests_window_minutes,
+                recent_requests_capacity,
+            ),
+        })
+    }
+
+    /// Returns the underlying simulation.
+    pub(crate) fn simulation(&self) -> &EngineSimulation {
+        &self.sim
+    }
+
+    /// Returns copies of the sketches counting non-duplicate requests per CID, one for each of the
+    /// last given number of minutes.
+    /// The minutes are counted back from the newest event, in event time.
+    /// The sketches are copied such that they can be merged without holding on to the simulation.
+    pub(crate) fn recent_request_counts(&self, minutes: u32) -> Vec<SpaceSaving> {
+        self.recent_requests.buckets(minutes).cloned().collect()
+    }
+
+    /// Ingests an event into the simulation and records the classification of its entries.
+    pub(crate) fn handle_event(&mut self, event: &PushedEvent) -> Result<()> {
+        let msg = JSONMessage::from(event.clone());
+        self.message_id += 1;
+        self.recent_requests.advance(msg.timestamp);
+        let res = self
+            .sim
+            .ingest(&msg, self.message_id)
+            .context("unable to ingest event")?;
+        self.record_metrics(&msg, res);
+
+        self.maintain(msg.timestamp);
+
+        Ok(())
+    }
+
+    fn record_metrics(&mut self, msg: &JSONMessage, res: IngestResult) {
+        for entry in res.wantlist_entries.iter().flatten() {
+            match entry.entry_type {
+                CSV_ENTRY_TYPE_CANCEL => {}
+                CSV_ENTRY_TYPE_SYNTHETIC_CANCEL_FULL_WANTLIST => {
+                    self.metrics.num_synthetic_cancels_full_wantlist.inc()
+                }
+                CSV_ENTRY_TYPE_SYNTHETIC_CANCEL_DISCONNECT => {
+                    self.metrics.num_synthetic_cancels_disconnect.inc()
+                }
+                _ => {
+                    let status = entry.duplicate_status;
+                    if status == CSV_DUPLICATE_STATUS_NO_DUP {
+                        self.metrics.num_requests_no_dup.inc();
+                        self.recent_requests.record(entry.cid_key());
+                    }
+                    if status & CSV_DUPLICATE_STATUS_DUP_FULL_WANTLIST != 0 {
+                        self.metrics.num_requests_dup_full_wantlist.inc();
+                    }
+                    if status & CSV_DUPLICATE_STATUS_DUP_RECONNECT != 0 {
+                        self.metrics.num_requests_dup_reconnect.inc();
+                    }
+                    if status & CSV_DUPLICATE_STATUS_DUP_SLIDING_WINDOW != 0 {
+                        self.metrics.num_requests_dup_sliding_window.inc();
+                    }
+                    if status & CSV_DUPLICATE_STATUS_DUP_REBROADCAST != 0 {
+                        self.metrics.num_requests_dup_rebroadcast.inc();
+                    }
+                    if entry.upgrades_earlier_request {
+                        self.metrics.num_upgrades.inc();
+                    }
+                }
+            }
+        }
+
+        if msg.received_entries.is_some() {
+            self.metrics
+                .active_wants_per_peer
+                .observe(self.sim.num_wanted_entries(&msg.peer) as f64);
+        }
+    }
+
+    /// Evicts the ledgers of peers disconnected for long enough and updates the gauges, once per
+    /// maintenance interval.
+    fn maintain(&mut self, ts: chrono::DateTime<chrono::Utc>) {
+        if self.next_maintenance.is_some_and(|next| ts < next) {
+            return;
+        }
+        self.next_maintenance = Some(ts + chrono::Duration::seconds(MAINTENANCE_INTERVAL_SECS));
+
+        let num_evicted = self
+            .sim
+            .evict_disconnected_ledgers(ts - self.evict_disconnected_after);
+        if num_evicted > 0 {
+            debug!("evicted {} ledgers of disconnected peers", num_evicted);
+            self.metrics.num_evicted_ledgers.inc_by(num_evicted as u64);
+        }
+
+        let num_connected = self.sim.num_connected_ledgers();
+        self.metrics.num_ledgers_connected.set(num_connected as i64);
+        self.metrics
+            .num_ledgers_disconnected
+            .set((self.sim.num_ledgers() - num_connected) as i64);
+        self.metrics
+            .num_active_wants
+            .set(self.sim.num_wanted_entries_total() as i64);
+    }
+}
@@ -1,44 +1,76 @@
-use crate::{Config, Geolocation};
+use crate::config::Config;
+use crate::origin_as::{ASLabeler, ASOrigin};
+use crate::prom::Geolocation;
 use failure::ResultExt;
 use ipfs_monitoring_plugin_client::monitoring::{EventType, PushedEvent};
+use ipfs_resolver_common::geoip;
 use maxminddb::Reader;
 use std::net::IpAddr;
-use std::path;
 use std::str::FromStr;
-use std::sync::Arc;
-use std::time::{Duration, UNIX_EPOCH};
+use std::sync::Mutex;
 
 use crate::Result;
 
-pub(crate) fn read_geoip_database(cfg: Config) -> Result<maxminddb::Reader<Vec<u8>>> {
-    let geoip_db_path = path::Path::new(&cfg.geoip_database_path);
+/// Determines the origin of events, i.e., the country and, optionally, the autonomous system of
+/// their address, and assigns the values of the `origin_as` label.
+pub(crate) struct OriginLocator {
+    country_db: Reader<Vec<u8>>,
+    /// The ASN database, if AS enrichment is configured.
+    asn_db: Option<Reader<Vec<u8>>>,
+    as_labeler: Mutex<ASLabeler>,
+}
 
-    let country_db_path = geoip_db_path.join("GeoLite2-Country.mmdb");
-    debug!(
-        "attempting to read GeoLite2 Country database at {:?}...",
-        country_db_path
-    );
-    let country_reader = maxminddb::Reader::open_readfile(country_db_path)
-        .context("unable to open GeoLite2 Country database")?;
-    debug!("successfully opened GeoLite2 Country database");
+impl OriginLocator {
+    /// Opens the GeoIP databases of the given configuration.
+    pub(crate) fn open(cfg: &Config) -> Result<OriginLocator> {
+        let country_db = read_geoip_database(cfg)?;
+        let asn_db = match cfg.asn {
+            Some(_) => Some(read_asn_database(cfg)?),
+            None => None,
+        };
 
-    let country_db_ts = chrono::DateTime::<chrono::Utc>::from(
-        UNIX_EPOCH + Duration::from_secs(country_reader.metadata.build_epoch),
-    );
-    debug!(
-        "loaded MaxMind country database \"{}\", created {}, with {} entries",
-        country_reader.metadata.database_type,
-        country_db_ts.format("%+"),
-        country_reader.metadata.node_count
-    );
-    if (chrono::Utc::now() - country_db_ts)
-        > chrono::Duration::from_std(Duration::from_secs(30 * 24 * 60 * 60)).unwrap()
-    {
-        warn!(
-            "MaxMind GeoIP country database is older than 30 days (created {})",
-            country_db_ts.format("%+")
-        )
+        Ok(OriginLocator {
+            country_db,
+            asn_db,
+            as_labeler: Mutex::new(ASLabeler::new(cfg.asn.clone())),
+        })
+    }
+
+    /// Returns the value of the `origin_as` label of events whose AS is not known.
+    pub(crate) fn unknown_as_label(&self) -> &'static str {
+        self.as_labeler.lock().unwrap().unknown_label()
+    }
+
+    /// Returns a number which changes whenever values of the `origin_as` label stop being in use.
+    pub(crate) fn as_labels_generation(&self) -> u64 {
+        self.as_labeler.lock().unwrap().generation()
+    }
+
+    /// Returns whether the given value of the `origin_as` label is currently in use.
+    pub(crate) fn is_as_label_in_use(&self, label: &str) -> bool {
+        self.as_labeler.lock().unwrap().is_in_use(label)
+    }
+
+    /// Determines the country of the given event and the value of its `origin_as` label.
+    pub(crate) fn locate_event(&self, event: &PushedEvent) -> (Geolocation, String) {
+        let origin_ip = origin_ip(event);
+        let geolocation = geolocate_ip(&self.country_db, origin_ip);
+        let as_origin = match &self.asn_db {
+            Some(asn_db) => lookup_as(asn_db, origin_ip),
+            None => ASOrigin::Unknown,
+        };
+        debug!(" determined AS of IP {:?} to be {:?}", origin_ip, as_origin);
+        let as_label = self.as_labeler.lock().unwrap().label(&as_origin);
+
+        (geolocation, as_label)
     }
+}
+
+fn read_geoip_database(cfg: &Config) -> Result<Reader<Vec<u8>>> {
+    let country_reader =
+        geoip::open_database(&cfg.geoip_database_path, geoip::COUNTRY_DATABASE_FILE)
+            .context("unable to open GeoLite2 Country database")?;
+    debug!("successfully opened GeoLite2 Country database");
 
     debug!("testing MaxMind database...");
     let google_country = country_reader
@@ -49,107 +81,47 @@ pub(crate) fn read_geoip_database(cfg: Config) -> Result<maxminddb::Reader<Vec<u
     Ok(country_reader)
 }
 
-pub(crate) fn geolocate_event(
-    country_db: &Arc<Reader<Vec<u8>>>,
-    event: &PushedEvent,
-) -> Geolocation {
-    // Extract a multiaddress to use for GeoIP queries.
-    let origin_ma = match &event.inner {
-        EventType::BitswapMessage(msg) => {
-            // We filter out any p2p-circuit addresses, since we cannot correctly geolocate those anyway.
-            msg.connected_addresses.iter().find(|a|
-                // Parse the multiaddress
-                match multiaddr::Multiaddr::from_str(a) {
-                    Ok(ma) => {
-                        // Test if any part of the multiaddress is p2p circuit.
-                        // Negate that, s.t. we find addresses where no part is p2p circuit.
-                        !ma.iter().any(|p| if let multiaddr::Protocol::P2pCircuit = p { true } else { false })
-                    }
-                    Err(err) => {
-                        // Probably a new protocol which we can't decode (yet)
-                        debug!("unable to decode multiaddress {}: {:?}",a,err);
-                        false
-                    }
-                }
-            ).map(|a| multiaddr::Multiaddr::from_str(a).unwrap())
-        }
-        EventType::ConnectionEvent(conn_event) => {
-            // Parse the multiaddress
-            match multiaddr::Multiaddr::from_str(&conn_event.remote) {
-                Ok(ma) => {
-                    if ma.iter().any(|p| {
-                        if let multiaddr::Protocol::P2pCircuit = p {
-                            true
-                        } else {
-                            false
-                        }
-                    }) {
-                        // This is a relayed connection, ignore it.
-                        None
-                    } else {
-                        Some(ma)
-                    }
-                }
-                Err(err) => {
-                    // Probably a new protocol which we can't decode (yet)
-                    debug!(
-                        "unable to decode multiaddress {}: {:?}",
-                        conn_event.remote, err
-                    );
-                    None
-                }
-            }
-        }
+fn read_asn_database(cfg: &Config) -> Result<Reader<Vec<u8>>> {
+    let asn_reader = geoip::open_database(&cfg.geoip_database_path, geoip::ASN_DATABASE_FILE)
+        .context("unable to open GeoLite2 ASN database")?;
+    debug!("successfully opened GeoLite2 ASN database");
+
+    debug!("testing MaxMind ASN database...");
+    let google_as = geoip::lookup_asn(&asn_reader, "8.8.8.8".parse().unwrap())
+        .context("unable to look up 8.8.8.8 in ASN database")?;
+    debug!("got AS {:?} for IP 8.8.8.8", google_as);
+
+    Ok(asn_reader)
+}
+
+/// Extracts the IP of the origin of the given event, unless it is relayed.
+fn origin_ip(event: &PushedEvent) -> Option<IpAddr> {
+    let addresses = match &event.inner {
+        EventType::BitswapMessage(msg) => msg.connected_addresses.as_slice(),
+        EventType::ConnectionEvent(conn_event) => std::slice::from_ref(&conn_event.remote),
     };
-    debug!(
-        "extracted origin multiaddress {:?} from event {:?}",
-        origin_ma, event
-    );
 
-    let origin_ip = origin_ma
-        .as_ref()
-        .map(|ma| ma.iter().next())
-        .map(|p| match p {
-            None => None,
-            Some(p) => match p {
-                multiaddr::Protocol::Ip4(addr) => Some(IpAddr::V4(addr)),
-                multiaddr::Protocol::Ip6(addr) => Some(IpAddr::V6(addr)),
-                _ => None,
-            },
-        })
-        .flatten();
-    debug!(
-        "extracted IP {:?} from multiaddress {:?}",
-        origin_ip, origin_ma
-    );
+    // We skip p2p-circuit addresses, since we cannot correctly geolocate those anyway.
+    let origin_ip = addresses.iter().find_map(|a| geoip::ip_from_address(a));
+    debug!("extracted IP {:?} from event {:?}", origin_ip, event);
+
+    origin_ip
+}
 
-    let geolocation: Geolocation = match origin_ip {
+/// Looks up the country of the given IP.
+fn geolocate_ip(country_db: &Reader<Vec<u8>>, origin_ip: Option<IpAddr>) -> Geolocation {
+    let geolocation = match origin_ip {
         None => Geolocation::Unknown,
-        Some(ip) => match country_db.lookup::<maxminddb::geoip2::Country>(ip) {
-            Ok(country) => country
-                .country
-                .as_ref()
-                .map(|o| o.iso_code)
-                .flatten()
-                .map(|iso_code| Geolocation::Alpha2(iso_code.to_string()))
-                .or_else(|| {
-                    debug!("Country lookup for IP {} has no country: {:?}", ip, country);
-                    Some(Geolocation::Unknown)
-                })
-                .unwrap(),
-            Err(err) => match err {
-                maxminddb::MaxMindDBError::AddressNotFoundError(e) => {
-                    debug!("IP {:?} not found in MaxMind database: {}", ip, e);
-                    Geolocation::Unknown
-                }
-                _ => {
-                    error!(
-                        "unable to lookup country for IP {} (from multiaddress {:?}): {:?}",
-                        ip, origin_ma, err
-                    );
-                    Geolocation::Error
-                }
-            },
+        Some(ip) => match geoip::lookup_country(country_db, ip) {
+            Ok(Some(iso_code)) => Geolocation::Alpha2(iso_code),
+            Ok(None) => {
+                debug!("IP {:?} has no country in MaxMind database", ip);
+                Geolocation::Unknown
+            }
+            Err(err) => {
+                error!("unable to lookup country for IP {}: {:?}", ip, err);
+                Geolocation::Error
+            }
         },
     };
     debug!(
@@ -159,3 +131,56 @@ pub(crate) fn geolocate_event(
 
     geolocation
 }
+
+/// Looks up the autonomous system of the given IP.
+fn lookup_as(asn_db: &Reader<Vec<u8>>, origin_ip: Option<IpAddr>) -> ASOrigin {
+    match origin_ip {
+        None => ASOrigin::Unknown,
+        Some(ip) => match geoip::lookup_asn(asn_db, ip) {
+            Ok(Some(asn)) => ASOrigin::AS(asn),
+            Ok(None) => {
+                debug!("IP {:?} not found in MaxMind ASN database", ip);
+                ASOrigin::Unknown
+            }
+            Err(err) => {
+                error!("unable to lookup AS for IP {}: {:?}", ip, err);
+                ASOrigin::Error
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipfs_monitoring_plugin_client::monitoring::ConnectionEventType;
+    use ipfs_monitoring_plugin_mock::events;
+
+    #[test]
+    fn origin_ip_skips_relayed_addresses() {
+        let ip = |s: &str| Some(IpAddr::from_str(s).unwrap());
+        let relayed =
+            "/ip4/1.2.3.4/tcp/4001/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN/p2p-circuit";
+
+        let msg = events::bitswap_message(&[
+            relayed,
+            "/dns4/example.com/tcp/4001",
+            "/ip4/5.6.7.8/tcp/4001",
+        ]);
+        assert_eq!(
+            origin_ip(&events::bitswap_event("peer", msg)),
+            ip("5.6.7.8")
+        );
+        let msg = events::bitswap_message(&[relayed]);
+        assert_eq!(origin_ip(&events::bitswap_event("peer", msg)), None);
+
+        let conn =
+            |remote| events::connection_event("peer", remote, ConnectionEventType::Connected);
+        assert_eq!(
+            origin_ip(&conn("/ip6/2001:db8::1/udp/4001/quic")),
+            ip("2001:db8::1")
+        );
+        assert_eq!(origin_ip(&conn(relayed)), None);
+        assert_eq!(origin_ip(&conn("/garbage")), None);
+    }
+}
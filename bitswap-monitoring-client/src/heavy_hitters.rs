@@ -0,0 +1,515 @@
+use crate::config::{HeavyHittersConfig, WindowConfig};
+use crate::prom::{HEAVY_HITTER_CIDS, HEAVY_HITTER_PEERS};
+use failure::ResultExt;
+use ipfs_resolver_common::Result;
+use prometheus::IntGaugeVec;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// The value of the `monitor` label of the heavy hitters of all monitors.
+pub(crate) const GLOBAL_LABEL: &str = "all";
+
+/// An item with its estimated count.
+/// The estimate exceeds the true count by at most `error`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct HeavyHitter {
+    pub(crate) item: String,
+    pub(crate) count: u64,
+    pub(crate) error: u64,
+}
+
+/// A Space-Saving sketch, which estimates the counts of the most frequent items of a stream with
+/// a fixed number of counters.
+///
+/// Once all counters are in use, a new item replaces the item with the smallest count, inheriting
+/// that count as its error.
+/// Every item occurring more often than the number of items counted divided by the capacity is
+/// guaranteed to be tracked.
+/// See Metwally et al., "Efficient Computation of Frequent and Top-k Elements in Data Streams".
+#[derive(Clone, Debug)]
+pub(crate) struct SpaceSaving {
+    capacity: usize,
+    /// Count and error by item.
+    counters: HashMap<String, (u64, u64)>,
+    /// Items ordered by count, to find the smallest one.
+    by_count: BTreeSet<(u64, String)>,
+}
+
+impl SpaceSaving {
+    pub(crate) fn new(capacity: usize) -> SpaceSaving {
+        SpaceSaving {
+            capacity,
+            counters: HashMap::new(),
+            by_count: BTreeSet::new(),
+        }
+    }
+
+    /// Counts an occurrence of the given item.
+    pub(crate) fn insert(&mut self, item: &str) {
+        self.insert_with_count(item, 1, 0)
+    }
+
+    fn insert_with_count(&mut self, item: &str, count: u64, error: u64) {
+        if let Some((c, _)) = self.counters.get_mut(item) {
+            self.by_count.remove(&(*c, item.to_string()));
+            *c += count;
+            self.by_count.insert((*c, item.to_string()));
+            return;
+        }
+
+        let (base, error) = if self.counters.len() < self.capacity {
+            (0, error)
+        } else {
+            // Replace the item with the smallest count.
+            let (min_count, min_item) = match self.by_count.pop_first() {
+                Some(min) => min,
+                // A sketch without capacity does not count anything.
+                None => return,
+            };
+            self.counters.remove(&min_item);
+            (min_count, error.max(min_count))
+        };
+        self.counters
+            .insert(item.to_string(), (base + count, error));
+        self.by_count.insert((base + count, item.to_string()));
+    }
+
+    /// Returns the smallest count tracked, which bounds the count of untracked items, or zero if
+    /// the sketch is not full.
+    fn min_count(&self) -> u64 {
+        if self.counters.len() < self.capacity {
+            0
+        } else {
+            self.by_count.first().map(|(c, _)| *c).unwrap_or(0)
+        }
+    }
+
+    /// Merges the given sketches into one with the given capacity.
+    /// Items missing from a sketch are assumed to have occurred as often as its smallest count, so
+    /// counts remain overestimates.
+    pub(crate) fn merge<'a, I>(sketches: I, capacity: usize) -> SpaceSaving
+    where
+        I: IntoIterator<Item = &'a SpaceSaving>,
+    {
+        let sketches = sketches.into_iter().collect::<Vec<_>>();
+        let items = sketches
+            .iter()
+            .flat_map(|s| s.counters.keys())
+            .collect::<BTreeSet<_>>();
+        let mut items = items
+            .into_iter()
+            .map(|item| {
+                let (count, error) = sketches
+                    .iter()
+                    .map(|s| {
+                        s.counters
+                            .get(item)
+                            .copied()
+                            .unwrap_or((s.min_count(), s.min_count()))
+                    })
+                    .fold((0, 0), |(c, e), (cc, ee)| (c + cc, e + ee));
+                (item, count, error)
+            })
+            .collect::<Vec<_>>();
+        items.sort_by(|(i1, c1, _), (i2, c2, _)| c2.cmp(c1).then(i1.cmp(i2)));
+
+        let mut result = SpaceSaving::new(capacity);
+        for (item, count, error) in items.into_iter().take(capacity) {
+            result.insert_with_count(item, count, error);
+        }
+        result
+    }
+
+    /// Returns the `n` items with the highest counts, ordered by count.
+    pub(crate) fn top(&self, n: usize) -> Vec<HeavyHitter> {
+        self.by_count
+            .iter()
+            .rev()
+            .take(n)
+            .map(|(count, item)| HeavyHitter {
+                item: item.clone(),
+                count: *count,
+                error: self.counters[item].1,
+            })
+            .collect()
+    }
+}
+
+/// A sketch over a tumbling or sliding window of event time.
+#[derive(Clone, Debug)]
+enum WindowedSketch {
+    /// Counts items in consecutive windows.
+    /// Queries return the last completed window.
+    Tumbling {
+        length_secs: i64,
+        current_window: Option<i64>,
+        current: SpaceSaving,
+        completed: SpaceSaving,
+    },
+    /// Counts items in panes, which are merged to cover the window.
+    /// Queries include the current pane.
+    Sliding {
+        pane_secs: i64,
+        num_panes: i64,
+        /// Panes by index, oldest first.
+        panes: VecDeque<(i64, SpaceSaving)>,
+    },
+}
+
+impl WindowedSketch {
+    fn new(cfg: &WindowConfig, capacity: usize) -> WindowedSketch {
+        match *cfg {
+            WindowConfig::Tumbling { length_secs } => WindowedSketch::Tumbling {
+                length_secs: length_secs as i64,
+                current_window: None,
+                current: SpaceSaving::new(capacity),
+                completed: SpaceSaving::new(capacity),
+            },
+            WindowConfig::Sliding { .. } => {
+                let (pane_secs, num_panes) = cfg.panes();
+                WindowedSketch::Sliding {
+                    pane_secs: pane_secs as i64,
+                    num_panes: num_panes as i64,
+                    panes: VecDeque::new(),
+                }
+            }
+        }
+    }
+
+    /// Advances the window to the given timestamp.
+    /// Returns whether a window or pane was completed, which changes the result of queries.
+    fn advance(&mut self, ts: chrono::DateTime<chrono::Utc>, capacity: usize) -> bool {
+        match self {
+            WindowedSketch::Tumbling {
+                length_secs,
+                current_window,
+                current,
+                completed,
+            } => {
+                let window = ts.timestamp().div_euclid(*length_secs);
+                match current_window {
+                    Some(w) if *w >= window => false,
+                    Some(w) => {
+                        let finished = std::mem::replace(current, SpaceSaving::new(capacity));
+                        // If there were no events in the window immediately preceding this one,
+                        // that window was empty.
+                        *completed = if *w + 1 == window {
+                            finished
+                        } else {
+                            SpaceSaving::new(capacity)
+                        };
+                        *current_window = Some(window);
+                        true
+                    }
+                    None => {
+                        *current_window = Some(window);
+                        false
+                    }
+                }
+            }
+            WindowedSketch::Sliding {
+                pane_secs,
+                num_panes,
+                panes,
+            } => {
+                let pane = ts.timestamp().div_euclid(*pane_secs);
+                if panes.back().is_some_and(|(p, _)| *p >= pane) {
+                    return false;
+                }
+                let completed = !panes.is_empty();
+                panes.push_back((pane, SpaceSaving::new(capacity)));
+                while panes.front().is_some_and(|(p, _)| *p <= pane - *num_panes) {
+                    panes.pop_front();
+                }
+                completed
+            }
+        }
+    }
+
+    fn insert(&mut self, item: &str) {
+        match self {
+            WindowedSketch::Tumbling { current, .. } => current.insert(item),
+            WindowedSketch::Sliding { panes, .. } => {
+                if let Some((_, pane)) = panes.back_mut() {
+                    pane.insert(item)
+                }
+            }
+        }
+    }
+
+    fn top(&self, n: usize, capacity: usize) -> Vec<HeavyHitter> {
+        match self {
+            WindowedSketch::Tumbling { completed, .. } => completed.top(n),
+            WindowedSketch::Sliding { panes, .. } => {
+                SpaceSaving::merge(panes.iter().map(|(_, p)| p), capacity).top(n)
+            }
+        }
+    }
+}
+
+/// The heavy hitters of a window, as returned by queries.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct WindowHeavyHitters {
+    pub(crate) window: String,
+    pub(crate) cids: Vec<HeavyHitter>,
+    pub(crate) peers: Vec<HeavyHitter>,
+}
+
+/// Sketches of requested CIDs and requesting peers over one window.
+struct WindowTracker {
+    name: String,
+    cids: WindowedSketch,
+    peers: WindowedSketch,
+    /// The label values of the gauges currently exported, to remove them once they drop out of
+    /// the top N.
+    exported_cids: Vec<[String; 4]>,
+    exported_peers: Vec<[String; 4]>,
+}
+
+impl WindowTracker {
+    fn update_gauges(&mut self, monitor: &str, top_n: usize, capacity: usize) {
+        Self::update_gauge(
+            &HEAVY_HITTER_CIDS,
+            &mut self.exported_cids,
+            monitor,
+            &self.name,
+            self.cids.top(top_n, capacity),
+        );
+        Self::update_gauge(
+            &HEAVY_HITTER_PEERS,
+            &mut self.exported_peers,
+            monitor,
+            &self.name,
+            self.peers.top(top_n, capacity),
+        );
+    }
+
+    fn update_gauge(
+        gauge: &IntGaugeVec,
+        exported: &mut Vec<[String; 4]>,
+        monitor: &str,
+        window: &str,
+        top: Vec<HeavyHitter>,
+    ) {
+        for labels in exported.drain(..) {
+            let labels = labels.iter().map(|l| l.as_str()).collect::<Vec<_>>();
+            // This fails if the gauge was never set, which is fine.
+            let _ = gauge.remove_label_values(&labels);
+        }
+        for (rank, hh) in top.into_iter().enumerate() {
+            let labels = [
+                monitor.to_string(),
+                window.to_string(),
+                (rank + 1).to_string(),
+                hh.item,
+            ];
+            gauge
+                .with_label_values(&labels.iter().map(|l| l.as_str()).collect::<Vec<_>>())
+                .set(hh.count as i64);
+            exported.push(labels);
+        }
+    }
+}
+
+/// Windowed sketches of one monitor, or of all monitors.
+struct Tracker {
+    monitor_label: String,
+    windows: Vec<WindowTracker>,
+}
+
+impl Tracker {
+    fn new(monitor_label: &str, cfg: &HeavyHittersConfig) -> Tracker {
+        Tracker {
+            monitor_label: monitor_label.to_string(),
+            windows: cfg
+                .windows
+                .iter()
+                .map(|w| WindowTracker {
+                    name: w.name(),
+                    cids: WindowedSketch::new(w, cfg.capacity),
+                    peers: WindowedSketch::new(w, cfg.capacity),
+                    exported_cids: Vec::new(),
+                    exported_peers: Vec::new(),
+                })
+                .collect(),
+        }
+    }
+
+    fn record<'a>(
+        &mut self,
+        ts: chrono::DateTime<chrono::Utc>,
+        peer: &str,
+        cids: impl Iterator<Item = &'a str> + Clone,
+        cfg: &HeavyHittersConfig,
+    ) {
+        for window in self.windows.iter_mut() {
+            let cids_completed = window.cids.advance(ts, cfg.capacity);
+            let peers_completed = window.peers.advance(ts, cfg.capacity);
+            if cids_completed || peers_completed {
+                window.update_gauges(&self.monitor_label, cfg.top_n, cfg.capacity);
+            }
+            for cid in cids.clone() {
+                window.cids.insert(cid);
+                window.peers.insert(peer);
+            }
+        }
+    }
+
+    fn query(&self, window: Option<&str>, n: usize, capacity: usize) -> Vec<WindowHeavyHitters> {
+        self.windows
+            .iter()
+            .filter(|w| window.is_none_or(|name| w.name == name))
+            .map(|w| WindowHeavyHitters {
+                window: w.name.clone(),
+                cids: w.cids.top(n, capacity),
+                peers: w.peers.top(n, capacity),
+            })
+            .collect()
+    }
+}
+
+/// Tracks the most requested CIDs and the peers sending the most requests, per monitor and over
+/// all monitors, over the configured windows of event time.
+/// The top N of each window are exported as gauges whenever a window or pane is completed.
+pub(crate) struct HeavyHitters {
+    cfg: HeavyHittersConfig,
+    global: Mutex<Tracker>,
+    monitors: Mutex<BTreeMap<String, Tracker>>,
+}
+
+impl HeavyHitters {
+    pub(crate) fn new(cfg: HeavyHittersConfig) -> Result<HeavyHitters> {
+        WindowConfig::validate_all(&cfg.windows).context("invalid windows")?;
+
+        Ok(HeavyHitters {
+            global: Mutex::new(Tracker::new(GLOBAL_LABEL, &cfg)),
+            monitors: Mutex::new(BTreeMap::new()),
+            cfg,
+        })
+    }
+
+    /// Counts the requests for the given CIDs by the given peer, received by the given monitor.
+    pub(crate) fn record<'a>(
+        &self,
+        monitor_name: &str,
+        ts: chrono::DateTime<chrono::Utc>,
+        peer: &str,
+        cids: impl Iterator<Item = &'a str> + Clone,
+    ) {
+        self.monitors
+            .lock()
+            .unwrap()
+            .entry(monitor_name.to_string())
+            .or_insert_with(|| Tracker::new(monitor_name, &self.cfg))
+            .record(ts, peer, cids.clone(), &self.cfg);
+        self.global
+            .lock()
+            .unwrap()
+            .record(ts, peer, cids, &self.cfg);
+    }
+
+    /// Returns the top `n` CIDs and peers of the given monitor, or of all monitors, for the given
+    /// window, or all windows.
+    /// Returns `None` if the monitor is unknown.
+    pub(crate) fn query(
+        &self,
+        monitor_name: Option<&str>,
+        window: Option<&str>,
+        n: usize,
+    ) -> Option<Vec<WindowHeavyHitters>> {
+        match monitor_name {
+            Some(name) => self
+                .monitors
+                .lock()
+                .unwrap()
+                .get(name)
+                .map(|t| t.query(window, n, self.cfg.capacity)),
+            None => Some(
+                self.global
+                    .lock()
+                    .unwrap()
+                    .query(window, n, self.cfg.capacity),
+            ),
+        }
+    }
+
+    /// Returns the number of items returned by queries if not specified.
+    pub(crate) fn top_n(&self) -> usize {
+        self.cfg.top_n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn space_saving_tracks_frequent_items() {
+        let mut sketch = SpaceSaving::new(10);
+        // a occurs 25 times, b 5 times, and 20 other items once each.
+        // Both occur more often than 45 items / 10 counters, so they are guaranteed to be tracked.
+        for i in 0..20 {
+            sketch.insert("a");
+            if i % 4 == 0 {
+                sketch.insert("b");
+            }
+            sketch.insert(&format!("x{}", i));
+        }
+        for _ in 0..5 {
+            sketch.insert("a");
+        }
+
+        let top = sketch.top(2);
+        assert_eq!(top[0].item, "a");
+        assert!(top[0].count >= 25 && top[0].count - top[0].error <= 25);
+        assert!(sketch.top(10).iter().any(|hh| hh.item == "b"));
+
+        let merged = SpaceSaving::merge([&sketch, &sketch], 10);
+        let top = merged.top(1);
+        assert_eq!(top[0].item, "a");
+        assert!(top[0].count >= 50 && top[0].count - top[0].error <= 50);
+    }
+
+    #[test]
+    fn windows_advance_by_event_time() {
+        let cfg = HeavyHittersConfig {
+            capacity: 10,
+            top_n: 2,
+            windows: vec![
+                WindowConfig::Tumbling { length_secs: 60 },
+                WindowConfig::Sliding {
+                    length_secs: 120,
+                    num_panes: 2,
+                },
+            ],
+        };
+        let hh = HeavyHitters::new(cfg).unwrap();
+        let ts = |secs| chrono::DateTime::from_timestamp(secs, 0).unwrap();
+
+        hh.record("m1", ts(0), "p1", ["a", "b"].into_iter());
+        hh.record("m2", ts(30), "p2", ["a"].into_iter());
+        hh.record("m1", ts(70), "p1", ["c"].into_iter());
+
+        let windows = hh.query(None, None, 10).unwrap();
+        assert_eq!(windows[0].window, "tumbling_60s");
+        // The first minute is completed.
+        let cids = windows[0].cids.iter().map(|c| (c.item.as_str(), c.count));
+        assert_eq!(cids.collect::<Vec<_>>(), vec![("a", 2), ("b", 1)]);
+        assert_eq!(windows[0].peers[0].item, "p1");
+        // The sliding window covers both minutes.
+        assert_eq!(windows[1].window, "sliding_120s");
+        assert_eq!(windows[1].cids.len(), 3);
+
+        let windows = hh.query(Some("m2"), Some("sliding_120s"), 10).unwrap();
+        assert_eq!(windows.len(), 1);
+        assert_eq!(windows[0].cids[0].item, "a");
+        assert!(hh.query(Some("m3"), None, 10).is_none());
+
+        // After two more minutes, the sliding window only covers the last minute.
+        hh.record("m1", ts(190), "p1", ["d"].into_iter());
+        let windows = hh.query(Some("m1"), None, 10).unwrap();
+        assert!(windows[0].cids.is_empty());
+        assert_eq!(windows[1].cids.len(), 1);
+    }
+}
@@ -5,31 +5,44 @@ extern crate lazy_static;
 #[macro_use]
 extern crate prometheus;
 
+use crate::api::WantlistAPI;
+use crate::cardinality::Cardinalities;
 use crate::config::Config;
 use crate::disklog::ToDiskLogger;
+use crate::engine::{EngineRegistry, LiveEngineSimulation};
+use crate::geolocation::OriginLocator;
+use crate::heavy_hitters::HeavyHitters;
 use crate::prom::{MetricsKey, MetricsMap, PublicGatewayStatus};
 use clap::{App, Arg};
 use failure::{err_msg, ResultExt};
 use futures_util::StreamExt;
+use ipfs_monitoring_plugin_client::file::FileMonitoringSource;
 use ipfs_monitoring_plugin_client::monitoring::{
-    BlockPresenceType, EventType, MonitoringClient, PushedEvent, RoutingKeyInformation,
+    BlockPresenceType, EventType, MonitoringClient, MonitoringClientOptions, MonitoringItem,
+    PushedEvent, RoutingKeyInformation,
 };
+use ipfs_monitoring_plugin_client::replay::ReplayClient;
+use ipfs_monitoring_plugin_client::source::MonitoringSource;
+use ipfs_monitoring_plugin_client::tcp::TCPMonitoringClient;
 use ipfs_resolver_common::wantlist::JSONWantType;
 use ipfs_resolver_common::{logging, Result};
-use maxminddb::Reader;
 use prom::{Geolocation, Metrics};
 use std::collections::HashSet;
 use std::env;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
 use tokio::select;
 use tokio::sync::RwLock;
 use tokio::task::JoinSet;
 
+mod api;
+mod cardinality;
 mod config;
 mod disklog;
+mod engine;
 mod gateways;
 mod geolocation;
+mod heavy_hitters;
+mod origin_as;
 mod prom;
 
 #[tokio::main]
@@ -68,15 +81,53 @@ async fn main() -> Result<()> {
 
 async fn run_with_config(cfg: Config) -> Result<()> {
     // Read GeoIP databases.
-    info!("reading MaxMind GeoLite2 database...");
-    let country_db =
-        geolocation::read_geoip_database(cfg.clone()).context("unable to open GeoIP databases")?;
-    let country_db = Arc::new(country_db);
-    info!("successfully read MaxMind database");
+    info!("reading MaxMind GeoLite2 databases...");
+    let origins = OriginLocator::open(&cfg).context("unable to open GeoIP databases")?;
+    let origins = Arc::new(origins);
+    info!("successfully read MaxMind databases");
+    if cfg.asn.is_some() {
+        info!("will label metrics by origin AS")
+    }
 
     if let Some(disk_logging_directory) = &cfg.disk_logging_directory {
         info!("will log to disk at {}", disk_logging_directory)
     }
+    if cfg.engine_simulation.is_some() {
+        info!("will run an engine simulation per monitor")
+    }
+    if cfg.heavy_hitters.is_some() {
+        info!("will track heavy hitters")
+    }
+    if cfg.cardinality.is_some() {
+        info!("will count distinct peers and CIDs")
+    }
+    let analyses = Analyses {
+        engines: EngineRegistry::new(
+            cfg.engine_simulation.clone(),
+            cfg.wantlist_api
+                .as_ref()
+                .map(|c| c.top_cids_max_minutes)
+                .unwrap_or(0),
+            cfg.wantlist_api
+                .as_ref()
+                .map(|c| c.top_cids_capacity)
+                .unwrap_or(0),
+        ),
+        heavy_hitters: cfg
+            .heavy_hitters
+            .clone()
+            .map(HeavyHitters::new)
+            .transpose()
+            .context("invalid heavy hitters config")?
+            .map(Arc::new),
+        cardinality: cfg
+            .cardinality
+            .clone()
+            .map(Cardinalities::new)
+            .transpose()
+            .context("unable to set up distinct counts")?
+            .map(Arc::new),
+    };
 
     // Read list of public gateway IDs.
     let known_gateways = Arc::new(RwLock::new(HashSet::new()));
@@ -109,6 +160,27 @@ async fn run_with_config(cfg: Config) -> Result<()> {
     prom::run_prometheus(prometheus_address)?;
     info!("started prometheus server");
 
+    if let Some(cardinality) = &analyses.cardinality {
+        cardinality.clone().spawn_gauge_updates();
+    }
+
+    // Set up wantlist API
+    if let Some(api_cfg) = &cfg.wantlist_api {
+        let api_address = api_cfg
+            .listen_address
+            .parse()
+            .expect("invalid wantlist API listen_address");
+
+        debug!("starting wantlist API");
+        WantlistAPI::new(
+            analyses.engines.clone(),
+            api_cfg.top_cids_max_minutes,
+            analyses.heavy_hitters.clone(),
+        )
+        .run(api_address)?;
+        info!("serving wantlist API on {}", api_address);
+    }
+
     // Set up shutdown channel
     let cancellation_token = tokio_util::sync::CancellationToken::new();
 
@@ -120,18 +192,19 @@ async fn run_with_config(cfg: Config) -> Result<()> {
         .into_iter()
         .for_each(|c| {
             c.monitor_names
-                .into_iter()
+                .iter()
                 .for_each(|name| {
                     let name = name.clone();
-                    let country_db = country_db.clone();
+                    let origins = origins.clone();
                     let known_gateways = known_gateways.clone();
                     let amqp_server_address = c.amqp_server_address.clone();
                     let disk_logging_dir = cfg.disk_logging_directory.clone();
+                    let analyses = analyses.clone();
                     let cancellation_token = cancellation_token.clone();
 
+                    let options = c.client_options(&name);
+
                     set.spawn(async move {
-                        // Create metrics for a few popular countries ahead of time.
-                        let mut metrics_by_country = Metrics::create_basic_set(&name);
                         let routing_keys = vec![
                             RoutingKeyInformation::BitswapMessages {
                                 monitor_name: name.clone(),
@@ -141,85 +214,170 @@ async fn run_with_config(cfg: Config) -> Result<()> {
                             },
                         ];
 
-                        loop {
-                            let country_db = country_db.clone();
+                        debug!(
+                            "connecting to AMQP server {} at {} and subscribing to events for monitor {}...",
+                            name, amqp_server_address, name
+                        );
+                        let client = MonitoringClient::new_with_options(&amqp_server_address, &routing_keys, options).await?;
+                        info!(
+                            "connected for monitor {} at {}",
+                            name, amqp_server_address
+                        );
 
-                            debug!(
-                                "connecting to AMQP server {} at {} and subscribing to events for monitor {}...",
-                                name, amqp_server_address, name
-                            );
-                            let client = MonitoringClient::new(&amqp_server_address, &routing_keys).await?;
-                            info!(
-                                "connected for monitor {} at {}",
-                                name, amqp_server_address
-                            );
+                        let res = run_source(
+                            &name,
+                            client,
+                            origins,
+                            &known_gateways,
+                            disk_logging_dir,
+                            analyses,
+                            &cancellation_token,
+                        )
+                        .await;
 
-                            // Create disk logger
-                            let disk_logger = if let Some(dir) = disk_logging_dir.clone() {
-                                Some(
-                                    ToDiskLogger::new_for_monitor(&dir, &name)
-                                        .await
-                                        .context("unable to set up disk logging")?,
-                                )
-                            } else {
-                                None
-                            };
-
-                            let res = receive_from_monitor(
-                                &mut metrics_by_country,
-                                &name,
-                                client,
-                                country_db,
-                                &known_gateways,
-                                &disk_logger,
-                                &cancellation_token,
-                            )
-                            .await;
-                            info!(
-                                "server {}, monitor {}: result: {:?}",
-                                amqp_server_address, name, res
-                            );
+                        // The client reconnects on its own, so if we end up here it gave up.
+                        res.and_then(|_| if cancellation_token.is_cancelled() {
+                            Ok(())
+                        } else {
+                            Err(err_msg(format!(
+                                "server {}, monitor {}: connection closed",
+                                amqp_server_address, name
+                            )))
+                        })
+                    });
+                })
+        });
 
-                            if let Some(logger) = disk_logger {
-                                info!("server {}, monitor {}: finalizing disk logs...",amqp_server_address, name);
-                                if let Err(e) = logger.close().await {
-                                    error!("server {}, monitor {}: unable to finalize disk logs: {:?}",amqp_server_address, name,e)
-                                } else {
-                                    debug!("server {}, monitor {}: successfully finalized disk logs",amqp_server_address, name);
-                                }
-                            }
+    // Connect to TCP monitors
+    for c in cfg.tcp_sources.into_iter() {
+        let origins = origins.clone();
+        let known_gateways = known_gateways.clone();
+        let disk_logging_dir = cfg.disk_logging_directory.clone();
+        let analyses = analyses.clone();
+        let cancellation_token = cancellation_token.clone();
+        let options = MonitoringClientOptions {
+            reconnect: Some(c.reconnect.clone()),
+            ..Default::default()
+        };
 
-                            if cancellation_token.is_cancelled() {
-                                info!(
-                                    "server {}, monitor {}: exiting",
-                                    amqp_server_address, name
-                                );
-                                return Ok(())
-                            }
+        set.spawn(async move {
+            let name = c.monitor_name;
+            debug!("connecting to monitor {} via TCP at {}...", name, c.address);
+            let client = TCPMonitoringClient::new_with_options(&c.address, &name, options).await?;
+            info!("connected to monitor {} via TCP at {}", name, c.address);
+
+            let res = run_source(
+                &name,
+                client,
+                origins,
+                &known_gateways,
+                disk_logging_dir,
+                analyses,
+                &cancellation_token,
+            )
+            .await;
+
+            // The client reconnects on its own, so if we end up here it gave up.
+            res.and_then(|_| {
+                if cancellation_token.is_cancelled() {
+                    Ok(())
+                } else {
+                    Err(err_msg(format!(
+                        "TCP monitor {} at {}: connection closed",
+                        name, c.address
+                    )))
+                }
+            })
+        });
+    }
 
-                            info!(
-                                "server {}, monitor {}: sleeping for one second",
-                                amqp_server_address, name
-                            );
-                            tokio::time::sleep(Duration::from_secs(1)).await;
-                        }
+    // Read events from files
+    for c in cfg.file_sources.into_iter() {
+        let origins = origins.clone();
+        let known_gateways = known_gateways.clone();
+        let disk_logging_dir = cfg.disk_logging_directory.clone();
+        let analyses = analyses.clone();
+        let cancellation_token = cancellation_token.clone();
 
-                    });
-                })
+        debug!(
+            "setting up reading from {} for monitor {}",
+            c.path, c.monitor_name
+        );
+        let source = FileMonitoringSource::new(&c.monitor_name, &c.path)
+            .context("unable to set up file source")?;
+
+        set.spawn(async move {
+            run_source(
+                &c.monitor_name,
+                source,
+                origins,
+                &known_gateways,
+                disk_logging_dir,
+                analyses,
+                &cancellation_token,
+            )
+            .await
         });
+    }
+
+    // Replay recorded traces
+    for c in cfg.replay_sources.into_iter() {
+        let origins = origins.clone();
+        let known_gateways = known_gateways.clone();
+        let analyses = analyses.clone();
+        let cancellation_token = cancellation_token.clone();
+
+        debug!(
+            "setting up replay of {:?} for monitor {} at speed {:?}",
+            c.input_globs, c.monitor_name, c.speed
+        );
+        let client = ReplayClient::new(&c.monitor_name, &c.input_globs, c.speed)
+            .context("unable to set up replay")?;
+
+        set.spawn(async move {
+            // We don't log replayed events to disk, they're on disk already.
+            run_source(
+                &c.monitor_name,
+                client,
+                origins,
+                &known_gateways,
+                None,
+                analyses,
+                &cancellation_token,
+            )
+            .await
+        });
+    }
 
     // Sleep forever (probably)
     let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
         .context("unable to set up signal handling")?;
-    tokio::select! {
-        res = set.join_next() => {
-            error!("listeners failed, shutting down: {:?}", res)
-        },
-        _ = tokio::signal::ctrl_c() => {
-            info!("received shutdown signal, shutting down...")
-        },
-        _ = sigterm.recv() => {
-            info!("received SIGTERM, shutting down...")
+    loop {
+        tokio::select! {
+            res = set.join_next() => {
+                match res {
+                    None => {
+                        info!("all sources finished, shutting down...");
+                        break
+                    }
+                    Some(Ok(Ok(()))) => {
+                        // Files and replays finish on their own, keep going with the others.
+                        debug!("source finished, {} remaining", set.len());
+                    }
+                    Some(res) => {
+                        error!("listeners failed, shutting down: {:?}", res);
+                        break
+                    }
+                }
+            },
+            _ = tokio::signal::ctrl_c() => {
+                info!("received shutdown signal, shutting down...");
+                break
+            },
+            _ = sigterm.recv() => {
+                info!("received SIGTERM, shutting down...");
+                break
+            }
         }
     }
 
@@ -229,19 +387,115 @@ async fn run_with_config(cfg: Config) -> Result<()> {
     // Wait for anything still running
     set.join_all().await;
 
+    // Persist distinct counts
+    if let Some(cardinality) = &analyses.cardinality {
+        cardinality
+            .save()
+            .context("unable to persist distinct counts")?;
+    }
+
     Ok(())
 }
 
-async fn receive_from_monitor(
+/// The real-time analyses run on events in addition to the metrics, shared by all monitors.
+#[derive(Clone)]
+struct Analyses {
+    engines: EngineRegistry,
+    heavy_hitters: Option<Arc<HeavyHitters>>,
+    cardinality: Option<Arc<Cardinalities>>,
+}
+
+/// The real-time analyses run on the events of one monitor.
+struct MonitorAnalyses {
+    engine: Option<Arc<Mutex<LiveEngineSimulation>>>,
+    heavy_hitters: Option<Arc<HeavyHitters>>,
+    cardinality: Option<Arc<Cardinalities>>,
+}
+
+/// Receives and analyzes events from the given source until it ends or we shut down.
+/// Optionally logs events to disk and runs further analyses.
+async fn run_source<S: MonitoringSource>(
+    monitor_name: &str,
+    source: S,
+    origins: Arc<OriginLocator>,
+    known_gateways: &Arc<RwLock<HashSet<String>>>,
+    disk_logging_dir: Option<String>,
+    analyses: Analyses,
+    cancellation_token: &tokio_util::sync::CancellationToken,
+) -> Result<()> {
+    // Create metrics for a few popular countries ahead of time.
+    let mut metrics_by_country =
+        Metrics::create_basic_set(monitor_name, origins.unknown_as_label());
+    let remote = source.remote().to_string();
+
+    // Create disk logger
+    let disk_logger = if let Some(dir) = disk_logging_dir {
+        Some(
+            ToDiskLogger::new_for_monitor(&dir, monitor_name)
+                .await
+                .context("unable to set up disk logging")?,
+        )
+    } else {
+        None
+    };
+
+    // Set up analyses
+    let analyses = MonitorAnalyses {
+        engine: analyses.engines.create(monitor_name)?,
+        heavy_hitters: analyses.heavy_hitters,
+        cardinality: analyses.cardinality,
+    };
+
+    let res = receive_from_monitor(
+        &mut metrics_by_country,
+        monitor_name,
+        source,
+        origins,
+        known_gateways,
+        &disk_logger,
+        &analyses,
+        cancellation_token,
+    )
+    .await;
+    info!("{}, monitor {}: result: {:?}", remote, monitor_name, res);
+
+    if let Some(logger) = disk_logger {
+        info!(
+            "{}, monitor {}: finalizing disk logs...",
+            remote, monitor_name
+        );
+        if let Err(e) = logger.close().await {
+            error!(
+                "{}, monitor {}: unable to finalize disk logs: {:?}",
+                remote, monitor_name, e
+            )
+        } else {
+            debug!(
+                "{}, monitor {}: successfully finalized disk logs",
+                remote, monitor_name
+            );
+        }
+    }
+
+    res
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn receive_from_monitor<S>(
     metrics_by_country: &mut prom::MetricsMap,
     monitor_name: &str,
-    mut client: MonitoringClient,
-    country_db: Arc<maxminddb::Reader<Vec<u8>>>,
+    mut client: S,
+    origins: Arc<OriginLocator>,
     known_gateways: &Arc<RwLock<HashSet<String>>>,
     disk_logger: &Option<ToDiskLogger>,
+    analyses: &MonitorAnalyses,
     cancellation_token: &tokio_util::sync::CancellationToken,
-) -> Result<()> {
+) -> Result<()>
+where
+    S: MonitoringSource,
+{
     let mut first = true;
+    let mut as_labels_generation = origins.as_labels_generation();
 
     loop {
         select! {
@@ -250,19 +504,41 @@ async fn receive_from_monitor(
                 break;
             }
             received = client.next() => {
-                if let Some(events) = received {
-                    let (_, events) = events.context("unable to receive events")?;
+                if let Some(item) = received {
+                    let events = match item.context("unable to receive events")? {
+                        MonitoringItem::Events(_, events) => events,
+                        MonitoringItem::Gap(gap) => {
+                            warn!(
+                                "monitor {}: missed data from {} to {}",
+                                monitor_name, gap.disconnected_at, gap.reconnected_at
+                            );
+                            prom::MONITORING_GAPS
+                                .with_label_values(&[monitor_name])
+                                .inc();
+                            prom::MONITORING_GAP_SECONDS
+                                .with_label_values(&[monitor_name])
+                                .inc_by(gap.duration().num_milliseconds() as f64 / 1000_f64);
+                            continue;
+                        }
+                    };
                     if first {
                         first = false;
                         info!("receiving messages for monitor {}...", monitor_name)
                     }
 
+                    let generation = origins.as_labels_generation();
+                    if generation != as_labels_generation {
+                        as_labels_generation = generation;
+                        remove_unused_as_metrics(metrics_by_country, monitor_name, &origins);
+                    }
+
                     handle_received_events(
                         metrics_by_country,
                         monitor_name,
-                        &country_db,
+                        &origins,
                         known_gateways,
                         disk_logger,
+                        analyses,
                         events,
                     )
                     .await?;
@@ -278,19 +554,40 @@ async fn receive_from_monitor(
     Ok(())
 }
 
+/// Removes the metrics of the given monitor whose `origin_as` label is not in use anymore, both
+/// from the map and from the registry, such that they are not exported anymore.
+fn remove_unused_as_metrics(
+    metrics_by_country: &mut MetricsMap,
+    monitor_name: &str,
+    origins: &OriginLocator,
+) {
+    metrics_by_country.retain(|key, _| {
+        if origins.is_as_label_in_use(&key.as_origin) {
+            return true;
+        }
+        debug!(
+            "{}: removing metrics for unused AS label {:?}",
+            monitor_name, key
+        );
+        Metrics::remove_for_key(monitor_name, key);
+        false
+    });
+}
+
 async fn handle_received_events(
     metrics_by_country: &mut MetricsMap,
     monitor_name: &str,
-    country_db: &Arc<Reader<Vec<u8>>>,
+    origins: &OriginLocator,
     known_gateways: &Arc<RwLock<HashSet<String>>>,
     disk_logger: &Option<ToDiskLogger>,
+    analyses: &MonitorAnalyses,
     events: Vec<PushedEvent>,
 ) -> Result<()> {
     for event in events {
-        let geolocation = geolocation::geolocate_event(&country_db, &event);
+        let (geolocation, as_label) = origins.locate_event(&event);
         debug!(
-            "{}: determined origin of event {:?} to be {:?}",
-            monitor_name, event, geolocation
+            "{}: determined origin of event {:?} to be {:?}, {:?}",
+            monitor_name, event, geolocation, as_label
         );
 
         let origin_type = if known_gateways.read().await.contains(&event.peer) {
@@ -302,6 +599,7 @@ async fn handle_received_events(
         let metrics_key = MetricsKey {
             geo_origin: geolocation,
             overlay_origin: origin_type,
+            as_origin: as_label,
         };
 
         let metrics = match metrics_by_country.get(&metrics_key) {
@@ -323,13 +621,17 @@ async fn handle_received_events(
                             metrics_key, e
                         );
                         // We use the Error country instead.
-                        // We know this is safe since that country is always present in the map.
+                        // We know this is safe since metrics can always be created for that
+                        // country.
+                        let error_key = MetricsKey {
+                            geo_origin: Geolocation::Error,
+                            ..metrics_key.clone()
+                        };
                         metrics_by_country
-                            .get(&MetricsKey {
-                                geo_origin: Geolocation::Error,
-                                overlay_origin: metrics_key.overlay_origin,
+                            .entry(error_key.clone())
+                            .or_insert_with(|| {
+                                Metrics::new_for_key(monitor_name, &error_key).unwrap()
                             })
-                            .unwrap()
                     }
                 }
             }
@@ -359,7 +661,32 @@ async fn handle_received_events(
             EventType::BitswapMessage(msg) => {
                 metrics.num_messages.inc();
 
+                if let Some(heavy_hitters) = &analyses.heavy_hitters {
+                    heavy_hitters.record(
+                        monitor_name,
+                        event.timestamp,
+                        &event.peer,
+                        msg.wantlist_entries
+                            .iter()
+                            .filter(|e| !e.cancel)
+                            .map(|e| e.cid.path.as_str()),
+                    );
+                }
+
                 if !msg.wantlist_entries.is_empty() {
+                    if let Some(cardinality) = &analyses.cardinality {
+                        cardinality.record(
+                            monitor_name,
+                            event.timestamp,
+                            &metrics_key,
+                            &event.peer,
+                            msg.wantlist_entries
+                                .iter()
+                                .filter(|e| !e.cancel)
+                                .map(|e| e.cid.path.as_str()),
+                        );
+                    }
+
                     if msg.full_wantlist {
                         metrics.num_wantlists_full.inc();
                     } else {
@@ -447,6 +774,16 @@ async fn handle_received_events(
             }
         }
 
+        // Simulate the engine
+        if let Some(engine) = &analyses.engine {
+            if let Err(e) = engine.lock().unwrap().handle_event(&event) {
+                warn!(
+                    "{}: unable to simulate event {:?}: {:?}",
+                    monitor_name, event, e
+                )
+            }
+        }
+
         // Log to disk
         if let Some(logger) = disk_logger {
             logger
@@ -0,0 +1,184 @@
+use crate::config::{ASLabelKind, ASNConfig};
+use crate::heavy_hitters::SpaceSaving;
+use ipfs_resolver_common::geoip::AutonomousSystem;
+use std::collections::HashSet;
+
+/// The value of the `origin_as` label if AS enrichment is disabled.
+pub(crate) const AS_LABEL_DISABLED: &str = "";
+
+/// The value of the `origin_as` label for events whose AS is not known, e.g., relayed
+/// connections or addresses not contained in the database.
+pub(crate) const AS_LABEL_UNKNOWN: &str = "Unknown";
+
+/// The value of the `origin_as` label for events whose AS could not be looked up.
+pub(crate) const AS_LABEL_ERROR: &str = "Error";
+
+/// The value of the `origin_as` label for events from ASes not among the top N.
+pub(crate) const AS_LABEL_OTHER: &str = "Other";
+
+/// The number of labeled events after which the set of labels used as-is is updated.
+const TOP_UPDATE_INTERVAL: u64 = 10_000;
+
+/// The number of labels counted per label used as-is.
+const SKETCH_CAPACITY_FACTOR: usize = 10;
+
+/// Represents the autonomous system an IPFS node is located in.
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub(crate) enum ASOrigin {
+    /// The AS could not be determined, e.g., because AS enrichment is disabled or the address is
+    /// not contained in the database.
+    Unknown,
+
+    /// The AS could not be determined because an error occurred.
+    Error,
+
+    /// The AS was found.
+    AS(AutonomousSystem),
+}
+
+/// Assigns values of the `origin_as` label, keeping the number of distinct values bounded.
+///
+/// Labels are counted with a Space-Saving sketch.
+/// Until N labels are known, every new label is used as-is.
+/// After that, the set of labels used as-is is replaced by the top N labels of the sketch every
+/// 10000 events, and all other labels are replaced by "Other".
+///
+/// Labels which drop out of the top N are not in use anymore, and the series labeled with them
+/// should be removed, see `generation` and `is_in_use`.
+/// Note that moving an AS between its own label and "Other" distorts rates computed over the
+/// time of the move: the events of the AS stop being counted in one series and start being
+/// counted in the other, whose counter may be reset, e.g., if it was removed earlier.
+pub(crate) struct ASLabeler {
+    cfg: Option<ASNConfig>,
+    counts: SpaceSaving,
+    top: HashSet<String>,
+    /// Incremented whenever labels drop out of the top N.
+    generation: u64,
+    num_events: u64,
+}
+
+impl ASLabeler {
+    /// Creates a labeler for the given configuration, or one which always returns an empty label
+    /// if AS enrichment is disabled.
+    pub(crate) fn new(cfg: Option<ASNConfig>) -> ASLabeler {
+        let capacity = cfg
+            .as_ref()
+            .map(|c| c.top_n * SKETCH_CAPACITY_FACTOR)
+            .unwrap_or(0);
+        ASLabeler {
+            cfg,
+            counts: SpaceSaving::new(capacity),
+            top: HashSet::new(),
+            generation: 0,
+            num_events: 0,
+        }
+    }
+
+    /// Returns the label of events whose AS is not known.
+    pub(crate) fn unknown_label(&self) -> &'static str {
+        if self.cfg.is_some() {
+            AS_LABEL_UNKNOWN
+        } else {
+            AS_LABEL_DISABLED
+        }
+    }
+
+    /// Returns a number which changes whenever labels stop being in use.
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns whether the given label is currently in use, i.e., whether it is one of the special
+    /// labels or among the labels used as-is.
+    pub(crate) fn is_in_use(&self, label: &str) -> bool {
+        self.cfg.is_none()
+            || [AS_LABEL_UNKNOWN, AS_LABEL_ERROR, AS_LABEL_OTHER].contains(&label)
+            || self.top.contains(label)
+    }
+
+    /// Counts an event from the given AS and returns its label.
+    pub(crate) fn label(&mut self, origin: &ASOrigin) -> String {
+        let cfg = match &self.cfg {
+            Some(cfg) => cfg,
+            None => return AS_LABEL_DISABLED.to_string(),
+        };
+        let asn = match origin {
+            ASOrigin::Unknown => return AS_LABEL_UNKNOWN.to_string(),
+            ASOrigin::Error => return AS_LABEL_ERROR.to_string(),
+            ASOrigin::AS(asn) => asn,
+        };
+        let label = match (cfg.label, &asn.organization) {
+            (ASLabelKind::Organization, Some(organization)) => organization.clone(),
+            _ => format!("AS{}", asn.number),
+        };
+        let top_n = cfg.top_n;
+
+        self.counts.insert(&label);
+        self.num_events += 1;
+        if self.num_events.is_multiple_of(TOP_UPDATE_INTERVAL) {
+            let top = self
+                .counts
+                .top(top_n)
+                .into_iter()
+                .map(|h| h.item)
+                .collect::<HashSet<_>>();
+            if !self.top.is_subset(&top) {
+                self.generation += 1;
+            }
+            self.top = top;
+            debug!("updated top ASes to {:?}", self.top);
+        } else if self.top.len() < top_n {
+            self.top.insert(label.clone());
+        }
+
+        if self.top.contains(&label) {
+            label
+        } else {
+            AS_LABEL_OTHER.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origin(number: u32, organization: &str) -> ASOrigin {
+        ASOrigin::AS(AutonomousSystem {
+            number,
+            organization: Some(organization.to_string()),
+        })
+    }
+
+    #[test]
+    fn buckets_labels_beyond_top_n() {
+        let mut labeler = ASLabeler::new(Some(ASNConfig {
+            label: ASLabelKind::Organization,
+            top_n: 2,
+        }));
+        assert_eq!(labeler.label(&origin(1, "A")), "A");
+        assert_eq!(labeler.label(&origin(1, "A")), "A");
+        assert_eq!(labeler.label(&origin(2, "B")), "B");
+        assert_eq!(labeler.label(&origin(3, "C")), AS_LABEL_OTHER);
+        assert_eq!(labeler.label(&ASOrigin::Unknown), AS_LABEL_UNKNOWN);
+        assert!(!labeler.is_in_use("C"));
+        assert_eq!(labeler.generation(), 0);
+
+        // Once C is among the most frequent labels, it replaces the least frequent one.
+        for _ in 4..TOP_UPDATE_INTERVAL {
+            labeler.label(&origin(3, "C"));
+        }
+        assert_eq!(labeler.label(&origin(3, "C")), "C");
+        assert_eq!(labeler.label(&origin(1, "A")), "A");
+        assert_eq!(labeler.label(&origin(2, "B")), AS_LABEL_OTHER);
+        // B is not in use anymore.
+        assert_eq!(labeler.generation(), 1);
+        assert!(!labeler.is_in_use("B"));
+        assert!(labeler.is_in_use("C"));
+        assert!(labeler.is_in_use(AS_LABEL_OTHER));
+
+        let mut disabled = ASLabeler::new(None);
+        assert_eq!(disabled.label(&origin(1, "A")), AS_LABEL_DISABLED);
+        assert_eq!(disabled.unknown_label(), AS_LABEL_DISABLED);
+    }
+}
@@ -1,7 +1,9 @@
 use failure::{err_msg, ResultExt};
 use ipfs_resolver_common::Result;
 use prometheus::core::{AtomicU64, GenericCounter};
-use prometheus::IntCounterVec;
+use prometheus::{
+    CounterVec, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 
@@ -9,49 +11,141 @@ lazy_static! {
     pub static ref BITSWAP_MESSAGES_RECEIVED: IntCounterVec = register_int_counter_vec!(
         "bitswap_messages_received",
         "number of bitswap messages (both requests and responses) received by monitor and origin country",
-        &["monitor","origin_country","origin_is_gateway"]
+        &["monitor","origin_country","origin_is_gateway","origin_as"]
     )
     .unwrap();
 
     pub static ref BITSWAP_BLOCKS_RECEIVED: IntCounterVec = register_int_counter_vec!(
         "bitswap_blocks_received",
         "number of blocks received via bitswap, by monitor and origin country",
-        &["monitor","origin_country","origin_is_gateway"]
+        &["monitor","origin_country","origin_is_gateway","origin_as"]
     )
     .unwrap();
 
     pub static ref BITSWAP_BLOCK_PRESENCES_RECEIVED: IntCounterVec = register_int_counter_vec!(
         "bitswap_block_presences_received",
         "number of block presences received via bitswap, by monitor, presence type, and origin country",
-        &["monitor","presence_type","origin_country","origin_is_gateway"]
+        &["monitor","presence_type","origin_country","origin_is_gateway","origin_as"]
     )
     .unwrap();
 
     pub static ref WANTLIST_ENTRIES_RECEIVED: IntCounterVec = register_int_counter_vec!(
         "wantlist_entries_received",
         "number of wantlist entries received by monitor, entry type, send_dont_have, and origin country",
-        &["monitor","entry_type","send_dont_have","origin_country","origin_is_gateway"]
+        &["monitor","entry_type","send_dont_have","origin_country","origin_is_gateway","origin_as"]
     )
     .unwrap();
 
     pub static ref WANTLISTS_RECEIVED: IntCounterVec = register_int_counter_vec!(
         "wantlists_received",
         "number of bitswap messages received for which the wantlist was not empty, by monitor, whether the wantlist was a full wantlist, and origin country",
-        &["monitor","full","origin_country","origin_is_gateway"]
+        &["monitor","full","origin_country","origin_is_gateway","origin_as"]
     )
     .unwrap();
 
     pub static ref CONNECTION_EVENTS_CONNECTED: IntCounterVec = register_int_counter_vec!(
         "connection_events_connected",
         "number of connect events by monitor and origin country",
-        &["monitor","origin_country","origin_is_gateway"]
+        &["monitor","origin_country","origin_is_gateway","origin_as"]
     )
     .unwrap();
 
     pub static ref CONNECTION_EVENTS_DISCONNECTED: IntCounterVec = register_int_counter_vec!(
         "connection_events_disconnected",
         "number of disconnect events by monitor and origin country",
-        &["monitor","origin_country","origin_is_gateway"]
+        &["monitor","origin_country","origin_is_gateway","origin_as"]
+    )
+    .unwrap();
+
+    pub static ref MONITORING_GAPS: IntCounterVec = register_int_counter_vec!(
+        "monitoring_gaps",
+        "number of times the connection to the AMQP server was lost and re-established, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+
+    pub static ref MONITORING_GAP_SECONDS: CounterVec = register_counter_vec!(
+        "monitoring_gap_seconds",
+        "total time during which no data was received due to connection loss, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+
+    pub static ref ENGINE_LEDGERS: IntGaugeVec = register_int_gauge_vec!(
+        "engine_ledgers",
+        "number of ledgers in the engine simulation, by monitor and whether the peer is connected",
+        &["monitor","connected"]
+    )
+    .unwrap();
+
+    pub static ref ENGINE_ACTIVE_WANTS: IntGaugeVec = register_int_gauge_vec!(
+        "engine_active_wants",
+        "number of entries currently wanted by all peers in the engine simulation, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+
+    pub static ref ENGINE_ACTIVE_WANTS_PER_PEER: HistogramVec = register_histogram_vec!(
+        "engine_active_wants_per_peer",
+        "number of entries wanted by a peer after each of its wantlists, by monitor",
+        &["monitor"],
+        vec![0.0, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0, 2000.0, 5000.0]
+    )
+    .unwrap();
+
+    pub static ref ENGINE_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "engine_requests",
+        "number of requests classified by the engine simulation, by monitor and duplicate status",
+        &["monitor","duplicate_status"]
+    )
+    .unwrap();
+
+    pub static ref ENGINE_UPGRADES: IntCounterVec = register_int_counter_vec!(
+        "engine_upgrades",
+        "number of WANT_BLOCK requests upgrading an earlier WANT_HAVE request, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+
+    pub static ref ENGINE_SYNTHETIC_CANCELS: IntCounterVec = register_int_counter_vec!(
+        "engine_synthetic_cancels",
+        "number of synthetic CANCELs generated by the engine simulation, by monitor and reason",
+        &["monitor","reason"]
+    )
+    .unwrap();
+
+    pub static ref ENGINE_EVICTED_LEDGERS: IntCounterVec = register_int_counter_vec!(
+        "engine_evicted_ledgers",
+        "number of ledgers of disconnected peers evicted from the engine simulation, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+
+    pub static ref HEAVY_HITTER_CIDS: IntGaugeVec = register_int_gauge_vec!(
+        "heavy_hitter_cids",
+        "estimated number of requests for the top requested CIDs, by monitor, window, rank, and CID",
+        &["monitor","window","rank","cid"]
+    )
+    .unwrap();
+
+    pub static ref HEAVY_HITTER_PEERS: IntGaugeVec = register_int_gauge_vec!(
+        "heavy_hitter_peers",
+        "estimated number of requests sent by the top requesting peers, by monitor, window, rank, and peer",
+        &["monitor","window","rank","peer"]
+    )
+    .unwrap();
+
+    pub static ref DISTINCT_PEERS: IntGaugeVec = register_int_gauge_vec!(
+        "distinct_peers",
+        "estimated number of distinct peers sending wantlists, by monitor, origin country, and window",
+        &["monitor","origin_country","origin_is_gateway","window"]
+    )
+    .unwrap();
+
+    pub static ref DISTINCT_CIDS: IntGaugeVec = register_int_gauge_vec!(
+        "distinct_cids",
+        "estimated number of distinct CIDs requested, by monitor, origin country, and window",
+        &["monitor","origin_country","origin_is_gateway","window"]
     )
     .unwrap();
 }
@@ -99,7 +193,7 @@ pub(crate) enum PublicGatewayStatus {
 }
 
 impl PublicGatewayStatus {
-    fn is_gateway_str(&self) -> &'static str {
+    pub(crate) fn is_gateway_str(&self) -> &'static str {
         match self {
             PublicGatewayStatus::NonGateway => "false",
             PublicGatewayStatus::Gateway => "true",
@@ -121,19 +215,42 @@ pub(crate) enum Geolocation {
     Alpha2(String),
 }
 
+impl Geolocation {
+    /// Returns the country name used in the `origin_country` label.
+    /// If the geolocation is [`Geolocation::Alpha2`] and not a valid 2-letter ISO3166-1 code, an
+    /// error is returned.
+    pub(crate) fn country_name(&self) -> Result<&'static str> {
+        match self {
+            Geolocation::Alpha2(country_code) => {
+                let country = celes::Country::from_alpha2(country_code)
+                    .map_err(|e| err_msg(format!("{}", e)))
+                    .context("invalid country code")?;
+                Ok(country.long_name)
+            }
+            Geolocation::Unknown => Ok(COUNTRY_NAME_UNKNOWN),
+            Geolocation::Error => Ok(COUNTRY_NAME_ERROR),
+        }
+    }
+}
+
 /// The key type for metrics.
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub(crate) struct MetricsKey {
     pub geo_origin: Geolocation,
     pub overlay_origin: PublicGatewayStatus,
+    /// The value of the `origin_as` label, see [`ASLabeler`](crate::origin_as::ASLabeler).
+    pub as_origin: String,
 }
 
 pub(crate) type MetricsMap = HashMap<MetricsKey, Metrics>;
 
 impl Metrics {
     /// Creates a set of metrics consisting of a few popular countries and the special
-    /// error-condition countries, for the given monitor.
-    pub(crate) fn create_basic_set(monitor_name: &str) -> HashMap<MetricsKey, Metrics> {
+    /// error-condition countries, for the given monitor and value of the `origin_as` label.
+    pub(crate) fn create_basic_set(
+        monitor_name: &str,
+        as_label: &str,
+    ) -> HashMap<MetricsKey, Metrics> {
         [
             // These are countries with high traffic, so we initialize them beforehand.
             celes::Country::germany(),
@@ -150,10 +267,12 @@ impl Metrics {
                 MetricsKey {
                     geo_origin: g.clone(),
                     overlay_origin: PublicGatewayStatus::NonGateway,
+                    as_origin: as_label.to_string(),
                 },
                 MetricsKey {
                     geo_origin: g,
                     overlay_origin: PublicGatewayStatus::Gateway,
+                    as_origin: as_label.to_string(),
                 },
             ]
         })
@@ -167,149 +286,228 @@ impl Metrics {
         .collect()
     }
 
-    /// Creates a new set of metrics for the given metrics key, encoding geolocation and gateway
-    /// status.
+    /// Creates a new set of metrics for the given metrics key, encoding geolocation, gateway
+    /// status, and autonomous system.
     /// If the geolocation is [`Geolocation::Alpha2`] and not a valid 2-letter ISO3166-1 code, an
     /// error is returned.
     pub(crate) fn new_for_key(monitor_name: &str, key: &MetricsKey) -> Result<Metrics> {
-        match &key.geo_origin {
-            Geolocation::Alpha2(country_code) => {
-                let country = celes::Country::from_alpha2(country_code)
-                    .map_err(|e| err_msg(format!("{}", e)))
-                    .context("invalid country code")?;
-                Ok(Self::new_for_country_name_and_gateway_status(
-                    monitor_name,
-                    country.long_name,
-                    key.overlay_origin,
-                ))
-            }
-            Geolocation::Unknown => Ok(Self::new_for_country_name_and_gateway_status(
-                monitor_name,
-                COUNTRY_NAME_UNKNOWN,
-                key.overlay_origin,
-            )),
-            Geolocation::Error => Ok(Self::new_for_country_name_and_gateway_status(
-                monitor_name,
-                COUNTRY_NAME_ERROR,
-                key.overlay_origin,
-            )),
-        }
+        Ok(Self::new_for_origin_labels(
+            monitor_name,
+            key.geo_origin.country_name()?,
+            key.overlay_origin,
+            &key.as_origin,
+        ))
     }
 
-    /// Creates a new set of metrics for the country name and gateway status.
-    fn new_for_country_name_and_gateway_status(
+    /// Creates a new set of metrics for the country name, gateway status, and AS label.
+    fn new_for_origin_labels(
         monitor_name: &str,
         country_name: &str,
         gateway_status: PublicGatewayStatus,
+        as_label: &str,
     ) -> Metrics {
+        let mut series = Self::series(monitor_name, country_name, gateway_status, as_label)
+            .into_iter()
+            .map(|(vec, labels)| vec.get_metric_with_label_values(&labels).unwrap());
+        let mut next = || series.next().unwrap();
+
+        // The fields are initialized in order, which must match the order of the series.
         Metrics {
-            num_messages: BITSWAP_MESSAGES_RECEIVED
-                .get_metric_with_label_values(&[
-                    monitor_name,
-                    country_name,
-                    gateway_status.is_gateway_str(),
-                ])
-                .unwrap(),
+            num_messages: next(),
+            num_entries_cancel: next(),
+            num_entries_want_block: next(),
+            num_entries_want_block_send_dont_have: next(),
+            num_entries_want_have: next(),
+            num_entries_want_have_send_dont_have: next(),
+            num_connected: next(),
+            num_disconnected: next(),
+            num_wantlists_incremental: next(),
+            num_wantlists_full: next(),
+            num_blocks: next(),
+            num_block_presence_have: next(),
+            num_block_presence_dont_have: next(),
+        }
+    }
 
-            num_entries_cancel: WANTLIST_ENTRIES_RECEIVED
-                .get_metric_with_label_values(&[
+    /// Removes the series of the metrics for the given metrics key from the registry, e.g.,
+    /// because its `origin_as` label is not used anymore.
+    pub(crate) fn remove_for_key(monitor_name: &str, key: &MetricsKey) {
+        let country_name = match key.geo_origin.country_name() {
+            Ok(name) => name,
+            // Metrics are never created for these.
+            Err(_) => return,
+        };
+        for (vec, labels) in Self::series(
+            monitor_name,
+            country_name,
+            key.overlay_origin,
+            &key.as_origin,
+        ) {
+            // This fails if the series was removed already, which is fine.
+            let _ = vec.remove_label_values(&labels);
+        }
+    }
+
+    /// Returns the metric vectors and label values of all series of a set of metrics.
+    fn series<'a>(
+        monitor_name: &'a str,
+        country_name: &'a str,
+        gateway_status: PublicGatewayStatus,
+        as_label: &'a str,
+    ) -> [(&'static IntCounterVec, Vec<&'a str>); 13] {
+        let is_gateway = gateway_status.is_gateway_str();
+        [
+            (
+                &BITSWAP_MESSAGES_RECEIVED,
+                vec![monitor_name, country_name, is_gateway, as_label],
+            ),
+            (
+                &WANTLIST_ENTRIES_RECEIVED,
+                vec![
                     monitor_name,
                     "cancel",
                     "false",
                     country_name,
-                    gateway_status.is_gateway_str(),
-                ])
-                .unwrap(),
-            num_entries_want_block: WANTLIST_ENTRIES_RECEIVED
-                .get_metric_with_label_values(&[
+                    is_gateway,
+                    as_label,
+                ],
+            ),
+            (
+                &WANTLIST_ENTRIES_RECEIVED,
+                vec![
                     monitor_name,
                     "want_block",
                     "false",
                     country_name,
-                    gateway_status.is_gateway_str(),
-                ])
-                .unwrap(),
-            num_entries_want_block_send_dont_have: WANTLIST_ENTRIES_RECEIVED
-                .get_metric_with_label_values(&[
+                    is_gateway,
+                    as_label,
+                ],
+            ),
+            (
+                &WANTLIST_ENTRIES_RECEIVED,
+                vec![
                     monitor_name,
                     "want_block",
                     "true",
                     country_name,
-                    gateway_status.is_gateway_str(),
-                ])
-                .unwrap(),
-            num_entries_want_have: WANTLIST_ENTRIES_RECEIVED
-                .get_metric_with_label_values(&[
+                    is_gateway,
+                    as_label,
+                ],
+            ),
+            (
+                &WANTLIST_ENTRIES_RECEIVED,
+                vec![
                     monitor_name,
                     "want_have",
                     "false",
                     country_name,
-                    gateway_status.is_gateway_str(),
-                ])
-                .unwrap(),
-            num_entries_want_have_send_dont_have: WANTLIST_ENTRIES_RECEIVED
-                .get_metric_with_label_values(&[
+                    is_gateway,
+                    as_label,
+                ],
+            ),
+            (
+                &WANTLIST_ENTRIES_RECEIVED,
+                vec![
                     monitor_name,
                     "want_have",
                     "true",
                     country_name,
-                    gateway_status.is_gateway_str(),
-                ])
-                .unwrap(),
-
-            num_connected: CONNECTION_EVENTS_CONNECTED
-                .get_metric_with_label_values(&[
-                    monitor_name,
-                    country_name,
-                    gateway_status.is_gateway_str(),
-                ])
-                .unwrap(),
-            num_disconnected: CONNECTION_EVENTS_DISCONNECTED
-                .get_metric_with_label_values(&[
-                    monitor_name,
-                    country_name,
-                    gateway_status.is_gateway_str(),
-                ])
-                .unwrap(),
-            num_wantlists_incremental: WANTLISTS_RECEIVED
-                .get_metric_with_label_values(&[
-                    monitor_name,
-                    "false",
-                    country_name,
-                    gateway_status.is_gateway_str(),
-                ])
-                .unwrap(),
-            num_wantlists_full: WANTLISTS_RECEIVED
-                .get_metric_with_label_values(&[
-                    monitor_name,
-                    "true",
-                    country_name,
-                    gateway_status.is_gateway_str(),
-                ])
-                .unwrap(),
-            num_blocks: BITSWAP_BLOCKS_RECEIVED
-                .get_metric_with_label_values(&[
-                    monitor_name,
-                    country_name,
-                    gateway_status.is_gateway_str(),
-                ])
-                .unwrap(),
-            num_block_presence_have: BITSWAP_BLOCK_PRESENCES_RECEIVED
-                .get_metric_with_label_values(&[
-                    monitor_name,
-                    "HAVE",
-                    country_name,
-                    gateway_status.is_gateway_str(),
-                ])
-                .unwrap(),
-            num_block_presence_dont_have: BITSWAP_BLOCK_PRESENCES_RECEIVED
-                .get_metric_with_label_values(&[
+                    is_gateway,
+                    as_label,
+                ],
+            ),
+            (
+                &CONNECTION_EVENTS_CONNECTED,
+                vec![monitor_name, country_name, is_gateway, as_label],
+            ),
+            (
+                &CONNECTION_EVENTS_DISCONNECTED,
+                vec![monitor_name, country_name, is_gateway, as_label],
+            ),
+            (
+                &WANTLISTS_RECEIVED,
+                vec![monitor_name, "false", country_name, is_gateway, as_label],
+            ),
+            (
+                &WANTLISTS_RECEIVED,
+                vec![monitor_name, "true", country_name, is_gateway, as_label],
+            ),
+            (
+                &BITSWAP_BLOCKS_RECEIVED,
+                vec![monitor_name, country_name, is_gateway, as_label],
+            ),
+            (
+                &BITSWAP_BLOCK_PRESENCES_RECEIVED,
+                vec![monitor_name, "HAVE", country_name, is_gateway, as_label],
+            ),
+            (
+                &BITSWAP_BLOCK_PRESENCES_RECEIVED,
+                vec![
                     monitor_name,
                     "DONT_HAVE",
                     country_name,
-                    gateway_status.is_gateway_str(),
-                ])
-                .unwrap(),
+                    is_gateway,
+                    as_label,
+                ],
+            ),
+        ]
+    }
+}
+
+/// The engine simulation metrics of a monitor.
+pub(crate) struct EngineMetrics {
+    /// Gauges for ledgers of connected and disconnected peers.
+    pub(crate) num_ledgers_connected: IntGauge,
+    pub(crate) num_ledgers_disconnected: IntGauge,
+
+    /// Gauge for the entries currently wanted by all peers.
+    pub(crate) num_active_wants: IntGauge,
+
+    /// Histogram of the number of entries wanted by a peer.
+    pub(crate) active_wants_per_peer: Histogram,
+
+    /// Counters for requests by duplicate status.
+    /// A request which is a duplicate for multiple reasons is counted for each of them.
+    pub(crate) num_requests_no_dup: IntCounter,
+    pub(crate) num_requests_dup_full_wantlist: IntCounter,
+    pub(crate) num_requests_dup_reconnect: IntCounter,
+    pub(crate) num_requests_dup_sliding_window: IntCounter,
+    pub(crate) num_requests_dup_rebroadcast: IntCounter,
+
+    /// Counter for requests upgrading an earlier request.
+    pub(crate) num_upgrades: IntCounter,
+
+    /// Counters for synthetic CANCELs, by reason.
+    pub(crate) num_synthetic_cancels_full_wantlist: IntCounter,
+    pub(crate) num_synthetic_cancels_disconnect: IntCounter,
+
+    /// Counter for evicted ledgers.
+    pub(crate) num_evicted_ledgers: IntCounter,
+}
+
+impl EngineMetrics {
+    /// Creates the engine simulation metrics for the given monitor.
+    pub(crate) fn new(monitor_name: &str) -> EngineMetrics {
+        EngineMetrics {
+            num_ledgers_connected: ENGINE_LEDGERS.with_label_values(&[monitor_name, "true"]),
+            num_ledgers_disconnected: ENGINE_LEDGERS.with_label_values(&[monitor_name, "false"]),
+            num_active_wants: ENGINE_ACTIVE_WANTS.with_label_values(&[monitor_name]),
+            active_wants_per_peer: ENGINE_ACTIVE_WANTS_PER_PEER.with_label_values(&[monitor_name]),
+            num_requests_no_dup: ENGINE_REQUESTS.with_label_values(&[monitor_name, "no_dup"]),
+            num_requests_dup_full_wantlist: ENGINE_REQUESTS
+                .with_label_values(&[monitor_name, "full_wantlist"]),
+            num_requests_dup_reconnect: ENGINE_REQUESTS
+                .with_label_values(&[monitor_name, "reconnect"]),
+            num_requests_dup_sliding_window: ENGINE_REQUESTS
+                .with_label_values(&[monitor_name, "sliding_window"]),
+            num_requests_dup_rebroadcast: ENGINE_REQUESTS
+                .with_label_values(&[monitor_name, "rebroadcast"]),
+            num_upgrades: ENGINE_UPGRADES.with_label_values(&[monitor_name]),
+            num_synthetic_cancels_full_wantlist: ENGINE_SYNTHETIC_CANCELS
+                .with_label_values(&[monitor_name, "full_wantlist"]),
+            num_synthetic_cancels_disconnect: ENGINE_SYNTHETIC_CANCELS
+                .with_label_values(&[monitor_name, "disconnect"]),
+            num_evicted_ledgers: ENGINE_EVICTED_LEDGERS.with_label_values(&[monitor_name]),
         }
     }
 }
@@ -320,3 +518,36 @@ pub(crate) fn run_prometheus(addr: SocketAddr) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_series_of_key() {
+        let key = MetricsKey {
+            geo_origin: Geolocation::Unknown,
+            overlay_origin: PublicGatewayStatus::NonGateway,
+            as_origin: "AS1".to_string(),
+        };
+        let metrics = Metrics::new_for_key("test-remove", &key).unwrap();
+        metrics.num_messages.inc();
+        metrics.num_entries_want_have.inc();
+
+        Metrics::remove_for_key("test-remove", &key);
+        let labels = ["test-remove", COUNTRY_NAME_UNKNOWN, "false", "AS1"];
+        assert!(BITSWAP_MESSAGES_RECEIVED
+            .remove_label_values(&labels)
+            .is_err());
+        assert!(WANTLIST_ENTRIES_RECEIVED
+            .remove_label_values(&[
+                "test-remove",
+                "want_have",
+                "false",
+                COUNTRY_NAME_UNKNOWN,
+                "false",
+                "AS1"
+            ])
+            .is_err());
+    }
+}
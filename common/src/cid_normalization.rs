@@ -0,0 +1,79 @@
+//! Normalization of CIDs, to identify the same content across CID versions and multibases.
+
+use cid::Cid;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// The multicodec code of raw binary data.
+const RAW_CODEC: u64 = 0x55;
+
+/// How CIDs are normalized before comparing them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CidNormalization {
+    /// CIDs are compared as sent.
+    #[default]
+    None,
+    /// CIDs are keyed on their codec and multihash.
+    /// They are represented as base32-encoded CIDv1, so a CIDv0 is equal to its CIDv1 equivalent.
+    CodecAndMultihash,
+    /// CIDs are keyed on their multihash only.
+    /// They are represented as base32-encoded CIDv1 with the raw codec.
+    Multihash,
+}
+
+impl CidNormalization {
+    /// Returns whether this normalizes CIDs at all.
+    pub fn is_enabled(&self) -> bool {
+        *self != CidNormalization::None
+    }
+
+    /// Normalizes the given CID, or returns `None` if normalization is disabled.
+    /// CIDs that cannot be parsed are returned as-is.
+    pub fn normalize(&self, cid: &str) -> Option<String> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let c = match Cid::try_from(cid) {
+            Ok(c) => c,
+            Err(err) => {
+                debug!("unable to parse CID {}, not normalizing: {}", cid, err);
+                return Some(cid.to_string());
+            }
+        };
+        let codec = match self {
+            CidNormalization::Multihash => RAW_CODEC,
+            _ => c.codec(),
+        };
+
+        Some(Cid::new_v1(codec, *c.hash()).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CID_V0: &str = "QmUNLLsPACCz1vLxQVkXqqLX5R1X345qqfHbsf67hvA3Nn";
+    const CID_V1: &str = "bafybeiczsscdsbs7ffqz55asqdf3smv6klcw3gofszvwlyarci47bgf354";
+    const CID_V1_BASE58: &str = "zdj7WbTaiJT1fgatdet9Ei9iDB5hdCxkbVyhyh8YTUnXMiwYi";
+    const CID_RAW: &str = "bafkreiczsscdsbs7ffqz55asqdf3smv6klcw3gofszvwlyarci47bgf354";
+
+    #[test]
+    fn normalizes_across_encodings() {
+        let n = CidNormalization::CodecAndMultihash;
+        assert_eq!(n.normalize(CID_V0).unwrap(), CID_V1);
+        assert_eq!(n.normalize(CID_V1_BASE58).unwrap(), CID_V1);
+        assert_eq!(n.normalize(CID_RAW).unwrap(), CID_RAW);
+
+        let n = CidNormalization::Multihash;
+        assert_eq!(n.normalize(CID_V0).unwrap(), CID_RAW);
+        assert_eq!(n.normalize(CID_RAW).unwrap(), CID_RAW);
+
+        assert_eq!(CidNormalization::None.normalize(CID_V0), None);
+        assert_eq!(
+            CidNormalization::Multihash.normalize("QmInvalid").unwrap(),
+            "QmInvalid"
+        );
+    }
+}
@@ -0,0 +1,249 @@
+//! Events pushed by our [monitoring plugin](https://github.com/trudi-group/ipfs-metric-exporter),
+//! as logged to disk by the `bitswap-monitoring-client`, and their conversion to the
+//! `JSONMessage`s the engine simulation ingests.
+
+use crate::wantlist::{JSONMessage, JSONWantlistEntry, JsonCID};
+use crate::Result;
+use failure::ResultExt;
+use serde::{Deserialize, Serialize};
+use serde_repr::*;
+
+/// A monitoring-related event.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PushedEvent {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub peer: String,
+
+    #[serde(flatten)]
+    pub inner: EventType,
+}
+
+impl PushedEvent {
+    /// Creates a constant-width identifier for this event.
+    /// This is potentially expensive.
+    pub fn constant_width_identifier(&self) -> String {
+        // TODO it would be nice if this didn't return a string.
+        // TODO I want something that implements Debug, and then formats this on the fly.
+        match &self.inner {
+            EventType::BitswapMessage(msg) => {
+                let mut addrs = msg.connected_addresses.join(", ");
+                addrs.truncate(30);
+                format!("{:52} [{:30}]", self.peer, addrs)
+            }
+            EventType::ConnectionEvent(conn_event) => {
+                format!("{:52} {:32}", self.peer, conn_event.remote)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum EventType {
+    #[serde(rename = "bitswap_message")]
+    BitswapMessage(BitswapMessage),
+    #[serde(rename = "connection_event")]
+    ConnectionEvent(ConnectionEvent),
+}
+
+/// A Bitswap message received by the monitor and subsequently pushed to us via TCP.
+/// This contains both "requests" (updates to the wantlist) as well as "responses" (blocks
+/// and block presences).
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct BitswapMessage {
+    pub wantlist_entries: Vec<JSONWantlistEntry>,
+    pub full_wantlist: bool,
+    pub blocks: Vec<JsonCID>,
+    pub block_presences: Vec<BlockPresence>,
+    pub connected_addresses: Vec<String>,
+}
+
+/// Block presence indication, as contained in a Bitswap message.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct BlockPresence {
+    pub cid: JsonCID,
+    pub block_presence_type: BlockPresenceType,
+}
+
+/// Block presence type constants for monitoring events.
+#[derive(Serialize_repr, Deserialize_repr, Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BlockPresenceType {
+    Have = 0,
+    DontHave = 1,
+}
+
+/// A connection event, as reported by IPFS.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ConnectionEvent {
+    pub remote: String,
+    pub connection_event_type: ConnectionEventType,
+}
+
+/// Connection event type constants for monitoring events.
+#[derive(Serialize_repr, Deserialize_repr, Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ConnectionEventType {
+    Connected = 0,
+    Disconnected = 1,
+}
+
+/// Converts an event to the input of the engine simulation.
+///
+/// The monitoring plugin reports all addresses of a peer, which are kept in
+/// `connected_addresses`.
+/// The first of them that can be parsed is used as the `address` of the message.
+/// Blocks and block presences are kept as well.
+/// Connection events do not indicate whether IPFS already knew the peer, so
+/// `connect_event_peer_found` is left empty, to be derived by the engine simulation.
+impl From<PushedEvent> for JSONMessage {
+    fn from(event: PushedEvent) -> Self {
+        let PushedEvent {
+            timestamp,
+            peer,
+            inner,
+        } = event;
+
+        match inner {
+            EventType::BitswapMessage(msg) => JSONMessage {
+                timestamp,
+                peer,
+                address: msg.connected_addresses.iter().find_map(|a| a.parse().ok()),
+                received_entries: Some(msg.wantlist_entries),
+                full_want_list: Some(msg.full_wantlist),
+                peer_connected: None,
+                peer_disconnected: None,
+                connect_event_peer_found: None,
+                connected_addresses: Some(msg.connected_addresses),
+                blocks: Some(msg.blocks),
+                block_presences: Some(msg.block_presences),
+            },
+            EventType::ConnectionEvent(conn_event) => {
+                let connected = match conn_event.connection_event_type {
+                    ConnectionEventType::Connected => true,
+                    ConnectionEventType::Disconnected => false,
+                };
+                JSONMessage {
+                    timestamp,
+                    peer,
+                    address: conn_event.remote.parse().ok(),
+                    received_entries: None,
+                    full_want_list: None,
+                    peer_connected: Some(connected),
+                    peer_disconnected: Some(!connected),
+                    connect_event_peer_found: None,
+                    connected_addresses: Some(vec![conn_event.remote]),
+                    blocks: None,
+                    block_presences: None,
+                }
+            }
+        }
+    }
+}
+
+/// Formats of traces of Bitswap messages on disk, one JSON object per line.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceFormat {
+    /// Detects the format from the first line of each file.
+    #[default]
+    Auto,
+    /// `JSONMessage`s, as logged by our modified IPFS.
+    Legacy,
+    /// `PushedEvent`s, as logged by the `bitswap-monitoring-client`.
+    PushedEvent,
+}
+
+/// Decodes the lines of trace files into `JSONMessage`s.
+#[derive(Clone, Debug)]
+pub struct TraceDecoder {
+    format: TraceFormat,
+    current_format: TraceFormat,
+}
+
+impl TraceDecoder {
+    pub fn new(format: TraceFormat) -> TraceDecoder {
+        TraceDecoder {
+            format,
+            current_format: format,
+        }
+    }
+
+    /// Resets the detected format, to be called at the start of every file.
+    pub fn start_file(&mut self) {
+        self.current_format = self.format;
+    }
+
+    /// Decodes a line of a trace file.
+    pub fn decode(&mut self, line: &str) -> Result<JSONMessage> {
+        let msg = match self.current_format {
+            TraceFormat::Legacy => {
+                serde_json::from_str(line).context("unable to decode message")?
+            }
+            TraceFormat::PushedEvent => serde_json::from_str::<PushedEvent>(line)
+                .context("unable to decode event")?
+                .into(),
+            TraceFormat::Auto => {
+                // Events must be tried first, since all fields of messages except for timestamp
+                // and peer are optional, which events have too.
+                match serde_json::from_str::<PushedEvent>(line) {
+                    Ok(event) => {
+                        debug!("detected trace format of events");
+                        self.current_format = TraceFormat::PushedEvent;
+                        event.into()
+                    }
+                    Err(_) => {
+                        let msg = serde_json::from_str(line)
+                            .context("unable to decode message in any format")?;
+                        debug!("detected trace format of legacy messages");
+                        self.current_format = TraceFormat::Legacy;
+                        msg
+                    }
+                }
+            }
+        };
+
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EVENTS: &str = r#"{"timestamp":"2024-01-01T00:00:00Z","peer":"QmPeer","connection_event":{"remote":"/ip4/1.2.3.4/udp/4001/quic-v1","connection_event_type":0}}
+{"timestamp":"2024-01-01T00:00:01Z","peer":"QmPeer","bitswap_message":{"wantlist_entries":[{"priority":1,"cancel":false,"send_dont_have":true,"cid":{"/":"QmCid"},"want_type":1}],"full_wantlist":false,"blocks":[{"/":"QmBlock"}],"block_presences":[{"cid":{"/":"QmCid"},"block_presence_type":1}],"connected_addresses":["/ip4/1.2.3.4/udp/4001/quic-v1","/ip4/1.2.3.4/tcp/4001"]}}"#;
+
+    const MESSAGE: &str = r#"{"timestamp":"2024-01-01T00:00:00Z","peer":"QmPeer","address":"/ip4/1.2.3.4/tcp/4001","received_entries":null,"full_want_list":null,"peer_connected":true,"peer_disconnected":false,"connect_event_peer_found":false}"#;
+
+    #[test]
+    fn detects_format_per_file() {
+        let mut decoder = TraceDecoder::new(TraceFormat::Auto);
+        let msgs = EVENTS
+            .lines()
+            .map(|l| decoder.decode(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(decoder.current_format, TraceFormat::PushedEvent);
+
+        assert_eq!(msgs[0].peer_connected, Some(true));
+        assert_eq!(msgs[0].peer_disconnected, Some(false));
+        assert_eq!(msgs[0].address, None);
+        assert_eq!(msgs[1].received_entries.as_ref().unwrap().len(), 1);
+        assert_eq!(msgs[1].full_want_list, Some(false));
+        assert_eq!(
+            msgs[1].address.as_ref().map(|a| a.to_string()),
+            Some("/ip4/1.2.3.4/tcp/4001".to_string())
+        );
+        assert_eq!(msgs[1].connected_addresses.as_ref().unwrap().len(), 2);
+        assert_eq!(msgs[1].blocks.as_ref().unwrap()[0].path, "QmBlock");
+        assert_eq!(
+            msgs[1].block_presences.as_ref().unwrap()[0].block_presence_type,
+            BlockPresenceType::DontHave
+        );
+
+        decoder.start_file();
+        let msg = decoder.decode(MESSAGE).unwrap();
+        assert_eq!(decoder.current_format, TraceFormat::Legacy);
+        assert_eq!(msg.connect_event_peer_found, Some(false));
+        assert_eq!(msg.connected_addresses, None);
+    }
+}
@@ -0,0 +1,300 @@
+//! Lookups of the origin of addresses in MaxMind GeoLite2 databases.
+
+use crate::Result;
+use failure::ResultExt;
+use maxminddb::{MaxMindDBError, Reader};
+use parity_multiaddr::{Multiaddr, Protocol};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// The file name of the GeoLite2 Country database.
+pub const COUNTRY_DATABASE_FILE: &str = "GeoLite2-Country.mmdb";
+
+/// The file name of the GeoLite2 ASN database.
+pub const ASN_DATABASE_FILE: &str = "GeoLite2-ASN.mmdb";
+
+/// The file name of the GeoLite2 City database.
+pub const CITY_DATABASE_FILE: &str = "GeoLite2-City.mmdb";
+
+/// The age after which a database is considered outdated.
+const MAX_DATABASE_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// The maximum number of addresses whose origin is cached by [`OriginLookup`].
+const MAX_CACHED_ADDRESSES: usize = 100_000;
+
+/// Opens the GeoLite2 database with the given file name in the given directory.
+/// Logs a warning if the database is older than 30 days.
+pub fn open_database<P: AsRef<Path>>(dir: P, file_name: &str) -> Result<Reader<Vec<u8>>> {
+    let path = dir.as_ref().join(file_name);
+    debug!("attempting to read GeoLite2 database at {:?}...", path);
+    let reader = Reader::open_readfile(&path)
+        .context(format!("unable to open GeoLite2 database at {:?}", path))?;
+
+    let db_ts = chrono::DateTime::<chrono::Utc>::from(
+        UNIX_EPOCH + Duration::from_secs(reader.metadata.build_epoch),
+    );
+    debug!(
+        "loaded MaxMind database \"{}\", created {}, with {} entries",
+        reader.metadata.database_type,
+        db_ts.format("%+"),
+        reader.metadata.node_count
+    );
+    if (chrono::Utc::now() - db_ts) > chrono::Duration::from_std(MAX_DATABASE_AGE).unwrap() {
+        warn!(
+            "MaxMind database {} is older than 30 days (created {})",
+            file_name,
+            db_ts.format("%+")
+        )
+    }
+
+    Ok(reader)
+}
+
+/// An autonomous system (AS).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AutonomousSystem {
+    /// The AS number.
+    pub number: u32,
+
+    /// The organization operating the AS, if known.
+    pub organization: Option<String>,
+}
+
+/// Maps lookups of addresses not contained in a database to `None`.
+fn not_found_as_none<T>(res: std::result::Result<T, MaxMindDBError>) -> Result<Option<T>> {
+    match res {
+        Ok(t) => Ok(Some(t)),
+        Err(MaxMindDBError::AddressNotFoundError(_)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Looks up the ISO 3166-1 alpha-2 code of the country of the given IP in a Country or City
+/// database.
+/// Returns `None` if the IP or its country is not known.
+pub fn lookup_country(db: &Reader<Vec<u8>>, ip: IpAddr) -> Result<Option<String>> {
+    let country = not_found_as_none(db.lookup::<maxminddb::geoip2::Country>(ip))?;
+
+    Ok(country
+        .and_then(|c| c.country)
+        .and_then(|c| c.iso_code)
+        .map(|c| c.to_string()))
+}
+
+/// Looks up the autonomous system of the given IP in an ASN database.
+/// Returns `None` if the IP or its AS is not known.
+pub fn lookup_asn(db: &Reader<Vec<u8>>, ip: IpAddr) -> Result<Option<AutonomousSystem>> {
+    let asn = not_found_as_none(db.lookup::<maxminddb::geoip2::Asn>(ip))?;
+
+    Ok(asn.and_then(|asn| {
+        asn.autonomous_system_number.map(|number| AutonomousSystem {
+            number,
+            organization: asn.autonomous_system_organization.map(|o| o.to_string()),
+        })
+    }))
+}
+
+/// Looks up the English name of the city of the given IP in a City database.
+/// Returns `None` if the IP or its city is not known.
+pub fn lookup_city(db: &Reader<Vec<u8>>, ip: IpAddr) -> Result<Option<String>> {
+    let city = not_found_as_none(db.lookup::<maxminddb::geoip2::City>(ip))?;
+
+    Ok(city
+        .and_then(|c| c.city)
+        .and_then(|c| c.names)
+        .and_then(|names| names.get("en").map(|n| n.to_string())))
+}
+
+/// Extracts the IP of the given multiaddress.
+/// Returns `None` for relayed (p2p-circuit) addresses, since their IP is not the IP of the peer,
+/// and for addresses not starting with an IP.
+pub fn ip_from_multiaddr(addr: &Multiaddr) -> Option<IpAddr> {
+    if addr.iter().any(|p| matches!(p, Protocol::P2pCircuit)) {
+        return None;
+    }
+
+    match addr.iter().next()? {
+        Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+        Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+        _ => None,
+    }
+}
+
+/// Parses the given multiaddress and extracts its IP, see [`ip_from_multiaddr`].
+/// Returns `None` for addresses that cannot be parsed.
+pub fn ip_from_address(address: &str) -> Option<IpAddr> {
+    match Multiaddr::from_str(address) {
+        Ok(addr) => ip_from_multiaddr(&addr),
+        Err(err) => {
+            // Probably a new protocol which we can't decode (yet)
+            debug!("unable to decode multiaddress {}: {:?}", address, err);
+            None
+        }
+    }
+}
+
+/// Configuration for enriching addresses with their origin, using MaxMind GeoLite2 databases.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GeoIPConfig {
+    /// The directory containing the databases.
+    /// The Country database is always used.
+    /// Defaults to /usr/local/share/GeoIP.
+    #[serde(default = "default_database_path")]
+    pub database_path: String,
+
+    /// Whether to look up the autonomous system and its organization in the ASN database.
+    /// Defaults to false.
+    #[serde(default)]
+    pub asn: bool,
+
+    /// Whether to look up the city in the City database.
+    /// Defaults to false.
+    #[serde(default)]
+    pub city: bool,
+}
+
+fn default_database_path() -> String {
+    "/usr/local/share/GeoIP".to_string()
+}
+
+/// The origin of an address, as written to the `origin_*` columns of output records.
+/// Fields are `None` if the respective lookup is disabled, failed, or found nothing.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AddressOrigin {
+    /// The ISO 3166-1 alpha-2 code of the country.
+    pub country: Option<String>,
+
+    /// The autonomous system number.
+    pub asn: Option<u32>,
+
+    /// The organization operating the autonomous system.
+    pub as_organization: Option<String>,
+
+    /// The English name of the city.
+    pub city: Option<String>,
+}
+
+/// Records with a multiaddress which can be enriched with the origin of that address.
+pub trait WithOrigin {
+    /// Returns the multiaddress of the record.
+    fn address(&self) -> &str;
+
+    /// Sets the `origin_*` fields of the record.
+    fn set_origin(&mut self, origin: AddressOrigin);
+}
+
+/// Looks up the origin of multiaddresses in the configured databases.
+/// Results are cached by address.
+pub struct OriginLookup {
+    country_db: Reader<Vec<u8>>,
+    asn_db: Option<Reader<Vec<u8>>>,
+    city_db: Option<Reader<Vec<u8>>>,
+    cache: HashMap<String, AddressOrigin>,
+}
+
+impl OriginLookup {
+    /// Opens the databases of the given configuration.
+    pub fn open(cfg: &GeoIPConfig) -> Result<OriginLookup> {
+        let country_db = open_database(&cfg.database_path, COUNTRY_DATABASE_FILE)?;
+        let asn_db = if cfg.asn {
+            Some(open_database(&cfg.database_path, ASN_DATABASE_FILE)?)
+        } else {
+            None
+        };
+        let city_db = if cfg.city {
+            Some(open_database(&cfg.database_path, CITY_DATABASE_FILE)?)
+        } else {
+            None
+        };
+
+        Ok(OriginLookup {
+            country_db,
+            asn_db,
+            city_db,
+            cache: HashMap::new(),
+        })
+    }
+
+    /// Looks up the origin of the given multiaddress.
+    /// Failed lookups are logged and result in empty fields.
+    pub fn lookup(&mut self, address: &str) -> AddressOrigin {
+        if let Some(origin) = self.cache.get(address) {
+            return origin.clone();
+        }
+
+        let origin = match ip_from_address(address) {
+            Some(ip) => self.lookup_ip(ip),
+            None => {
+                debug!("unable to extract IP from multiaddress {}", address);
+                AddressOrigin::default()
+            }
+        };
+
+        if self.cache.len() >= MAX_CACHED_ADDRESSES {
+            self.cache.clear();
+        }
+        self.cache.insert(address.to_string(), origin.clone());
+
+        origin
+    }
+
+    fn lookup_ip(&self, ip: IpAddr) -> AddressOrigin {
+        let country = lookup_country(&self.country_db, ip).unwrap_or_else(|e| {
+            warn!("unable to look up country of {}: {:?}", ip, e);
+            None
+        });
+        let asn = self.asn_db.as_ref().and_then(|db| {
+            lookup_asn(db, ip).unwrap_or_else(|e| {
+                warn!("unable to look up AS of {}: {:?}", ip, e);
+                None
+            })
+        });
+        let city = self.city_db.as_ref().and_then(|db| {
+            lookup_city(db, ip).unwrap_or_else(|e| {
+                warn!("unable to look up city of {}: {:?}", ip, e);
+                None
+            })
+        });
+
+        AddressOrigin {
+            country,
+            asn: asn.as_ref().map(|asn| asn.number),
+            as_organization: asn.and_then(|asn| asn.organization),
+            city,
+        }
+    }
+
+    /// Sets the origin of the given record to the origin of its address.
+    pub fn enrich<R: WithOrigin>(&mut self, record: &mut R) {
+        let origin = self.lookup(record.address());
+        record.set_origin(origin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_from_multiaddr() {
+        let ip = |a: &str| ip_from_multiaddr(&Multiaddr::from_str(a).unwrap());
+
+        assert_eq!(
+            ip("/ip4/1.2.3.4/tcp/4001"),
+            Some(IpAddr::from_str("1.2.3.4").unwrap())
+        );
+        assert_eq!(
+            ip("/ip6/2001:db8::1/udp/4001/quic"),
+            Some(IpAddr::from_str("2001:db8::1").unwrap())
+        );
+        assert_eq!(ip("/dns4/example.com/tcp/4001"), None);
+        assert_eq!(
+            ip("/ip4/1.2.3.4/tcp/4001/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN/p2p-circuit"),
+            None
+        );
+    }
+}
@@ -6,7 +6,12 @@ extern crate failure;
 use failure::{Error, ResultExt};
 use std::path::PathBuf;
 
+pub mod cid_normalization;
+pub mod events;
+pub mod geoip;
 pub mod logging;
+pub mod output;
+pub mod snapshot;
 pub mod wantlist;
 
 pub type Result<T> = std::result::Result<T, Error>;
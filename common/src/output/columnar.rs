@@ -0,0 +1,625 @@
+use arrow_array::builder::{
+    BooleanBuilder, Float64Builder, Int32Builder, Int64Builder, StringBuilder, UInt32Builder,
+    UInt64Builder,
+};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef};
+use serde::ser::{Impossible, SerializeStruct};
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::sync::Arc;
+
+/// The type of a column in columnar output formats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnType {
+    Boolean,
+    Int32,
+    Int64,
+    UInt32,
+    UInt64,
+    Float64,
+    /// A string column.
+    Utf8,
+    /// A string column with few distinct values, like CIDs or peer IDs.
+    /// This is dictionary-encoded in Parquet output, and a normal string column otherwise.
+    Dictionary,
+}
+
+/// A column of a `Record`.
+#[derive(Clone, Debug)]
+pub struct Column {
+    pub name: &'static str,
+    pub column_type: ColumnType,
+    pub nullable: bool,
+}
+
+impl Column {
+    /// Creates a non-nullable column.
+    pub fn new(name: &'static str, column_type: ColumnType) -> Column {
+        Column {
+            name,
+            column_type,
+            nullable: false,
+        }
+    }
+
+    /// Creates a nullable column, usually for `Option` fields.
+    pub fn nullable(name: &'static str, column_type: ColumnType) -> Column {
+        Column {
+            name,
+            column_type,
+            nullable: true,
+        }
+    }
+
+    fn data_type(&self) -> DataType {
+        match self.column_type {
+            ColumnType::Boolean => DataType::Boolean,
+            ColumnType::Int32 => DataType::Int32,
+            ColumnType::Int64 => DataType::Int64,
+            ColumnType::UInt32 => DataType::UInt32,
+            ColumnType::UInt64 => DataType::UInt64,
+            ColumnType::Float64 => DataType::Float64,
+            ColumnType::Utf8 | ColumnType::Dictionary => DataType::Utf8,
+        }
+    }
+}
+
+/// A flat struct that can be written to an `OutputSink`.
+///
+/// CSV output uses the `Serialize` implementation directly.
+/// For columnar output, every field is serialized into the column at the same position, which
+/// must have the same name as the field.
+/// Integers are converted to the column type, if they fit.
+pub trait Record: Serialize {
+    /// Returns the columns of this record, in the order of its fields.
+    fn columns() -> Vec<Column>;
+}
+
+/// Builds the Arrow schema for the given columns.
+pub(crate) fn schema(columns: &[Column]) -> SchemaRef {
+    Arc::new(Schema::new(
+        columns
+            .iter()
+            .map(|c| Field::new(c.name, c.data_type(), c.nullable))
+            .collect::<Vec<_>>(),
+    ))
+}
+
+/// An error converting a record to columns.
+#[derive(Debug)]
+pub struct ColumnarError(String);
+
+impl fmt::Display for ColumnarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ColumnarError {}
+
+impl serde::ser::Error for ColumnarError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        ColumnarError(msg.to_string())
+    }
+}
+
+type ColumnarResult<T> = std::result::Result<T, ColumnarError>;
+
+enum ColumnBuilder {
+    Boolean(BooleanBuilder),
+    Int32(Int32Builder),
+    Int64(Int64Builder),
+    UInt32(UInt32Builder),
+    UInt64(UInt64Builder),
+    Float64(Float64Builder),
+    Utf8(StringBuilder),
+}
+
+impl ColumnBuilder {
+    fn new(column_type: ColumnType) -> ColumnBuilder {
+        match column_type {
+            ColumnType::Boolean => ColumnBuilder::Boolean(BooleanBuilder::new()),
+            ColumnType::Int32 => ColumnBuilder::Int32(Int32Builder::new()),
+            ColumnType::Int64 => ColumnBuilder::Int64(Int64Builder::new()),
+            ColumnType::UInt32 => ColumnBuilder::UInt32(UInt32Builder::new()),
+            ColumnType::UInt64 => ColumnBuilder::UInt64(UInt64Builder::new()),
+            ColumnType::Float64 => ColumnBuilder::Float64(Float64Builder::new()),
+            ColumnType::Utf8 | ColumnType::Dictionary => ColumnBuilder::Utf8(StringBuilder::new()),
+        }
+    }
+
+    fn append_i64(&mut self, v: i64) -> ColumnarResult<()> {
+        let out_of_range = |_| ColumnarError(format!("value {} out of range for column", v));
+        match self {
+            ColumnBuilder::Int32(b) => b.append_value(i32::try_from(v).map_err(out_of_range)?),
+            ColumnBuilder::Int64(b) => b.append_value(v),
+            ColumnBuilder::UInt32(b) => b.append_value(u32::try_from(v).map_err(out_of_range)?),
+            ColumnBuilder::UInt64(b) => b.append_value(u64::try_from(v).map_err(out_of_range)?),
+            ColumnBuilder::Float64(b) => b.append_value(v as f64),
+            _ => return Err(ColumnarError("unexpected integer".to_string())),
+        }
+        Ok(())
+    }
+
+    fn append_u64(&mut self, v: u64) -> ColumnarResult<()> {
+        match self {
+            ColumnBuilder::UInt64(b) => b.append_value(v),
+            _ => self
+                .append_i64(i64::try_from(v).map_err(|_| {
+                    ColumnarError(format!("value {} out of range for column", v))
+                })?)?,
+        }
+        Ok(())
+    }
+
+    fn append_f64(&mut self, v: f64) -> ColumnarResult<()> {
+        match self {
+            ColumnBuilder::Float64(b) => b.append_value(v),
+            _ => return Err(ColumnarError("unexpected float".to_string())),
+        }
+        Ok(())
+    }
+
+    fn append_bool(&mut self, v: bool) -> ColumnarResult<()> {
+        match self {
+            ColumnBuilder::Boolean(b) => b.append_value(v),
+            _ => return Err(ColumnarError("unexpected bool".to_string())),
+        }
+        Ok(())
+    }
+
+    fn append_str(&mut self, v: &str) -> ColumnarResult<()> {
+        match self {
+            ColumnBuilder::Utf8(b) => b.append_value(v),
+            _ => return Err(ColumnarError("unexpected string".to_string())),
+        }
+        Ok(())
+    }
+
+    fn append_null(&mut self) {
+        match self {
+            ColumnBuilder::Boolean(b) => b.append_null(),
+            ColumnBuilder::Int32(b) => b.append_null(),
+            ColumnBuilder::Int64(b) => b.append_null(),
+            ColumnBuilder::UInt32(b) => b.append_null(),
+            ColumnBuilder::UInt64(b) => b.append_null(),
+            ColumnBuilder::Float64(b) => b.append_null(),
+            ColumnBuilder::Utf8(b) => b.append_null(),
+        }
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            ColumnBuilder::Boolean(b) => Arc::new(b.finish()),
+            ColumnBuilder::Int32(b) => Arc::new(b.finish()),
+            ColumnBuilder::Int64(b) => Arc::new(b.finish()),
+            ColumnBuilder::UInt32(b) => Arc::new(b.finish()),
+            ColumnBuilder::UInt64(b) => Arc::new(b.finish()),
+            ColumnBuilder::Float64(b) => Arc::new(b.finish()),
+            ColumnBuilder::Utf8(b) => Arc::new(b.finish()),
+        }
+    }
+}
+
+/// Collects records into Arrow record batches.
+pub(crate) struct BatchBuilder {
+    schema: SchemaRef,
+    names: Vec<&'static str>,
+    builders: Vec<ColumnBuilder>,
+    num_rows: usize,
+}
+
+impl BatchBuilder {
+    pub(crate) fn new(columns: &[Column]) -> BatchBuilder {
+        BatchBuilder {
+            schema: schema(columns),
+            names: columns.iter().map(|c| c.name).collect(),
+            builders: columns
+                .iter()
+                .map(|c| ColumnBuilder::new(c.column_type))
+                .collect(),
+            num_rows: 0,
+        }
+    }
+
+    pub(crate) fn num_rows(&self) -> usize {
+        self.num_rows
+    }
+
+    pub(crate) fn append<R: Record>(&mut self, record: &R) -> ColumnarResult<()> {
+        record.serialize(RowSerializer {
+            names: &self.names,
+            builders: &mut self.builders,
+            next_column: 0,
+        })?;
+        self.num_rows += 1;
+        Ok(())
+    }
+
+    /// Returns a batch of all records appended since the last call.
+    pub(crate) fn finish(&mut self) -> std::result::Result<RecordBatch, ArrowError> {
+        self.num_rows = 0;
+        RecordBatch::try_new(
+            self.schema.clone(),
+            self.builders.iter_mut().map(|b| b.finish()).collect(),
+        )
+    }
+}
+
+/// Serializes a struct into one row of column builders.
+struct RowSerializer<'a> {
+    names: &'a [&'static str],
+    builders: &'a mut [ColumnBuilder],
+    next_column: usize,
+}
+
+impl<'a> Serializer for RowSerializer<'a> {
+    type Ok = ();
+    type Error = ColumnarError;
+    type SerializeSeq = Impossible<(), ColumnarError>;
+    type SerializeTuple = Impossible<(), ColumnarError>;
+    type SerializeTupleStruct = Impossible<(), ColumnarError>;
+    type SerializeTupleVariant = Impossible<(), ColumnarError>;
+    type SerializeMap = Impossible<(), ColumnarError>;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Impossible<(), ColumnarError>;
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> ColumnarResult<Self> {
+        Ok(self)
+    }
+
+    fn serialize_bool(self, _v: bool) -> ColumnarResult<()> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_i8(self, _v: i8) -> ColumnarResult<()> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_i16(self, _v: i16) -> ColumnarResult<()> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_i32(self, _v: i32) -> ColumnarResult<()> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_i64(self, _v: i64) -> ColumnarResult<()> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_u8(self, _v: u8) -> ColumnarResult<()> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_u16(self, _v: u16) -> ColumnarResult<()> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_u32(self, _v: u32) -> ColumnarResult<()> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_u64(self, _v: u64) -> ColumnarResult<()> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_f32(self, _v: f32) -> ColumnarResult<()> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_f64(self, _v: f64) -> ColumnarResult<()> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_char(self, _v: char) -> ColumnarResult<()> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_str(self, _v: &str) -> ColumnarResult<()> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> ColumnarResult<()> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_none(self) -> ColumnarResult<()> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, _value: &T) -> ColumnarResult<()> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_unit(self) -> ColumnarResult<()> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> ColumnarResult<()> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+    ) -> ColumnarResult<()> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> ColumnarResult<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> ColumnarResult<()> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> ColumnarResult<Self::SerializeSeq> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> ColumnarResult<Self::SerializeTuple> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> ColumnarResult<Self::SerializeTupleStruct> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> ColumnarResult<Self::SerializeTupleVariant> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> ColumnarResult<Self::SerializeMap> {
+        Err(not_a_struct())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> ColumnarResult<Self::SerializeStructVariant> {
+        Err(not_a_struct())
+    }
+}
+
+impl<'a> SerializeStruct for RowSerializer<'a> {
+    type Ok = ();
+    type Error = ColumnarError;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> ColumnarResult<()> {
+        let i = self.next_column;
+        match self.names.get(i) {
+            Some(name) if *name == key => {}
+            _ => {
+                return Err(ColumnarError(format!(
+                    "field {} does not match column {}",
+                    key, i
+                )))
+            }
+        }
+        self.next_column += 1;
+
+        value
+            .serialize(FieldSerializer {
+                builder: &mut self.builders[i],
+            })
+            .map_err(|err| ColumnarError(format!("column {}: {}", key, err)))
+    }
+
+    fn end(self) -> ColumnarResult<()> {
+        if self.next_column != self.names.len() {
+            return Err(ColumnarError(format!(
+                "expected {} fields, got {}",
+                self.names.len(),
+                self.next_column
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn not_a_struct() -> ColumnarError {
+    ColumnarError("records must be structs".to_string())
+}
+
+fn not_a_scalar() -> ColumnarError {
+    ColumnarError("fields must be scalars".to_string())
+}
+
+/// Serializes a single field into a column builder.
+struct FieldSerializer<'a> {
+    builder: &'a mut ColumnBuilder,
+}
+
+impl<'a> Serializer for FieldSerializer<'a> {
+    type Ok = ();
+    type Error = ColumnarError;
+    type SerializeSeq = Impossible<(), ColumnarError>;
+    type SerializeTuple = Impossible<(), ColumnarError>;
+    type SerializeTupleStruct = Impossible<(), ColumnarError>;
+    type SerializeTupleVariant = Impossible<(), ColumnarError>;
+    type SerializeMap = Impossible<(), ColumnarError>;
+    type SerializeStruct = Impossible<(), ColumnarError>;
+    type SerializeStructVariant = Impossible<(), ColumnarError>;
+
+    fn serialize_bool(self, v: bool) -> ColumnarResult<()> {
+        self.builder.append_bool(v)
+    }
+
+    fn serialize_i8(self, v: i8) -> ColumnarResult<()> {
+        self.builder.append_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> ColumnarResult<()> {
+        self.builder.append_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> ColumnarResult<()> {
+        self.builder.append_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> ColumnarResult<()> {
+        self.builder.append_i64(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> ColumnarResult<()> {
+        self.builder.append_u64(v as u64)
+    }
+
+    fn serialize_u16(self, v: u16) -> ColumnarResult<()> {
+        self.builder.append_u64(v as u64)
+    }
+
+    fn serialize_u32(self, v: u32) -> ColumnarResult<()> {
+        self.builder.append_u64(v as u64)
+    }
+
+    fn serialize_u64(self, v: u64) -> ColumnarResult<()> {
+        self.builder.append_u64(v)
+    }
+
+    fn serialize_f32(self, v: f32) -> ColumnarResult<()> {
+        self.builder.append_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> ColumnarResult<()> {
+        self.builder.append_f64(v)
+    }
+
+    fn serialize_char(self, v: char) -> ColumnarResult<()> {
+        self.builder.append_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> ColumnarResult<()> {
+        self.builder.append_str(v)
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> ColumnarResult<()> {
+        Err(not_a_scalar())
+    }
+
+    fn serialize_none(self) -> ColumnarResult<()> {
+        self.builder.append_null();
+        Ok(())
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> ColumnarResult<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> ColumnarResult<()> {
+        self.builder.append_null();
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> ColumnarResult<()> {
+        self.builder.append_null();
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> ColumnarResult<()> {
+        self.builder.append_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> ColumnarResult<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> ColumnarResult<()> {
+        Err(not_a_scalar())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> ColumnarResult<Self::SerializeSeq> {
+        Err(not_a_scalar())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> ColumnarResult<Self::SerializeTuple> {
+        Err(not_a_scalar())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> ColumnarResult<Self::SerializeTupleStruct> {
+        Err(not_a_scalar())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> ColumnarResult<Self::SerializeTupleVariant> {
+        Err(not_a_scalar())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> ColumnarResult<Self::SerializeMap> {
+        Err(not_a_scalar())
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> ColumnarResult<Self::SerializeStruct> {
+        Err(not_a_scalar())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> ColumnarResult<Self::SerializeStructVariant> {
+        Err(not_a_scalar())
+    }
+}
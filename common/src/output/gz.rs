@@ -0,0 +1,138 @@
+use crate::Result;
+use failure::ResultExt;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread::JoinHandle;
+
+/// The size of chunks passed to the compression thread.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// The number of chunks buffered for the compression thread.
+const CHANNEL_DEPTH: usize = 4;
+
+/// A writer producing a gzipped file, compressing on a separate thread.
+///
+/// Errors of the compression thread are reported on the next write, or when finishing.
+/// Dropping this without calling `finish` still finishes the file, but ignores errors.
+pub struct GzFileWriter {
+    buf: Vec<u8>,
+    chunks: Option<SyncSender<Vec<u8>>>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl GzFileWriter {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<GzFileWriter> {
+        let path = path.as_ref();
+        let f = File::create(path).context(format!("unable to create {}", path.display()))?;
+
+        Self::from_file(path, f)
+    }
+
+    /// Opens an existing file, truncates it to the given length, and appends a new gzip member to
+    /// it.
+    /// Decompressing the file yields the concatenation of all members.
+    pub fn append<P: AsRef<Path>>(path: P, len: u64) -> Result<GzFileWriter> {
+        let path = path.as_ref();
+        let mut f = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .context(format!("unable to open {}", path.display()))?;
+        f.set_len(len)
+            .context(format!("unable to truncate {}", path.display()))?;
+        f.seek(SeekFrom::End(0))
+            .context(format!("unable to seek in {}", path.display()))?;
+
+        Self::from_file(path, f)
+    }
+
+    fn from_file(path: &Path, f: File) -> Result<GzFileWriter> {
+        let mut out = GzEncoder::new(BufWriter::new(f), Compression::default());
+
+        let (chunks_tx, chunks_rx) = sync_channel::<Vec<u8>>(CHANNEL_DEPTH);
+        let thread = std::thread::Builder::new()
+            .name(format!("gzip-{}", path.display()))
+            .spawn(move || {
+                for chunk in chunks_rx {
+                    out.write_all(&chunk)?;
+                }
+                out.finish()?.flush()
+            })
+            .context("unable to spawn compression thread")?;
+
+        Ok(GzFileWriter {
+            buf: Vec::with_capacity(CHUNK_SIZE),
+            chunks: Some(chunks_tx),
+            thread: Some(thread),
+        })
+    }
+
+    /// Flushes all buffered data and waits for the file to be written completely.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.finish_inner()
+    }
+
+    fn finish_inner(&mut self) -> io::Result<()> {
+        self.send_chunk()?;
+        // Dropping the sender ends the compression thread.
+        self.chunks.take();
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .map_err(|_| io::Error::other("compression thread panicked"))?,
+            None => Ok(()),
+        }
+    }
+
+    fn send_chunk(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+        let sent = self
+            .chunks
+            .as_ref()
+            .map(|chunks| chunks.send(chunk).is_ok())
+            .unwrap_or(false);
+        if !sent {
+            // The thread failed, find out why.
+            self.chunks.take();
+            return match self.thread.take().map(|t| t.join()) {
+                Some(Ok(Err(err))) => Err(err),
+                _ => Err(io::Error::other("compression thread failed")),
+            };
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for GzFileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.send_chunk()?;
+        }
+
+        Ok(buf.len())
+    }
+
+    /// Passes buffered data on to the compression thread.
+    /// This does not wait for the data to be written.
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_chunk()
+    }
+}
+
+impl Drop for GzFileWriter {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish_inner() {
+            error!("unable to finish compressed output: {}", err)
+        }
+    }
+}
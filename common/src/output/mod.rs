@@ -0,0 +1,529 @@
+//! Output sinks for the tabular data produced by our tools.
+//!
+//! Records can be written as gzipped CSV, Parquet, or Arrow IPC files, see `OutputFormat`.
+
+mod columnar;
+mod gz;
+
+pub use columnar::{Column, ColumnType, ColumnarError, Record};
+pub use gz::GzFileWriter;
+
+use crate::Result;
+use arrow_ipc::writer::FileWriter;
+use columnar::BatchBuilder;
+use failure::ResultExt;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use parquet::schema::types::ColumnPath;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+/// The file format to write output in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    /// Gzipped CSV, one file per rotation.
+    #[default]
+    CsvGz,
+    /// Zstd-compressed Parquet with typed columns, one row group per rotation.
+    /// Columns holding CIDs, peer IDs and the like are dictionary-encoded.
+    Parquet,
+    /// An Arrow IPC file, one record batch per rotation.
+    ArrowIpc,
+}
+
+/// Configuration for output sinks.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OutputConfig {
+    /// The format to write output in.
+    /// Defaults to gzipped CSV.
+    #[serde(default)]
+    pub format: OutputFormat,
+
+    /// The maximum number of records to collect in memory before writing them to columnar output.
+    /// Defaults to 65536.
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+}
+
+fn default_batch_size() -> usize {
+    65536
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            format: OutputFormat::default(),
+            batch_size: default_batch_size(),
+        }
+    }
+}
+
+/// Replaces "$id$" in the given pattern with the given ID, formatted in such a way that the
+/// resulting paths are lexicographically ordered.
+pub fn path_from_pattern(pattern: &str, id: i64) -> String {
+    pattern.replace("$id$", &format!("{:09}", id))
+}
+
+/// The state of a gzipped CSV output file at a checkpoint, to resume writing to it later.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OutputCheckpoint {
+    /// The path of the file.
+    pub path: PathBuf,
+    /// The length of the file at the checkpoint, in bytes.
+    pub len: u64,
+    /// Whether the CSV header has been written already.
+    pub header_written: bool,
+}
+
+enum SinkWriter {
+    CsvGz(csv::Writer<GzFileWriter>),
+    Parquet(ArrowWriter<BufWriter<File>>),
+    ArrowIpc(FileWriter<BufWriter<File>>),
+}
+
+/// A sink for records of one type, writing them in the configured `OutputFormat`.
+///
+/// Sinks created from a path pattern can be rotated.
+/// For CSV, this starts a new file, with "$id$" replaced by the given ID.
+/// For columnar formats, all records go into the file created first, and rotating starts a new
+/// row group or record batch instead.
+///
+/// Dropping a sink without calling `finish` still finishes the output, but ignores errors.
+pub struct OutputSink<R: Record> {
+    config: OutputConfig,
+    pattern: Option<String>,
+    path: PathBuf,
+    header_written: bool,
+    writer: Option<SinkWriter>,
+    batch: BatchBuilder,
+    _record: PhantomData<fn(&R)>,
+}
+
+impl<R: Record> OutputSink<R> {
+    /// Creates a sink writing to the given path.
+    pub fn create<P: AsRef<Path>>(config: &OutputConfig, path: P) -> Result<OutputSink<R>> {
+        let columns = R::columns();
+        let writer = Self::create_writer(config, &columns, path.as_ref())?;
+
+        Ok(OutputSink {
+            config: config.clone(),
+            pattern: None,
+            path: path.as_ref().to_path_buf(),
+            header_written: false,
+            writer: Some(writer),
+            batch: BatchBuilder::new(&columns),
+            _record: PhantomData,
+        })
+    }
+
+    /// Creates a sink writing to paths derived from the given pattern, which should contain
+    /// "$id$".
+    /// The first file is created for the given ID.
+    pub fn create_from_pattern(
+        config: &OutputConfig,
+        pattern: &str,
+        first_id: i64,
+    ) -> Result<OutputSink<R>> {
+        let mut sink = Self::create(config, path_from_pattern(pattern, first_id))?;
+        sink.pattern = Some(pattern.to_string());
+
+        Ok(sink)
+    }
+
+    /// Resumes writing to a file at the given checkpoint, discarding everything written to it
+    /// after the checkpoint.
+    /// This is only supported for gzipped CSV output.
+    pub fn resume(config: &OutputConfig, checkpoint: &OutputCheckpoint) -> Result<OutputSink<R>> {
+        ensure!(
+            config.format == OutputFormat::CsvGz,
+            "resuming is only supported for gzipped CSV output"
+        );
+        debug!(
+            "resuming output at {} after {} bytes",
+            checkpoint.path.display(),
+            checkpoint.len
+        );
+
+        Ok(OutputSink {
+            config: config.clone(),
+            pattern: None,
+            path: checkpoint.path.clone(),
+            header_written: checkpoint.header_written,
+            writer: Some(Self::resume_writer(checkpoint)?),
+            batch: BatchBuilder::new(&R::columns()),
+            _record: PhantomData,
+        })
+    }
+
+    fn resume_writer(checkpoint: &OutputCheckpoint) -> Result<SinkWriter> {
+        Ok(SinkWriter::CsvGz(
+            csv::WriterBuilder::new()
+                .has_headers(!checkpoint.header_written)
+                .from_writer(GzFileWriter::append(&checkpoint.path, checkpoint.len)?),
+        ))
+    }
+
+    fn create_writer(config: &OutputConfig, columns: &[Column], path: &Path) -> Result<SinkWriter> {
+        debug!("creating {:?} output at {}", config.format, path.display());
+        let writer = match config.format {
+            OutputFormat::CsvGz => {
+                SinkWriter::CsvGz(csv::Writer::from_writer(GzFileWriter::create(path)?))
+            }
+            OutputFormat::Parquet => {
+                let props = columns
+                    .iter()
+                    .filter(|c| c.column_type == ColumnType::Dictionary)
+                    .fold(
+                        WriterProperties::builder()
+                            .set_compression(Compression::ZSTD(ZstdLevel::default()))
+                            .set_dictionary_enabled(false),
+                        |props, c| {
+                            props.set_column_dictionary_enabled(ColumnPath::from(c.name), true)
+                        },
+                    )
+                    .build();
+                let f =
+                    File::create(path).context(format!("unable to create {}", path.display()))?;
+                SinkWriter::Parquet(
+                    ArrowWriter::try_new(BufWriter::new(f), columnar::schema(columns), Some(props))
+                        .context("unable to create Parquet writer")?,
+                )
+            }
+            OutputFormat::ArrowIpc => {
+                let f =
+                    File::create(path).context(format!("unable to create {}", path.display()))?;
+                SinkWriter::ArrowIpc(
+                    FileWriter::try_new_buffered(f, &columnar::schema(columns))
+                        .context("unable to create Arrow IPC writer")?,
+                )
+            }
+        };
+
+        Ok(writer)
+    }
+
+    /// Writes a record.
+    pub fn write(&mut self, record: &R) -> Result<()> {
+        match self.writer.as_mut() {
+            Some(SinkWriter::CsvGz(w)) => {
+                w.serialize(record).context("unable to write CSV")?;
+                self.header_written = true;
+            }
+            Some(_) => {
+                self.batch
+                    .append(record)
+                    .context("unable to convert record to columns")?;
+                if self.batch.num_rows() >= self.config.batch_size {
+                    self.write_batch()?;
+                }
+            }
+            None => unreachable!("write to finished sink"),
+        }
+
+        Ok(())
+    }
+
+    /// Rotates the output, see the type documentation.
+    /// This does nothing for sinks not created from a pattern.
+    pub fn rotate(&mut self, id: i64) -> Result<()> {
+        let pattern = match self.pattern.as_ref() {
+            Some(pattern) => pattern,
+            None => return Ok(()),
+        };
+
+        if let Some(SinkWriter::CsvGz(_)) = self.writer {
+            let path = PathBuf::from(path_from_pattern(pattern, id));
+            let writer = Self::create_writer(&self.config, &R::columns(), &path)?;
+            let old_writer = self.writer.replace(writer);
+            self.path = path;
+            self.header_written = false;
+            return Self::finish_writer(old_writer);
+        }
+
+        self.write_batch()?;
+        if let Some(SinkWriter::Parquet(w)) = self.writer.as_mut() {
+            w.flush().context("unable to write Parquet row group")?;
+        }
+
+        Ok(())
+    }
+
+    /// Waits for all records written so far to be written to disk, and returns a checkpoint to
+    /// resume writing from later.
+    /// This is only supported for gzipped CSV output, for which the current gzip member is
+    /// finished and a new one started.
+    pub fn checkpoint(&mut self) -> Result<OutputCheckpoint> {
+        ensure!(
+            self.config.format == OutputFormat::CsvGz,
+            "checkpoints are only supported for gzipped CSV output"
+        );
+        Self::finish_writer(self.writer.take())?;
+
+        let checkpoint = OutputCheckpoint {
+            path: self.path.clone(),
+            len: std::fs::metadata(&self.path)
+                .context(format!("unable to stat {}", self.path.display()))?
+                .len(),
+            header_written: self.header_written,
+        };
+        self.writer = Some(Self::resume_writer(&checkpoint)?);
+
+        Ok(checkpoint)
+    }
+
+    /// Writes all buffered records and waits for the output to be written completely.
+    pub fn finish(mut self) -> Result<()> {
+        self.finish_inner()
+    }
+
+    fn finish_inner(&mut self) -> Result<()> {
+        if self.writer.is_none() {
+            return Ok(());
+        }
+        self.write_batch()?;
+
+        Self::finish_writer(self.writer.take())
+    }
+
+    fn finish_writer(writer: Option<SinkWriter>) -> Result<()> {
+        match writer {
+            Some(SinkWriter::CsvGz(w)) => w
+                .into_inner()
+                .map_err(|err| err.into_error())
+                .context("unable to flush CSV output")?
+                .finish()
+                .context("unable to write compressed output")?,
+            Some(SinkWriter::Parquet(w)) => w
+                .into_inner()
+                .context("unable to finish Parquet output")?
+                .flush()
+                .context("unable to flush Parquet output")?,
+            Some(SinkWriter::ArrowIpc(w)) => w
+                .into_inner()
+                .context("unable to finish Arrow IPC output")?
+                .flush()
+                .context("unable to flush Arrow IPC output")?,
+            None => {}
+        }
+
+        Ok(())
+    }
+
+    fn write_batch(&mut self) -> Result<()> {
+        if self.batch.num_rows() == 0 {
+            return Ok(());
+        }
+        let batch = self
+            .batch
+            .finish()
+            .context("unable to build record batch")?;
+
+        match self.writer.as_mut() {
+            Some(SinkWriter::Parquet(w)) => w.write(&batch).context("unable to write Parquet")?,
+            Some(SinkWriter::ArrowIpc(w)) => {
+                w.write(&batch).context("unable to write Arrow IPC")?
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Record> Drop for OutputSink<R> {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish_inner() {
+            error!("unable to finish output: {}", err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Int64Type, UInt32Type};
+    use arrow_array::RecordBatch;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use std::path::PathBuf;
+
+    #[derive(Serialize)]
+    struct TestRecord {
+        id: i64,
+        cid: String,
+        latency: Option<u32>,
+    }
+
+    impl Record for TestRecord {
+        fn columns() -> Vec<Column> {
+            vec![
+                Column::new("id", ColumnType::Int64),
+                Column::new("cid", ColumnType::Dictionary),
+                Column::nullable("latency", ColumnType::UInt32),
+            ]
+        }
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("output-test-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_records(format: OutputFormat, pattern: &str) {
+        let config = OutputConfig {
+            format,
+            batch_size: 2,
+        };
+        let mut sink = OutputSink::create_from_pattern(&config, pattern, 0).unwrap();
+        for id in 0..5 {
+            if id == 3 {
+                sink.rotate(id).unwrap();
+            }
+            sink.write(&TestRecord {
+                id,
+                cid: format!("cid{}", id % 2),
+                latency: if id % 2 == 0 { Some(id as u32) } else { None },
+            })
+            .unwrap();
+        }
+        sink.finish().unwrap();
+    }
+
+    fn check_batches(batches: Vec<RecordBatch>) {
+        let ids = batches
+            .iter()
+            .flat_map(|b| b.column(0).as_primitive::<Int64Type>().values().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![0, 1, 2, 3, 4]);
+
+        let cids = batches
+            .iter()
+            .flat_map(|b| {
+                b.column(1)
+                    .as_string::<i32>()
+                    .iter()
+                    .map(|c| c.unwrap().to_string())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(cids, vec!["cid0", "cid1", "cid0", "cid1", "cid0"]);
+
+        let latencies = batches
+            .iter()
+            .flat_map(|b| {
+                b.column(2)
+                    .as_primitive::<UInt32Type>()
+                    .iter()
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(latencies, vec![Some(0), None, Some(2), None, Some(4)]);
+    }
+
+    #[test]
+    fn csv_rotates_files() {
+        let dir = test_dir("csv");
+        let pattern = dir.join("out-$id$.csv.gz");
+        write_records(OutputFormat::CsvGz, pattern.to_str().unwrap());
+
+        let read = |id| {
+            let f = File::open(path_from_pattern(pattern.to_str().unwrap(), id)).unwrap();
+            let mut s = String::new();
+            std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(f), &mut s).unwrap();
+            s
+        };
+        assert_eq!(read(0), "id,cid,latency\n0,cid0,0\n1,cid1,\n2,cid0,2\n");
+        assert_eq!(read(3), "id,cid,latency\n3,cid1,\n4,cid0,4\n");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parquet_uses_row_groups() {
+        let dir = test_dir("parquet");
+        let pattern = dir.join("out-$id$.parquet");
+        write_records(OutputFormat::Parquet, pattern.to_str().unwrap());
+
+        let f = File::open(path_from_pattern(pattern.to_str().unwrap(), 0)).unwrap();
+        let builder = ParquetRecordBatchReaderBuilder::try_new(f).unwrap();
+        let metadata = builder.metadata().clone();
+        let row_groups = metadata
+            .row_groups()
+            .iter()
+            .map(|rg| rg.num_rows())
+            .collect::<Vec<_>>();
+        assert_eq!(row_groups, vec![3, 2]);
+        assert!(metadata
+            .row_group(0)
+            .column(1)
+            .dictionary_page_offset()
+            .is_some());
+        assert!(metadata
+            .row_group(0)
+            .column(0)
+            .dictionary_page_offset()
+            .is_none());
+
+        check_batches(builder.build().unwrap().map(|b| b.unwrap()).collect());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn arrow_ipc_roundtrip() {
+        let dir = test_dir("ipc");
+        let pattern = dir.join("out-$id$.arrow");
+        write_records(OutputFormat::ArrowIpc, pattern.to_str().unwrap());
+
+        let f = File::open(path_from_pattern(pattern.to_str().unwrap(), 0)).unwrap();
+        let reader = arrow_ipc::reader::FileReader::try_new(f, None).unwrap();
+        let batches = reader.map(|b| b.unwrap()).collect::<Vec<_>>();
+        assert_eq!(
+            batches.iter().map(|b| b.num_rows()).collect::<Vec<_>>(),
+            vec![2, 1, 2]
+        );
+
+        check_batches(batches);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn csv_resumes_at_checkpoint() {
+        let dir = test_dir("resume");
+        let path = dir.join("out.csv.gz");
+        let config = OutputConfig::default();
+        let record = |id: i64| TestRecord {
+            id,
+            cid: "cid".to_string(),
+            latency: None,
+        };
+
+        let mut sink = OutputSink::create(&config, &path).unwrap();
+        sink.write(&record(0)).unwrap();
+        let checkpoint = sink.checkpoint().unwrap();
+        sink.write(&record(1)).unwrap();
+        // This is lost, as if we crashed.
+        sink.finish().unwrap();
+
+        let mut sink = OutputSink::resume(&config, &checkpoint).unwrap();
+        sink.write(&record(2)).unwrap();
+        sink.finish().unwrap();
+
+        let mut s = String::new();
+        std::io::Read::read_to_string(
+            &mut flate2::read::MultiGzDecoder::new(File::open(&path).unwrap()),
+            &mut s,
+        )
+        .unwrap();
+        assert_eq!(s, "id,cid,latency\n0,cid,\n2,cid,\n");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
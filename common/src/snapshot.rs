@@ -0,0 +1,87 @@
+//! Snapshots of the state of long-running tools, to resume them after a crash.
+//!
+//! Snapshots are written as gzipped JSON.
+//! Each snapshot is tagged with the kind of state it holds and a format version, which are checked
+//! when reading it.
+
+use crate::Result;
+use failure::ResultExt;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+/// The version of the snapshot format.
+/// This must be incremented whenever a change to any state breaks compatibility.
+pub const SNAPSHOT_VERSION: u32 = 8;
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot<T> {
+    kind: String,
+    version: u32,
+    state: T,
+}
+
+/// Writes a snapshot of the given state to the given path.
+///
+/// The snapshot is first written to a temporary file next to the target, which is then renamed.
+/// An existing snapshot is thus only replaced once the new one is complete.
+pub fn write_snapshot<T: Serialize, P: AsRef<Path>>(path: P, kind: &str, state: &T) -> Result<()> {
+    let path = path.as_ref();
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let f = File::create(&tmp_path).context("unable to create snapshot file")?;
+    let mut out = BufWriter::new(GzEncoder::new(BufWriter::new(f), Compression::fast()));
+    serde_json::to_writer(
+        &mut out,
+        &Snapshot {
+            kind: kind.to_string(),
+            version: SNAPSHOT_VERSION,
+            state,
+        },
+    )
+    .context("unable to serialize snapshot")?;
+    let f = out
+        .into_inner()
+        .map_err(|err| err.into_error())
+        .context("unable to write snapshot")?
+        .finish()
+        .context("unable to write snapshot")?
+        .into_inner()
+        .map_err(|err| err.into_error())
+        .context("unable to write snapshot")?;
+    f.sync_all().context("unable to sync snapshot")?;
+
+    std::fs::rename(&tmp_path, path).context("unable to replace snapshot")?;
+    debug!("wrote {} snapshot to {}", kind, path.display());
+
+    Ok(())
+}
+
+/// Reads a snapshot of the given kind from the given path.
+pub fn read_snapshot<T: DeserializeOwned, P: AsRef<Path>>(path: P, kind: &str) -> Result<T> {
+    let f = File::open(path).context("unable to open snapshot file")?;
+    let snapshot: Snapshot<T> =
+        serde_json::from_reader(BufReader::new(GzDecoder::new(BufReader::new(f))))
+            .context("unable to deserialize snapshot")?;
+
+    ensure!(
+        snapshot.kind == kind,
+        "expected {} snapshot, got {}",
+        kind,
+        snapshot.kind
+    );
+    ensure!(
+        snapshot.version == SNAPSHOT_VERSION,
+        "unsupported snapshot version {}, expected {}",
+        snapshot.version,
+        SNAPSHOT_VERSION
+    );
+
+    Ok(snapshot.state)
+}
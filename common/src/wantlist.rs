@@ -1,9 +1,14 @@
+use crate::cid_normalization::CidNormalization;
+use crate::events::{BlockPresence, BlockPresenceType};
+use crate::geoip::{AddressOrigin, WithOrigin};
+use crate::output::{Column, ColumnType, Record};
 use crate::Result;
 use failure::{err_msg, ResultExt};
 use parity_multiaddr::Multiaddr;
 use serde::{Deserialize, Serialize};
 use serde_repr::*;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::mem;
 
 /// Constants for the `want_type` field of a `JSONWantlistEntry`.
@@ -37,7 +42,8 @@ pub struct JSONWantlistEntry {
 }
 
 /// A wantlist message.
-/// These are produced by the modified Go implementation of IPFS.
+/// These are produced by the modified Go implementation of IPFS, or converted from `PushedEvent`s
+/// logged by the `bitswap-monitoring-client`.
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct JSONMessage {
     pub timestamp: chrono::DateTime<chrono::Utc>,
@@ -47,7 +53,38 @@ pub struct JSONMessage {
     pub full_want_list: Option<bool>,
     pub peer_connected: Option<bool>,
     pub peer_disconnected: Option<bool>,
+    /// Whether IPFS knew the peer at the time of a connection event.
+    /// This is missing for converted events, for which it is derived from the simulated ledger.
     pub connect_event_peer_found: Option<bool>,
+
+    /// All addresses of the peer, for converted events.
+    /// This is always set for converted events, and never for messages produced by the modified Go
+    /// implementation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connected_addresses: Option<Vec<String>>,
+    /// The blocks sent by the peer, for converted events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blocks: Option<Vec<JsonCID>>,
+    /// The block presences sent by the peer, for converted events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_presences: Option<Vec<BlockPresence>>,
+}
+
+impl JSONMessage {
+    /// Returns whether this message was converted from a `PushedEvent`.
+    pub fn is_converted(&self) -> bool {
+        self.connected_addresses.is_some()
+    }
+
+    /// Returns the address of the peer, or the first address reported for it if that could not be
+    /// parsed.
+    pub fn address_string(&self) -> Option<String> {
+        self.address.as_ref().map(|a| a.to_string()).or_else(|| {
+            self.connected_addresses
+                .as_ref()
+                .and_then(|addrs| addrs.first().cloned())
+        })
+    }
 }
 
 /// Message type constants for CSV files.
@@ -91,6 +128,9 @@ pub const CSV_DUPLICATE_STATUS_NO_DUP: u32 = 0;
 pub const CSV_DUPLICATE_STATUS_DUP_FULL_WANTLIST: u32 = 1;
 pub const CSV_DUPLICATE_STATUS_DUP_RECONNECT: u32 = 2;
 pub const CSV_DUPLICATE_STATUS_DUP_SLIDING_WINDOW: u32 = 4;
+/// Only set by engine models that periodically rebroadcast their wants, for wants repeated around
+/// the rebroadcast interval, see `EngineSemantics::is_rebroadcast`.
+pub const CSV_DUPLICATE_STATUS_DUP_REBROADCAST: u32 = 8;
 
 // TODO keep this in sync with `unification::matcher::OutputCSVWantlistEntry`.
 /// A wantlist entry, to be serialized as CSV.
@@ -137,9 +177,70 @@ pub struct CSVWantlistEntry {
     /// This is only valid for requests of type `WANT_BLOCK`, as they can upgrade earlier
     /// `WANT_HAVE` requests.
     pub upgrades_earlier_request: bool,
+
+    /// The ISO 3166-1 alpha-2 code of the country of the address, if enriched, see `geoip`.
+    #[serde(default)]
+    pub origin_country: Option<String>,
+    /// The number of the autonomous system of the address, if enriched.
+    #[serde(default)]
+    pub origin_asn: Option<u32>,
+    /// The organization operating the autonomous system of the address, if enriched.
+    #[serde(default)]
+    pub origin_as_organization: Option<String>,
+    /// The city of the address, if enriched.
+    #[serde(default)]
+    pub origin_city: Option<String>,
+
+    /// The normalized CID, if CID normalization is enabled.
+    /// This is used to identify entries for the same content.
+    #[serde(default)]
+    pub normalized_cid: Option<String>,
+}
+
+impl Record for CSVWantlistEntry {
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::new("message_id", ColumnType::Int64),
+            Column::new("message_type", ColumnType::Int32),
+            Column::new("timestamp_seconds", ColumnType::Int64),
+            Column::new("timestamp_subsec_milliseconds", ColumnType::UInt32),
+            Column::new("peer_id", ColumnType::Dictionary),
+            Column::new("address", ColumnType::Dictionary),
+            Column::new("priority", ColumnType::Int32),
+            Column::new("entry_type", ColumnType::Int32),
+            Column::new("cid", ColumnType::Dictionary),
+            Column::new("duplicate_status", ColumnType::UInt32),
+            Column::new("sliding_window_smallest_match", ColumnType::UInt32),
+            Column::new("secs_since_earlier_message", ColumnType::UInt32),
+            Column::new("upgrades_earlier_request", ColumnType::Boolean),
+            Column::nullable("origin_country", ColumnType::Dictionary),
+            Column::nullable("origin_asn", ColumnType::UInt32),
+            Column::nullable("origin_as_organization", ColumnType::Dictionary),
+            Column::nullable("origin_city", ColumnType::Dictionary),
+            Column::nullable("normalized_cid", ColumnType::Dictionary),
+        ]
+    }
+}
+
+impl WithOrigin for CSVWantlistEntry {
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn set_origin(&mut self, origin: AddressOrigin) {
+        self.origin_country = origin.country;
+        self.origin_asn = origin.asn;
+        self.origin_as_organization = origin.as_organization;
+        self.origin_city = origin.city;
+    }
 }
 
 impl CSVWantlistEntry {
+    /// Returns the CID to identify this entry by, i.e., the normalized CID if available.
+    pub fn cid_key(&self) -> &str {
+        self.normalized_cid.as_deref().unwrap_or(&self.cid)
+    }
+
     pub fn from_wantlist_entries(
         entries: Vec<WantlistEntry>,
         message: &JSONMessage,
@@ -154,28 +255,34 @@ impl CSVWantlistEntry {
 
         entries
             .into_iter()
-            .map(|e| CSVWantlistEntry {
-                message_id: id,
-                message_type,
-                timestamp_seconds: message_timestamp_seconds,
-                timestamp_subsec_milliseconds: message_timestamp_subsec_millis,
-                peer_id: message.peer.clone(),
-                address: match &message.address {
-                    Some(address) => address.to_string(),
-                    None => "".to_string(),
-                },
-                priority: 0,
-                entry_type,
-                cid: e.cid,
-                duplicate_status,
-                sliding_window_smallest_match,
-                secs_since_earlier_message: 0,
-                upgrades_earlier_request: false,
+            .map(|e| {
+                let (cid, normalized_cid) = e.into_cids();
+                CSVWantlistEntry {
+                    message_id: id,
+                    message_type,
+                    timestamp_seconds: message_timestamp_seconds,
+                    timestamp_subsec_milliseconds: message_timestamp_subsec_millis,
+                    peer_id: message.peer.clone(),
+                    address: message.address_string().unwrap_or_default(),
+                    priority: 0,
+                    entry_type,
+                    cid,
+                    normalized_cid,
+                    duplicate_status,
+                    sliding_window_smallest_match,
+                    secs_since_earlier_message: 0,
+                    upgrades_earlier_request: false,
+                    origin_country: None,
+                    origin_asn: None,
+                    origin_as_organization: None,
+                    origin_city: None,
+                }
             })
             .collect()
     }
 
     pub fn from_json_message(message: JSONMessage, id: i64) -> Result<Vec<CSVWantlistEntry>> {
+        let address = message.address_string().unwrap_or_default();
         let entries = message
             .received_entries
             .ok_or_else(|| err_msg("no entries when converting from JSON message"))?;
@@ -184,10 +291,6 @@ impl CSVWantlistEntry {
         let timestamp_seconds = timestamp.timestamp();
         let timestamp_subsec_millis = timestamp.timestamp_subsec_millis();
         let full_want_list = message.full_want_list;
-        let address = match &message.address {
-            Some(address) => address.to_string(),
-            None => "".to_string(),
-        };
 
         let csv_entries = entries
             .into_iter()
@@ -232,10 +335,15 @@ impl CSVWantlistEntry {
                     }
                 },
                 cid: entry.cid.path,
+                normalized_cid: None,
                 duplicate_status: CSV_DUPLICATE_STATUS_NO_DUP,
                 sliding_window_smallest_match: 0,
                 secs_since_earlier_message: 0,
                 upgrades_earlier_request: false,
+                origin_country: None,
+                origin_asn: None,
+                origin_as_organization: None,
+                origin_city: None,
             })
             .collect();
 
@@ -262,6 +370,49 @@ pub struct CSVConnectionEvent {
     pub address: String,
     /// The type of the connection event, see the `CSV_CONNECTION_TYPE_` constants.
     pub event_type: i32,
+
+    /// The ISO 3166-1 alpha-2 code of the country of the address, if enriched, see `geoip`.
+    #[serde(default)]
+    pub origin_country: Option<String>,
+    /// The number of the autonomous system of the address, if enriched.
+    #[serde(default)]
+    pub origin_asn: Option<u32>,
+    /// The organization operating the autonomous system of the address, if enriched.
+    #[serde(default)]
+    pub origin_as_organization: Option<String>,
+    /// The city of the address, if enriched.
+    #[serde(default)]
+    pub origin_city: Option<String>,
+}
+
+impl Record for CSVConnectionEvent {
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::new("message_id", ColumnType::Int64),
+            Column::new("timestamp_seconds", ColumnType::Int64),
+            Column::new("timestamp_subsec_millis", ColumnType::UInt32),
+            Column::new("peer_id", ColumnType::Dictionary),
+            Column::new("address", ColumnType::Dictionary),
+            Column::new("event_type", ColumnType::Int32),
+            Column::nullable("origin_country", ColumnType::Dictionary),
+            Column::nullable("origin_asn", ColumnType::UInt32),
+            Column::nullable("origin_as_organization", ColumnType::Dictionary),
+            Column::nullable("origin_city", ColumnType::Dictionary),
+        ]
+    }
+}
+
+impl WithOrigin for CSVConnectionEvent {
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn set_origin(&mut self, origin: AddressOrigin) {
+        self.origin_country = origin.country;
+        self.origin_asn = origin.asn;
+        self.origin_as_organization = origin.as_organization;
+        self.origin_city = origin.city;
+    }
 }
 
 impl CSVConnectionEvent {
@@ -302,17 +453,209 @@ impl CSVConnectionEvent {
             message_id: id,
             timestamp_seconds: message.timestamp.timestamp(),
             timestamp_subsec_millis: message.timestamp.timestamp_subsec_millis(),
+            address: message.address_string().unwrap_or_default(),
             peer_id: message.peer,
-            address: match &message.address {
-                Some(address) => address.to_string(),
-                None => "".to_string(),
-            },
             event_type,
+            origin_country: None,
+            origin_asn: None,
+            origin_as_organization: None,
+            origin_city: None,
         })
     }
 }
 
-#[derive(Clone, Debug, Ord, PartialOrd, PartialEq, Eq)]
+/// Response type constants for CSV files.
+pub const CSV_RESPONSE_TYPE_BLOCK: i32 = 1;
+pub const CSV_RESPONSE_TYPE_HAVE: i32 = 2;
+pub const CSV_RESPONSE_TYPE_DONT_HAVE: i32 = 3;
+
+/// A block or block presence received from a peer, matched to the outstanding request for the same
+/// CID in the ledger of that peer, to be serialized as CSV.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CSVResponse {
+    /// The ID of the message containing the response.
+    pub message_id: i64,
+
+    /// Timestamp as seconds since the Unix epoch.
+    pub timestamp_seconds: i64,
+    /// Sub-second milliseconds of the timestamp.
+    pub timestamp_subsec_milliseconds: u32,
+
+    /// The ID of the sending peer.
+    pub peer_id: String,
+    /// The underlay multiaddress of the sending peer, if available.
+    pub address: String,
+
+    /// Response type, see `CSV_RESPONSE_TYPE_` constants.
+    pub response_type: i32,
+    /// The human-readable CID as was sent in the original message, not normalized.
+    pub cid: String,
+
+    /// The ID of the message that last requested the CID, if any.
+    pub request_message_id: Option<i64>,
+    /// The entry type of the matched request, see `CSV_ENTRY_TYPE_` constants.
+    pub request_entry_type: Option<i32>,
+    /// The time between the matched request and this response, in milliseconds.
+    pub latency_ms: Option<u64>,
+    /// Whether no request for the CID was outstanding.
+    pub unsolicited: bool,
+    /// Whether this is a `DONT_HAVE` to a request that did not ask for those.
+    pub unrequested_dont_have: bool,
+
+    /// The ISO 3166-1 alpha-2 code of the country of the address, if enriched, see `geoip`.
+    #[serde(default)]
+    pub origin_country: Option<String>,
+    /// The number of the autonomous system of the address, if enriched.
+    #[serde(default)]
+    pub origin_asn: Option<u32>,
+    /// The organization operating the autonomous system of the address, if enriched.
+    #[serde(default)]
+    pub origin_as_organization: Option<String>,
+    /// The city of the address, if enriched.
+    #[serde(default)]
+    pub origin_city: Option<String>,
+
+    /// The normalized CID, if CID normalization is enabled.
+    #[serde(default)]
+    pub normalized_cid: Option<String>,
+}
+
+impl Record for CSVResponse {
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::new("message_id", ColumnType::Int64),
+            Column::new("timestamp_seconds", ColumnType::Int64),
+            Column::new("timestamp_subsec_milliseconds", ColumnType::UInt32),
+            Column::new("peer_id", ColumnType::Dictionary),
+            Column::new("address", ColumnType::Dictionary),
+            Column::new("response_type", ColumnType::Int32),
+            Column::new("cid", ColumnType::Dictionary),
+            Column::nullable("request_message_id", ColumnType::Int64),
+            Column::nullable("request_entry_type", ColumnType::Int32),
+            Column::nullable("latency_ms", ColumnType::UInt64),
+            Column::new("unsolicited", ColumnType::Boolean),
+            Column::new("unrequested_dont_have", ColumnType::Boolean),
+            Column::nullable("origin_country", ColumnType::Dictionary),
+            Column::nullable("origin_asn", ColumnType::UInt32),
+            Column::nullable("origin_as_organization", ColumnType::Dictionary),
+            Column::nullable("origin_city", ColumnType::Dictionary),
+            Column::nullable("normalized_cid", ColumnType::Dictionary),
+        ]
+    }
+}
+
+impl WithOrigin for CSVResponse {
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn set_origin(&mut self, origin: AddressOrigin) {
+        self.origin_country = origin.country;
+        self.origin_asn = origin.asn;
+        self.origin_as_organization = origin.as_organization;
+        self.origin_city = origin.city;
+    }
+}
+
+/// Request end reason constants for CSV files.
+pub const CSV_REQUEST_END_REASON_CANCEL: i32 = 1;
+pub const CSV_REQUEST_END_REASON_FULL_WANTLIST: i32 = 2;
+pub const CSV_REQUEST_END_REASON_DISCONNECT: i32 = 3;
+pub const CSV_REQUEST_END_REASON_END_OF_SIMULATION: i32 = 4;
+
+/// The lifetime of a request, from the first WANT for a CID until the entry is removed from the
+/// ledger of the peer, to be serialized as CSV.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CSVRequestLifetime {
+    /// The ID of the requesting peer.
+    pub peer_id: String,
+    /// The human-readable CID as was sent in the last request, not normalized.
+    pub cid: String,
+
+    /// The ID of the message that first requested the CID.
+    pub start_message_id: i64,
+    /// Timestamp of the first request as seconds since the Unix epoch.
+    pub start_timestamp_seconds: i64,
+    /// Sub-second milliseconds of the timestamp of the first request.
+    pub start_timestamp_subsec_milliseconds: u32,
+    /// Entry type of the first request, see `CSV_ENTRY_TYPE_` constants.
+    pub start_entry_type: i32,
+    /// Entry type of the last request, see `CSV_ENTRY_TYPE_` constants.
+    pub end_entry_type: i32,
+    /// Whether a `WANT_BLOCK` upgraded an earlier `WANT_HAVE` during the lifetime.
+    pub upgraded: bool,
+    /// The number of times the CID was requested again during the lifetime.
+    pub num_resends: u32,
+
+    /// The ID of the message that ended the request.
+    pub end_message_id: i64,
+    /// Timestamp of the end as seconds since the Unix epoch.
+    pub end_timestamp_seconds: i64,
+    /// Sub-second milliseconds of the timestamp of the end.
+    pub end_timestamp_subsec_milliseconds: u32,
+    /// The reason for the end, see `CSV_REQUEST_END_REASON_` constants.
+    pub end_reason: i32,
+    /// The duration of the request in milliseconds.
+    pub duration_ms: u64,
+
+    /// The normalized CID, if CID normalization is enabled.
+    pub normalized_cid: Option<String>,
+}
+
+impl Record for CSVRequestLifetime {
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::new("peer_id", ColumnType::Dictionary),
+            Column::new("cid", ColumnType::Dictionary),
+            Column::new("start_message_id", ColumnType::Int64),
+            Column::new("start_timestamp_seconds", ColumnType::Int64),
+            Column::new("start_timestamp_subsec_milliseconds", ColumnType::UInt32),
+            Column::new("start_entry_type", ColumnType::Int32),
+            Column::new("end_entry_type", ColumnType::Int32),
+            Column::new("upgraded", ColumnType::Boolean),
+            Column::new("num_resends", ColumnType::UInt32),
+            Column::new("end_message_id", ColumnType::Int64),
+            Column::new("end_timestamp_seconds", ColumnType::Int64),
+            Column::new("end_timestamp_subsec_milliseconds", ColumnType::UInt32),
+            Column::new("end_reason", ColumnType::Int32),
+            Column::new("duration_ms", ColumnType::UInt64),
+            Column::nullable("normalized_cid", ColumnType::Dictionary),
+        ]
+    }
+}
+
+impl CSVRequestLifetime {
+    fn from_wantlist_entries<'a>(
+        entries: impl IntoIterator<Item = &'a WantlistEntry>,
+        peer: &str,
+        ts: chrono::DateTime<chrono::Utc>,
+        msg_id: i64,
+        end_reason: i32,
+    ) -> Vec<CSVRequestLifetime> {
+        entries
+            .into_iter()
+            .map(|e| CSVRequestLifetime {
+                peer_id: peer.to_string(),
+                cid: e.sent_cid.as_ref().unwrap_or(&e.cid).clone(),
+                normalized_cid: e.sent_cid.as_ref().map(|_| e.cid.clone()),
+                start_message_id: e.first_message_id,
+                start_timestamp_seconds: e.first_ts.timestamp(),
+                start_timestamp_subsec_milliseconds: e.first_ts.timestamp_subsec_millis(),
+                start_entry_type: e.first_entry_type,
+                end_entry_type: e.csv_entry_type(),
+                upgraded: e.upgraded,
+                num_resends: e.num_resends,
+                end_message_id: msg_id,
+                end_timestamp_seconds: ts.timestamp(),
+                end_timestamp_subsec_milliseconds: ts.timestamp_subsec_millis(),
+                end_reason,
+                duration_ms: (ts - e.first_ts).num_milliseconds().max(0) as u64,
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Ord, PartialOrd, PartialEq, Eq, Serialize, Deserialize)]
 enum WantType {
     Block,
     Have,
@@ -335,16 +678,120 @@ impl WantType {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct WantlistEntry {
+    /// The CID identifying this entry, normalized if CID normalization is enabled.
     cid: String,
+    /// The CID as was sent in the last request, if CID normalization is enabled.
+    sent_cid: Option<String>,
     want_type: WantType,
+    send_dont_have: bool,
     ts: chrono::DateTime<chrono::Utc>,
+    /// The ID of the message that last requested this entry.
+    message_id: i64,
+
+    /// The beginning of the lifetime of this request.
+    first_ts: chrono::DateTime<chrono::Utc>,
+    first_message_id: i64,
+    first_entry_type: i32,
+    /// Whether this entry was upgraded from `WANT_HAVE` to `WANT_BLOCK` during its lifetime.
+    upgraded: bool,
+    /// The number of times this entry was requested again during its lifetime.
+    num_resends: u32,
+    /// The priority of the task for this entry in the peer task queue, which is the highest
+    /// priority this entry was requested with during its lifetime.
+    #[serde(default)]
+    priority: i32,
+}
+
+impl WantlistEntry {
+    fn from_json_entry(
+        e: &JSONWantlistEntry,
+        ts: chrono::DateTime<chrono::Utc>,
+        message_id: i64,
+    ) -> WantlistEntry {
+        let mut entry = WantlistEntry {
+            cid: e.cid.path.clone(),
+            sent_cid: None,
+            want_type: WantType::from_json_entry(e),
+            send_dont_have: e.send_dont_have,
+            ts,
+            message_id,
+            first_ts: ts,
+            first_message_id: message_id,
+            first_entry_type: 0,
+            upgraded: false,
+            num_resends: 0,
+            priority: e.priority,
+        };
+        entry.first_entry_type = entry.csv_entry_type();
+        entry
+    }
+
+    /// Returns the CID as sent and the normalized CID, if CID normalization is enabled.
+    fn into_cids(self) -> (String, Option<String>) {
+        match self.sent_cid {
+            Some(sent_cid) => (sent_cid, Some(self.cid)),
+            None => (self.cid, None),
+        }
+    }
+
+    /// Continues the lifetime of an earlier request for the same CID with this one.
+    fn continue_lifetime(&mut self, earlier: &WantlistEntry) {
+        self.first_ts = earlier.first_ts;
+        self.first_message_id = earlier.first_message_id;
+        self.first_entry_type = earlier.first_entry_type;
+        self.upgraded = earlier.upgraded
+            || (earlier.want_type == WantType::Have && self.want_type == WantType::Block);
+        self.num_resends = earlier.num_resends + 1;
+        self.priority = self.priority.max(earlier.priority);
+    }
+
+    /// Returns whether this entry was requested again since the beginning of its lifetime with
+    /// its current want type.
+    fn was_resent(&self) -> bool {
+        self.message_id != self.first_message_id
+    }
+
+    /// Returns the CID as was sent in the last request.
+    pub fn sent_cid(&self) -> &str {
+        self.sent_cid.as_deref().unwrap_or(&self.cid)
+    }
+
+    /// Returns the normalized CID, if CID normalization is enabled.
+    pub fn normalized_cid(&self) -> Option<&str> {
+        self.sent_cid.as_ref().map(|_| self.cid.as_str())
+    }
+
+    /// Returns the timestamp of the request which started the lifetime of this entry.
+    pub fn first_requested_at(&self) -> chrono::DateTime<chrono::Utc> {
+        self.first_ts
+    }
+
+    /// Returns the timestamp of the last request for this entry.
+    pub fn last_requested_at(&self) -> chrono::DateTime<chrono::Utc> {
+        self.ts
+    }
+
+    /// Returns the priority of the task for this entry in the peer task queue.
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    /// Returns the CSV entry type of the request for this entry.
+    pub fn csv_entry_type(&self) -> i32 {
+        match (&self.want_type, self.send_dont_have) {
+            (WantType::Block, false) => CSV_ENTRY_TYPE_WANT_BLOCK,
+            (WantType::Block, true) => CSV_ENTRY_TYPE_WANT_BLOCK_SEND_DONT_HAVE,
+            (WantType::Have, false) => CSV_ENTRY_TYPE_WANT_HAVE,
+            (WantType::Have, true) => CSV_ENTRY_TYPE_WANT_HAVE_SEND_DONT_HAVE,
+        }
+    }
 }
 
 /// A ledger keeps track of the entries WANTed by a peer, and some metadata about connection status
 /// and timestamps.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Ledger {
     /// A counter for parallel connections.
     /// In the optimal case this is always zero or one, but IPFS misreports events sometimes.
@@ -360,10 +807,14 @@ pub struct Ledger {
 
     /// The beginning of the current overlay session, if we are connected.
     connected_ts: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// When we last got disconnected from this peer, if we are disconnected.
+    #[serde(default)]
+    disconnected_ts: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 /// The configuration of our engine simulation.
-#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct EngineSimulationConfig {
     /// Whether to allow the `full_want_list` field to be unspecified in JSON messages.
     /// This is for historic reasons, as we did not track this field in the very beginning of our
@@ -391,13 +842,212 @@ pub struct EngineSimulationConfig {
     /// The window sizes in seconds to sort duplicate entries into.
     /// These will eventually be sorted.
     pub sliding_window_lengths: Vec<u32>,
+
+    /// The semantics of the BitSwap engine to simulate.
+    /// Defaults to the engine of v0.5 of the Go IPFS client.
+    #[serde(default)]
+    pub model: EngineModel,
+
+    /// Whether to emit `CSVRequestLifetime` records whenever an entry is removed from a ledger.
+    /// `ipfs-json-to-csv` and `unify-bitswap-traces` enable this if an output file for request
+    /// lifetimes is configured.
+    /// Defaults to `false`.
+    #[serde(default)]
+    pub emit_request_lifetimes: bool,
+
+    /// How to normalize CIDs before comparing them.
+    /// If enabled, ledgers are keyed on normalized CIDs, which are added to the output.
+    /// Defaults to no normalization.
+    #[serde(default)]
+    pub cid_normalization: CidNormalization,
 }
 
-/// A simulation of the BitSwap engine as was present in v0.5 of the Go IPFS client.
-#[derive(Clone, Debug, Default)]
+/// The maximum number of entries in the wantlist of a peer, as enforced by boxo.
+pub const BOXO_MAX_WANTLIST_ENTRIES: usize = 1024;
+
+/// The interval at which boxo clients rebroadcast their wantlists, in seconds.
+pub const BOXO_REBROADCAST_INTERVAL_SECS: u32 = 30;
+
+/// The deviation from the rebroadcast interval, in seconds, up to which a repeated want is still
+/// considered a rebroadcast.
+/// This accounts for the resolution of our timestamps and for delays in sending.
+pub const REBROADCAST_TOLERANCE_SECS: u32 = 2;
+
+/// The BitSwap engine semantics an `EngineSimulation` follows, as selected in its configuration.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EngineModel {
+    /// The engine as was present in v0.5 of the Go IPFS client, see `GoIpfsV05Semantics`.
+    #[default]
+    #[serde(rename = "go_ipfs_v0_5")]
+    GoIpfsV05,
+    /// The engine of current kubo, as implemented in boxo, see `BoxoSemantics`.
+    #[serde(rename = "boxo")]
+    Boxo,
+}
+
+impl EngineModel {
+    /// Returns the semantics of this model.
+    pub fn semantics(&self) -> &'static dyn EngineSemantics {
+        match self {
+            EngineModel::GoIpfsV05 => &GoIpfsV05Semantics,
+            EngineModel::Boxo => &BoxoSemantics,
+        }
+    }
+}
+
+/// The aspects of the BitSwap engine in which implementations differ, as far as they affect the
+/// ledgers, the classification of entries, and the order in which wants are served.
+pub trait EngineSemantics: std::fmt::Debug + Send + Sync {
+    /// Returns the maximum number of entries in the wantlist of a peer, if any.
+    /// Wants for new CIDs beyond this are ignored.
+    fn max_wantlist_entries(&self) -> Option<usize>;
+
+    /// Returns whether a `WANT_HAVE` replaces an existing `WANT_BLOCK` for the same CID.
+    fn downgrades_want_block(&self) -> bool;
+
+    /// Returns the interval at which clients rebroadcast their wantlists, in seconds, if they do.
+    fn rebroadcast_interval_secs(&self) -> Option<u32>;
+
+    /// Returns whether a want repeating an existing entry in an incremental message is a periodic
+    /// rebroadcast, which is then marked with `CSV_DUPLICATE_STATUS_DUP_REBROADCAST`.
+    ///
+    /// Clients rebroadcast all their wants on one timer, so the first rebroadcast of a want
+    /// follows its request within one interval, and later rebroadcasts follow one interval apart,
+    /// both up to `REBROADCAST_TOLERANCE_SECS`.
+    fn is_rebroadcast(&self, secs_since_earlier_message: u32, earlier_was_resent: bool) -> bool {
+        self.rebroadcast_interval_secs()
+            .map(|interval| {
+                let earliest = if earlier_was_resent {
+                    interval.saturating_sub(REBROADCAST_TOLERANCE_SECS)
+                } else {
+                    0
+                };
+                (earliest..=interval + REBROADCAST_TOLERANCE_SECS)
+                    .contains(&secs_since_earlier_message)
+            })
+            .unwrap_or(false)
+    }
+
+    /// Orders the entries of a ledger by when the peer task queue serves them.
+    ///
+    /// Both engines schedule tasks using go-peertaskqueue: by descending priority, and in the
+    /// order in which they were created among equal priorities.
+    /// A repeated want merges into the existing task, which keeps its creation time and takes the
+    /// higher of both priorities.
+    fn task_queue_order(&self, a: &WantlistEntry, b: &WantlistEntry) -> Ordering {
+        b.priority
+            .cmp(&a.priority)
+            .then(a.first_ts.cmp(&b.first_ts))
+            .then(a.first_message_id.cmp(&b.first_message_id))
+            .then(a.cid.cmp(&b.cid))
+    }
+}
+
+/// The engine as was present in v0.5 of the Go IPFS client.
+/// Wants replace existing entries for the same CID, including their want type, and wantlists
+/// are unbounded.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GoIpfsV05Semantics;
+
+impl EngineSemantics for GoIpfsV05Semantics {
+    fn max_wantlist_entries(&self) -> Option<usize> {
+        None
+    }
+
+    fn downgrades_want_block(&self) -> bool {
+        true
+    }
+
+    fn rebroadcast_interval_secs(&self) -> Option<u32> {
+        None
+    }
+}
+
+/// The engine of current kubo, as implemented in boxo.
+/// The wantlist of each peer is capped at `BOXO_MAX_WANTLIST_ENTRIES`, and a `WANT_HAVE` does not
+/// downgrade an existing `WANT_BLOCK`.
+/// Wants repeating an existing entry in incremental messages around the rebroadcast interval of
+/// `BOXO_REBROADCAST_INTERVAL_SECS` are marked as rebroadcasts.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BoxoSemantics;
+
+impl EngineSemantics for BoxoSemantics {
+    fn max_wantlist_entries(&self) -> Option<usize> {
+        Some(BOXO_MAX_WANTLIST_ENTRIES)
+    }
+
+    fn downgrades_want_block(&self) -> bool {
+        false
+    }
+
+    fn rebroadcast_interval_secs(&self) -> Option<u32> {
+        Some(BOXO_REBROADCAST_INTERVAL_SECS)
+    }
+}
+
+/// The context in which the entries of a message are applied to a ledger.
+struct ApplyContext<'a> {
+    /// The ID of the message.
+    msg_id: i64,
+    /// The semantics of the simulated engine.
+    semantics: &'a dyn EngineSemantics,
+    /// The CIDs as sent, keyed by normalized CID, if CID normalization is enabled.
+    sent_cids: Option<&'a HashMap<String, String>>,
+}
+
+impl ApplyContext<'_> {
+    /// Creates a ledger entry for the given wantlist entry of the message.
+    fn new_entry(
+        &self,
+        entry: &JSONWantlistEntry,
+        ts: chrono::DateTime<chrono::Utc>,
+    ) -> WantlistEntry {
+        let mut e = WantlistEntry::from_json_entry(entry, ts, self.msg_id);
+        e.sent_cid = self.sent_cids.and_then(|s| s.get(&e.cid).cloned());
+        e
+    }
+}
+
+/// A simulation of the BitSwap engine, following the semantics of the configured `EngineModel`.
+///
+/// The complete state, including the configuration, can be serialized, to resume the simulation
+/// later.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(from = "EngineSimulationState")]
 pub struct EngineSimulation {
     peers: HashMap<String, Ledger>,
     cfg: EngineSimulationConfig,
+    /// The peers currently WANTing each CID, derived from the ledgers.
+    /// This is not serialized, but rebuilt when deserializing.
+    #[serde(skip)]
+    peers_by_cid: HashMap<String, HashSet<String>>,
+}
+
+/// The serialized state of an `EngineSimulation`.
+#[derive(Deserialize)]
+struct EngineSimulationState {
+    peers: HashMap<String, Ledger>,
+    cfg: EngineSimulationConfig,
+}
+
+impl From<EngineSimulationState> for EngineSimulation {
+    fn from(state: EngineSimulationState) -> Self {
+        let mut peers_by_cid: HashMap<String, HashSet<String>> = HashMap::new();
+        for (peer_id, ledger) in state.peers.iter() {
+            for e in ledger.wanted_entries.iter() {
+                peers_by_cid
+                    .entry(e.cid.clone())
+                    .or_default()
+                    .insert(peer_id.clone());
+            }
+        }
+
+        EngineSimulation {
+            peers: state.peers,
+            cfg: state.cfg,
+            peers_by_cid,
+        }
+    }
 }
 
 impl EngineSimulation {
@@ -405,6 +1055,131 @@ impl EngineSimulation {
     pub fn num_ledgers(&self) -> usize {
         self.peers.len()
     }
+
+    /// Returns the configuration of this simulation, with sorted sliding windows.
+    pub fn config(&self) -> &EngineSimulationConfig {
+        &self.cfg
+    }
+
+    /// Returns the number of ledgers of peers we are currently connected to.
+    pub fn num_connected_ledgers(&self) -> usize {
+        self.peers
+            .values()
+            .filter(|ledger| ledger.connection_count > 0)
+            .count()
+    }
+
+    /// Returns the number of entries currently WANTed by the given peer.
+    pub fn num_wanted_entries(&self, peer: &str) -> usize {
+        self.peers
+            .get(peer)
+            .map(|ledger| ledger.wanted_entries.len())
+            .unwrap_or(0)
+    }
+
+    /// Returns the entries currently WANTed by the given peer, sorted by CID, if we have a ledger
+    /// for it.
+    pub fn wanted_entries(&self, peer: &str) -> Option<&[WantlistEntry]> {
+        self.peers
+            .get(peer)
+            .map(|ledger| ledger.wanted_entries.as_slice())
+    }
+
+    /// Returns the entries currently WANTed by the given peer in the order in which the peer task
+    /// queue of the simulated engine serves them, if we have a ledger for it.
+    pub fn task_queue(&self, peer: &str) -> Option<Vec<&WantlistEntry>> {
+        let semantics = self.cfg.model.semantics();
+        self.peers.get(peer).map(|ledger| {
+            let mut entries = ledger.wanted_entries.iter().collect::<Vec<_>>();
+            entries.sort_by(|a, b| semantics.task_queue_order(a, b));
+            entries
+        })
+    }
+
+    /// Returns the peers currently WANTing the given CID, together with their entries for it.
+    /// The CID is normalized first, if CID normalization is enabled.
+    /// This only looks at the ledgers of peers WANTing the CID, using an index.
+    pub fn peers_wanting(&self, cid: &str) -> Vec<(&str, &WantlistEntry)> {
+        let normalized_cid = self.cfg.cid_normalization.normalize(cid);
+        let cid = normalized_cid.as_deref().unwrap_or(cid);
+        self.peers_by_cid
+            .get(cid)
+            .into_iter()
+            .flatten()
+            .filter_map(|peer_id| {
+                let (peer_id, ledger) = self.peers.get_key_value(peer_id)?;
+                ledger
+                    .wanted_entries
+                    .binary_search_by(|e| e.cid.as_str().cmp(cid))
+                    .ok()
+                    .map(|i| (peer_id.as_str(), &ledger.wanted_entries[i]))
+            })
+            .collect()
+    }
+
+    /// Updates the index of peers WANTing each CID for the given CIDs of the given peer.
+    fn update_peers_by_cid<'a>(&mut self, peer: &str, cids: impl IntoIterator<Item = &'a str>) {
+        let wanted_entries = self
+            .peers
+            .get(peer)
+            .map(|ledger| ledger.wanted_entries.as_slice())
+            .unwrap_or_default();
+        for cid in cids {
+            let wanted = wanted_entries
+                .binary_search_by(|e| e.cid.as_str().cmp(cid))
+                .is_ok();
+            if wanted {
+                self.peers_by_cid
+                    .entry(cid.to_string())
+                    .or_default()
+                    .insert(peer.to_string());
+            } else if let Some(peers) = self.peers_by_cid.get_mut(cid) {
+                peers.remove(peer);
+                if peers.is_empty() {
+                    self.peers_by_cid.remove(cid);
+                }
+            }
+        }
+    }
+
+    /// Returns the number of entries currently WANTed by all peers.
+    pub fn num_wanted_entries_total(&self) -> usize {
+        self.peers
+            .values()
+            .map(|ledger| ledger.wanted_entries.len())
+            .sum()
+    }
+
+    /// Removes the ledgers of peers we got disconnected from before the given timestamp and did
+    /// not reconnect to since.
+    /// This bounds the memory used by long-running simulations.
+    /// Requests re-sent by these peers after they reconnect are not marked as reconnect
+    /// duplicates.
+    /// Returns the number of ledgers removed.
+    pub fn evict_disconnected_ledgers(
+        &mut self,
+        disconnected_before: chrono::DateTime<chrono::Utc>,
+    ) -> usize {
+        let evicted = self
+            .peers
+            .iter()
+            .filter(|(_, ledger)| {
+                ledger.connection_count == 0
+                    && ledger
+                        .disconnected_ts
+                        .is_some_and(|ts| ts < disconnected_before)
+            })
+            .map(|(peer_id, _)| peer_id.clone())
+            .collect::<Vec<_>>();
+        for peer_id in evicted.iter() {
+            let ledger = self.peers.remove(peer_id).unwrap();
+            self.update_peers_by_cid(
+                peer_id,
+                ledger.wanted_entries.iter().map(|e| e.cid.as_str()),
+            );
+        }
+        evicted.len()
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -412,6 +1187,12 @@ pub struct IngestResult {
     pub missing_ledger: bool,
     pub wantlist_entries: Option<Vec<CSVWantlistEntry>>,
     pub connection_event: Option<CSVConnectionEvent>,
+    /// Blocks and block presences of the message, matched to requests.
+    /// This is only set for messages that carry responses, i.e., converted events.
+    pub responses: Option<Vec<CSVResponse>>,
+    /// Lifetimes of requests which ended with the message, if enabled via
+    /// `EngineSimulationConfig::emit_request_lifetimes`.
+    pub request_lifetimes: Option<Vec<CSVRequestLifetime>>,
 }
 
 impl EngineSimulation {
@@ -426,9 +1207,30 @@ impl EngineSimulation {
         Ok(EngineSimulation {
             peers: HashMap::new(),
             cfg,
+            peers_by_cid: HashMap::new(),
         })
     }
 
+    /// Generates the lifetimes of all requests still outstanding at the end of the simulation.
+    pub fn generate_end_of_simulation_lifetimes(
+        &self,
+        ts: chrono::DateTime<chrono::Utc>,
+        msg_id: i64,
+    ) -> Vec<CSVRequestLifetime> {
+        self.peers
+            .iter()
+            .flat_map(|(peer_id, ledger)| {
+                CSVRequestLifetime::from_wantlist_entries(
+                    &ledger.wanted_entries,
+                    peer_id,
+                    ts,
+                    msg_id,
+                    CSV_REQUEST_END_REASON_END_OF_SIMULATION,
+                )
+            })
+            .collect()
+    }
+
     /// Generates end-of-simulation synthetic CANCEL entries.
     pub fn generate_end_of_simulation_entries(
         self,
@@ -440,10 +1242,9 @@ impl EngineSimulation {
         self.peers
             .into_iter()
             .map(|(peer_id, ledger)| {
-                ledger
-                    .wanted_entries
-                    .into_iter()
-                    .map(move |e| CSVWantlistEntry {
+                ledger.wanted_entries.into_iter().map(move |e| {
+                    let (cid, normalized_cid) = e.into_cids();
+                    CSVWantlistEntry {
                         message_id: msg_id,
                         message_type: CSV_MESSAGE_TYPE_SYNTHETIC,
                         timestamp_seconds: ts_secs,
@@ -452,12 +1253,18 @@ impl EngineSimulation {
                         address: "".to_string(),
                         priority: 0,
                         entry_type: CSV_ENTRY_TYPE_SYNTHETIC_CANCEL_END_OF_SIMULATION,
-                        cid: e.cid,
+                        cid,
+                        normalized_cid,
                         duplicate_status: CSV_DUPLICATE_STATUS_NO_DUP,
                         sliding_window_smallest_match: 0,
                         secs_since_earlier_message: 0,
                         upgrades_earlier_request: false,
-                    })
+                        origin_country: None,
+                        origin_asn: None,
+                        origin_as_organization: None,
+                        origin_city: None,
+                    }
+                })
             })
             .flatten()
             .collect()
@@ -473,17 +1280,34 @@ impl EngineSimulation {
         let ledger = self.peers.entry(msg.peer.clone()).or_insert_with(|| {
             debug!("received wantlist from {} ({:?}), but don't have a ledger for that peer. Starting empty one with one connection.", msg.peer, msg.address);
             missing_ledger = true;
-            Ledger { connection_count: 1, wanted_entries: Default::default(), wanted_entries_before_disconnect: Default::default(), connected_ts: Some(msg.timestamp.clone()) }
+            Ledger { connection_count: 1, wanted_entries: Default::default(), wanted_entries_before_disconnect: Default::default(), connected_ts: Some(msg.timestamp.clone()), disconnected_ts: None }
         });
         if ledger.connection_count == 0 {
             warn!("got wantlist entries from peer {}, but we are still disconnected from that peer (was previously connected). This is either an error in how IPFS notifies about connection events, or in how we ingest them.", msg.peer);
             ledger.connection_count = 1;
             ledger.connected_ts = Some(msg.timestamp.clone());
+            ledger.disconnected_ts = None;
         }
         assert!(ledger.connection_count > 0);
 
+        // Responses answer requests made before this message, so we match them before updating
+        // the ledger.
+        let responses = Self::match_responses(ledger, msg, msg_id, self.cfg.cid_normalization);
+
         let (mut full_wl_dups, mut full_wl_synth_cancels) = (None, None);
-        let (new_wants, new_cancels) = Self::split_wants_cancels(&entries);
+        // With CID normalization, the ledger is keyed on normalized CIDs.
+        // We remember the CIDs as sent, to report them in synthetic entries.
+        let normalized = Self::normalize_entries(self.cfg.cid_normalization, entries);
+        let (json_entries, sent_cids) = match normalized.as_ref() {
+            Some((normalized_entries, sent_cids)) => (normalized_entries, Some(sent_cids)),
+            None => (entries, None),
+        };
+        let (new_wants, new_cancels) = Self::split_wants_cancels(json_entries);
+        let ctx = ApplyContext {
+            msg_id,
+            semantics: self.cfg.model.semantics(),
+            sent_cids,
+        };
 
         // Compute entry time differences and upgrade statuses between the new message and the
         // existing ledger.
@@ -503,17 +1327,33 @@ impl EngineSimulation {
         );
 
         // Now update the ledger.
+        let mut request_lifetimes = Vec::new();
         match &msg.full_want_list {
             Some(full) => match full {
                 true => {
-                    let new_wants = new_wants
+                    // With CID normalization, a full wantlist can contain multiple entries for the
+                    // same CID, of which we keep the last one.
+                    let last_index = new_wants
+                        .iter()
+                        .enumerate()
+                        .map(|(i, w)| (w.cid.path.as_str(), i))
+                        .collect::<HashMap<_, _>>();
+                    let mut new_wants = new_wants
                         .iter()
-                        .map(|c| WantlistEntry {
-                            cid: c.cid.path.clone(),
-                            ts: msg.timestamp.clone(),
-                            want_type: WantType::from_json_entry(c),
-                        })
-                        .collect();
+                        .enumerate()
+                        .filter(|(i, w)| last_index[w.cid.path.as_str()] == *i)
+                        .take(ctx.semantics.max_wantlist_entries().unwrap_or(usize::MAX))
+                        .map(|(_, c)| ctx.new_entry(c, msg.timestamp))
+                        .collect::<Vec<_>>();
+                    // Requests for CIDs which are already wanted continue their lifetime.
+                    for e in new_wants.iter_mut() {
+                        if let Ok(i) = ledger
+                            .wanted_entries
+                            .binary_search_by(|old| old.cid.cmp(&e.cid))
+                        {
+                            e.continue_lifetime(&ledger.wanted_entries[i]);
+                        }
+                    }
                     let old_wants = mem::replace(&mut ledger.wanted_entries, new_wants);
                     ledger
                         .wanted_entries
@@ -522,6 +1362,20 @@ impl EngineSimulation {
                         !Self::check_ledger_for_duplicates(&ledger.wanted_entries),
                         "ledger contains duplicates"
                     );
+                    if self.cfg.emit_request_lifetimes {
+                        request_lifetimes = CSVRequestLifetime::from_wantlist_entries(
+                            old_wants.iter().filter(|old| {
+                                ledger
+                                    .wanted_entries
+                                    .binary_search_by(|e| e.cid.cmp(&old.cid))
+                                    .is_err()
+                            }),
+                            &msg.peer,
+                            msg.timestamp,
+                            msg_id,
+                            CSV_REQUEST_END_REASON_FULL_WANTLIST,
+                        );
+                    }
 
                     let (full_wl_dups_t, full_wl_synth_cancels_t) =
                         Self::get_duplicate_entries_and_synth_cancels_from_full_wantlist(
@@ -534,25 +1388,45 @@ impl EngineSimulation {
                     }
                 }
                 false => {
-                    Self::apply_new_entries(
+                    let canceled = Self::apply_new_entries(
                         &mut ledger.wanted_entries,
                         new_wants,
                         new_cancels,
                         &msg.peer,
                         msg.timestamp.clone(),
+                        &ctx,
                     )?;
+                    if self.cfg.emit_request_lifetimes {
+                        request_lifetimes = CSVRequestLifetime::from_wantlist_entries(
+                            &canceled,
+                            &msg.peer,
+                            msg.timestamp,
+                            msg_id,
+                            CSV_REQUEST_END_REASON_CANCEL,
+                        );
+                    }
                 }
             },
             None => {
                 if self.cfg.allow_empty_full_wantlist {
                     debug!("got empty full_want_list, assuming incremental.");
-                    Self::apply_new_entries(
+                    let canceled = Self::apply_new_entries(
                         &mut ledger.wanted_entries,
                         new_wants,
                         new_cancels,
                         &msg.peer,
                         msg.timestamp.clone(),
+                        &ctx,
                     )?;
+                    if self.cfg.emit_request_lifetimes {
+                        request_lifetimes = CSVRequestLifetime::from_wantlist_entries(
+                            &canceled,
+                            &msg.peer,
+                            msg.timestamp,
+                            msg_id,
+                            CSV_REQUEST_END_REASON_CANCEL,
+                        );
+                    }
                 } else {
                     error!("got empty full_want_list: {:?}", msg);
                     return Err(err_msg("got empty full_want_list, should be set"));
@@ -579,6 +1453,12 @@ impl EngineSimulation {
         });
         let mut entries = CSVWantlistEntry::from_json_message(msg.clone(), msg_id)
             .context("unable to convert entries to JSON")?;
+        if sent_cids.is_some() {
+            entries
+                .iter_mut()
+                .zip(json_entries.iter())
+                .for_each(|(e, ee)| e.normalized_cid = Some(ee.cid.path.clone()));
+        }
 
         // Mark duplicates in the generated entries.
         for entry in entries.iter_mut() {
@@ -589,7 +1469,9 @@ impl EngineSimulation {
             if let Some(full_wl_dups) = full_wl_dups.as_ref() {
                 if full_wl_dups
                     .iter()
-                    .find(|e| e.cid == entry.cid && e.want_type == WantType::from_csv_entry(entry))
+                    .find(|e| {
+                        e.cid == entry.cid_key() && e.want_type == WantType::from_csv_entry(entry)
+                    })
                     .is_some()
                 {
                     // This is a dup
@@ -599,7 +1481,9 @@ impl EngineSimulation {
             if let Some(reconnect_dups) = reconnect_dups.as_ref() {
                 if reconnect_dups
                     .iter()
-                    .find(|e| e.cid == entry.cid && e.want_type == WantType::from_csv_entry(entry))
+                    .find(|e| {
+                        e.cid == entry.cid_key() && e.want_type == WantType::from_csv_entry(entry)
+                    })
                     .is_some()
                 {
                     // This is a dup too
@@ -622,14 +1506,20 @@ impl EngineSimulation {
 
         // We now know they are the same length and same ordering, so we can zip 'em up and be
         // gucci.
+        let semantics = self.cfg.model.semantics();
         entries
             .iter_mut()
             .filter(|e| e.entry_type != CSV_ENTRY_TYPE_CANCEL)
             .zip(offsets_since_earlier_messages.into_iter())
             .for_each(|(e, (ee, offset))| {
-                assert_eq!(e.cid, ee.cid.path);
-                if let Some(offset) = offset {
+                assert_eq!(e.cid_key(), ee.cid.path);
+                if let Some((offset, earlier_was_resent)) = offset {
                     e.secs_since_earlier_message = offset;
+                    if e.message_type == CSV_MESSAGE_TYPE_INCREMENTAL
+                        && semantics.is_rebroadcast(offset, earlier_was_resent)
+                    {
+                        e.duplicate_status += CSV_DUPLICATE_STATUS_DUP_REBROADCAST;
+                    }
 
                     // Figure out if it matches any sliding window.
                     // cfg.sliding_window_lengths is sorted, so we take the first match that is
@@ -660,7 +1550,7 @@ impl EngineSimulation {
             .filter(|e| e.entry_type != CSV_ENTRY_TYPE_CANCEL)
             .zip(upgrade_statuses.into_iter())
             .for_each(|(e, (ee, upgraded))| {
-                assert_eq!(e.cid, ee.cid.path);
+                assert_eq!(e.cid_key(), ee.cid.path);
                 e.upgrades_earlier_request = upgraded;
             });
 
@@ -678,7 +1568,7 @@ impl EngineSimulation {
             .filter(|e| e.entry_type == CSV_ENTRY_TYPE_CANCEL)
             .zip(offsets_since_request_for_cancels.into_iter())
             .for_each(|(e, (ee, secs))| {
-                assert_eq!(e.cid, ee.cid.path);
+                assert_eq!(e.cid_key(), ee.cid.path);
                 if let Some(secs) = secs {
                     e.secs_since_earlier_message = secs;
                 }
@@ -693,9 +1583,78 @@ impl EngineSimulation {
             missing_ledger,
             wantlist_entries: Some(entries),
             connection_event: None,
+            responses,
+            request_lifetimes: self.cfg.emit_request_lifetimes.then_some(request_lifetimes),
         })
     }
 
+    /// Matches the blocks and block presences of a message to the entries of the ledger of the
+    /// sending peer.
+    /// Returns `None` if the message does not carry responses.
+    fn match_responses(
+        ledger: &Ledger,
+        msg: &JSONMessage,
+        msg_id: i64,
+        cid_normalization: CidNormalization,
+    ) -> Option<Vec<CSVResponse>> {
+        if msg.blocks.is_none() && msg.block_presences.is_none() {
+            return None;
+        }
+        let address = msg.address_string().unwrap_or_default();
+
+        let blocks = msg
+            .blocks
+            .iter()
+            .flatten()
+            .map(|b| (&b.path, CSV_RESPONSE_TYPE_BLOCK));
+        let presences = msg.block_presences.iter().flatten().map(|p| {
+            (
+                &p.cid.path,
+                match p.block_presence_type {
+                    BlockPresenceType::Have => CSV_RESPONSE_TYPE_HAVE,
+                    BlockPresenceType::DontHave => CSV_RESPONSE_TYPE_DONT_HAVE,
+                },
+            )
+        });
+
+        let responses = blocks
+            .chain(presences)
+            .map(|(cid, response_type)| {
+                let normalized_cid = cid_normalization.normalize(cid);
+                let key = normalized_cid.as_ref().unwrap_or(cid);
+                let request = ledger
+                    .wanted_entries
+                    .binary_search_by(|e| e.cid.cmp(key))
+                    .ok()
+                    .map(|i| &ledger.wanted_entries[i]);
+
+                CSVResponse {
+                    message_id: msg_id,
+                    timestamp_seconds: msg.timestamp.timestamp(),
+                    timestamp_subsec_milliseconds: msg.timestamp.timestamp_subsec_millis(),
+                    peer_id: msg.peer.clone(),
+                    address: address.clone(),
+                    response_type,
+                    cid: cid.clone(),
+                    normalized_cid,
+                    request_message_id: request.map(|r| r.message_id),
+                    request_entry_type: request.map(|r| r.csv_entry_type()),
+                    latency_ms: request
+                        .map(|r| (msg.timestamp - r.ts).num_milliseconds().max(0) as u64),
+                    unsolicited: request.is_none(),
+                    unrequested_dont_have: response_type == CSV_RESPONSE_TYPE_DONT_HAVE
+                        && request.map(|r| !r.send_dont_have).unwrap_or(false),
+                    origin_country: None,
+                    origin_asn: None,
+                    origin_as_organization: None,
+                    origin_city: None,
+                }
+            })
+            .collect();
+
+        Some(responses)
+    }
+
     fn calculate_secs_since_request_for_cancels(
         ledger: &Ledger,
         new_cancels: &Vec<&JSONWantlistEntry>,
@@ -757,12 +1716,14 @@ impl EngineSimulation {
             .collect()
     }
 
+    /// Returns, for each new entry, the seconds since the earlier request for the same CID and
+    /// want type, and whether that earlier request was itself a resend, if there is one.
     fn get_secs_until_earlier_message_with_same_cid_and_want_type(
         ledger: &Ledger,
         msg_ts: chrono::DateTime<chrono::Utc>,
         new_entries: &Vec<&JSONWantlistEntry>,
         new_cancels: &Vec<&JSONWantlistEntry>,
-    ) -> Vec<(JSONWantlistEntry, Option<u32>)> {
+    ) -> Vec<(JSONWantlistEntry, Option<(u32, bool)>)> {
         new_entries
             .iter()
             .cloned()
@@ -782,9 +1743,12 @@ impl EngineSimulation {
                     let diff = msg_ts - existing.ts;
                     if diff.num_seconds() == 0 {
                         // Pathological case of less than one second since last message.
-                        (e.clone(), Some(1))
+                        (e.clone(), Some((1, existing.was_resent())))
                     } else {
-                        (e.clone(), Some(diff.num_seconds() as u32))
+                        (
+                            e.clone(),
+                            Some((diff.num_seconds() as u32, existing.was_resent())),
+                        )
                     }
                 } else {
                     (e.clone(), None)
@@ -857,7 +1821,28 @@ impl EngineSimulation {
 
     fn ingest_connection_event(&mut self, msg: &JSONMessage, msg_id: i64) -> Result<IngestResult> {
         debug!("ingesting connection event {:?}", msg);
+        let derived_msg;
+        let msg = match msg.connect_event_peer_found {
+            // Traces of the modified Go implementation always contain this, so we leave them
+            // alone and fail below if it is missing.
+            Some(_) => msg,
+            None if !msg.is_converted() => msg,
+            None => {
+                // We consider the peer found if we have a connection to it.
+                let found = self
+                    .peers
+                    .get(&msg.peer)
+                    .map(|ledger| ledger.connection_count > 0)
+                    .unwrap_or(false);
+                derived_msg = JSONMessage {
+                    connect_event_peer_found: Some(found),
+                    ..msg.clone()
+                };
+                &derived_msg
+            }
+        };
         let mut missing_ledger = false;
+        let mut request_lifetimes = Vec::new();
         match &msg.peer_disconnected {
             Some(disconnected) => {
                 let found = msg.connect_event_peer_found.ok_or_else(|| {
@@ -876,7 +1861,7 @@ impl EngineSimulation {
                         // decrement the counter right away.
                         debug!("creating new ledger with one connection for peer {} since we got a disconnection event",msg.peer);
                         missing_ledger = true;
-                        Ledger { connection_count: 1, wanted_entries: Default::default(), wanted_entries_before_disconnect: Default::default(), connected_ts: Some(msg.timestamp.clone()) }
+                        Ledger { connection_count: 1, wanted_entries: Default::default(), wanted_entries_before_disconnect: Default::default(), connected_ts: Some(msg.timestamp.clone()), disconnected_ts: None }
                     });
                     debug!("working on ledger {:?}", ledger);
 
@@ -891,6 +1876,7 @@ impl EngineSimulation {
                     }
 
                     if ledger.connection_count == 0 {
+                        ledger.disconnected_ts = Some(msg.timestamp);
                         if !ledger.wanted_entries.is_empty() {
                             debug!("found wanted entries, generating synthetic CANCELs");
                             ledger.wanted_entries_before_disconnect =
@@ -916,6 +1902,16 @@ impl EngineSimulation {
                                     CSVConnectionEvent::from_json_message(msg.clone(), msg_id)
                                         .unwrap(),
                                 ),
+                                responses: None,
+                                request_lifetimes: self.cfg.emit_request_lifetimes.then(|| {
+                                    CSVRequestLifetime::from_wantlist_entries(
+                                        ledger.wanted_entries_before_disconnect.iter().flatten(),
+                                        &msg.peer,
+                                        msg.timestamp,
+                                        msg_id,
+                                        CSV_REQUEST_END_REASON_DISCONNECT,
+                                    )
+                                }),
                             });
                         }
                     }
@@ -956,6 +1952,15 @@ impl EngineSimulation {
                                 // about found==false and didn't report an earlier disconnect.
                                 // That means we need to clear out our ledger.
                                 let entries = mem::take(&mut ledger.wanted_entries);
+                                if self.cfg.emit_request_lifetimes {
+                                    request_lifetimes = CSVRequestLifetime::from_wantlist_entries(
+                                        &entries,
+                                        &msg.peer,
+                                        msg.timestamp,
+                                        msg_id,
+                                        CSV_REQUEST_END_REASON_DISCONNECT,
+                                    );
+                                }
                                 ledger.wanted_entries_before_disconnect = Some(entries);
                             }
                         }
@@ -973,11 +1978,13 @@ impl EngineSimulation {
                             wanted_entries: Default::default(),
                             wanted_entries_before_disconnect: Default::default(),
                             connected_ts: Some(msg.timestamp.clone()),
+                            disconnected_ts: None,
                         }
                     });
                     debug!("working with ledger {:?}", ledger);
 
                     ledger.connection_count += 1;
+                    ledger.disconnected_ts = None;
                 }
             }
             None => {
@@ -1001,11 +2008,45 @@ impl EngineSimulation {
             connection_event: Some(
                 CSVConnectionEvent::from_json_message(msg.clone(), msg_id).unwrap(),
             ),
+            responses: None,
+            request_lifetimes: self.cfg.emit_request_lifetimes.then_some(request_lifetimes),
         })
     }
 
     /// Ingests a new message, advancing the simulation and emitting entries.
     pub fn ingest(&mut self, msg: &JSONMessage, msg_id: i64) -> Result<IngestResult> {
+        // Incremental wantlists only change the entries for their CIDs, everything else can
+        // replace the whole ledger.
+        let incremental = msg.received_entries.is_some() && msg.full_want_list != Some(true);
+        let mut changed_cids = match (incremental, self.peers.get(&msg.peer)) {
+            (false, Some(ledger)) => ledger
+                .wanted_entries
+                .iter()
+                .map(|e| e.cid.clone())
+                .collect::<Vec<_>>(),
+            _ => Vec::new(),
+        };
+
+        let res = self.ingest_message(msg, msg_id);
+
+        match (incremental, self.peers.get(&msg.peer)) {
+            (true, _) => changed_cids.extend(msg.received_entries.iter().flatten().map(|e| {
+                self.cfg
+                    .cid_normalization
+                    .normalize(&e.cid.path)
+                    .unwrap_or_else(|| e.cid.path.clone())
+            })),
+            (false, Some(ledger)) => {
+                changed_cids.extend(ledger.wanted_entries.iter().map(|e| e.cid.clone()))
+            }
+            (false, None) => {}
+        }
+        self.update_peers_by_cid(&msg.peer, changed_cids.iter().map(|c| c.as_str()));
+
+        res
+    }
+
+    fn ingest_message(&mut self, msg: &JSONMessage, msg_id: i64) -> Result<IngestResult> {
         match &msg.received_entries {
             Some(entries) => {
                 // This is a wantlist message.
@@ -1036,6 +2077,34 @@ impl EngineSimulation {
         }
     }
 
+    /// Normalizes the CIDs of the given entries, if enabled.
+    /// Returns the normalized entries and a mapping of normalized CIDs to the CIDs as sent.
+    fn normalize_entries(
+        cid_normalization: CidNormalization,
+        entries: &[JSONWantlistEntry],
+    ) -> Option<(Vec<JSONWantlistEntry>, HashMap<String, String>)> {
+        if !cid_normalization.is_enabled() {
+            return None;
+        }
+
+        let mut sent_cids = HashMap::new();
+        let normalized_entries = entries
+            .iter()
+            .map(|e| {
+                let normalized_cid = cid_normalization.normalize(&e.cid.path).unwrap();
+                sent_cids.insert(normalized_cid.clone(), e.cid.path.clone());
+                JSONWantlistEntry {
+                    cid: JsonCID {
+                        path: normalized_cid,
+                    },
+                    ..e.clone()
+                }
+            })
+            .collect();
+
+        Some((normalized_entries, sent_cids))
+    }
+
     fn split_wants_cancels(
         new_entries: &[JSONWantlistEntry],
     ) -> (Vec<&JSONWantlistEntry>, Vec<&JSONWantlistEntry>) {
@@ -1065,10 +2134,12 @@ impl EngineSimulation {
         cancels: Vec<&JSONWantlistEntry>,
         peer: &str,
         ts: chrono::DateTime<chrono::Utc>,
-    ) -> Result<()> {
+        ctx: &ApplyContext,
+    ) -> Result<Vec<WantlistEntry>> {
+        let mut canceled = Vec::new();
         for cancel in cancels {
             if let Ok(i) = current_entries.binary_search_by(|e| e.cid.cmp(&cancel.cid.path)) {
-                current_entries.remove(i);
+                canceled.push(current_entries.remove(i));
             } else {
                 // Not found.
                 warn!(
@@ -1081,18 +2152,34 @@ impl EngineSimulation {
         for want in wants {
             match current_entries.binary_search_by(|e| e.cid.cmp(&want.cid.path)) {
                 Ok(i) => {
-                    // we already have the entry, we need to update its timestamp and want type.
-                    current_entries[i].ts = ts.clone();
-                    current_entries[i].want_type = WantType::from_json_entry(want);
+                    if !ctx.semantics.downgrades_want_block()
+                        && current_entries[i].want_type == WantType::Block
+                        && WantType::from_json_entry(want) == WantType::Have
+                    {
+                        // The task in the peer task queue still takes the higher priority.
+                        current_entries[i].num_resends += 1;
+                        current_entries[i].priority =
+                            current_entries[i].priority.max(want.priority);
+                        continue;
+                    }
+                    // we already have the entry, we need to update its timestamp, want type, and
+                    // the message it was requested in.
+                    let mut entry = ctx.new_entry(want, ts);
+                    entry.continue_lifetime(&current_entries[i]);
+                    current_entries[i] = entry;
+                }
+                Err(i) => {
+                    if let Some(max_entries) = ctx.semantics.max_wantlist_entries() {
+                        if current_entries.len() >= max_entries {
+                            debug!(
+                                "wantlist of peer {} is full, ignoring WANT for CID {}",
+                                peer, want.cid.path
+                            );
+                            continue;
+                        }
+                    }
+                    current_entries.insert(i, ctx.new_entry(want, ts))
                 }
-                Err(i) => current_entries.insert(
-                    i,
-                    WantlistEntry {
-                        cid: want.cid.path.clone(),
-                        ts: ts.clone(),
-                        want_type: WantType::from_json_entry(want),
-                    },
-                ),
             }
         }
 
@@ -1101,6 +2188,461 @@ impl EngineSimulation {
             "ledger contains duplicates"
         );
 
-        Ok(())
+        Ok(canceled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::PushedEvent;
+
+    const EVENTS: &str = r#"{"timestamp":"2024-01-01T00:00:00Z","peer":"QmPeer","bitswap_message":{"wantlist_entries":[{"priority":1,"cancel":false,"send_dont_have":false,"cid":{"/":"QmA"},"want_type":0}],"full_wantlist":false,"blocks":[],"block_presences":[],"connected_addresses":["/ip4/1.2.3.4/tcp/4001"]}}
+{"timestamp":"2024-01-01T00:00:01.5Z","peer":"QmPeer","bitswap_message":{"wantlist_entries":[],"full_wantlist":false,"blocks":[{"/":"QmA"}],"block_presences":[{"cid":{"/":"QmA"},"block_presence_type":1},{"cid":{"/":"QmB"},"block_presence_type":0}],"connected_addresses":["/ip4/1.2.3.4/tcp/4001"]}}"#;
+
+    #[test]
+    fn matches_responses_to_requests() {
+        let mut sim = EngineSimulation::new(EngineSimulationConfig::default()).unwrap();
+        let results = EVENTS
+            .lines()
+            .enumerate()
+            .map(|(i, l)| {
+                let event: PushedEvent = serde_json::from_str(l).unwrap();
+                sim.ingest(&event.into(), i as i64 + 1).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        assert!(results[0].responses.as_ref().unwrap().is_empty());
+        let responses = results[1].responses.as_ref().unwrap();
+        assert_eq!(responses.len(), 3);
+
+        let block = &responses[0];
+        assert_eq!(block.response_type, CSV_RESPONSE_TYPE_BLOCK);
+        assert_eq!(block.request_message_id, Some(1));
+        assert_eq!(block.request_entry_type, Some(CSV_ENTRY_TYPE_WANT_BLOCK));
+        assert_eq!(block.latency_ms, Some(1500));
+        assert!(!block.unsolicited);
+        assert!(!block.unrequested_dont_have);
+
+        let dont_have = &responses[1];
+        assert_eq!(dont_have.response_type, CSV_RESPONSE_TYPE_DONT_HAVE);
+        assert!(!dont_have.unsolicited);
+        assert!(dont_have.unrequested_dont_have);
+
+        let have = &responses[2];
+        assert_eq!(have.response_type, CSV_RESPONSE_TYPE_HAVE);
+        assert_eq!(have.request_message_id, None);
+        assert_eq!(have.latency_ms, None);
+        assert!(have.unsolicited);
+    }
+
+    fn want(secs: i64, want_type: JSONWantType) -> JSONMessage {
+        JSONMessage {
+            timestamp: chrono::DateTime::from_timestamp(secs, 0).unwrap(),
+            peer: "QmPeer".to_string(),
+            address: None,
+            received_entries: Some(vec![JSONWantlistEntry {
+                priority: 1,
+                cancel: false,
+                send_dont_have: false,
+                cid: JsonCID {
+                    path: "QmA".to_string(),
+                },
+                want_type,
+            }]),
+            full_want_list: Some(false),
+            peer_connected: None,
+            peer_disconnected: None,
+            connect_event_peer_found: None,
+            connected_addresses: None,
+            blocks: None,
+            block_presences: None,
+        }
+    }
+
+    #[test]
+    fn follows_engine_model() {
+        let msgs = [
+            want(0, JSONWantType::Block),
+            want(10, JSONWantType::Have),
+            want(20, JSONWantType::Block),
+        ];
+        let simulate = |model| {
+            let mut sim = EngineSimulation::new(EngineSimulationConfig {
+                model,
+                ..Default::default()
+            })
+            .unwrap();
+            msgs.iter()
+                .enumerate()
+                .map(|(i, m)| sim.ingest(m, i as i64).unwrap().wantlist_entries.unwrap()[0].clone())
+                .collect::<Vec<_>>()
+        };
+
+        // The WANT_HAVE downgrades the entry, which the following WANT_BLOCK upgrades again.
+        let entries = simulate(EngineModel::GoIpfsV05);
+        assert!(entries[2].upgrades_earlier_request);
+        assert_eq!(entries[2].duplicate_status, CSV_DUPLICATE_STATUS_NO_DUP);
+
+        // The entry stays a WANT_BLOCK, which is rebroadcast.
+        let entries = simulate(EngineModel::Boxo);
+        assert!(!entries[2].upgrades_earlier_request);
+        assert_eq!(
+            entries[2].duplicate_status,
+            CSV_DUPLICATE_STATUS_DUP_REBROADCAST
+        );
+        assert_eq!(entries[2].secs_since_earlier_message, 20);
+    }
+
+    #[test]
+    fn marks_rebroadcasts_around_interval() {
+        let mut sim = EngineSimulation::new(EngineSimulationConfig {
+            model: EngineModel::Boxo,
+            ..Default::default()
+        })
+        .unwrap();
+        let statuses = [0, 5, 36, 50, 80]
+            .iter()
+            .enumerate()
+            .map(|(i, secs)| {
+                sim.ingest(&want(*secs, JSONWantType::Block), i as i64)
+                    .unwrap()
+                    .wantlist_entries
+                    .unwrap()[0]
+                    .duplicate_status
+                    & CSV_DUPLICATE_STATUS_DUP_REBROADCAST
+            })
+            .collect::<Vec<_>>();
+
+        // The first rebroadcast follows within one interval, later ones one interval apart.
+        assert_eq!(
+            statuses,
+            vec![
+                0,
+                CSV_DUPLICATE_STATUS_DUP_REBROADCAST,
+                CSV_DUPLICATE_STATUS_DUP_REBROADCAST,
+                0,
+                CSV_DUPLICATE_STATUS_DUP_REBROADCAST,
+            ]
+        );
+    }
+
+    #[test]
+    fn orders_task_queue_by_priority() {
+        let msg = |secs, cid: &str, priority| JSONMessage {
+            received_entries: Some(vec![JSONWantlistEntry {
+                priority,
+                cid: JsonCID {
+                    path: cid.to_string(),
+                },
+                ..want(secs, JSONWantType::Block).received_entries.unwrap()[0].clone()
+            }]),
+            ..want(secs, JSONWantType::Block)
+        };
+        let mut sim = EngineSimulation::new(EngineSimulationConfig::default()).unwrap();
+        let msgs = [
+            msg(0, "QmA", 1),
+            msg(1, "QmB", 1),
+            msg(2, "QmC", 5),
+            msg(3, "QmA", 3),
+            msg(4, "QmB", 0),
+        ];
+        for (i, m) in msgs.iter().enumerate() {
+            sim.ingest(m, i as i64).unwrap();
+        }
+
+        // Repeated wants keep their place and the higher priority.
+        let queue = sim
+            .task_queue("QmPeer")
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.cid.as_str(), e.priority()))
+            .collect::<Vec<_>>();
+        assert_eq!(queue, vec![("QmC", 5), ("QmA", 3), ("QmB", 1)]);
+        assert!(sim.task_queue("QmOther").is_none());
+    }
+
+    #[test]
+    fn tracks_request_lifetimes() {
+        let mut sim = EngineSimulation::new(EngineSimulationConfig {
+            emit_request_lifetimes: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let mut cancel = want(30, JSONWantType::Block);
+        cancel.received_entries.as_mut().unwrap()[0].cancel = true;
+        let msgs = [
+            want(0, JSONWantType::Have),
+            want(10, JSONWantType::Block),
+            cancel,
+            want(40, JSONWantType::Have),
+        ];
+        let lifetimes = msgs
+            .iter()
+            .enumerate()
+            .flat_map(|(i, m)| sim.ingest(m, i as i64).unwrap().request_lifetimes.unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(lifetimes.len(), 1);
+        let l = &lifetimes[0];
+        assert_eq!(l.start_message_id, 0);
+        assert_eq!(l.start_entry_type, CSV_ENTRY_TYPE_WANT_HAVE);
+        assert_eq!(l.end_entry_type, CSV_ENTRY_TYPE_WANT_BLOCK);
+        assert!(l.upgraded);
+        assert_eq!(l.num_resends, 1);
+        assert_eq!(l.end_message_id, 2);
+        assert_eq!(l.end_reason, CSV_REQUEST_END_REASON_CANCEL);
+        assert_eq!(l.duration_ms, 30_000);
+
+        let end = chrono::DateTime::from_timestamp(50, 0).unwrap();
+        let lifetimes = sim.generate_end_of_simulation_lifetimes(end, 4);
+        assert_eq!(lifetimes.len(), 1);
+        assert_eq!(lifetimes[0].start_message_id, 3);
+        assert_eq!(
+            lifetimes[0].end_reason,
+            CSV_REQUEST_END_REASON_END_OF_SIMULATION
+        );
+        assert_eq!(lifetimes[0].duration_ms, 10_000);
+    }
+
+    #[test]
+    fn normalizes_cids() {
+        let cid_v0 = "QmUNLLsPACCz1vLxQVkXqqLX5R1X345qqfHbsf67hvA3Nn";
+        let cid_v1 = "bafybeiczsscdsbs7ffqz55asqdf3smv6klcw3gofszvwlyarci47bgf354";
+        let mut upgrade = want(10, JSONWantType::Block);
+        upgrade.received_entries.as_mut().unwrap()[0].cid.path = cid_v1.to_string();
+        let mut msgs = [want(0, JSONWantType::Have), upgrade];
+        msgs[0].received_entries.as_mut().unwrap()[0].cid.path = cid_v0.to_string();
+
+        let simulate = |cid_normalization| {
+            let mut sim = EngineSimulation::new(EngineSimulationConfig {
+                cid_normalization,
+                ..Default::default()
+            })
+            .unwrap();
+            msgs.iter()
+                .enumerate()
+                .map(|(i, m)| sim.ingest(m, i as i64).unwrap().wantlist_entries.unwrap()[0].clone())
+                .collect::<Vec<_>>()
+        };
+
+        let entries = simulate(CidNormalization::None);
+        assert!(!entries[1].upgrades_earlier_request);
+        assert_eq!(entries[1].normalized_cid, None);
+
+        let entries = simulate(CidNormalization::CodecAndMultihash);
+        assert!(entries[1].upgrades_earlier_request);
+        assert_eq!(entries[0].cid, cid_v0);
+        assert_eq!(entries[0].normalized_cid.as_deref(), Some(cid_v1));
+    }
+
+    #[test]
+    fn dedupes_normalized_full_wantlist() {
+        let cid_v0 = "QmUNLLsPACCz1vLxQVkXqqLX5R1X345qqfHbsf67hvA3Nn";
+        let cid_v1 = "bafybeiczsscdsbs7ffqz55asqdf3smv6klcw3gofszvwlyarci47bgf354";
+        let mut full = want(0, JSONWantType::Have);
+        full.full_want_list = Some(true);
+        let mut entries = full.received_entries.take().unwrap();
+        entries[0].cid.path = cid_v0.to_string();
+        entries.push(JSONWantlistEntry {
+            cid: JsonCID {
+                path: cid_v1.to_string(),
+            },
+            want_type: JSONWantType::Block,
+            ..entries[0].clone()
+        });
+        full.received_entries = Some(entries);
+
+        let mut sim = EngineSimulation::new(EngineSimulationConfig {
+            cid_normalization: CidNormalization::CodecAndMultihash,
+            ..Default::default()
+        })
+        .unwrap();
+        let res = sim.ingest(&full, 0).unwrap();
+        assert_eq!(res.wantlist_entries.unwrap().len(), 2);
+
+        // The last entry for the CID is kept.
+        let ledger = &sim.peers["QmPeer"].wanted_entries;
+        assert_eq!(ledger.len(), 1);
+        assert_eq!(ledger[0].cid, cid_v1);
+        assert_eq!(ledger[0].want_type, WantType::Block);
+        assert_eq!(ledger[0].sent_cid.as_deref(), Some(cid_v1));
+    }
+
+    /// Creates a connection event as converted from a `PushedEvent`.
+    fn connection_event(secs: i64, connected: bool) -> JSONMessage {
+        JSONMessage {
+            received_entries: None,
+            full_want_list: None,
+            peer_connected: Some(connected),
+            peer_disconnected: Some(!connected),
+            connected_addresses: Some(vec!["/ip4/1.2.3.4/tcp/4001".to_string()]),
+            ..want(secs, JSONWantType::Block)
+        }
+    }
+
+    #[test]
+    fn derives_peer_found_only_for_converted_events() {
+        let mut sim = EngineSimulation::new(EngineSimulationConfig::default()).unwrap();
+        sim.ingest(&connection_event(0, true), 0).unwrap();
+        sim.ingest(&connection_event(1, false), 1).unwrap();
+        assert_eq!(sim.num_connected_ledgers(), 0);
+
+        let legacy = JSONMessage {
+            connected_addresses: None,
+            ..connection_event(2, true)
+        };
+        assert!(sim.ingest(&legacy, 2).is_err());
+    }
+
+    #[test]
+    fn evicts_disconnected_ledgers() {
+        let mut sim = EngineSimulation::new(EngineSimulationConfig::default()).unwrap();
+        let msgs = [
+            connection_event(0, true),
+            want(1, JSONWantType::Have),
+            connection_event(10, false),
+        ];
+        for (i, m) in msgs.iter().enumerate() {
+            sim.ingest(m, i as i64).unwrap();
+        }
+        assert_eq!(sim.num_ledgers(), 1);
+        assert_eq!(sim.num_connected_ledgers(), 0);
+        assert_eq!(sim.num_wanted_entries("QmPeer"), 0);
+
+        let ts = |secs| chrono::DateTime::from_timestamp(secs, 0).unwrap();
+        assert_eq!(sim.evict_disconnected_ledgers(ts(10)), 0);
+        assert_eq!(sim.evict_disconnected_ledgers(ts(11)), 1);
+        assert_eq!(sim.num_ledgers(), 0);
+
+        // The reconnected peer is not evicted.
+        sim.ingest(&connection_event(20, true), 3).unwrap();
+        sim.ingest(&want(21, JSONWantType::Have), 4).unwrap();
+        assert_eq!(sim.evict_disconnected_ledgers(ts(30)), 0);
+        assert_eq!(sim.num_connected_ledgers(), 1);
+        assert_eq!(sim.num_wanted_entries_total(), 1);
+
+        let wanted = sim.wanted_entries("QmPeer").unwrap();
+        assert_eq!(wanted.len(), 1);
+        assert_eq!(wanted[0].sent_cid(), "QmA");
+        assert_eq!(wanted[0].first_requested_at(), ts(21));
+        assert_eq!(sim.peers_wanting("QmA").len(), 1);
+        assert!(sim.peers_wanting("QmB").is_empty());
+    }
+
+    #[test]
+    fn indexes_peers_by_cid() {
+        let msg = |secs, peer: &str, full, entries: &[(&str, bool)]| JSONMessage {
+            peer: peer.to_string(),
+            full_want_list: Some(full),
+            received_entries: Some(
+                entries
+                    .iter()
+                    .map(|(cid, cancel)| JSONWantlistEntry {
+                        cancel: *cancel,
+                        cid: JsonCID {
+                            path: cid.to_string(),
+                        },
+                        ..want(secs, JSONWantType::Have).received_entries.unwrap()[0].clone()
+                    })
+                    .collect(),
+            ),
+            ..want(secs, JSONWantType::Have)
+        };
+        let peers_wanting = |sim: &EngineSimulation, cid| {
+            let mut peers = sim
+                .peers_wanting(cid)
+                .into_iter()
+                .map(|(p, _)| p.to_string())
+                .collect::<Vec<_>>();
+            peers.sort();
+            peers
+        };
+        // The index must agree with the ledgers at all times.
+        let check = |sim: &EngineSimulation| {
+            for cid in ["QmA", "QmB", "QmC"] {
+                let mut expected = sim
+                    .peers
+                    .iter()
+                    .filter(|(_, l)| l.wanted_entries.iter().any(|e| e.cid == cid))
+                    .map(|(p, _)| p.clone())
+                    .collect::<Vec<_>>();
+                expected.sort();
+                assert_eq!(peers_wanting(sim, cid), expected, "peers wanting {}", cid);
+            }
+        };
+
+        let mut sim = EngineSimulation::new(EngineSimulationConfig::default()).unwrap();
+        let msgs = [
+            msg(0, "QmPeer1", false, &[("QmA", false), ("QmB", false)]),
+            msg(1, "QmPeer2", false, &[("QmA", false)]),
+            msg(2, "QmPeer1", false, &[("QmA", true)]),
+            msg(3, "QmPeer2", true, &[("QmC", false)]),
+            JSONMessage {
+                peer: "QmPeer1".to_string(),
+                ..connection_event(4, false)
+            },
+        ];
+        for (i, m) in msgs.iter().enumerate() {
+            sim.ingest(m, i as i64).unwrap();
+            check(&sim);
+        }
+        assert!(peers_wanting(&sim, "QmA").is_empty());
+        assert!(peers_wanting(&sim, "QmB").is_empty());
+        assert_eq!(peers_wanting(&sim, "QmC"), vec!["QmPeer2"]);
+
+        // The index is rebuilt from serialized state.
+        let restored: EngineSimulation =
+            serde_json::from_str(&serde_json::to_string(&sim).unwrap()).unwrap();
+        check(&restored);
+        assert_eq!(peers_wanting(&restored, "QmC"), vec!["QmPeer2"]);
+
+        // Evicted ledgers are removed from the index.
+        sim.ingest(&msg(5, "QmPeer3", false, &[("QmC", false)]), 5)
+            .unwrap();
+        sim.ingest(
+            &JSONMessage {
+                peer: "QmPeer2".to_string(),
+                ..connection_event(6, false)
+            },
+            6,
+        )
+        .unwrap();
+        sim.evict_disconnected_ledgers(chrono::DateTime::from_timestamp(10, 0).unwrap());
+        check(&sim);
+        assert_eq!(peers_wanting(&sim, "QmC"), vec!["QmPeer3"]);
+    }
+
+    #[test]
+    fn normalized_cid_is_last_column() {
+        let mut sim = EngineSimulation::new(EngineSimulationConfig::default()).unwrap();
+        let entry = sim
+            .ingest(&want(0, JSONWantType::Have), 0)
+            .unwrap()
+            .wantlist_entries
+            .unwrap()[0]
+            .clone();
+
+        let mut w = csv::Writer::from_writer(vec![]);
+        w.serialize(&entry).unwrap();
+        let out = String::from_utf8(w.into_inner().unwrap()).unwrap();
+        let header = out.lines().next().unwrap().split(',').collect::<Vec<_>>();
+        let columns = CSVWantlistEntry::columns();
+        assert_eq!(header, columns.iter().map(|c| c.name).collect::<Vec<_>>());
+        assert_eq!(header.last(), Some(&"normalized_cid"));
+
+        // Files written before the column was added can still be read.
+        let old = out
+            .lines()
+            .map(|l| l.rsplit_once(',').unwrap().0)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let read = csv::Reader::from_reader(old.as_bytes())
+            .deserialize::<CSVWantlistEntry>()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(read.cid, entry.cid);
+        assert_eq!(read.normalized_cid, None);
     }
 }
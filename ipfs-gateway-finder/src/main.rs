@@ -6,8 +6,10 @@ use failure::{err_msg, ResultExt};
 use futures_util::StreamExt;
 use ipfs_api_backend_hyper::{IpfsApi, IpfsClient, TryFromUri};
 use ipfs_monitoring_plugin_client::monitoring::{
-    EventType, MonitoringClient, PushedEvent, RoutingKeyInformation,
+    EventType, MonitoringClient, MonitoringClientOptions, MonitoringItem, PushedEvent,
+    ReconnectConfig, RoutingKeyInformation,
 };
+use ipfs_monitoring_plugin_client::source::MonitoringSource;
 use ipfs_resolver_common::wantlist::JSONWantlistEntry;
 use ipfs_resolver_common::{logging, Result};
 use rand::{Rng, SeedableRng};
@@ -246,21 +248,67 @@ async fn do_probing() -> Result<()> {
 
     // Start listening for bitswap messages
     debug!("connecting to AMQP server at {}...", amqp_server_address);
-    let amqp_client = MonitoringClient::new(
+    let amqp_client = MonitoringClient::new_with_options(
         amqp_server_address,
         &vec![RoutingKeyInformation::BitswapMessages {
             monitor_name: monitor_name.to_string(),
         }],
+        MonitoringClientOptions {
+            reconnect: Some(ReconnectConfig::default()),
+            skip_undecodable: true,
+            ..Default::default()
+        },
     )
     .await
     .context("unable to connect to AMQP server")?;
     info!("connected to AMQP server");
 
+    probe_gateways(
+        gateway_states.clone(),
+        cids,
+        amqp_client,
+        num_http_tries,
+        http_timeout_secs,
+        Duration::from_secs(120),
+    )
+    .await?;
+
+    // Remove data from IPFS.
+    debug!("removing data from monitoring IPFS node...");
+    cleanup_ipfs(&ipfs_client, gateway_states.clone())
+        .await
+        .context(
+            "unable to remove data from monitoring IPFS node. Probably needs manual cleanup",
+        )?;
+    info!("removed data from monitoring IPFS node");
+
+    // Print results
+    info!("printing results..");
+    if produce_csv {
+        print_csv(gateway_states).await?;
+    } else {
+        print_json(gateway_states).await?;
+    }
+
+    Ok(())
+}
+
+/// Probes the gateways via HTTP while watching the given source for Bitswap requests for their
+/// CIDs.
+/// Waits for the given grace period after HTTP probing is done, to catch late Bitswap messages.
+async fn probe_gateways<S: MonitoringSource + 'static>(
+    gateway_states: Arc<HashMap<String, Mutex<ProbingState>>>,
+    cids: HashSet<String>,
+    monitoring_source: S,
+    num_http_tries: u32,
+    http_timeout_secs: u32,
+    grace_period: Duration,
+) -> Result<()> {
     let (monitoring_ready_tx, monitoring_ready_rx) = tokio::sync::oneshot::channel();
     let monitoring_client = Monitor::monitor_bitswap(
         gateway_states.clone(),
         cids,
-        amqp_client,
+        monitoring_source,
         monitoring_ready_tx,
     )
     .await
@@ -290,7 +338,7 @@ async fn do_probing() -> Result<()> {
     }
 
     info!("all HTTP workers are done or timed out, waiting some more time for bitswap messages...");
-    tokio::time::sleep(Duration::from_secs(120)).await;
+    tokio::time::sleep(grace_period).await;
 
     debug!("shutting down Bitswap monitoring...");
     monitoring_client
@@ -299,23 +347,6 @@ async fn do_probing() -> Result<()> {
         .context("unable to cleanly shutdown Bitswap monitoring -- did the connection die?")?;
     info!("shut down Bitswap monitoring");
 
-    // Remove data from IPFS.
-    debug!("removing data from monitoring IPFS node...");
-    cleanup_ipfs(&ipfs_client, gateway_states.clone())
-        .await
-        .context(
-            "unable to remove data from monitoring IPFS node. Probably needs manual cleanup",
-        )?;
-    info!("removed data from monitoring IPFS node");
-
-    // Print results
-    info!("printing results..");
-    if produce_csv {
-        print_csv(gateway_states).await?;
-    } else {
-        print_json(gateway_states).await?;
-    }
-
     Ok(())
 }
 
@@ -326,10 +357,10 @@ struct Monitor {
 
 impl Monitor {
     /// Starts a task to listen on the specified bitswap monitor for any of the given CIDs.
-    async fn monitor_bitswap(
+    async fn monitor_bitswap<S: MonitoringSource + 'static>(
         gateway_states: Arc<HashMap<String, Mutex<ProbingState>>>,
         mut cids: HashSet<String>,
-        mut monitoring_client: MonitoringClient,
+        mut monitoring_client: S,
         monitoring_ready_tx: tokio::sync::oneshot::Sender<()>,
     ) -> Result<Monitor> {
         // Build an index that maps from CID to the gateway the CID was sent to.
@@ -365,7 +396,10 @@ impl Monitor {
                                         error!("monitoring failed: {:?}",e);
                                         break;
                                     }
-                                    Ok((_,events) ) => {
+                                    Ok(MonitoringItem::Gap(gap)) => {
+                                        warn!("bitswap monitoring reconnected, missed messages from {} to {}", gap.disconnected_at, gap.reconnected_at);
+                                    }
+                                    Ok(MonitoringItem::Events(_,events)) => {
                                         if let Some(sender) = ready_tx.take() {
                                             debug!("got bitswap messages, connection is working");
                                             sender.send(()).unwrap();
@@ -756,3 +790,129 @@ async fn print_csv(gateway_states: Arc<HashMap<String, Mutex<ProbingState>>>) ->
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use ipfs_monitoring_plugin_client::monitoring::ConnectionEventType;
+    use ipfs_monitoring_plugin_client::tcp::TCPMonitoringClient;
+    use ipfs_monitoring_plugin_mock::events;
+    use ipfs_monitoring_plugin_mock::publisher::{MockPublisher, ScriptStep};
+    use ipfs_resolver_common::wantlist::JSONWantType;
+    use std::convert::Infallible;
+
+    const CID: &str = "bafkreigh2akiscaildcqabsyg3dfr6chu3fgpregiymsck7e7aqa4s52zy";
+    const OTHER_CID: &str = "bafkreidgvpkjawlxz6sffxzwgooowe5yt7i6wsyg236mfoks77nywkptdq";
+    const GATEWAY_PEER: &str = "12D3KooWSoLzampfxc4t3sy9z7yq1Cgzbi7zGXpV7nvt5hfeKUhR";
+    const GATEWAY_ADDRESS: &str = "/ip4/127.0.0.1/tcp/4001";
+
+    /// Starts a gateway serving the given data for the given CID.
+    /// Like a real gateway, it requests the CID via Bitswap before responding, which is
+    /// published via the given publisher.
+    fn start_gateway(publisher: Arc<MockPublisher>, cid: &'static str, data: Vec<u8>) -> String {
+        let make_svc = make_service_fn(move |_| {
+            let publisher = publisher.clone();
+            let data = data.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let resp = if req.uri().path() == format!("/ipfs/{}", cid) {
+                        let mut want = events::want(GATEWAY_PEER, cid, JSONWantType::Have, false);
+                        if let EventType::BitswapMessage(msg) = &mut want.inner {
+                            msg.connected_addresses = vec![GATEWAY_ADDRESS.to_string()];
+                        }
+                        publisher.publish(&[want]).unwrap();
+                        Response::new(Body::from(data.clone()))
+                    } else {
+                        Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::empty())
+                            .unwrap()
+                    };
+                    async move { Ok::<_, Infallible>(resp) }
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        format!("http://{}/ipfs/:hash", addr)
+    }
+
+    fn probing_state(cid: &str, data: &[u8]) -> Mutex<ProbingState> {
+        Mutex::new(ProbingState {
+            data: Some(data.to_vec()),
+            cid_v1: Some(cid.to_string()),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn probe_against_mock_monitor() {
+        let publisher = Arc::new(MockPublisher::start().await.unwrap());
+        let data = b"some random data".to_vec();
+        let working_gateway = start_gateway(publisher.clone(), CID, data.clone());
+        // Nothing listens on this port after the listener is dropped.
+        let unreachable_gateway = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/ipfs/:hash", listener.local_addr().unwrap())
+        };
+
+        let gateway_states = Arc::new(HashMap::from([
+            (working_gateway.clone(), probing_state(CID, &data)),
+            (
+                unreachable_gateway.clone(),
+                probing_state(OTHER_CID, b"other data"),
+            ),
+        ]));
+        let cids = HashSet::from([CID.to_string(), OTHER_CID.to_string()]);
+
+        let source = TCPMonitoringClient::new(&publisher.address(), "mock")
+            .await
+            .unwrap();
+        // Monitoring is ready once the first event is received.
+        let script = publisher.play(vec![
+            ScriptStep::WaitForConsumers(1),
+            ScriptStep::Publish(vec![events::connection_event(
+                GATEWAY_PEER,
+                GATEWAY_ADDRESS,
+                ConnectionEventType::Connected,
+            )]),
+        ]);
+        let (res, script_res) = tokio::join!(
+            probe_gateways(
+                gateway_states.clone(),
+                cids,
+                source,
+                2,
+                5,
+                Duration::from_millis(500),
+            ),
+            script
+        );
+        script_res.unwrap();
+        res.unwrap();
+
+        let state = gateway_states.get(&working_gateway).unwrap().lock().await;
+        assert!(state.http_success_timestamp.is_some());
+        assert!(state.http_error_message.is_none());
+        assert_eq!(state.http_requests_sent, Some(1));
+        let msg = state.bitswap_message.as_ref().unwrap();
+        assert_eq!(msg.peer, GATEWAY_PEER);
+        assert_eq!(msg.wantlist_entry.cid.path, CID);
+        assert_eq!(msg.connected_addresses, vec![GATEWAY_ADDRESS.to_string()]);
+
+        let state = gateway_states
+            .get(&unreachable_gateway)
+            .unwrap()
+            .lock()
+            .await;
+        assert!(state.http_success_timestamp.is_none());
+        assert!(state.http_error_message.is_some());
+        assert_eq!(state.http_requests_sent, Some(2));
+        assert!(state.bitswap_message.is_none());
+    }
+}
@@ -1,4 +1,7 @@
 use failure::ResultExt;
+use ipfs_resolver_common::events::TraceFormat;
+use ipfs_resolver_common::geoip::GeoIPConfig;
+use ipfs_resolver_common::output::OutputConfig;
 use ipfs_resolver_common::{wantlist, Result};
 use serde::{Deserialize, Serialize};
 use std::fs::File;
@@ -7,11 +10,63 @@ use std::path::{Path, PathBuf};
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct Config {
     pub(crate) input_globs: Vec<String>,
+
+    /// The format of the input files, either legacy wantlist messages as logged by our modified
+    /// IPFS, or events as logged by the `bitswap-monitoring-client`.
+    /// Defaults to detecting the format of each file.
+    #[serde(default)]
+    pub(crate) input_format: TraceFormat,
+
     pub(crate) wantlist_output_file_pattern: String,
     pub(crate) connection_events_output_file: String,
     pub(crate) connection_duration_output_file: String,
     pub(crate) ledger_count_output_file: String,
+
+    /// The file to write blocks and block presences to, matched to the requests in the ledger of
+    /// the sending peer.
+    /// Only events logged by the `bitswap-monitoring-client` carry these.
+    /// Defaults to not writing responses.
+    #[serde(default)]
+    pub(crate) responses_output_file: Option<String>,
+
+    /// The file to write the lifetimes of requests to, from the first WANT until the entry is
+    /// removed from the ledger of the peer.
+    /// Defaults to not writing request lifetimes.
+    #[serde(default)]
+    pub(crate) request_lifetimes_output_file: Option<String>,
+
     pub(crate) simulation_config: wantlist::EngineSimulationConfig,
+
+    /// The number of threads to run the engine simulation on.
+    /// Messages are sharded across these by peer ID.
+    /// The output does not depend on this.
+    /// Defaults to 1.
+    #[serde(default = "default_num_workers")]
+    pub(crate) num_workers: usize,
+
+    /// The format to write all output files in.
+    /// For columnar formats, the wantlist entries are written to the file for the first message
+    /// ID, with one row group or record batch per input file.
+    /// Defaults to gzipped CSV.
+    #[serde(default)]
+    pub(crate) output: OutputConfig,
+
+    /// Configures enrichment of wantlist entries, connection events, and responses with the
+    /// origin of their address, i.e., its country and, optionally, autonomous system and city.
+    /// Defaults to no enrichment, leaving the `origin_` columns empty.
+    #[serde(default)]
+    pub(crate) geoip: Option<GeoIPConfig>,
+
+    /// The file to write a snapshot of the simulation and output state to after every input
+    /// file, to resume processing from with `--resume`.
+    /// This requires gzipped CSV output.
+    /// Defaults to no snapshots.
+    #[serde(default)]
+    pub(crate) snapshot_file: Option<String>,
+}
+
+fn default_num_workers() -> usize {
+    1
 }
 
 impl Config {
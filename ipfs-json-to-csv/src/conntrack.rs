@@ -1,22 +1,23 @@
 use failure::{err_msg, ResultExt};
+use ipfs_resolver_common::output::{Column, ColumnType, Record};
 use ipfs_resolver_common::{wantlist, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct ConnectionDurationTracker {
     beginning_ts: Option<chrono::DateTime<chrono::Utc>>,
     connections: HashMap<String, PeerConnectionBuffer>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 struct PeerConnectionBuffer {
     connection_start: Option<chrono::DateTime<chrono::Utc>>,
     connected_address: Option<String>,
     past_connections: Vec<ConnectionMetadata>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct ConnectionMetadata {
     start: chrono::DateTime<chrono::Utc>,
     end: chrono::DateTime<chrono::Utc>,
@@ -47,6 +48,19 @@ pub(crate) struct CSVConnectionMetadata {
     address: Option<String>,
 }
 
+impl Record for CSVConnectionMetadata {
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::new("peer_id", ColumnType::Dictionary),
+            Column::new("start_ts_seconds", ColumnType::Int64),
+            Column::new("start_ts_subsec_millis", ColumnType::UInt32),
+            Column::new("end_ts_seconds", ColumnType::Int64),
+            Column::new("end_ts_subsec_millis", ColumnType::UInt32),
+            Column::nullable("address", ColumnType::Dictionary),
+        ]
+    }
+}
+
 impl ConnectionDurationTracker {
     pub fn new() -> ConnectionDurationTracker {
         ConnectionDurationTracker {
@@ -89,11 +103,7 @@ impl ConnectionDurationTracker {
                 .peer_disconnected
                 .ok_or(err_msg("missing disconnected"))
                 .context("message has Some(connected), but disconnected is missing")?;
-            let found = msg
-                .connect_event_peer_found
-                .ok_or(err_msg("missing found"))
-                .context("message had Some(connected), but found is missing")?;
-            let addr = msg.address.clone().map(|a| a.to_string());
+            let addr = msg.address_string();
 
             if !connected && !disconnected {
                 debug!(
@@ -107,7 +117,6 @@ impl ConnectionDurationTracker {
                 msg.peer.clone(),
                 connected,
                 disconnected,
-                found,
                 addr,
                 msg.timestamp,
             )
@@ -122,7 +131,6 @@ impl ConnectionDurationTracker {
         peer_id: String,
         connected: bool,
         disconnected: bool,
-        _found: bool,
         addr: Option<String>,
         ts: chrono::DateTime<chrono::Utc>,
     ) -> Result<()> {
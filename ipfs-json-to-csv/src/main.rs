@@ -3,19 +3,25 @@ extern crate log;
 
 mod config;
 mod conntrack;
+mod pipeline;
 
-use crate::conntrack::ConnectionDurationTracker;
+use crate::pipeline::{Pipeline, PipelineItem, SimulationState};
 use clap::{App, Arg};
-use csv::Writer;
-use failure::{err_msg, ResultExt};
-use flate2::read::GzDecoder;
-use flate2::write::GzEncoder;
-use flate2::Compression;
-use ipfs_resolver_common::{logging, wantlist, Result};
+use failure::{ensure, err_msg, ResultExt};
+use ipfs_resolver_common::geoip::OriginLookup;
+use ipfs_resolver_common::output::{
+    Column, ColumnType, OutputCheckpoint, OutputConfig, OutputFormat, OutputSink, Record,
+};
+use ipfs_resolver_common::wantlist::{
+    CSVConnectionEvent, CSVRequestLifetime, CSVResponse, CSVWantlistEntry, EngineSimulation,
+    IngestResult,
+};
+use ipfs_resolver_common::{logging, snapshot, Result};
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io;
-use std::io::{BufRead, BufReader, BufWriter};
+use std::path::PathBuf;
+
+/// The kind of snapshots written by this tool.
+const SNAPSHOT_KIND: &str = "ipfs-json-to-csv";
 
 fn main() -> Result<()> {
     logging::set_up_logging()?;
@@ -34,6 +40,11 @@ fn main() -> Result<()> {
                 .default_value("config.yaml")
                 .help("the config file to load"),
         )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .help("resume processing from the configured snapshot file"),
+        )
         .get_matches();
 
     if !matches.is_present("cfg") {
@@ -43,7 +54,9 @@ fn main() -> Result<()> {
     let config = matches.value_of("cfg").unwrap();
 
     info!("attempting to load config from file '{}'", config);
-    let config = config::Config::open(config).context("unable to load config")?;
+    let mut config = config::Config::open(config).context("unable to load config")?;
+    config.simulation_config.emit_request_lifetimes =
+        config.request_lifetimes_output_file.is_some();
 
     info!(
         "output file for wantlist entries is {}",
@@ -61,18 +74,27 @@ fn main() -> Result<()> {
         "output file for ledger counts is {}",
         config.ledger_count_output_file
     );
+    if let Some(responses_output_file) = config.responses_output_file.as_ref() {
+        info!("output file for responses is {}", responses_output_file);
+    }
+    if let Some(request_lifetimes_output_file) = config.request_lifetimes_output_file.as_ref() {
+        info!(
+            "output file for request lifetimes is {}",
+            request_lifetimes_output_file
+        );
+    }
+    info!("output format is {:?}", config.output.format);
+    info!("input format is {:?}", config.input_format);
     debug!("simulation config is {:?}", config.simulation_config);
+    if let Some(snapshot_file) = config.snapshot_file.as_ref() {
+        info!("writing snapshots to {}", snapshot_file);
+    }
 
-    do_transform(config).context("unable to do transformation")?;
+    do_transform(config, matches.is_present("resume")).context("unable to do transformation")?;
 
     Ok(())
 }
 
-struct SingleFileTransformResult {
-    timestamps: Option<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)>,
-    num_missing_ledgers: usize,
-}
-
 #[derive(Debug, Serialize, Deserialize)]
 struct CSVLedgerCount {
     ts_secs: i64,
@@ -80,164 +102,297 @@ struct CSVLedgerCount {
     total_ledgers: usize,
 }
 
-fn do_transform_single_file(
-    mut infile: BufReader<GzDecoder<File>>,
-    wl_writer: &mut csv::Writer<GzEncoder<BufWriter<File>>>,
-    conn_writer: &mut csv::Writer<GzEncoder<BufWriter<File>>>,
-    engine: &mut wantlist::EngineSimulation,
-    current_message_id: &mut i64,
-    conn_tracker: &mut ConnectionDurationTracker,
-) -> Result<SingleFileTransformResult> {
-    let mut buf = String::new();
-    let mut first_message_ts = None;
-    let mut last_message_ts = None;
-    let mut missing_ledgers = 0;
-    while let Ok(n) = infile.read_line(&mut buf) {
-        if n == 0 {
-            break;
-        }
-        *current_message_id += 1;
+impl Record for CSVLedgerCount {
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::new("ts_secs", ColumnType::Int64),
+            Column::new("missing_ledgers", ColumnType::UInt64),
+            Column::new("total_ledgers", ColumnType::UInt64),
+        ]
+    }
+}
 
-        let message: wantlist::JSONMessage = serde_json::from_str(&buf)?;
-        debug!("decoded message {:?}", message);
-        if first_message_ts.is_none() {
-            first_message_ts = Some(message.timestamp);
-        }
-        last_message_ts.replace(message.timestamp);
+/// A snapshot of the transformation after an input file, from which it can be resumed.
+#[derive(Serialize, Deserialize)]
+struct TransformSnapshot {
+    processed_files: Vec<PathBuf>,
+    final_ts: Option<chrono::DateTime<chrono::Utc>>,
+    simulation: SimulationState,
+    conn_events_output: OutputCheckpoint,
+    connection_durations_output: OutputCheckpoint,
+    ledger_count_output: OutputCheckpoint,
+    responses_output: Option<OutputCheckpoint>,
+    request_lifetimes_output: Option<OutputCheckpoint>,
+}
 
-        // Add to connection tracker.
-        conn_tracker
-            .push(&message)
-            .context("unable to track connection duration")?;
+/// Per-file state while transforming.
+struct FileTransformState {
+    timestamps: Option<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)>,
+    num_messages: usize,
+    num_missing_ledgers: usize,
+    total_ledgers: usize,
+    started: std::time::Instant,
+}
 
-        // Update simulated wantlists.
-        let ingest_result = engine.ingest(&message, *current_message_id)?;
-        debug!("ingest result: {:?}", ingest_result);
+fn do_transform(cfg: config::Config, resume: bool) -> Result<()> {
+    ensure!(
+        cfg.snapshot_file.is_none() || cfg.output.format == OutputFormat::CsvGz,
+        "snapshots require gzipped CSV output"
+    );
+    let mut input_files = cfg.glob_results().context("unable to glob")?;
+    debug!("paths: {:?}", input_files);
 
-        if ingest_result.missing_ledger {
-            missing_ledgers += 1;
-        }
-        if let Some(entries) = ingest_result.wantlist_entries.as_ref() {
-            entries
+    let (
+        mut processed_files,
+        mut final_ts,
+        simulation_state,
+        mut conn_events_output_sink,
+        mut connection_durations_output_sink,
+        mut ledger_count_output_sink,
+        mut responses_output_sink,
+        mut request_lifetimes_output_sink,
+    ) = if resume {
+        let snapshot_file = cfg
+            .snapshot_file
+            .as_ref()
+            .ok_or_else(|| err_msg("resuming requires a snapshot file"))?;
+        info!("resuming from snapshot {}", snapshot_file);
+        let snapshot: TransformSnapshot = snapshot::read_snapshot(snapshot_file, SNAPSHOT_KIND)
+            .context("unable to read snapshot")?;
+
+        ensure!(
+            input_files.starts_with(&snapshot.processed_files),
+            "input files do not match the files processed before the snapshot"
+        );
+        let simulation_config = EngineSimulation::new(cfg.simulation_config.clone())
+            .context("invalid simulation config")?
+            .config()
+            .clone();
+        ensure!(
+            snapshot
+                .simulation
+                .engines
                 .iter()
-                .try_for_each(|e| wl_writer.serialize(e))
-                .context("unable to serialize wantlist entries")?;
-        }
-        if let Some(conn_event) = ingest_result.connection_event.as_ref() {
-            conn_writer
-                .serialize(conn_event)
-                .context("unable to serialize connection event")?;
+                .all(|e| *e.config() == simulation_config),
+            "simulation config does not match the snapshot"
+        );
+        if snapshot.simulation.engines.len() != cfg.num_workers {
+            warn!(
+                "snapshot was taken with {} workers, continuing with those",
+                snapshot.simulation.engines.len()
+            )
         }
+        input_files.drain(..snapshot.processed_files.len());
+        info!(
+            "skipping {} already processed files",
+            snapshot.processed_files.len()
+        );
 
-        buf.clear();
-    }
-
-    Ok(SingleFileTransformResult {
-        timestamps: if let Some(first) = first_message_ts {
-            Some((first, last_message_ts.unwrap()))
-        } else {
-            None
-        },
-        num_missing_ledgers: missing_ledgers,
-    })
-}
-
-fn do_transform(cfg: config::Config) -> Result<()> {
-    let mut engine = wantlist::EngineSimulation::new(cfg.simulation_config.clone())
-        .context("unable to set up engine simulation")?;
-    let mut current_message_id: i64 = 0;
-    let mut conn_tracker = ConnectionDurationTracker::new();
-    let mut final_ts = None;
-
-    let mut conn_events_output_writer = csv::Writer::from_writer(GzEncoder::new(
-        io::BufWriter::new(
-            std::fs::File::create(cfg.connection_events_output_file.clone())
+        (
+            snapshot.processed_files,
+            snapshot.final_ts,
+            snapshot.simulation,
+            OutputSink::resume(&cfg.output, &snapshot.conn_events_output)
+                .context("unable to resume connection events output")?,
+            OutputSink::resume(&cfg.output, &snapshot.connection_durations_output)
+                .context("unable to resume connection duration output")?,
+            OutputSink::resume(&cfg.output, &snapshot.ledger_count_output)
+                .context("unable to resume missing ledgers output")?,
+            resume_optional_output(
+                &cfg.output,
+                cfg.responses_output_file.as_ref(),
+                snapshot.responses_output.as_ref(),
+            )
+            .context("unable to resume responses output")?,
+            resume_optional_output(
+                &cfg.output,
+                cfg.request_lifetimes_output_file.as_ref(),
+                snapshot.request_lifetimes_output.as_ref(),
+            )
+            .context("unable to resume request lifetimes output")?,
+        )
+    } else {
+        (
+            Vec::new(),
+            None,
+            SimulationState::new(cfg.simulation_config.clone(), cfg.num_workers)?,
+            OutputSink::create(&cfg.output, &cfg.connection_events_output_file)
                 .context("unable to open connection events output file for writing")?,
-        ),
-        Compression::default(),
-    ));
-    let mut connection_durations_output_writer = csv::Writer::from_writer(GzEncoder::new(
-        io::BufWriter::new(
-            std::fs::File::create(cfg.connection_duration_output_file.clone())
+            OutputSink::create(&cfg.output, &cfg.connection_duration_output_file)
                 .context("unable to open connection duration output file for writing")?,
-        ),
-        Compression::default(),
-    ));
-    let mut ledger_count_output_writer = csv::Writer::from_writer(GzEncoder::new(
-        io::BufWriter::new(
-            std::fs::File::create(cfg.ledger_count_output_file.clone())
+            OutputSink::create(&cfg.output, &cfg.ledger_count_output_file)
                 .context("unable to open missing ledgers output file for writing")?,
-        ),
-        Compression::default(),
-    ));
-
-    let input_files = cfg.glob_results().context("unable to glob")?;
-    debug!("paths: {:?}", input_files);
-
-    for path in input_files {
-        info!("now working on {}", path.display());
-        let input_file = BufReader::new(GzDecoder::new(
-            std::fs::File::open(&path).context("unable to open input file for reading")?,
-        ));
-
-        let mut wl_output_writer =
-            create_wl_output_writer(cfg.wantlist_output_file_pattern.clone(), current_message_id)
-                .context("unable to create output file")?;
-
-        let id_before = current_message_id;
-        let before = std::time::Instant::now();
-        let transform_result = do_transform_single_file(
-            input_file,
-            &mut wl_output_writer,
-            &mut conn_events_output_writer,
-            &mut engine,
-            &mut current_message_id,
-            &mut conn_tracker,
+            cfg.responses_output_file
+                .as_ref()
+                .map(|f| OutputSink::create(&cfg.output, f))
+                .transpose()
+                .context("unable to open responses output file for writing")?,
+            cfg.request_lifetimes_output_file
+                .as_ref()
+                .map(|f| OutputSink::create(&cfg.output, f))
+                .transpose()
+                .context("unable to open request lifetimes output file for writing")?,
         )
-        .context(format!("unable to process file {}", path.display()))?;
-        let num_messages = current_message_id - id_before;
-        let time_diff = before.elapsed();
-
-        info!(
-            "processed {} messages in {:.1}s => {:.1}msg/s",
-            num_messages,
-            time_diff.as_secs_f32(),
-            (num_messages as f64) / time_diff.as_secs_f64()
-        );
-
-        match transform_result.timestamps {
-            Some((first, last)) => {
-                info!("first ts: {}, last ts: {}", first, last);
-
-                ledger_count_output_writer
-                    .serialize(CSVLedgerCount {
-                        ts_secs: last.timestamp(),
-                        missing_ledgers: transform_result.num_missing_ledgers,
-                        total_ledgers: engine.num_ledgers(),
-                    })
-                    .context("unable to serialize missing ledgers record")?;
+    };
+    let mut origin_lookup = cfg
+        .geoip
+        .as_ref()
+        .map(OriginLookup::open)
+        .transpose()
+        .context("unable to open GeoIP databases")?;
+    let mut current_message_id = simulation_state.message_id;
+    // This is created for the first input file and rotated for every following one.
+    // With snapshots, it is created anew for every input file instead.
+    let mut wl_output_sink = None;
+    let num_files = input_files.len();
 
-                final_ts.replace(last);
+    info!(
+        "using {} engine simulation workers",
+        simulation_state.engines.len()
+    );
+    let started = std::time::Instant::now();
+    let mut total_bytes = 0;
+    let mut pipeline = Pipeline::start(
+        input_files,
+        cfg.input_format,
+        simulation_state,
+        cfg.snapshot_file.is_some(),
+    )
+    .context("unable to start processing pipeline")?;
+
+    let mut file_state = None;
+    let res = (|| -> Result<()> {
+        for item in pipeline.by_ref() {
+            match item? {
+                PipelineItem::FileStart { path, message_id } => {
+                    info!("now working on {}", path.display());
+                    rotate_wl_output(
+                        &mut wl_output_sink,
+                        &cfg.output,
+                        &cfg.wantlist_output_file_pattern,
+                        message_id,
+                    )?;
+                    file_state = Some(FileTransformState {
+                        timestamps: None,
+                        num_messages: 0,
+                        num_missing_ledgers: 0,
+                        total_ledgers: 0,
+                        started: std::time::Instant::now(),
+                    });
+                }
+                PipelineItem::Messages {
+                    first_message_id,
+                    first_ts,
+                    last_ts,
+                    mut results,
+                    missing_ledgers,
+                    total_ledgers,
+                } => {
+                    let state = file_state
+                        .as_mut()
+                        .ok_or_else(|| err_msg("messages outside of file"))?;
+                    current_message_id = first_message_id + results.len() as i64 - 1;
+                    state.num_messages += results.len();
+                    state.num_missing_ledgers += missing_ledgers;
+                    state.total_ledgers = total_ledgers;
+                    state.timestamps = Some(match state.timestamps {
+                        Some((first, _)) => (first, last_ts),
+                        None => (first_ts, last_ts),
+                    });
+
+                    if let Some(origin_lookup) = origin_lookup.as_mut() {
+                        enrich_ingest_results(&mut results, origin_lookup);
+                    }
+                    write_ingest_results(
+                        &results,
+                        wl_output_sink
+                            .as_mut()
+                            .ok_or_else(|| err_msg("messages outside of file"))?,
+                        &mut conn_events_output_sink,
+                        responses_output_sink.as_mut(),
+                        request_lifetimes_output_sink.as_mut(),
+                    )?;
+                }
+                PipelineItem::FileEnd {
+                    path,
+                    bytes_read,
+                    state: simulation_state,
+                } => {
+                    let state = file_state
+                        .take()
+                        .ok_or_else(|| err_msg("end of file without start"))?;
+                    debug!("done with {}", path.display());
+                    total_bytes += bytes_read;
+                    finish_file(state, &mut ledger_count_output_sink, &mut final_ts)?;
+                    processed_files.push(path);
+
+                    if let (Some(snapshot_file), Some(simulation_state)) =
+                        (cfg.snapshot_file.as_ref(), simulation_state)
+                    {
+                        if let Some(wl_output_sink) = wl_output_sink.take() {
+                            wl_output_sink
+                                .finish()
+                                .context("unable to write wantlist output")?;
+                        }
+                        let snapshot = TransformSnapshot {
+                            processed_files: processed_files.clone(),
+                            final_ts,
+                            simulation: simulation_state,
+                            conn_events_output: conn_events_output_sink.checkpoint()?,
+                            connection_durations_output: connection_durations_output_sink
+                                .checkpoint()?,
+                            ledger_count_output: ledger_count_output_sink.checkpoint()?,
+                            responses_output: responses_output_sink
+                                .as_mut()
+                                .map(|s| s.checkpoint())
+                                .transpose()?,
+                            request_lifetimes_output: request_lifetimes_output_sink
+                                .as_mut()
+                                .map(|s| s.checkpoint())
+                                .transpose()?,
+                        };
+                        snapshot::write_snapshot(snapshot_file, SNAPSHOT_KIND, &snapshot)
+                            .context("unable to write snapshot")?;
+                    }
+                }
             }
-            None => info!("empty file?"),
         }
-        info!(
-            "{} missing ledgers, {} ledgers total",
-            transform_result.num_missing_ledgers,
-            engine.num_ledgers()
-        );
-    }
+
+        Ok(())
+    })();
+    // We always shut down the pipeline, since its error is likely the cause of ours.
+    let output = match (res, pipeline.finish()) {
+        (_, Err(err)) | (Err(err), _) => return Err(err),
+        (Ok(_), Ok(output)) => output,
+    };
+
+    let elapsed = started.elapsed();
+    info!(
+        "processed {} messages ({:.1}MiB) from {} files in {:.1}s => {:.1}msg/s, {:.1}MiB/s",
+        current_message_id,
+        total_bytes as f64 / (1024 * 1024) as f64,
+        num_files,
+        elapsed.as_secs_f32(),
+        (current_message_id as f64) / elapsed.as_secs_f64(),
+        total_bytes as f64 / (1024 * 1024) as f64 / elapsed.as_secs_f64()
+    );
 
     info!("finalizing connection tracker...");
     if let Some(ts) = final_ts {
-        let connections = conn_tracker.finalize(ts);
-
-        info!("writing connections CSV...");
+        let mut connections = output
+            .conn_trackers
+            .into_iter()
+            .flat_map(|t| t.finalize(ts))
+            .collect::<Vec<_>>();
+        // Sort by peer, to make the output independent of the number of workers.
+        connections.sort_by(|(p1, _), (p2, _)| p1.cmp(p2));
+
+        info!("writing connections...");
         for (peer_id, conns) in connections.into_iter() {
             for c in conns.into_iter() {
                 let to_encode = c.to_csv(peer_id.clone());
-                connection_durations_output_writer
-                    .serialize(to_encode)
+                connection_durations_output_sink
+                    .write(&to_encode)
                     .context("unable to serialize connection metadata")?;
             }
         }
@@ -247,33 +402,212 @@ fn do_transform(cfg: config::Config) -> Result<()> {
 
     info!("finalizing engine simulation...");
     if let Some(ts) = final_ts {
-        let end_of_simulation_cancels =
-            engine.generate_end_of_simulation_entries(ts, current_message_id + 1);
+        if let Some(request_lifetimes_output_sink) = request_lifetimes_output_sink.as_mut() {
+            let mut lifetimes = output
+                .engines
+                .iter()
+                .flat_map(|e| e.generate_end_of_simulation_lifetimes(ts, current_message_id + 1))
+                .collect::<Vec<_>>();
+            // Sort by peer, to make the output independent of the number of workers.
+            // This is stable, so lifetimes are still sorted by CID within each peer.
+            lifetimes.sort_by(|l1, l2| l1.peer_id.cmp(&l2.peer_id));
+
+            lifetimes
+                .iter()
+                .try_for_each(|l| request_lifetimes_output_sink.write(l))
+                .context("unable to serialize end-of-simulation request lifetimes")?;
+        }
 
-        let mut wl_output_writer =
-            create_wl_output_writer(cfg.wantlist_output_file_pattern.clone(), current_message_id)
-                .context("unable to create output file")?;
+        let mut end_of_simulation_cancels = output
+            .engines
+            .into_iter()
+            .flat_map(|e| e.generate_end_of_simulation_entries(ts, current_message_id + 1))
+            .collect::<Vec<_>>();
+        // Sort by peer, to make the output independent of the number of workers.
+        // This is stable, so entries are still sorted by CID within each peer.
+        end_of_simulation_cancels.sort_by(|e1, e2| e1.peer_id.cmp(&e2.peer_id));
+
+        rotate_wl_output(
+            &mut wl_output_sink,
+            &cfg.output,
+            &cfg.wantlist_output_file_pattern,
+            current_message_id,
+        )?;
+        let wl_output_sink = wl_output_sink
+            .as_mut()
+            .ok_or_else(|| err_msg("missing wantlist output"))?;
 
         end_of_simulation_cancels
             .iter()
-            .try_for_each(|e| wl_output_writer.serialize(e))
+            .try_for_each(|e| wl_output_sink.write(e))
             .context("unable to serialize end-of-simulation synthetic cancels")?;
     } else {
         warn!("missing final timestamp, unable to finalize")
     }
 
+    if let Some(wl_output_sink) = wl_output_sink {
+        wl_output_sink
+            .finish()
+            .context("unable to write wantlist output")?;
+    }
+    conn_events_output_sink
+        .finish()
+        .context("unable to write connection events output")?;
+    connection_durations_output_sink
+        .finish()
+        .context("unable to write connection duration output")?;
+    ledger_count_output_sink
+        .finish()
+        .context("unable to write missing ledgers output")?;
+    if let Some(responses_output_sink) = responses_output_sink {
+        responses_output_sink
+            .finish()
+            .context("unable to write responses output")?;
+    }
+    if let Some(request_lifetimes_output_sink) = request_lifetimes_output_sink {
+        request_lifetimes_output_sink
+            .finish()
+            .context("unable to write request lifetimes output")?;
+    }
+
     Ok(())
 }
 
-fn create_wl_output_writer(
-    pattern: String,
+/// Sets the origin of the wantlist entries, connection events, and responses of the given results.
+fn enrich_ingest_results(results: &mut [IngestResult], origin_lookup: &mut OriginLookup) {
+    for ingest_result in results {
+        if let Some(entries) = ingest_result.wantlist_entries.as_mut() {
+            entries.iter_mut().for_each(|e| origin_lookup.enrich(e));
+        }
+        if let Some(conn_event) = ingest_result.connection_event.as_mut() {
+            origin_lookup.enrich(conn_event);
+        }
+        if let Some(responses) = ingest_result.responses.as_mut() {
+            responses.iter_mut().for_each(|r| origin_lookup.enrich(r));
+        }
+    }
+}
+
+fn write_ingest_results(
+    results: &[IngestResult],
+    wl_sink: &mut OutputSink<CSVWantlistEntry>,
+    conn_sink: &mut OutputSink<CSVConnectionEvent>,
+    mut responses_sink: Option<&mut OutputSink<CSVResponse>>,
+    mut request_lifetimes_sink: Option<&mut OutputSink<CSVRequestLifetime>>,
+) -> Result<()> {
+    for ingest_result in results {
+        if let Some(entries) = ingest_result.wantlist_entries.as_ref() {
+            entries
+                .iter()
+                .try_for_each(|e| wl_sink.write(e))
+                .context("unable to serialize wantlist entries")?;
+        }
+        if let Some(conn_event) = ingest_result.connection_event.as_ref() {
+            conn_sink
+                .write(conn_event)
+                .context("unable to serialize connection event")?;
+        }
+        if let (Some(responses), Some(responses_sink)) =
+            (ingest_result.responses.as_ref(), responses_sink.as_mut())
+        {
+            responses
+                .iter()
+                .try_for_each(|r| responses_sink.write(r))
+                .context("unable to serialize responses")?;
+        }
+        if let (Some(lifetimes), Some(request_lifetimes_sink)) = (
+            ingest_result.request_lifetimes.as_ref(),
+            request_lifetimes_sink.as_mut(),
+        ) {
+            lifetimes
+                .iter()
+                .try_for_each(|l| request_lifetimes_sink.write(l))
+                .context("unable to serialize request lifetimes")?;
+        }
+    }
+
+    Ok(())
+}
+
+fn finish_file(
+    state: FileTransformState,
+    ledger_count_output_sink: &mut OutputSink<CSVLedgerCount>,
+    final_ts: &mut Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<()> {
+    let FileTransformState {
+        timestamps,
+        num_messages,
+        num_missing_ledgers,
+        total_ledgers,
+        started,
+    } = state;
+
+    let time_diff = started.elapsed();
+    info!(
+        "processed {} messages in {:.1}s => {:.1}msg/s",
+        num_messages,
+        time_diff.as_secs_f32(),
+        (num_messages as f64) / time_diff.as_secs_f64()
+    );
+
+    match timestamps {
+        Some((first, last)) => {
+            info!("first ts: {}, last ts: {}", first, last);
+
+            ledger_count_output_sink
+                .write(&CSVLedgerCount {
+                    ts_secs: last.timestamp(),
+                    missing_ledgers: num_missing_ledgers,
+                    total_ledgers,
+                })
+                .context("unable to serialize missing ledgers record")?;
+
+            final_ts.replace(last);
+        }
+        None => info!("empty file?"),
+    }
+    info!(
+        "{} missing ledgers, {} ledgers total",
+        num_missing_ledgers, total_ledgers
+    );
+
+    Ok(())
+}
+
+/// Resumes an optional output, which must be configured if and only if it was configured when
+/// the snapshot was taken.
+fn resume_optional_output<T: Record>(
+    config: &OutputConfig,
+    file: Option<&String>,
+    checkpoint: Option<&OutputCheckpoint>,
+) -> Result<Option<OutputSink<T>>> {
+    match (file, checkpoint) {
+        (Some(_), Some(checkpoint)) => Ok(Some(OutputSink::resume(config, checkpoint)?)),
+        (None, None) => Ok(None),
+        _ => Err(err_msg(
+            "output must be configured both for the snapshot and now, or neither",
+        )),
+    }
+}
+
+/// Rotates the wantlist output to the given message ID, creating it if necessary.
+fn rotate_wl_output(
+    sink: &mut Option<OutputSink<CSVWantlistEntry>>,
+    config: &OutputConfig,
+    pattern: &str,
     current_message_id: i64,
-) -> Result<Writer<GzEncoder<BufWriter<std::fs::File>>>> {
-    Ok(csv::Writer::from_writer(GzEncoder::new(
-        io::BufWriter::new(
-            std::fs::File::create(pattern.replace("$id$", &format!("{:09}", current_message_id)))
-                .context("unable to open wantlist output file for writing")?,
-        ),
-        Compression::default(),
-    )))
+) -> Result<()> {
+    match sink.as_mut() {
+        Some(sink) => sink
+            .rotate(current_message_id)
+            .context("unable to rotate wantlist output")?,
+        None => {
+            sink.replace(
+                OutputSink::create_from_pattern(config, pattern, current_message_id)
+                    .context("unable to open wantlist output file for writing")?,
+            );
+        }
+    }
+
+    Ok(())
 }
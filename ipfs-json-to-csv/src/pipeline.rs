@@ -0,0 +1,758 @@
+use crate::conntrack::ConnectionDurationTracker;
+use failure::{ensure, err_msg, ResultExt};
+use flate2::read::GzDecoder;
+use ipfs_resolver_common::events::{TraceDecoder, TraceFormat};
+use ipfs_resolver_common::wantlist::{EngineSimulation, EngineSimulationConfig, IngestResult};
+use ipfs_resolver_common::{wantlist, Result};
+use serde::{Deserialize, Serialize};
+use std::hash::Hasher;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread::JoinHandle;
+use twox_hash::XxHash64;
+
+/// The number of lines or messages passed between stages at once.
+const BATCH_SIZE: usize = 10_000;
+
+/// The number of batches buffered between two stages.
+/// Together with `BATCH_SIZE`, this bounds the memory used for messages in flight.
+const CHANNEL_DEPTH: usize = 4;
+
+/// An item produced by the pipeline, in input order.
+pub(crate) enum PipelineItem {
+    /// Processing of an input file begins.
+    /// `message_id` is the ID of the last message of the previous file.
+    FileStart { path: PathBuf, message_id: i64 },
+
+    /// The results of ingesting a batch of messages, in order.
+    Messages {
+        first_message_id: i64,
+        first_ts: chrono::DateTime<chrono::Utc>,
+        last_ts: chrono::DateTime<chrono::Utc>,
+        results: Vec<IngestResult>,
+        missing_ledgers: usize,
+        total_ledgers: usize,
+    },
+
+    /// Processing of an input file is done.
+    /// If snapshots are enabled, this carries the state of the simulation after the file.
+    FileEnd {
+        path: PathBuf,
+        bytes_read: usize,
+        state: Option<SimulationState>,
+    },
+}
+
+/// The state of the simulation after all input has been processed.
+pub(crate) struct PipelineOutput {
+    pub(crate) engines: Vec<EngineSimulation>,
+    pub(crate) conn_trackers: Vec<ConnectionDurationTracker>,
+}
+
+/// The state of the simulation between two input files, from which processing can be resumed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct SimulationState {
+    /// The ID of the last message processed.
+    pub(crate) message_id: i64,
+    /// The engine simulation of each worker.
+    pub(crate) engines: Vec<EngineSimulation>,
+    /// The connection tracker of each worker.
+    pub(crate) conn_trackers: Vec<ConnectionDurationTracker>,
+}
+
+impl SimulationState {
+    /// Creates the initial state of a simulation with the given number of workers.
+    pub(crate) fn new(
+        simulation_config: EngineSimulationConfig,
+        num_workers: usize,
+    ) -> Result<SimulationState> {
+        let engine = EngineSimulation::new(simulation_config)
+            .context("unable to set up engine simulation")?;
+        let num_workers = num_workers.max(1);
+
+        Ok(SimulationState {
+            message_id: 0,
+            engines: vec![engine; num_workers],
+            conn_trackers: vec![ConnectionDurationTracker::new(); num_workers],
+        })
+    }
+}
+
+/// A pipeline to decompress, parse, and simulate the engine on a series of input files.
+///
+/// Decompression and parsing run on one thread each.
+/// The engine simulation and connection tracking are sharded by peer ID across a number of
+/// worker threads.
+/// Results are reassembled in input order, such that the output does not depend on the number of
+/// workers.
+pub(crate) struct Pipeline {
+    items: Receiver<DispatchItem>,
+    results: Vec<Receiver<WorkerOutput>>,
+    num_ledgers: Vec<usize>,
+    threads: Vec<JoinHandle<Result<()>>>,
+    workers: Vec<JoinHandle<Result<(EngineSimulation, ConnectionDurationTracker)>>>,
+}
+
+/// Produces items in input order.
+/// Once this returns `None`, call `finish` to collect errors and the final state.
+impl Iterator for Pipeline {
+    type Item = Result<PipelineItem>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // This fails once all input has been processed or a stage failed.
+        let item = self.items.recv().ok()?;
+
+        Some(match item {
+            DispatchItem::FileStart(path, message_id) => {
+                Ok(PipelineItem::FileStart { path, message_id })
+            }
+            DispatchItem::FileEnd {
+                path,
+                bytes_read,
+                message_id,
+                snapshot,
+            } => {
+                let state = if snapshot {
+                    match self.collect_snapshot(message_id) {
+                        Ok(state) => Some(state),
+                        Err(err) => return Some(Err(err)),
+                    }
+                } else {
+                    None
+                };
+                Ok(PipelineItem::FileEnd {
+                    path,
+                    bytes_read,
+                    state,
+                })
+            }
+            DispatchItem::Messages {
+                first_message_id,
+                first_ts,
+                last_ts,
+                len,
+            } => {
+                self.collect_results(len)
+                    .map(|(results, missing_ledgers)| PipelineItem::Messages {
+                        first_message_id,
+                        first_ts,
+                        last_ts,
+                        results,
+                        missing_ledgers,
+                        total_ledgers: self.num_ledgers.iter().sum(),
+                    })
+            }
+        })
+    }
+}
+
+enum ReaderItem {
+    FileStart(PathBuf),
+    Lines(Vec<String>),
+    FileEnd(PathBuf, usize),
+}
+
+enum ParsedItem {
+    FileStart(PathBuf, i64),
+    Messages(i64, Vec<wantlist::JSONMessage>),
+    FileEnd(PathBuf, usize, i64),
+}
+
+enum DispatchItem {
+    FileStart(PathBuf, i64),
+    Messages {
+        first_message_id: i64,
+        first_ts: chrono::DateTime<chrono::Utc>,
+        last_ts: chrono::DateTime<chrono::Utc>,
+        len: usize,
+    },
+    FileEnd {
+        path: PathBuf,
+        bytes_read: usize,
+        message_id: i64,
+        snapshot: bool,
+    },
+}
+
+/// A slice of a batch of messages, assigned to one worker.
+/// Each message is tagged with its index in the batch and its ID.
+type WorkerBatch = Vec<(usize, i64, wantlist::JSONMessage)>;
+
+enum WorkerInput {
+    Batch(WorkerBatch),
+    /// Asks the worker for a copy of its state.
+    Snapshot,
+}
+
+struct WorkerResult {
+    results: Vec<(usize, IngestResult)>,
+    missing_ledgers: usize,
+    num_ledgers: usize,
+}
+
+enum WorkerOutput {
+    Results(WorkerResult),
+    Snapshot(EngineSimulation, ConnectionDurationTracker),
+}
+
+impl Pipeline {
+    /// Starts processing the given input files, in order, from the given state.
+    /// The simulation runs on one worker per engine in the state.
+    ///
+    /// If `snapshots` is set, a copy of the state is produced at the end of every input file.
+    pub(crate) fn start(
+        input_files: Vec<PathBuf>,
+        input_format: TraceFormat,
+        state: SimulationState,
+        snapshots: bool,
+    ) -> Result<Pipeline> {
+        let SimulationState {
+            message_id,
+            engines,
+            conn_trackers,
+        } = state;
+        ensure!(
+            !engines.is_empty() && engines.len() == conn_trackers.len(),
+            "invalid simulation state"
+        );
+        let num_workers = engines.len();
+        let (lines_tx, lines_rx) = sync_channel(CHANNEL_DEPTH);
+        let (parsed_tx, parsed_rx) = sync_channel(CHANNEL_DEPTH);
+        let (items_tx, items_rx) = sync_channel(CHANNEL_DEPTH);
+
+        let mut worker_inputs = Vec::new();
+        let mut results = Vec::new();
+        let mut workers = Vec::new();
+        for (i, (engine, conn_tracker)) in engines.into_iter().zip(conn_trackers).enumerate() {
+            let (batch_tx, batch_rx) = sync_channel(CHANNEL_DEPTH);
+            let (result_tx, result_rx) = sync_channel(CHANNEL_DEPTH);
+            worker_inputs.push(batch_tx);
+            results.push(result_rx);
+            workers.push(
+                std::thread::Builder::new()
+                    .name(format!("engine-{}", i))
+                    .spawn(move || Self::simulate(engine, conn_tracker, batch_rx, result_tx))
+                    .context("unable to spawn worker thread")?,
+            );
+        }
+
+        let threads = vec![
+            std::thread::Builder::new()
+                .name("reader".to_string())
+                .spawn(move || Self::read(input_files, lines_tx))
+                .context("unable to spawn reader thread")?,
+            std::thread::Builder::new()
+                .name("parser".to_string())
+                .spawn(move || Self::parse(lines_rx, parsed_tx, input_format, message_id))
+                .context("unable to spawn parser thread")?,
+            std::thread::Builder::new()
+                .name("dispatcher".to_string())
+                .spawn(move || Self::dispatch(parsed_rx, worker_inputs, items_tx, snapshots))
+                .context("unable to spawn dispatcher thread")?,
+        ];
+
+        Ok(Pipeline {
+            items: items_rx,
+            results,
+            num_ledgers: vec![0; num_workers],
+            threads,
+            workers,
+        })
+    }
+
+    /// Waits for all stages to finish and returns the final state of the simulation.
+    pub(crate) fn finish(self) -> Result<PipelineOutput> {
+        let Pipeline {
+            items,
+            results,
+            threads,
+            workers,
+            ..
+        } = self;
+        // Dropping these unblocks upstream stages, should we have stopped early.
+        drop(items);
+        drop(results);
+
+        let mut first_err = None;
+        for t in threads {
+            let res = t
+                .join()
+                .map_err(|_| err_msg("pipeline thread panicked"))
+                .and_then(|res| res);
+            if let Err(err) = res {
+                first_err.get_or_insert(err);
+            }
+        }
+
+        let mut engines = Vec::new();
+        let mut conn_trackers = Vec::new();
+        for w in workers {
+            match w
+                .join()
+                .map_err(|_| err_msg("worker thread panicked"))
+                .and_then(|res| res)
+            {
+                Ok((engine, conn_tracker)) => {
+                    engines.push(engine);
+                    conn_trackers.push(conn_tracker);
+                }
+                Err(err) => {
+                    first_err.get_or_insert(err);
+                }
+            }
+        }
+
+        match first_err {
+            Some(err) => Err(err),
+            None => Ok(PipelineOutput {
+                engines,
+                conn_trackers,
+            }),
+        }
+    }
+
+    fn collect_results(&mut self, len: usize) -> Result<(Vec<IngestResult>, usize)> {
+        let mut merged: Vec<Option<IngestResult>> = vec![None; len];
+        let mut missing_ledgers = 0;
+
+        for (i, results) in self.results.iter().enumerate() {
+            let res = match results.recv() {
+                Ok(WorkerOutput::Results(res)) => res,
+                Ok(WorkerOutput::Snapshot(..)) => return Err(err_msg("unexpected snapshot")),
+                Err(_) => return Err(err_msg("engine worker failed")),
+            };
+            missing_ledgers += res.missing_ledgers;
+            self.num_ledgers[i] = res.num_ledgers;
+            for (idx, res) in res.results {
+                merged[idx] = Some(res);
+            }
+        }
+
+        let merged = merged
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| err_msg("missing ingestion result"))?;
+
+        Ok((merged, missing_ledgers))
+    }
+
+    fn collect_snapshot(&mut self, message_id: i64) -> Result<SimulationState> {
+        let mut engines = Vec::new();
+        let mut conn_trackers = Vec::new();
+
+        for results in self.results.iter() {
+            match results.recv() {
+                Ok(WorkerOutput::Snapshot(engine, conn_tracker)) => {
+                    engines.push(engine);
+                    conn_trackers.push(conn_tracker);
+                }
+                Ok(WorkerOutput::Results(_)) => return Err(err_msg("expected snapshot")),
+                Err(_) => return Err(err_msg("engine worker failed")),
+            }
+        }
+
+        Ok(SimulationState {
+            message_id,
+            engines,
+            conn_trackers,
+        })
+    }
+
+    fn read(input_files: Vec<PathBuf>, lines_out: SyncSender<ReaderItem>) -> Result<()> {
+        for path in input_files {
+            if lines_out.send(ReaderItem::FileStart(path.clone())).is_err() {
+                return Ok(());
+            }
+
+            let mut infile = BufReader::new(GzDecoder::new(
+                std::fs::File::open(&path).context("unable to open input file for reading")?,
+            ));
+            let mut bytes_read = 0;
+            let mut lines = Vec::with_capacity(BATCH_SIZE);
+            loop {
+                let mut buf = String::new();
+                let n = match infile.read_line(&mut buf) {
+                    Ok(n) => n,
+                    Err(err) => {
+                        // This happens for truncated files, e.g., if the client was killed while
+                        // writing. We keep what we have and continue with the next file.
+                        warn!(
+                            "unable to read from {}, skipping rest of file: {}",
+                            path.display(),
+                            err
+                        );
+                        break;
+                    }
+                };
+                if n == 0 {
+                    break;
+                }
+                bytes_read += n;
+                lines.push(buf);
+
+                if lines.len() == BATCH_SIZE {
+                    let batch = std::mem::replace(&mut lines, Vec::with_capacity(BATCH_SIZE));
+                    if lines_out.send(ReaderItem::Lines(batch)).is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+
+            if !lines.is_empty() && lines_out.send(ReaderItem::Lines(lines)).is_err() {
+                return Ok(());
+            }
+            if lines_out
+                .send(ReaderItem::FileEnd(path, bytes_read))
+                .is_err()
+            {
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parse(
+        lines_in: Receiver<ReaderItem>,
+        messages_out: SyncSender<ParsedItem>,
+        input_format: TraceFormat,
+        mut current_message_id: i64,
+    ) -> Result<()> {
+        let mut current_path = None;
+        let mut decoder = TraceDecoder::new(input_format);
+
+        for item in lines_in {
+            let item = match item {
+                ReaderItem::FileStart(path) => {
+                    current_path = Some(path.clone());
+                    decoder.start_file();
+                    ParsedItem::FileStart(path, current_message_id)
+                }
+                ReaderItem::FileEnd(path, bytes_read) => {
+                    ParsedItem::FileEnd(path, bytes_read, current_message_id)
+                }
+                ReaderItem::Lines(lines) => {
+                    let first_message_id = current_message_id + 1;
+                    let messages = lines
+                        .iter()
+                        .map(|line| {
+                            current_message_id += 1;
+                            decoder.decode(line)
+                        })
+                        .collect::<Result<Vec<_>>>()
+                        .context(format!(
+                            "unable to decode message in {}",
+                            current_path
+                                .as_ref()
+                                .map(|p| p.display().to_string())
+                                .unwrap_or_default()
+                        ))?;
+                    ParsedItem::Messages(first_message_id, messages)
+                }
+            };
+
+            if messages_out.send(item).is_err() {
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn dispatch(
+        messages_in: Receiver<ParsedItem>,
+        workers: Vec<SyncSender<WorkerInput>>,
+        items_out: SyncSender<DispatchItem>,
+        snapshots: bool,
+    ) -> Result<()> {
+        for item in messages_in {
+            let item = match item {
+                ParsedItem::FileStart(path, message_id) => {
+                    DispatchItem::FileStart(path, message_id)
+                }
+                ParsedItem::FileEnd(path, bytes_read, message_id) => {
+                    if snapshots {
+                        for worker in workers.iter() {
+                            if worker.send(WorkerInput::Snapshot).is_err() {
+                                return Ok(());
+                            }
+                        }
+                    }
+
+                    DispatchItem::FileEnd {
+                        path,
+                        bytes_read,
+                        message_id,
+                        snapshot: snapshots,
+                    }
+                }
+                ParsedItem::Messages(first_message_id, messages) => {
+                    let len = messages.len();
+                    let first_ts = messages[0].timestamp;
+                    let last_ts = messages[len - 1].timestamp;
+
+                    let mut batches: Vec<WorkerBatch> = vec![Vec::new(); workers.len()];
+                    for (idx, msg) in messages.into_iter().enumerate() {
+                        let shard = Self::shard(&msg.peer, workers.len());
+                        batches[shard].push((idx, first_message_id + idx as i64, msg));
+                    }
+                    // Every worker gets a batch, possibly empty, to keep them in lockstep.
+                    for (batch, worker) in batches.into_iter().zip(workers.iter()) {
+                        if worker.send(WorkerInput::Batch(batch)).is_err() {
+                            // The worker failed, the error is reported when it is joined.
+                            return Ok(());
+                        }
+                    }
+
+                    DispatchItem::Messages {
+                        first_message_id,
+                        first_ts,
+                        last_ts,
+                        len,
+                    }
+                }
+            };
+
+            if items_out.send(item).is_err() {
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Assigns a peer to a worker.
+    /// The hash must be stable across releases, since the simulations of the workers are saved in
+    /// snapshots.
+    fn shard(peer: &str, num_workers: usize) -> usize {
+        let mut hasher = XxHash64::with_seed(0);
+        hasher.write(peer.as_bytes());
+        (hasher.finish() % num_workers as u64) as usize
+    }
+
+    fn simulate(
+        mut engine: EngineSimulation,
+        mut conn_tracker: ConnectionDurationTracker,
+        inputs: Receiver<WorkerInput>,
+        results_out: SyncSender<WorkerOutput>,
+    ) -> Result<(EngineSimulation, ConnectionDurationTracker)> {
+        for input in inputs {
+            let batch = match input {
+                WorkerInput::Batch(batch) => batch,
+                WorkerInput::Snapshot => {
+                    let snapshot = WorkerOutput::Snapshot(engine.clone(), conn_tracker.clone());
+                    if results_out.send(snapshot).is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            };
+
+            let mut results = Vec::with_capacity(batch.len());
+            let mut missing_ledgers = 0;
+            for (idx, message_id, message) in batch {
+                debug!("decoded message {:?}", message);
+
+                // Add to connection tracker.
+                conn_tracker
+                    .push(&message)
+                    .context("unable to track connection duration")?;
+
+                // Update simulated wantlists.
+                let ingest_result = engine
+                    .ingest(&message, message_id)
+                    .context(format!("unable to ingest message {}", message_id))?;
+                debug!("ingest result: {:?}", ingest_result);
+
+                if ingest_result.missing_ledger {
+                    missing_ledgers += 1;
+                }
+                results.push((idx, ingest_result));
+            }
+
+            let res = WorkerResult {
+                results,
+                missing_ledgers,
+                num_ledgers: engine.num_ledgers(),
+            };
+            if results_out.send(WorkerOutput::Results(res)).is_err() {
+                break;
+            }
+        }
+
+        Ok((engine, conn_tracker))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn write_input(path: &PathBuf, first_ts: i64, num_messages: usize) {
+        let mut input = String::new();
+        for i in 0..num_messages {
+            let peer = format!("peer{}", i % 7);
+            let ts = chrono::DateTime::from_timestamp(first_ts + i as i64, 0).unwrap();
+            let msg = if i < 7 {
+                serde_json::json!({
+                    "timestamp": ts, "peer": peer, "address": null, "received_entries": null,
+                    "full_want_list": null, "peer_connected": true, "peer_disconnected": false,
+                    "connect_event_peer_found": true,
+                })
+            } else {
+                serde_json::json!({
+                    "timestamp": ts, "peer": peer, "address": null,
+                    "received_entries": [{
+                        "priority": 1, "cancel": i % 3 == 0, "send_dont_have": false,
+                        "cid": { "/": format!("cid{}", i % 11) }, "want_type": 0,
+                    }],
+                    "full_want_list": i % 13 == 0, "peer_connected": null,
+                    "peer_disconnected": null, "connect_event_peer_found": null,
+                })
+            };
+            input.push_str(&format!("{}\n", msg));
+        }
+
+        let mut out = GzEncoder::new(std::fs::File::create(path).unwrap(), Compression::fast());
+        out.write_all(input.as_bytes()).unwrap();
+        out.finish().unwrap();
+    }
+
+    fn initial_state(num_workers: usize) -> SimulationState {
+        let cfg = EngineSimulationConfig {
+            insert_full_wantlist_synth_cancels: true,
+            insert_disconnect_synth_cancels: true,
+            reconnect_duplicate_duration_secs: 5,
+            sliding_window_lengths: vec![1, 10, 100],
+            ..Default::default()
+        };
+        SimulationState::new(cfg, num_workers).unwrap()
+    }
+
+    /// Runs the pipeline and returns its output, serialized for comparison, and the snapshots
+    /// taken, if enabled.
+    fn run(
+        input_files: Vec<PathBuf>,
+        state: SimulationState,
+        snapshots: bool,
+    ) -> (Vec<String>, Vec<SimulationState>) {
+        let mut pipeline =
+            Pipeline::start(input_files, TraceFormat::Auto, state, snapshots).unwrap();
+
+        let mut output = Vec::new();
+        let mut states = Vec::new();
+        for item in pipeline.by_ref() {
+            output.push(match item.unwrap() {
+                PipelineItem::FileStart { message_id, .. } => format!("start {}", message_id),
+                PipelineItem::FileEnd {
+                    bytes_read, state, ..
+                } => {
+                    states.extend(state);
+                    format!("end {}", bytes_read)
+                }
+                PipelineItem::Messages {
+                    first_message_id,
+                    results,
+                    missing_ledgers,
+                    total_ledgers,
+                    ..
+                } => format!(
+                    "{} {} {} {}",
+                    first_message_id,
+                    missing_ledgers,
+                    total_ledgers,
+                    results
+                        .iter()
+                        .map(|r| format!(
+                            "{}{}",
+                            serde_json::to_string(&r.wantlist_entries).unwrap(),
+                            serde_json::to_string(&r.connection_event).unwrap()
+                        ))
+                        .collect::<String>()
+                ),
+            });
+        }
+        let final_state = pipeline.finish().unwrap();
+        let mut end_of_simulation_entries = final_state
+            .engines
+            .into_iter()
+            .flat_map(|e| e.generate_end_of_simulation_entries(chrono::DateTime::UNIX_EPOCH, 0))
+            .map(|e| serde_json::to_string(&e).unwrap())
+            .collect::<Vec<_>>();
+        end_of_simulation_entries.sort();
+        output.push(end_of_simulation_entries.join("\n"));
+
+        (output, states)
+    }
+
+    fn write_inputs(name: &str) -> (PathBuf, Vec<PathBuf>) {
+        let dir = std::env::temp_dir().join(format!("json-to-csv-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input_files = vec![dir.join("a.json.gz"), dir.join("b.json.gz")];
+        write_input(&input_files[0], 1_600_000_000, BATCH_SIZE + 500);
+        write_input(&input_files[1], 1_700_000_000, 100);
+
+        (dir, input_files)
+    }
+
+    #[test]
+    fn output_independent_of_workers() {
+        let (dir, input_files) = write_inputs("workers");
+
+        let (sequential, _) = run(input_files.clone(), initial_state(1), false);
+        let (parallel, _) = run(input_files, initial_state(3), false);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Two files, the first one split into two batches, and the final state of the engines.
+        assert_eq!(sequential.len(), 8);
+        assert_eq!(sequential, parallel);
+    }
+
+    #[test]
+    fn resume_from_snapshot() {
+        let (dir, input_files) = write_inputs("resume");
+
+        let (uninterrupted, states) = run(input_files.clone(), initial_state(2), true);
+        assert_eq!(states.len(), 2);
+
+        // Round-trip through the snapshot format, then resume after the first file.
+        let snapshot = dir.join("snapshot.json.gz");
+        ipfs_resolver_common::snapshot::write_snapshot(&snapshot, "test", &states[0]).unwrap();
+        let state: SimulationState =
+            ipfs_resolver_common::snapshot::read_snapshot(&snapshot, "test").unwrap();
+        let (resumed, _) = run(input_files[1..].to_vec(), state, false);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(uninterrupted[4..], resumed[..]);
+    }
+
+    #[test]
+    fn truncated_file_is_skipped() {
+        let (dir, mut input_files) = write_inputs("truncated");
+        let data = std::fs::read(&input_files[1]).unwrap();
+        let truncated = dir.join("truncated.json.gz");
+        std::fs::write(&truncated, &data[..data.len() / 2]).unwrap();
+        input_files.insert(1, truncated);
+
+        let (output, _) = run(input_files, initial_state(2), false);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The truncated file is started and ended, and the last file is processed normally.
+        assert_eq!(output.iter().filter(|o| o.starts_with("start")).count(), 3);
+        assert_eq!(output.iter().filter(|o| o.starts_with("end")).count(), 3);
+    }
+
+    #[test]
+    fn shard_is_stable() {
+        // These must not change, otherwise existing snapshots become invalid.
+        let shards = ["peer0", "peer1", "peer2", "peer3"]
+            .into_iter()
+            .map(|p| Pipeline::shard(p, 16))
+            .collect::<Vec<_>>();
+        assert_eq!(shards, vec![6, 7, 4, 3]);
+    }
+}
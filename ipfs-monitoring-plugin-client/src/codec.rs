@@ -0,0 +1,431 @@
+use crate::monitoring::{
+    BitswapMessage, BlockPresence, BlockPresenceType, ConnectionEvent, EventType, PushedEvent,
+};
+use failure::{err_msg, ResultExt};
+use flate2::bufread::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use ipfs_resolver_common::wantlist::{JSONWantType, JSONWantlistEntry, JsonCID};
+use ipfs_resolver_common::Result;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::str::FromStr;
+
+pub const CONTENT_TYPE_JSON: &str = "application/json";
+pub const CONTENT_TYPE_CBOR: &str = "application/cbor";
+pub const CONTENT_ENCODING_GZIP: &str = "gzip";
+pub const CONTENT_ENCODING_ZSTD: &str = "zstd";
+
+/// The zstd compression level to use, favoring speed.
+const ZSTD_COMPRESSION_LEVEL: i32 = 3;
+
+/// Encodings for batches of events published via AMQP.
+///
+/// The codec is indicated by the `content_type` and `content_encoding` properties of each
+/// message.
+/// Messages without a `content_type` are assumed to be gzipped JSON, which is what older versions
+/// of the plugin publish.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadCodec {
+    /// A gzipped JSON array of events.
+    #[default]
+    GzipJson,
+    /// An uncompressed JSON array of events.
+    Json,
+    /// A zstd-compressed JSON array of events.
+    ZstdJson,
+    /// A CBOR array of events, with CIDs encoded in binary where possible.
+    Cbor,
+}
+
+impl PayloadCodec {
+    /// Returns the `content_type` to set for messages encoded with this codec.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            PayloadCodec::GzipJson | PayloadCodec::Json | PayloadCodec::ZstdJson => {
+                CONTENT_TYPE_JSON
+            }
+            PayloadCodec::Cbor => CONTENT_TYPE_CBOR,
+        }
+    }
+
+    /// Returns the `content_encoding` to set for messages encoded with this codec, if any.
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            PayloadCodec::GzipJson => Some(CONTENT_ENCODING_GZIP),
+            PayloadCodec::ZstdJson => Some(CONTENT_ENCODING_ZSTD),
+            PayloadCodec::Json | PayloadCodec::Cbor => None,
+        }
+    }
+
+    /// Determines the codec from the `content_type` and `content_encoding` properties of a
+    /// message, falling back to gzipped JSON if no `content_type` is set.
+    pub fn from_properties(
+        content_type: Option<&str>,
+        content_encoding: Option<&str>,
+    ) -> Result<PayloadCodec> {
+        match (content_type, content_encoding) {
+            (None, _) => Ok(PayloadCodec::GzipJson),
+            (Some(CONTENT_TYPE_JSON), None) => Ok(PayloadCodec::Json),
+            (Some(CONTENT_TYPE_JSON), Some(CONTENT_ENCODING_GZIP)) => Ok(PayloadCodec::GzipJson),
+            (Some(CONTENT_TYPE_JSON), Some(CONTENT_ENCODING_ZSTD)) => Ok(PayloadCodec::ZstdJson),
+            (Some(CONTENT_TYPE_CBOR), None) => Ok(PayloadCodec::Cbor),
+            (Some(t), e) => Err(err_msg(format!(
+                "unsupported content type {} with encoding {:?}",
+                t, e
+            ))),
+        }
+    }
+
+    /// Encodes a batch of events.
+    pub fn encode(&self, events: &[PushedEvent]) -> Result<Vec<u8>> {
+        let b = match self {
+            PayloadCodec::GzipJson => {
+                let mut e = GzEncoder::new(Vec::new(), Compression::default());
+                serde_json::to_writer(&mut e, &events)?;
+                e.finish()?
+            }
+            PayloadCodec::Json => serde_json::to_vec(&events)?,
+            PayloadCodec::ZstdJson => {
+                let mut e = zstd::Encoder::new(Vec::new(), ZSTD_COMPRESSION_LEVEL)?;
+                serde_json::to_writer(&mut e, &events)?;
+                e.finish()?
+            }
+            PayloadCodec::Cbor => {
+                let events: Vec<CompactEvent> = events.iter().map(CompactEvent::from).collect();
+                let mut b = Vec::new();
+                ciborium::into_writer(&events, &mut b).context("unable to encode CBOR")?;
+                b
+            }
+        };
+        debug!("encoded {} bytes using {:?}: {:x?}", b.len(), self, b);
+
+        Ok(b)
+    }
+
+    /// Decodes a batch of events.
+    pub fn decode(&self, payload: &[u8]) -> Result<Vec<PushedEvent>> {
+        debug!(
+            "decoding {} bytes using {:?}: {:x?}",
+            payload.len(),
+            self,
+            payload
+        );
+        match self {
+            PayloadCodec::GzipJson => {
+                serde_json::from_reader(GzDecoder::new(payload)).map_err(|err| err.into())
+            }
+            PayloadCodec::Json => serde_json::from_slice(payload).map_err(|err| err.into()),
+            PayloadCodec::ZstdJson => {
+                serde_json::from_reader(zstd::Decoder::new(payload)?).map_err(|err| err.into())
+            }
+            PayloadCodec::Cbor => {
+                let events: Vec<CompactEvent> =
+                    ciborium::from_reader(payload).context("unable to decode CBOR")?;
+                Ok(events.into_iter().map(PushedEvent::from).collect())
+            }
+        }
+    }
+}
+
+/// A CID, encoded in binary if it could be parsed, or as its string representation otherwise.
+/// These are distinguished by the CBOR major type (byte string or text string).
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+enum CompactCID {
+    Binary(#[serde(with = "serde_bytes")] Vec<u8>),
+    Text(String),
+}
+
+impl<'de> Deserialize<'de> for CompactCID {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct CompactCIDVisitor;
+
+        impl<'de> Visitor<'de> for CompactCIDVisitor {
+            type Value = CompactCID;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a binary CID or a string")
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> std::result::Result<CompactCID, E> {
+                Ok(CompactCID::Binary(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E: de::Error>(
+                self,
+                v: Vec<u8>,
+            ) -> std::result::Result<CompactCID, E> {
+                Ok(CompactCID::Binary(v))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<CompactCID, E> {
+                Ok(CompactCID::Text(v.to_string()))
+            }
+
+            fn visit_string<E: de::Error>(self, v: String) -> std::result::Result<CompactCID, E> {
+                Ok(CompactCID::Text(v))
+            }
+        }
+
+        deserializer.deserialize_any(CompactCIDVisitor)
+    }
+}
+
+impl From<&JsonCID> for CompactCID {
+    fn from(c: &JsonCID) -> Self {
+        match cid::Cid::from_str(&c.path) {
+            Ok(parsed) if parsed.to_string() == c.path => CompactCID::Binary(parsed.to_bytes()),
+            // Anything we can't reproduce exactly is passed as-is.
+            _ => CompactCID::Text(c.path.clone()),
+        }
+    }
+}
+
+impl From<CompactCID> for JsonCID {
+    fn from(c: CompactCID) -> Self {
+        let path = match c {
+            CompactCID::Binary(b) => match cid::Cid::try_from(b.as_slice()) {
+                Ok(c) => c.to_string(),
+                Err(e) => format!("invalid CID {:x?}: {}", b, e),
+            },
+            CompactCID::Text(s) => s,
+        };
+        JsonCID { path }
+    }
+}
+
+/// The compact binary representation of a `PushedEvent`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CompactEvent {
+    #[serde(with = "chrono::serde::ts_nanoseconds")]
+    timestamp: chrono::DateTime<chrono::Utc>,
+    peer: String,
+    inner: CompactEventType,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum CompactEventType {
+    BitswapMessage(CompactBitswapMessage),
+    ConnectionEvent(ConnectionEvent),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CompactBitswapMessage {
+    wantlist_entries: Vec<CompactWantlistEntry>,
+    full_wantlist: bool,
+    blocks: Vec<CompactCID>,
+    block_presences: Vec<(CompactCID, BlockPresenceType)>,
+    connected_addresses: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CompactWantlistEntry {
+    priority: i32,
+    cancel: bool,
+    send_dont_have: bool,
+    cid: CompactCID,
+    want_type: JSONWantType,
+}
+
+impl From<&PushedEvent> for CompactEvent {
+    fn from(e: &PushedEvent) -> Self {
+        let inner = match &e.inner {
+            EventType::BitswapMessage(msg) => {
+                CompactEventType::BitswapMessage(CompactBitswapMessage {
+                    wantlist_entries: msg
+                        .wantlist_entries
+                        .iter()
+                        .map(|entry| CompactWantlistEntry {
+                            priority: entry.priority,
+                            cancel: entry.cancel,
+                            send_dont_have: entry.send_dont_have,
+                            cid: CompactCID::from(&entry.cid),
+                            want_type: entry.want_type,
+                        })
+                        .collect(),
+                    full_wantlist: msg.full_wantlist,
+                    blocks: msg.blocks.iter().map(CompactCID::from).collect(),
+                    block_presences: msg
+                        .block_presences
+                        .iter()
+                        .map(|p| (CompactCID::from(&p.cid), p.block_presence_type))
+                        .collect(),
+                    connected_addresses: msg.connected_addresses.clone(),
+                })
+            }
+            EventType::ConnectionEvent(conn_event) => {
+                CompactEventType::ConnectionEvent(conn_event.clone())
+            }
+        };
+
+        CompactEvent {
+            timestamp: e.timestamp,
+            peer: e.peer.clone(),
+            inner,
+        }
+    }
+}
+
+impl From<CompactEvent> for PushedEvent {
+    fn from(e: CompactEvent) -> Self {
+        let inner = match e.inner {
+            CompactEventType::BitswapMessage(msg) => EventType::BitswapMessage(BitswapMessage {
+                wantlist_entries: msg
+                    .wantlist_entries
+                    .into_iter()
+                    .map(|entry| JSONWantlistEntry {
+                        priority: entry.priority,
+                        cancel: entry.cancel,
+                        send_dont_have: entry.send_dont_have,
+                        cid: entry.cid.into(),
+                        want_type: entry.want_type,
+                    })
+                    .collect(),
+                full_wantlist: msg.full_wantlist,
+                blocks: msg.blocks.into_iter().map(JsonCID::from).collect(),
+                block_presences: msg
+                    .block_presences
+                    .into_iter()
+                    .map(|(cid, block_presence_type)| BlockPresence {
+                        cid: cid.into(),
+                        block_presence_type,
+                    })
+                    .collect(),
+                connected_addresses: msg.connected_addresses,
+            }),
+            CompactEventType::ConnectionEvent(conn_event) => EventType::ConnectionEvent(conn_event),
+        };
+
+        PushedEvent {
+            timestamp: e.timestamp,
+            peer: e.peer,
+            inner,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitoring::ConnectionEventType;
+
+    fn test_events() -> Vec<PushedEvent> {
+        let ts = chrono::DateTime::parse_from_rfc3339("2024-01-02T03:04:05.123456789Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let peer = "12D3KooWSoLzampfxc4t3sy9z7yq1Cgzbi7zGXpV7nvt5hfeKUhR".to_string();
+        vec![
+            PushedEvent {
+                timestamp: ts,
+                peer: peer.clone(),
+                inner: EventType::ConnectionEvent(ConnectionEvent {
+                    remote: "/ip4/1.2.3.4/tcp/4001".to_string(),
+                    connection_event_type: ConnectionEventType::Connected,
+                }),
+            },
+            PushedEvent {
+                timestamp: ts,
+                peer,
+                inner: EventType::BitswapMessage(BitswapMessage {
+                    wantlist_entries: vec![JSONWantlistEntry {
+                        priority: 1,
+                        cancel: false,
+                        send_dont_have: true,
+                        cid: JsonCID {
+                            path: "QmY7Yh4UquoXHLPFo2XbhXkhBvFoPwmQUSa92pxnxjQuPU".to_string(),
+                        },
+                        want_type: JSONWantType::Have,
+                    }],
+                    full_wantlist: false,
+                    blocks: vec![JsonCID {
+                        path: "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi"
+                            .to_string(),
+                    }],
+                    block_presences: vec![BlockPresence {
+                        cid: JsonCID {
+                            path: "not a CID".to_string(),
+                        },
+                        block_presence_type: BlockPresenceType::DontHave,
+                    }],
+                    connected_addresses: vec!["/ip4/1.2.3.4/tcp/4001".to_string()],
+                }),
+            },
+        ]
+    }
+
+    #[test]
+    fn round_trip_all_codecs() {
+        let events = test_events();
+        let expected = serde_json::to_value(&events).unwrap();
+
+        for codec in [
+            PayloadCodec::GzipJson,
+            PayloadCodec::Json,
+            PayloadCodec::ZstdJson,
+            PayloadCodec::Cbor,
+        ] {
+            let encoded = codec.encode(&events).unwrap();
+            let decoded = codec.decode(&encoded).unwrap();
+            assert_eq!(
+                serde_json::to_value(&decoded).unwrap(),
+                expected,
+                "{:?}",
+                codec
+            );
+        }
+    }
+
+    #[test]
+    fn cbor_encodes_cids_in_binary() {
+        let c = JsonCID {
+            path: "QmY7Yh4UquoXHLPFo2XbhXkhBvFoPwmQUSa92pxnxjQuPU".to_string(),
+        };
+        assert!(matches!(CompactCID::from(&c), CompactCID::Binary(_)));
+
+        let c = JsonCID {
+            path: "not a CID".to_string(),
+        };
+        assert_eq!(
+            CompactCID::from(&c),
+            CompactCID::Text("not a CID".to_string())
+        );
+    }
+
+    #[test]
+    fn codec_from_properties() {
+        for codec in [
+            PayloadCodec::GzipJson,
+            PayloadCodec::Json,
+            PayloadCodec::ZstdJson,
+            PayloadCodec::Cbor,
+        ] {
+            assert_eq!(
+                PayloadCodec::from_properties(Some(codec.content_type()), codec.content_encoding())
+                    .unwrap(),
+                codec
+            );
+        }
+
+        // Older plugin versions don't set any properties.
+        assert_eq!(
+            PayloadCodec::from_properties(None, None).unwrap(),
+            PayloadCodec::GzipJson
+        );
+        assert!(PayloadCodec::from_properties(Some("text/plain"), None).is_err());
+    }
+
+    #[test]
+    fn decodes_plain_json_without_encoding() {
+        let events = test_events();
+        let payload = serde_json::to_vec(&events).unwrap();
+
+        let codec = PayloadCodec::from_properties(Some(CONTENT_TYPE_JSON), None).unwrap();
+        assert_eq!(codec, PayloadCodec::Json);
+        assert_eq!(
+            serde_json::to_value(codec.decode(&payload).unwrap()).unwrap(),
+            serde_json::to_value(&events).unwrap()
+        );
+    }
+}
@@ -0,0 +1,290 @@
+use crate::monitoring::{MonitoringItem, PushedEvent};
+use crate::replay::{self, ReplaySpeed};
+use failure::ResultExt;
+use flate2::bufread::MultiGzDecoder;
+use futures::prelude::*;
+use ipfs_resolver_common::Result;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor, ErrorKind, Read};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc::{Receiver, Sender};
+
+/// The path to pass to read from stdin instead of a file.
+pub const STDIN_PATH: &str = "-";
+
+/// The magic bytes at the start of a gzip stream.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// A source of events read from a file or stdin.
+///
+/// Input is newline-delimited JSON-encoded `PushedEvent`s, optionally gzipped.
+/// Compression is detected automatically.
+/// Events are passed on as fast as they are read, without pacing.
+/// Use a `ReplayClient` to replay recorded traces at their original pace.
+/// The stream ends at the end of the input.
+#[derive(Debug)]
+pub struct FileMonitoringSource {
+    pub remote: String,
+    msg_in: Receiver<Result<MonitoringItem>>,
+}
+
+impl Stream for FileMonitoringSource {
+    type Item = Result<MonitoringItem>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.msg_in.poll_recv(cx)
+    }
+}
+
+impl FileMonitoringSource {
+    /// Creates a new source reading from the given path, or stdin if the path is `-`.
+    /// Events are attributed to the given monitor name.
+    /// This spawns a thread to read and decode the input, and a task to batch the events.
+    pub fn new(monitor_name: &str, path: &str) -> Result<Self> {
+        let remote = if path == STDIN_PATH {
+            "stdin".to_string()
+        } else {
+            path.to_string()
+        };
+        let reader = if path == STDIN_PATH {
+            // We detect compression in the reader thread, since this blocks until input arrives.
+            None
+        } else {
+            Some(open_event_file(Path::new(path))?)
+        };
+
+        let (event_sender, event_receiver) =
+            tokio::sync::mpsc::channel(replay::MAX_REPLAY_BATCH_SIZE);
+        let (msg_sender, msg_receiver) = tokio::sync::mpsc::channel(1);
+
+        let thread_remote = remote.clone();
+        std::thread::Builder::new()
+            .name(format!("file-{}", monitor_name))
+            .spawn(move || {
+                let res = match reader {
+                    Some(reader) => Ok(reader),
+                    None => event_reader(std::io::stdin())
+                        .context("unable to read from stdin")
+                        .map_err(|err| err.into()),
+                }
+                .and_then(|reader| read_events(reader, &event_sender));
+                if let Err(err) = res {
+                    let err = err.context(format!("unable to read events from {}", thread_remote));
+                    // We ignore this error because we return immediately.
+                    let _ = event_sender.blocking_send(Err(err.into()));
+                }
+                debug!("{}: done reading", thread_remote)
+            })
+            .context("unable to spawn reader thread")?;
+
+        tokio::spawn(replay::pace_events(
+            monitor_name.to_string(),
+            ReplaySpeed::Unlimited,
+            event_receiver,
+            msg_sender,
+        ));
+
+        Ok(FileMonitoringSource {
+            remote,
+            msg_in: msg_receiver,
+        })
+    }
+}
+
+/// Opens the given file for reading events, decompressing it if necessary.
+pub(crate) fn open_event_file(path: &Path) -> Result<Box<dyn BufRead + Send>> {
+    let f = File::open(path).context("unable to open input file for reading")?;
+    let reader = event_reader(f).context("unable to read input file")?;
+
+    Ok(reader)
+}
+
+/// Wraps the given reader, decompressing its contents if they are gzipped.
+fn event_reader<R: Read + Send + 'static>(mut r: R) -> std::io::Result<Box<dyn BufRead + Send>> {
+    // Pipes may deliver the magic bytes in separate reads, so we read until we have both or the
+    // input ends, and put them back in front of the rest of the input.
+    let mut magic = [0_u8; GZIP_MAGIC.len()];
+    let mut n = 0;
+    while n < magic.len() {
+        match r.read(&mut magic[n..]) {
+            Ok(0) => break,
+            Ok(read) => n += read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    let r = BufReader::new(Cursor::new(magic[..n].to_vec()).chain(r));
+
+    if magic[..n] == GZIP_MAGIC {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(r))))
+    } else {
+        Ok(Box::new(r))
+    }
+}
+
+/// Reads newline-delimited JSON-encoded events from the given reader and passes them on, until
+/// the end of the input or until the receiver is closed.
+pub(crate) fn read_events<R: BufRead>(
+    mut reader: R,
+    events_out: &Sender<Result<PushedEvent>>,
+) -> Result<()> {
+    let mut buf = String::new();
+
+    loop {
+        buf.clear();
+        let n = reader.read_line(&mut buf).context("unable to read input")?;
+        if n == 0 {
+            return Ok(());
+        }
+        if buf.trim().is_empty() {
+            continue;
+        }
+
+        let event: PushedEvent =
+            serde_json::from_str(&buf).context("unable to deserialize event")?;
+        if events_out.blocking_send(Ok(event)).is_err() {
+            // Receiver closed, we're shutting down.
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitoring::{
+        ConnectionEvent, ConnectionEventType, EventType, RoutingKeyInformation,
+    };
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    const MONITOR_NAME: &str = "test";
+
+    fn connection_event() -> PushedEvent {
+        PushedEvent {
+            timestamp: chrono::Utc::now(),
+            peer: "12D3KooWSoLzampfxc4t3sy9z7yq1Cgzbi7zGXpV7nvt5hfeKUhR".to_string(),
+            inner: EventType::ConnectionEvent(ConnectionEvent {
+                remote: "/ip4/1.2.3.4/tcp/4001".to_string(),
+                connection_event_type: ConnectionEventType::Connected,
+            }),
+        }
+    }
+
+    fn ndjson(events: &[PushedEvent]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for event in events {
+            serde_json::to_writer(&mut buf, event).unwrap();
+            buf.push(b'\n');
+        }
+        buf
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(data).unwrap();
+        enc.finish().unwrap()
+    }
+
+    /// A reader returning at most one byte per read, like a slow pipe.
+    struct OneByteReader(Cursor<Vec<u8>>);
+
+    impl Read for OneByteReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    fn read_all(reader: Box<dyn BufRead + Send>) -> Vec<PushedEvent> {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(100);
+        read_events(reader, &sender).unwrap();
+        drop(sender);
+
+        let mut events = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            events.push(event.unwrap())
+        }
+        events
+    }
+
+    #[test]
+    fn detects_gzip_in_separate_reads() {
+        let events = vec![connection_event(), connection_event()];
+
+        let gzipped = OneByteReader(Cursor::new(gzip(&ndjson(&events))));
+        assert_eq!(read_all(event_reader(gzipped).unwrap()).len(), 2);
+
+        let plain = OneByteReader(Cursor::new(ndjson(&events)));
+        assert_eq!(read_all(event_reader(plain).unwrap()).len(), 2);
+
+        // Inputs shorter than the magic are passed on as-is.
+        let mut short = event_reader(Cursor::new(vec![0x1f])).unwrap();
+        let mut buf = Vec::new();
+        short.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, vec![0x1f]);
+        assert!(read_all(event_reader(std::io::empty()).unwrap()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn reads_plain_and_gzipped_files() {
+        let events = vec![connection_event(), connection_event(), connection_event()];
+        for (name, data) in [
+            ("plain.json", ndjson(&events)),
+            ("gzipped.json.gz", gzip(&ndjson(&events))),
+        ] {
+            let path = std::env::temp_dir().join(format!(
+                "ipfs-monitoring-plugin-client-file-test-{}-{}",
+                std::process::id(),
+                name
+            ));
+            std::fs::write(&path, data).unwrap();
+
+            let mut source =
+                FileMonitoringSource::new(MONITOR_NAME, path.to_str().unwrap()).unwrap();
+            let mut num_events = 0;
+            while let Some(item) = source.next().await {
+                match item.unwrap() {
+                    MonitoringItem::Events(key, events) => {
+                        assert_eq!(
+                            key,
+                            RoutingKeyInformation::ConnectionEvents {
+                                monitor_name: MONITOR_NAME.to_string()
+                            }
+                        );
+                        num_events += events.len();
+                    }
+                    MonitoringItem::Gap(gap) => panic!("unexpected gap {:?}", gap),
+                }
+            }
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(num_events, 3, "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_on_invalid_events() {
+        let path = std::env::temp_dir().join(format!(
+            "ipfs-monitoring-plugin-client-file-test-{}-invalid.json",
+            std::process::id()
+        ));
+        let mut data = ndjson(&[connection_event()]);
+        data.extend_from_slice(b"not an event\n");
+        std::fs::write(&path, data).unwrap();
+
+        let mut source = FileMonitoringSource::new(MONITOR_NAME, path.to_str().unwrap()).unwrap();
+        let mut items = Vec::new();
+        while let Some(item) = source.next().await {
+            items.push(item);
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(items.len(), 2);
+        assert!(items[0].is_ok());
+        assert!(items[1].is_err());
+    }
+}
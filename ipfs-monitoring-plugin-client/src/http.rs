@@ -1,30 +1,152 @@
-use failure::{err_msg, ResultExt};
+use failure::{Fail, ResultExt};
 use ipfs_resolver_common::wantlist::JsonCID;
 use ipfs_resolver_common::Result;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_repr::*;
+use std::fmt;
 use std::fmt::Debug;
+use std::time::Duration;
 
-const API_BASE_PATH: &str = "/metric_plugin/v1";
-const API_PATH_PING: &str = "/ping";
-const API_PATH_BROADCAST_WANT: &str = "/broadcast_want";
-const API_PATH_BROADCAST_CANCEL: &str = "/broadcast_cancel";
-const API_PATH_BROADCAST_WANT_CANCEL: &str = "/broadcast_want_cancel";
-const API_PATH_SAMPLE_PEER_METADATA: &str = "/sample_peer_metadata";
+pub const API_BASE_PATH: &str = "/metric_plugin/v1";
+pub const API_PATH_PING: &str = "/ping";
+pub const API_PATH_BROADCAST_WANT: &str = "/broadcast_want";
+pub const API_PATH_BROADCAST_CANCEL: &str = "/broadcast_cancel";
+pub const API_PATH_BROADCAST_WANT_CANCEL: &str = "/broadcast_want_cancel";
+pub const API_PATH_SAMPLE_PEER_METADATA: &str = "/sample_peer_metadata";
+pub const API_PATH_MONITORING_ADDRESSES: &str = "/monitoring_addresses";
 
+/// Errors returned by the `APIClient`.
+#[derive(Debug)]
+pub enum APIError {
+    /// The request could not be sent or the response could not be received, including timeouts.
+    Transport(reqwest::Error),
+
+    /// The API responded with a non-success HTTP status and no plugin-reported error.
+    Status { status: u16, body: String },
+
+    /// The plugin reported an error via the `status` and `error` fields of its response.
+    Plugin { status: i32, error: String },
+
+    /// The response could not be decoded.
+    Decode(serde_json::Error),
+
+    /// The plugin responded with neither a result nor an error.
+    MissingResult,
+}
+
+impl fmt::Display for APIError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            APIError::Transport(e) => write!(f, "unable to query API: {}", e),
+            APIError::Status { status, body } => {
+                write!(f, "API returned HTTP status {}: {}", status, body)
+            }
+            APIError::Plugin { status, error } => {
+                write!(f, "plugin returned error (status {}): {}", status, error)
+            }
+            APIError::Decode(e) => write!(f, "unable to decode response: {}", e),
+            APIError::MissingResult => write!(f, "plugin returned neither a result nor an error"),
+        }
+    }
+}
+
+impl Fail for APIError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        match self {
+            APIError::Transport(e) => Some(e),
+            APIError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl APIError {
+    /// Returns whether the error is likely transient, i.e., whether retrying makes sense.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            APIError::Transport(e) => e.is_timeout() || e.is_connect(),
+            APIError::Status { status, .. } => *status >= 500 || *status == 429,
+            APIError::Plugin { .. } | APIError::Decode(_) | APIError::MissingResult => false,
+        }
+    }
+
+    /// Returns whether the request was never sent, i.e., whether it is safe to retry requests
+    /// which are not idempotent.
+    fn is_connect(&self) -> bool {
+        matches!(self, APIError::Transport(e) if e.is_connect())
+    }
+}
+
+pub type APIResult<T> = std::result::Result<T, APIError>;
+
+/// Options for an `APIClient`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct APIClientOptions {
+    /// The timeout for each request, in milliseconds.
+    /// Defaults to 30000.
+    #[serde(default = "default_timeout_millis")]
+    pub timeout_millis: u64,
+
+    /// The number of times to retry requests which failed with a transient error.
+    /// Requests which are not idempotent, i.e., broadcasts, are only retried if they could not be
+    /// sent at all.
+    /// Defaults to 2.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+
+    /// The delay before the first retry, in milliseconds, doubled for each further retry.
+    /// Defaults to 500.
+    #[serde(default = "default_retry_backoff_millis")]
+    pub retry_backoff_millis: u64,
+}
+
+fn default_timeout_millis() -> u64 {
+    30_000
+}
+
+fn default_max_retries() -> u32 {
+    2
+}
+
+fn default_retry_backoff_millis() -> u64 {
+    500
+}
+
+impl Default for APIClientOptions {
+    fn default() -> Self {
+        APIClientOptions {
+            timeout_millis: default_timeout_millis(),
+            max_retries: default_max_retries(),
+            retry_backoff_millis: default_retry_backoff_millis(),
+        }
+    }
+}
+
+/// A client for the HTTP API of the metric exporter plugin.
 #[derive(Debug)]
 pub struct APIClient {
     base_url: reqwest::Url,
     client: reqwest::Client,
+    options: APIClientOptions,
 }
 
 impl APIClient {
     pub fn new(base_url: &str) -> Result<APIClient> {
+        Self::new_with_options(base_url, APIClientOptions::default())
+    }
+
+    pub fn new_with_options(base_url: &str, options: APIClientOptions) -> Result<APIClient> {
         let u = reqwest::Url::parse(base_url).context("unable to parse base URL")?;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(options.timeout_millis))
+            .build()
+            .context("unable to build HTTP client")?;
 
         Ok(APIClient {
             base_url: u,
-            client: reqwest::Client::new(),
+            client,
+            options,
         })
     }
 
@@ -36,56 +158,120 @@ impl APIClient {
         u
     }
 
-    pub async fn ping(&self) -> Result<()> {
-        let _ = self
-            .client
-            .get(self.build_address(API_PATH_PING))
-            .send()
-            .await
-            .context("unable to query API")?
-            .json::<JSONResponse<PingResponse>>()
-            .await
-            .context("unable to decode JSON")?
-            .into_result()?;
+    /// Performs a request, retrying on transient errors.
+    /// Requests which are not idempotent are only retried if they could not be sent.
+    async fn request<T, F>(&self, build_request: F, idempotent: bool) -> APIResult<T>
+    where
+        T: DeserializeOwned,
+        F: Fn() -> reqwest::RequestBuilder,
+    {
+        let mut attempt = 0;
+        loop {
+            let err = match Self::request_once(build_request()).await {
+                Ok(res) => return Ok(res),
+                Err(err) => err,
+            };
+
+            let retryable = if idempotent {
+                err.is_transient()
+            } else {
+                err.is_connect()
+            };
+            if !retryable || attempt >= self.options.max_retries {
+                return Err(err);
+            }
+
+            let delay = Duration::from_millis(
+                self.options
+                    .retry_backoff_millis
+                    .saturating_mul(1 << attempt.min(16)),
+            );
+            attempt += 1;
+            warn!(
+                "API request failed (attempt {}): {}, retrying in {:?}",
+                attempt, err, delay
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn request_once<T: DeserializeOwned>(req: reqwest::RequestBuilder) -> APIResult<T> {
+        let resp = req.send().await.map_err(APIError::Transport)?;
+        let status = resp.status();
+        let body = resp.bytes().await.map_err(APIError::Transport)?;
+        let parsed = serde_json::from_slice::<JSONResponse<T>>(&body);
+
+        if !status.is_success() {
+            return Err(match parsed {
+                Ok(JSONResponse {
+                    status,
+                    error: Some(error),
+                    ..
+                }) => APIError::Plugin { status, error },
+                _ => APIError::Status {
+                    status: status.as_u16(),
+                    body: String::from_utf8_lossy(&body).to_string(),
+                },
+            });
+        }
+
+        parsed.map_err(APIError::Decode)?.into_result()
+    }
+
+    pub async fn ping(&self) -> APIResult<()> {
+        let _: PingResponse = self
+            .request(|| self.client.get(self.build_address(API_PATH_PING)), true)
+            .await?;
 
         Ok(())
     }
+
+    pub async fn monitoring_addresses(&self) -> APIResult<Vec<String>> {
+        let resp: MonitoringAddressesResponse = self
+            .request(
+                || {
+                    self.client
+                        .get(self.build_address(API_PATH_MONITORING_ADDRESSES))
+                },
+                true,
+            )
+            .await?;
+
+        Ok(resp.addresses)
+    }
+
     pub async fn sample_peer_metadata(
         &self,
         only_connected: bool,
-    ) -> Result<SamplePeerMetadataResponse> {
-        let resp = self
-            .client
-            .get(self.build_address(API_PATH_SAMPLE_PEER_METADATA))
-            .query(&[("only_connected", only_connected)])
-            .send()
-            .await
-            .context("unable to query API")?
-            .json::<JSONResponse<SamplePeerMetadataResponse>>()
-            .await
-            .context("unable to decode JSON")?
-            .into_result()?;
-
-        Ok(resp)
+    ) -> APIResult<SamplePeerMetadataResponse> {
+        self.request(
+            || {
+                self.client
+                    .get(self.build_address(API_PATH_SAMPLE_PEER_METADATA))
+                    .query(&[("only_connected", only_connected)])
+            },
+            true,
+        )
+        .await
     }
 
     pub async fn broadcast_bitswap_want(
         &self,
         cids: Vec<String>,
-    ) -> Result<Vec<BroadcastBitswapWantEntry>> {
-        let resp = self
-            .client
-            .post(self.build_address(API_PATH_BROADCAST_WANT))
-            .json(&BroadcastBitswapWantRequest {
-                cids: cids.into_iter().map(|c| JsonCID { path: c }).collect(),
-            })
-            .send()
-            .await
-            .context("unable to query API")?
-            .json::<JSONResponse<BroadcastBitswapWantResponse>>()
-            .await
-            .context("unable to decode JSON")?
-            .into_result()?;
+    ) -> APIResult<Vec<BroadcastBitswapWantEntry>> {
+        let req = BroadcastBitswapWantRequest {
+            cids: cids.into_iter().map(|c| JsonCID { path: c }).collect(),
+        };
+        let resp: BroadcastBitswapWantResponse = self
+            .request(
+                || {
+                    self.client
+                        .post(self.build_address(API_PATH_BROADCAST_WANT))
+                        .json(&req)
+                },
+                false,
+            )
+            .await?;
 
         Ok(resp.peers)
     }
@@ -93,20 +279,20 @@ impl APIClient {
     pub async fn broadcast_bitswap_cancel(
         &self,
         cids: Vec<String>,
-    ) -> Result<Vec<BroadcastBitswapCancelEntry>> {
-        let resp = self
-            .client
-            .post(self.build_address(API_PATH_BROADCAST_CANCEL))
-            .json(&BroadcastBitswapCancelRequest {
-                cids: cids.into_iter().map(|c| JsonCID { path: c }).collect(),
-            })
-            .send()
-            .await
-            .context("unable to query API")?
-            .json::<JSONResponse<BroadcastBitswapCancelResponse>>()
-            .await
-            .context("unable to decode JSON")?
-            .into_result()?;
+    ) -> APIResult<Vec<BroadcastBitswapCancelEntry>> {
+        let req = BroadcastBitswapCancelRequest {
+            cids: cids.into_iter().map(|c| JsonCID { path: c }).collect(),
+        };
+        let resp: BroadcastBitswapCancelResponse = self
+            .request(
+                || {
+                    self.client
+                        .post(self.build_address(API_PATH_BROADCAST_CANCEL))
+                        .json(&req)
+                },
+                false,
+            )
+            .await?;
 
         Ok(resp.peers)
     }
@@ -115,21 +301,21 @@ impl APIClient {
         &self,
         cids: Vec<String>,
         seconds_before_cancel: u32,
-    ) -> Result<Vec<BroadcastBitswapWantCancelEntry>> {
-        let resp = self
-            .client
-            .post(self.build_address(API_PATH_BROADCAST_WANT_CANCEL))
-            .json(&BroadcastBitswapWantCancelRequest {
-                cids: cids.into_iter().map(|c| JsonCID { path: c }).collect(),
-                seconds_before_cancel,
-            })
-            .send()
-            .await
-            .context("unable to query API")?
-            .json::<JSONResponse<BroadcastBitswapWantCancelResponse>>()
-            .await
-            .context("unable to decode JSON")?
-            .into_result()?;
+    ) -> APIResult<Vec<BroadcastBitswapWantCancelEntry>> {
+        let req = BroadcastBitswapWantCancelRequest {
+            cids: cids.into_iter().map(|c| JsonCID { path: c }).collect(),
+            seconds_before_cancel,
+        };
+        let resp: BroadcastBitswapWantCancelResponse = self
+            .request(
+                || {
+                    self.client
+                        .post(self.build_address(API_PATH_BROADCAST_WANT_CANCEL))
+                        .json(&req)
+                },
+                false,
+            )
+            .await?;
 
         Ok(resp.peers)
     }
@@ -151,7 +337,7 @@ pub struct BroadcastBitswapWantCancelRequest {
     seconds_before_cancel: u32,
 }
 
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct JSONResponse<T> {
     pub status: i32,
     pub result: Option<T>,
@@ -159,15 +345,18 @@ pub struct JSONResponse<T> {
 }
 
 impl<T> JSONResponse<T> {
-    fn into_result(self) -> Result<T> {
-        if let Some(err) = self.error {
-            return Err(err_msg(format!("remote returned error: {}", err)));
+    fn into_result(self) -> APIResult<T> {
+        if let Some(error) = self.error {
+            return Err(APIError::Plugin {
+                status: self.status,
+                error,
+            });
         }
         if let Some(resp) = self.result {
             return Ok(resp);
         }
 
-        return Err(err_msg("remote returned neither a response nor an error"));
+        Err(APIError::MissingResult)
     }
 }
 
@@ -176,22 +365,22 @@ pub struct PingResponse {}
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct MonitoringAddressesResponse {
-    addresses: Vec<String>,
+    pub addresses: Vec<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct BroadcastBitswapWantResponse {
-    peers: Vec<BroadcastBitswapWantEntry>,
+    pub peers: Vec<BroadcastBitswapWantEntry>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct BroadcastBitswapCancelResponse {
-    peers: Vec<BroadcastBitswapCancelEntry>,
+    pub peers: Vec<BroadcastBitswapCancelEntry>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct BroadcastBitswapWantCancelResponse {
-    peers: Vec<BroadcastBitswapWantCancelEntry>,
+    pub peers: Vec<BroadcastBitswapWantCancelEntry>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -291,3 +480,231 @@ pub struct PeerMetadataEntry {
     /// A list of multiaddresses to which we currently hold a connection.
     pub connected_multiaddresses: Option<Vec<String>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Method, Request, Response, Server, StatusCode};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Clone, Copy)]
+    enum Behavior {
+        Ok,
+        PluginError,
+        Unavailable,
+        Slow,
+    }
+
+    const TS: &str = "2024-01-02T03:04:05Z";
+
+    fn ok_result(method: &Method, path: &str) -> Option<serde_json::Value> {
+        let path = path.strip_prefix(API_BASE_PATH)?;
+        let sent = serde_json::json!({
+            "timestamp_before_send": TS,
+            "send_duration_millis": 3,
+            "error": null,
+        });
+        let want_sent = serde_json::json!({
+            "timestamp_before_send": TS,
+            "send_duration_millis": 3,
+            "error": null,
+            "request_type_sent": TCP_BITSWAP_REQUEST_TYPE_HAVE,
+        });
+
+        let res = match (method, path) {
+            (&Method::GET, API_PATH_PING) => serde_json::json!({}),
+            (&Method::GET, API_PATH_MONITORING_ADDRESSES) => {
+                serde_json::json!({ "addresses": ["amqp://localhost:5672"] })
+            }
+            (&Method::GET, API_PATH_SAMPLE_PEER_METADATA) => serde_json::json!({
+                "timestamp": TS,
+                "num_connections": 1,
+                "peer_metadata": [{
+                    "peer_id": "peer",
+                    "connectedness": 1,
+                    "multiaddresses": [],
+                    "protocols": null,
+                    "agent_version": "go-ipfs",
+                    "latency_ewma_ns": null,
+                    "connected_multiaddresses": null,
+                }],
+            }),
+            (&Method::POST, API_PATH_BROADCAST_WANT) => {
+                let mut entry = want_sent;
+                entry["peer"] = "peer".into();
+                serde_json::json!({ "peers": [entry] })
+            }
+            (&Method::POST, API_PATH_BROADCAST_CANCEL) => {
+                let mut entry = sent;
+                entry["peer"] = "peer".into();
+                serde_json::json!({ "peers": [entry] })
+            }
+            (&Method::POST, API_PATH_BROADCAST_WANT_CANCEL) => serde_json::json!({
+                "peers": [{
+                    "peer": "peer",
+                    "want_status": want_sent,
+                    "cancel_status": sent,
+                }],
+            }),
+            _ => return None,
+        };
+
+        Some(res)
+    }
+
+    async fn handle(
+        behavior: Behavior,
+        requests: Arc<AtomicUsize>,
+        req: Request<Body>,
+    ) -> std::result::Result<Response<Body>, Infallible> {
+        requests.fetch_add(1, Ordering::SeqCst);
+
+        let (status, body) = match behavior {
+            Behavior::Unavailable => (StatusCode::SERVICE_UNAVAILABLE, "unavailable".to_string()),
+            Behavior::PluginError => (
+                StatusCode::BAD_REQUEST,
+                serde_json::json!({ "status": 400, "result": null, "error": "no CIDs" })
+                    .to_string(),
+            ),
+            Behavior::Slow => {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                (StatusCode::OK, "{}".to_string())
+            }
+            Behavior::Ok => match ok_result(req.method(), req.uri().path()) {
+                Some(result) => (
+                    StatusCode::OK,
+                    serde_json::json!({ "status": 200, "result": result, "error": null })
+                        .to_string(),
+                ),
+                None => (StatusCode::NOT_FOUND, "not found".to_string()),
+            },
+        };
+
+        Ok(Response::builder()
+            .status(status)
+            .body(Body::from(body))
+            .unwrap())
+    }
+
+    /// Starts a mock plugin API, returning its address and a counter of requests served.
+    fn mock_api(behavior: Behavior) -> (SocketAddr, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let make_svc = make_service_fn(move |_| {
+            let requests = counter.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    handle(behavior, requests.clone(), req)
+                }))
+            }
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        (addr, requests)
+    }
+
+    fn client(addr: SocketAddr) -> APIClient {
+        APIClient::new_with_options(
+            &format!("http://{}", addr),
+            APIClientOptions {
+                timeout_millis: 500,
+                max_retries: 2,
+                retry_backoff_millis: 10,
+            },
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn all_endpoints() {
+        let (addr, requests) = mock_api(Behavior::Ok);
+        let c = client(addr);
+        let cids = vec!["QmPZ9gcCEpqKTo6aq61g2nXGUhM4iCL3ewB6LDXZCtioEB".to_string()];
+
+        c.ping().await.unwrap();
+        assert_eq!(
+            c.monitoring_addresses().await.unwrap(),
+            vec!["amqp://localhost:5672".to_string()]
+        );
+
+        let metadata = c.sample_peer_metadata(true).await.unwrap();
+        assert_eq!(metadata.num_connections, 1);
+        assert_eq!(
+            metadata.peer_metadata[0].connectedness,
+            PeerMetadataConnectedness::Connected
+        );
+
+        let want = c.broadcast_bitswap_want(cids.clone()).await.unwrap();
+        assert_eq!(
+            want[0].request_type_sent,
+            Some(TCP_BITSWAP_REQUEST_TYPE_HAVE)
+        );
+        let cancel = c.broadcast_bitswap_cancel(cids.clone()).await.unwrap();
+        assert_eq!(cancel[0].send_duration_millis, 3);
+        let want_cancel = c.broadcast_bitswap_want_cancel(cids, 5).await.unwrap();
+        assert_eq!(want_cancel[0].peer, "peer");
+
+        assert_eq!(requests.load(Ordering::SeqCst), 6);
+    }
+
+    #[tokio::test]
+    async fn plugin_error() {
+        let (addr, requests) = mock_api(Behavior::PluginError);
+        let c = client(addr);
+
+        match c.broadcast_bitswap_want(vec![]).await {
+            Err(APIError::Plugin { status, error }) => {
+                assert_eq!(status, 400);
+                assert_eq!(error, "no CIDs");
+            }
+            res => panic!("expected plugin error, got {:?}", res),
+        }
+        // Plugin errors are not transient.
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn status_error_is_retried() {
+        let (addr, requests) = mock_api(Behavior::Unavailable);
+        let c = client(addr);
+
+        match c.ping().await {
+            Err(APIError::Status { status, body }) => {
+                assert_eq!(status, 503);
+                assert_eq!(body, "unavailable");
+            }
+            res => panic!("expected status error, got {:?}", res),
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // Broadcasts were sent, so they are not retried.
+        assert!(c.broadcast_bitswap_cancel(vec![]).await.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let (addr, _) = mock_api(Behavior::Slow);
+        let c = APIClient::new_with_options(
+            &format!("http://{}", addr),
+            APIClientOptions {
+                timeout_millis: 100,
+                max_retries: 0,
+                retry_backoff_millis: 10,
+            },
+        )
+        .unwrap();
+
+        match c.ping().await {
+            Err(err @ APIError::Transport(_)) => assert!(err.is_transient()),
+            res => panic!("expected transport error, got {:?}", res),
+        }
+    }
+}
@@ -3,5 +3,10 @@
 #[macro_use]
 extern crate log;
 
+pub mod codec;
+pub mod file;
 pub mod http;
 pub mod monitoring;
+pub mod replay;
+pub mod source;
+pub mod tcp;
@@ -1,25 +1,32 @@
-use bytes::Buf;
+use crate::codec::PayloadCodec;
 use failure::err_msg;
 use failure::ResultExt;
-use flate2::bufread::GzDecoder;
-use flate2::write::GzEncoder;
-use flate2::Compression;
 use futures::prelude::*;
-use ipfs_resolver_common::wantlist::{JSONWantlistEntry, JsonCID};
 use ipfs_resolver_common::Result;
 use lapin::message::Delivery;
 use lapin::options::{
-    BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions,
+    BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions, BasicQosOptions,
     ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
 };
-use lapin::types::FieldTable;
 use lapin::types::ShortString;
+use lapin::types::{AMQPValue, FieldTable};
 use lapin::{BasicProperties, Channel, Connection, ConnectionProperties, Consumer, ExchangeKind};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use serde_repr::*;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::select;
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+// The events are defined in the common package, such that traces can be ingested by tools which
+// don't depend on this.
+pub use ipfs_resolver_common::events::{
+    BitswapMessage, BlockPresence, BlockPresenceType, ConnectionEvent, ConnectionEventType,
+    EventType, PushedEvent,
+};
 
 pub const ROUTING_KEY_PREFIX_MONITOR: &str = "monitor";
 pub const ROUTING_KEY_SUFFIX_BITSWAP_MESSAGES: &str = "bitswap_messages";
@@ -51,8 +58,17 @@ async fn set_up_exchange(c: &Channel) -> Result<()> {
 async fn publish_message(
     c: &Channel,
     routing_key: &RoutingKeyInformation,
+    codec: PayloadCodec,
+    expiration_millis: u32,
     payload: &[u8],
 ) -> Result<()> {
+    let mut properties = BasicProperties::default()
+        .with_expiration(ShortString::from(expiration_millis.to_string()))
+        .with_content_type(ShortString::from(codec.content_type()));
+    if let Some(encoding) = codec.content_encoding() {
+        properties = properties.with_content_encoding(ShortString::from(encoding));
+    }
+
     c.basic_publish(
         EXCHANGE_NAME_PASSIVE_MONITORING,
         &routing_key.to_routing_key(),
@@ -63,22 +79,80 @@ async fn publish_message(
             immediate: false,
         },
         payload,
-        BasicProperties::default().with_expiration(ShortString::from("60000")),
+        properties,
     )
     .await?;
     Ok(())
 }
 
-async fn set_up_queue_and_subscribe(c: &Channel, routing_keys: &[String]) -> Result<Consumer> {
-    let queue = c
-        .queue_declare(
-            "",
+/// Declares the queue for the given mode, binds it with the given routing keys, and starts
+/// consuming from it.
+/// Returns the consumer and whether the queue existed already, i.e., whether we are resuming
+/// consumption of a shared queue.
+async fn set_up_queue_and_subscribe(
+    conn: &Connection,
+    c: &Channel,
+    routing_keys: &[String],
+    options: &MonitoringClientOptions,
+) -> Result<(Consumer, bool)> {
+    let mut arguments = FieldTable::default();
+    if let Some(ttl) = options.message_ttl_millis {
+        arguments.insert(
+            ShortString::from("x-message-ttl"),
+            AMQPValue::LongLongInt(ttl as i64),
+        );
+    }
+
+    let (queue_name, declare_options, exists) = match &options.queue {
+        QueueMode::Exclusive => (
+            "".to_string(),
             QueueDeclareOptions {
                 exclusive: true,
                 ..Default::default()
             },
-            FieldTable::default(),
-        )
+            false,
+        ),
+        QueueMode::Shared { name, durable } => {
+            // A failed passive declaration closes the channel, so we use a throwaway one.
+            let probe = conn
+                .create_channel()
+                .await
+                .context("unable to set up AMQP channel")?;
+            let exists = probe
+                .queue_declare(
+                    name,
+                    QueueDeclareOptions {
+                        passive: true,
+                        ..Default::default()
+                    },
+                    FieldTable::default(),
+                )
+                .await
+                .is_ok();
+            if probe.status().connected() {
+                // We ignore this error because the channel is unused.
+                let _ = probe.close(200, "OK").await;
+            }
+
+            (
+                name.clone(),
+                QueueDeclareOptions {
+                    durable: *durable,
+                    ..Default::default()
+                },
+                exists,
+            )
+        }
+    };
+
+    if let Some(prefetch_count) = options.prefetch_count {
+        c.basic_qos(prefetch_count, BasicQosOptions::default())
+            .await
+            .context("unable to set prefetch count")?;
+    }
+
+    let queue = c
+        .queue_declare(&queue_name, declare_options, arguments)
         .await
         .context("unable to declare queue")?;
 
@@ -102,7 +176,8 @@ async fn set_up_queue_and_subscribe(c: &Channel, routing_keys: &[String]) -> Res
             BasicConsumeOptions {
                 no_local: false,
                 no_ack: false,
-                exclusive: true,
+                // Shared queues are consumed by competing consumers.
+                exclusive: matches!(options.queue, QueueMode::Exclusive),
                 nowait: false,
             },
             FieldTable::default(),
@@ -110,22 +185,38 @@ async fn set_up_queue_and_subscribe(c: &Channel, routing_keys: &[String]) -> Res
         .await
         .context("unable to basic.consume")?;
 
-    Ok(consumer)
+    Ok((consumer, exists))
 }
 
-fn decode_messages(payload: Vec<u8>) -> Result<Vec<PushedEvent>> {
-    debug!("decoding {} bytes: {:x?}", payload.len(), payload);
-    serde_json::from_reader(GzDecoder::new(std::io::Cursor::new(payload).reader()))
-        .map_err(|err| err.into())
+/// Decodes a delivery using the codec indicated by its properties.
+fn decode_messages(properties: &BasicProperties, payload: &[u8]) -> Result<Vec<PushedEvent>> {
+    let codec = PayloadCodec::from_properties(
+        properties.content_type().as_ref().map(|t| t.as_str()),
+        properties.content_encoding().as_ref().map(|e| e.as_str()),
+    )?;
+    codec.decode(payload)
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum RoutingKeyInformation {
     ConnectionEvents { monitor_name: String },
     BitswapMessages { monitor_name: String },
 }
 
 impl RoutingKeyInformation {
+    /// Derives the routing key under which the given event would have been published by the
+    /// monitor with the given name.
+    pub fn for_event(monitor_name: &str, event: &PushedEvent) -> RoutingKeyInformation {
+        match &event.inner {
+            EventType::BitswapMessage(_) => RoutingKeyInformation::BitswapMessages {
+                monitor_name: monitor_name.to_string(),
+            },
+            EventType::ConnectionEvent(_) => RoutingKeyInformation::ConnectionEvents {
+                monitor_name: monitor_name.to_string(),
+            },
+        }
+    }
+
     fn to_routing_key(&self) -> String {
         match self {
             RoutingKeyInformation::ConnectionEvents { monitor_name } => {
@@ -173,23 +264,373 @@ fn decode_routing_key(routing_key: &str) -> Result<RoutingKeyInformation> {
     }
 }
 
-fn encode_messages(msgs: &[PushedEvent]) -> Result<Vec<u8>> {
-    let mut e = GzEncoder::new(Vec::new(), Compression::default());
-    serde_json::to_writer(&mut e, &msgs)?;
-    let b: Vec<u8> = e.finish()?;
-    debug!("encoded {} bytes: {:x?}", b.len(), b);
-    Ok(b)
+/// An item produced by a `MonitoringClient`.
+#[derive(Clone, Debug)]
+pub enum MonitoringItem {
+    /// A batch of events received with the given routing key.
+    Events(RoutingKeyInformation, Vec<PushedEvent>),
+    /// The connection to the AMQP server was lost and later re-established.
+    /// Events published in between were not received.
+    /// This is not reported when resuming a shared queue which outlived the connection.
+    Gap(ConnectionGap),
+}
+
+/// A period during which a `MonitoringClient` was not connected and data is missing.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConnectionGap {
+    pub disconnected_at: chrono::DateTime<chrono::Utc>,
+    pub reconnected_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl ConnectionGap {
+    /// Returns the length of the gap.
+    pub fn duration(&self) -> chrono::Duration {
+        self.reconnected_at - self.disconnected_at
+    }
+}
+
+/// Options for a `MonitoringClient`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MonitoringClientOptions {
+    /// If set, the client re-establishes lost connections according to this configuration and
+    /// reports the resulting gaps in the stream.
+    /// If not set, the stream produces an error and ends when the connection is lost.
+    #[serde(default)]
+    pub reconnect: Option<ReconnectConfig>,
+
+    /// Whether to reject and skip deliveries that cannot be decoded.
+    /// If not set, an undecodable delivery is rejected and fails the connection, which is then
+    /// re-established or ends the stream, depending on `reconnect`.
+    /// Defaults to false.
+    #[serde(default)]
+    pub skip_undecodable: bool,
+
+    /// The codec to use for publishing events.
+    /// Incoming events are decoded according to the codec indicated by each message.
+    /// Defaults to gzipped JSON, which all versions of the plugin understand.
+    #[serde(default)]
+    pub publish_codec: PayloadCodec,
+
+    /// The expiration to set on published events, in milliseconds.
+    /// Defaults to 60000.
+    #[serde(default = "default_publish_expiration_millis")]
+    pub publish_expiration_millis: u32,
+
+    /// The kind of queue to consume events from.
+    /// Defaults to an exclusive queue.
+    #[serde(default)]
+    pub queue: QueueMode,
+
+    /// The maximum number of unacknowledged deliveries to receive at once.
+    /// If not set, the server's default applies, which is usually unlimited.
+    #[serde(default)]
+    pub prefetch_count: Option<u16>,
+
+    /// The time after which events in the queue expire if not consumed, in milliseconds.
+    /// Note that the server rejects declaring an existing queue with a different TTL.
+    /// If not set, events in the queue do not expire, apart from their per-message expiration.
+    #[serde(default)]
+    pub message_ttl_millis: Option<u32>,
+}
+
+impl Default for MonitoringClientOptions {
+    fn default() -> Self {
+        MonitoringClientOptions {
+            reconnect: None,
+            skip_undecodable: false,
+            publish_codec: PayloadCodec::default(),
+            publish_expiration_millis: default_publish_expiration_millis(),
+            queue: QueueMode::default(),
+            prefetch_count: None,
+            message_ttl_millis: None,
+        }
+    }
+}
+
+fn default_publish_expiration_millis() -> u32 {
+    60_000
+}
+
+/// The kind of queue a `MonitoringClient` consumes events from.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueMode {
+    /// An exclusive, auto-named queue, which is deleted when the client disconnects.
+    /// Events published while disconnected are lost.
+    #[default]
+    Exclusive,
+    /// A named queue, which outlives the client's connection.
+    /// All clients using the same name share the queue as competing consumers, i.e., each event
+    /// is delivered to only one of them.
+    /// Events published while disconnected are kept in the queue, subject to their expiration.
+    /// A durable queue also survives restarts of the server.
+    Shared {
+        name: String,
+        #[serde(default)]
+        durable: bool,
+    },
+}
+
+/// Configures exponential backoff for reconnection attempts.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReconnectConfig {
+    /// The delay before the first reconnection attempt, in milliseconds.
+    /// Defaults to 500.
+    #[serde(default = "default_initial_backoff_millis")]
+    pub initial_backoff_millis: u64,
+
+    /// The maximum delay between reconnection attempts, in milliseconds.
+    /// Defaults to 60000.
+    #[serde(default = "default_max_backoff_millis")]
+    pub max_backoff_millis: u64,
+
+    /// The factor by which the delay grows with each failed attempt.
+    /// Defaults to 2.
+    #[serde(default = "default_backoff_multiplier")]
+    pub backoff_multiplier: f64,
+
+    /// The fraction by which each delay is randomly varied, in [0,1].
+    /// Defaults to 0.2, i.e., delays vary by up to 20% in either direction.
+    #[serde(default = "default_backoff_jitter")]
+    pub jitter: f64,
+
+    /// The maximum number of consecutive failed attempts before giving up.
+    /// If not set, reconnection is attempted indefinitely.
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+
+    /// The minimum lifetime of a connection, in milliseconds, for it to be considered stable.
+    /// A connection lost before is only re-established after a delay, which grows with each
+    /// consecutive short-lived connection, as if the attempts had failed.
+    /// This avoids reconnecting in a tight loop to a server which accepts and then drops
+    /// connections.
+    /// Defaults to 10000.
+    #[serde(default = "default_stable_connection_millis")]
+    pub stable_connection_millis: u64,
+}
+
+fn default_initial_backoff_millis() -> u64 {
+    500
+}
+
+fn default_max_backoff_millis() -> u64 {
+    60_000
+}
+
+fn default_backoff_multiplier() -> f64 {
+    2_f64
+}
+
+fn default_backoff_jitter() -> f64 {
+    0.2
+}
+
+fn default_stable_connection_millis() -> u64 {
+    10_000
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_backoff_millis: default_initial_backoff_millis(),
+            max_backoff_millis: default_max_backoff_millis(),
+            backoff_multiplier: default_backoff_multiplier(),
+            jitter: default_backoff_jitter(),
+            max_attempts: None,
+            stable_connection_millis: default_stable_connection_millis(),
+        }
+    }
+}
+
+impl ReconnectConfig {
+    /// Runs the given connection attempt until it succeeds or the maximum number of attempts is
+    /// exceeded.
+    pub(crate) async fn retry<F, Fut, T>(
+        &self,
+        remote: &str,
+        mut attempt_connection: F,
+    ) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match attempt_connection().await {
+                Ok(res) => return Ok(res),
+                Err(err) => {
+                    attempt += 1;
+                    if let Some(max_attempts) = self.max_attempts {
+                        if attempt >= max_attempts {
+                            return Err(err
+                                .context(format!("giving up after {} attempts", attempt))
+                                .into());
+                        }
+                    }
+                    let delay = self.backoff(attempt - 1);
+                    warn!(
+                        "unable to connect to {} (attempt {}): {}, retrying in {:?}",
+                        remote, attempt, err, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    /// Computes the delay before the given (zero-based) attempt, including jitter.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let base = (self.initial_backoff_millis as f64)
+            * self
+                .backoff_multiplier
+                .max(1_f64)
+                .powi(attempt.min(64) as i32);
+        let base = base.min(self.max_backoff_millis as f64);
+        let jitter = self.jitter.clamp(0_f64, 1_f64);
+        let factor = if jitter > 0_f64 {
+            rand::thread_rng().gen_range(1_f64 - jitter..=1_f64 + jitter)
+        } else {
+            1_f64
+        };
+
+        Duration::from_millis((base * factor) as u64)
+    }
+}
+
+/// The reason a consumer stopped delivering messages.
+pub(crate) enum ConsumerExit {
+    /// The receiving end of the client was dropped.
+    SubscriberGone,
+    /// The consumer or connection was closed by the remote end.
+    ConsumerClosed,
+    /// The connection or consumer failed.
+    Failed(failure::Error),
+}
+
+/// Re-establishes lost connections to a monitor, as configured, and reports the resulting gaps.
+/// This is shared by the AMQP and TCP clients.
+pub(crate) struct Reconnector {
+    remote: String,
+    reconnect: Option<ReconnectConfig>,
+    /// When the current connection was established.
+    connected_at: Instant,
+    /// The number of consecutive connections which were lost before becoming stable.
+    short_lived_connections: u32,
+}
+
+impl Reconnector {
+    /// Creates a reconnector for a connection to the given remote which was just established.
+    pub(crate) fn new(remote: &str, reconnect: Option<ReconnectConfig>) -> Reconnector {
+        Reconnector {
+            remote: remote.to_string(),
+            reconnect,
+            connected_at: Instant::now(),
+            short_lived_connections: 0,
+        }
+    }
+
+    /// Handles the exit of a consumer of the current connection.
+    /// If reconnection is configured and the subscriber is still around, the connection is
+    /// re-established using the given connection attempt, which also returns whether the
+    /// connection resumed where the previous one left off.
+    /// Unless it did, a gap is reported to the subscriber.
+    /// Returns `None` if the caller should quit, after passing on any error to the subscriber.
+    pub(crate) async fn reconnect<F, Fut, T>(
+        &mut self,
+        exit: ConsumerExit,
+        msg_out: &Sender<Result<MonitoringItem>>,
+        attempt_connection: F,
+    ) -> Option<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<(T, bool)>>,
+    {
+        let addr = &self.remote;
+        let (err, cfg) = match (exit, &self.reconnect) {
+            (ConsumerExit::SubscriberGone, _) => {
+                debug!("{}: subscriber gone, quitting", addr);
+                return None;
+            }
+            (ConsumerExit::ConsumerClosed, None) => {
+                debug!("{}: connection closed, quitting", addr);
+                return None;
+            }
+            (ConsumerExit::Failed(err), None) => {
+                // We ignore this error because we return immediately.
+                let _ = msg_out.send(Err(err)).await;
+                return None;
+            }
+            (ConsumerExit::ConsumerClosed, Some(cfg)) => {
+                (err_msg("connection closed by remote"), cfg)
+            }
+            (ConsumerExit::Failed(err), Some(cfg)) => (err, cfg),
+        };
+
+        let disconnected_at = chrono::Utc::now();
+        let delay =
+            if self.connected_at.elapsed() < Duration::from_millis(cfg.stable_connection_millis) {
+                self.short_lived_connections += 1;
+                cfg.backoff(self.short_lived_connections - 1)
+            } else {
+                self.short_lived_connections = 0;
+                Duration::ZERO
+            };
+        warn!(
+            "{}: connection lost: {}, reconnecting in {:?}...",
+            addr, err, delay
+        );
+        let res = select! {
+            _ = msg_out.closed() => {
+                debug!("{}: subscriber gone while reconnecting, quitting", addr);
+                return None;
+            }
+            res = async {
+                tokio::time::sleep(delay).await;
+                cfg.retry(addr, attempt_connection).await
+            } => res
+        };
+        match res {
+            Err(err) => {
+                error!("{}: unable to reconnect: {}", addr, err);
+                // We ignore this error because we return immediately.
+                let _ = msg_out.send(Err(err)).await;
+                None
+            }
+            Ok((conn, resumed)) => {
+                self.connected_at = Instant::now();
+                let gap = ConnectionGap {
+                    disconnected_at,
+                    reconnected_at: chrono::Utc::now(),
+                };
+                info!(
+                    "{}: reconnected after {}ms",
+                    addr,
+                    gap.duration().num_milliseconds()
+                );
+                if resumed {
+                    // Events published in the meantime were kept by the remote.
+                    info!("{}: resumed where the previous connection left off", addr);
+                } else if msg_out.send(Ok(MonitoringItem::Gap(gap))).await.is_err() {
+                    debug!("{}: unable to report gap, quitting", addr);
+                    return None;
+                }
+                Some(conn)
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct MonitoringClient {
     pub remote: String,
-    chan: Channel,
-    msg_in: Receiver<Result<(RoutingKeyInformation, Vec<PushedEvent>)>>,
+    publish_codec: PayloadCodec,
+    publish_expiration_millis: u32,
+    chan: watch::Receiver<Channel>,
+    msg_in: Receiver<Result<MonitoringItem>>,
 }
 
 impl Stream for MonitoringClient {
-    type Item = Result<(RoutingKeyInformation, Vec<PushedEvent>)>;
+    type Item = Result<MonitoringItem>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         self.msg_in.poll_recv(cx)
@@ -197,40 +638,57 @@ impl Stream for MonitoringClient {
 }
 
 impl MonitoringClient {
+    /// Connects to the given AMQP server and subscribes to the given routing keys.
+    /// The connection is not re-established if it fails.
     pub async fn new(
         addr: &str,
         routing_keys: &[RoutingKeyInformation],
     ) -> Result<MonitoringClient> {
-        let conn = connect(addr)
-            .await
-            .context("unable to connect to RabbitMQ")?;
-
-        let chan = conn
-            .create_channel()
-            .await
-            .context("unable to set up AMQP channel")?;
+        Self::new_with_options(addr, routing_keys, MonitoringClientOptions::default()).await
+    }
 
-        set_up_exchange(&chan)
-            .await
-            .context("unable to set up exchange")?;
+    /// Connects to the given AMQP server and subscribes to the given routing keys.
+    /// If reconnection is configured, the initial connection is also attempted with backoff.
+    pub async fn new_with_options(
+        addr: &str,
+        routing_keys: &[RoutingKeyInformation],
+        options: MonitoringClientOptions,
+    ) -> Result<MonitoringClient> {
+        let routing_keys = routing_keys
+            .iter()
+            .map(|k| k.to_routing_key())
+            .collect::<Vec<_>>();
 
-        let consumer = set_up_queue_and_subscribe(
-            &chan,
-            &routing_keys
-                .iter()
-                .map(|k| k.to_routing_key())
-                .collect::<Vec<_>>(),
-        )
-        .await
-        .context("unable to set up queue and subscribe")?;
+        let (chan, consumer, resumed) = match &options.reconnect {
+            None => Self::establish(addr, &routing_keys, &options).await?,
+            Some(cfg) => {
+                cfg.retry(addr, || Self::establish(addr, &routing_keys, &options))
+                    .await?
+            }
+        };
+        if resumed {
+            info!("{}: resuming consumption from existing queue", addr);
+        }
 
+        let publish_codec = options.publish_codec;
+        let publish_expiration_millis = options.publish_expiration_millis;
+        let (chan_sender, chan_receiver) = watch::channel(chan);
         let (msg_sender, msg_receiver) = tokio::sync::mpsc::channel(1);
 
-        tokio::spawn(Self::process_incoming_messages(consumer, msg_sender));
+        tokio::spawn(Self::run(
+            addr.to_string(),
+            routing_keys,
+            options,
+            consumer,
+            chan_sender,
+            msg_sender,
+        ));
 
         Ok(MonitoringClient {
             remote: addr.to_string(),
-            chan,
+            publish_codec,
+            publish_expiration_millis,
+            chan: chan_receiver,
             msg_in: msg_receiver,
         })
     }
@@ -240,138 +698,355 @@ impl MonitoringClient {
         routing_key: &RoutingKeyInformation,
         msg: &[PushedEvent],
     ) -> Result<()> {
-        let payload = encode_messages(msg)?;
-        publish_message(&self.chan, routing_key, &payload).await?;
+        let payload = self.publish_codec.encode(msg)?;
+        let chan = self.chan.borrow().clone();
+        publish_message(
+            &chan,
+            routing_key,
+            self.publish_codec,
+            self.publish_expiration_millis,
+            &payload,
+        )
+        .await?;
         Ok(())
     }
 
-    async fn process_incoming_messages(
+    /// Connects, sets up the exchange, and subscribes to the given routing keys.
+    /// Returns whether an existing shared queue was resumed.
+    async fn establish(
+        addr: &str,
+        routing_keys: &[String],
+        options: &MonitoringClientOptions,
+    ) -> Result<(Channel, Consumer, bool)> {
+        let conn = connect(addr)
+            .await
+            .context("unable to connect to RabbitMQ")?;
+
+        let chan = conn
+            .create_channel()
+            .await
+            .context("unable to set up AMQP channel")?;
+
+        set_up_exchange(&chan)
+            .await
+            .context("unable to set up exchange")?;
+
+        let (consumer, resumed) = set_up_queue_and_subscribe(&conn, &chan, routing_keys, options)
+            .await
+            .context("unable to set up queue and subscribe")?;
+
+        Ok((chan, consumer, resumed))
+    }
+
+    async fn run(
+        addr: String,
+        routing_keys: Vec<String>,
+        options: MonitoringClientOptions,
         mut consumer: Consumer,
-        msg_out: Sender<Result<(RoutingKeyInformation, Vec<PushedEvent>)>>,
+        chan_out: watch::Sender<Channel>,
+        msg_out: Sender<Result<MonitoringItem>>,
     ) {
+        let mut reconnector = Reconnector::new(&addr, options.reconnect.clone());
+        loop {
+            let exit =
+                Self::process_incoming_messages(consumer, &msg_out, options.skip_undecodable).await;
+            let establish = || {
+                Self::establish(&addr, &routing_keys, &options)
+                    .map_ok(|(chan, consumer, resumed)| ((chan, consumer), resumed))
+            };
+            match reconnector.reconnect(exit, &msg_out, establish).await {
+                None => return,
+                Some((chan, new_consumer)) => {
+                    // This only fails if the client was dropped, which we notice eventually.
+                    let _ = chan_out.send(chan);
+                    consumer = new_consumer;
+                }
+            }
+        }
+    }
+
+    /// Decodes and passes on deliveries until the consumer or the subscriber goes away.
+    /// If `skip_undecodable` is set, deliveries that cannot be decoded are rejected and logged,
+    /// otherwise they cause the consumer to fail.
+    async fn process_incoming_messages(
+        mut consumer: Consumer,
+        msg_out: &Sender<Result<MonitoringItem>>,
+        skip_undecodable: bool,
+    ) -> ConsumerExit {
         while let Some(delivery) = consumer.next().await {
             match delivery {
-                Err(err) => {
-                    // We ignore this error because we return immediately.
-                    let _ = msg_out.send(Err(err.into())).await;
-                    return;
-                }
+                Err(err) => return ConsumerExit::Failed(err.into()),
                 Ok(delivery) => {
                     let Delivery {
                         data,
                         acker,
                         routing_key,
+                        properties,
                         ..
                     } = delivery;
                     match decode_routing_key(routing_key.as_str())
-                        .and_then(|key| decode_messages(data).map(|msg| (key, msg)))
+                        .and_then(|key| decode_messages(&properties, &data).map(|msg| (key, msg)))
                     {
                         Ok((key, msg)) => {
+                            if msg_out
+                                .send(Ok(MonitoringItem::Events(key, msg)))
+                                .await
+                                .is_err()
+                            {
+                                debug!("unable to pass on decoded message, quitting");
+                                // Put the delivery back, so that it is not lost for shared queues.
+                                let opts = BasicNackOptions {
+                                    requeue: true,
+                                    ..Default::default()
+                                };
+                                if let Err(e) = acker.nack(opts).await {
+                                    warn!("unable to NACK undelivered message: {:?}", e);
+                                }
+                                return ConsumerExit::SubscriberGone;
+                            }
+                            // We only ACK once the message was passed on.
                             if let Err(e) = acker.ack(BasicAckOptions::default()).await {
                                 // This probably means something is wrong, so let's abort.
                                 error!("unable to ACK incoming delivery: {:?}", e);
-                                if let Err(e) = msg_out.send(Err(e.into())).await {
-                                    error!("unable to notify subscriber of error: {:?}", e);
-                                }
-                                return;
-                            }
-                            if let Err(_) = msg_out.send(Ok((key, msg))).await {
-                                debug!("unable to pass on decoded message, quitting");
-                                return;
+                                return ConsumerExit::Failed(e.into());
                             }
                         }
                         Err(err) => {
                             error!("unable to decode incoming delivery: {:?}", err);
                             if let Err(e) = acker.nack(BasicNackOptions::default()).await {
                                 error!("unable to NACK incoming delivery: {:?}", e);
+                                return ConsumerExit::Failed(e.into());
                             }
-                            if let Err(_) = msg_out.send(Err(err)).await {
-                                error!("unable to notify subscriber of incoming error, quitting");
+                            if !skip_undecodable {
+                                return ConsumerExit::Failed(err);
                             }
-                            return;
                         }
                     }
                 }
             }
         }
+
+        ConsumerExit::ConsumerClosed
     }
 }
 
-/// A monitoring-related event.
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct PushedEvent {
-    pub timestamp: chrono::DateTime<chrono::Utc>,
-    pub peer: String,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
 
-    #[serde(flatten)]
-    pub inner: EventType,
-}
+    fn config_without_jitter() -> ReconnectConfig {
+        ReconnectConfig {
+            initial_backoff_millis: 100,
+            max_backoff_millis: 1000,
+            backoff_multiplier: 2_f64,
+            jitter: 0_f64,
+            max_attempts: None,
+            stable_connection_millis: 1000,
+        }
+    }
 
-impl PushedEvent {
-    /// Creates a constant-width identifier for this event.
-    /// This is potentially expensive.
-    pub fn constant_width_identifier(&self) -> String {
-        // TODO it would be nice if this didn't return a string.
-        // TODO I want something that implements Debug, and then formats this on the fly.
-        match &self.inner {
-            EventType::BitswapMessage(msg) => {
-                let mut addrs = msg.connected_addresses.join(", ");
-                addrs.truncate(30);
-                format!("{:52} [{:30}]", self.peer, addrs)
-            }
-            EventType::ConnectionEvent(conn_event) => {
-                format!("{:52} {:32}", self.peer, conn_event.remote)
-            }
+    #[test]
+    fn backoff_grows_up_to_max() {
+        let cfg = config_without_jitter();
+        let delays = (0..6)
+            .map(|attempt| cfg.backoff(attempt).as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+
+        // Large attempt numbers must not overflow.
+        assert_eq!(cfg.backoff(u32::MAX), Duration::from_millis(1000));
+
+        // Multipliers below one do not shrink the delay.
+        let cfg = ReconnectConfig {
+            backoff_multiplier: 0.5,
+            ..config_without_jitter()
+        };
+        assert_eq!(cfg.backoff(3), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn backoff_jitter_stays_within_bounds() {
+        let cfg = ReconnectConfig {
+            jitter: 0.2,
+            ..config_without_jitter()
+        };
+        let delays = (0..1000)
+            .map(|_| cfg.backoff(4).as_millis())
+            .collect::<Vec<_>>();
+        assert!(delays.iter().all(|d| (800..=1200).contains(d)));
+        assert!(delays.iter().any(|d| *d != delays[0]));
+
+        // Jitter is clamped to [0,1].
+        let cfg = ReconnectConfig {
+            jitter: 5_f64,
+            ..config_without_jitter()
+        };
+        assert!((0..1000).all(|_| cfg.backoff(0) <= Duration::from_millis(200)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_gives_up_after_max_attempts() {
+        let cfg = ReconnectConfig {
+            max_attempts: Some(3),
+            ..config_without_jitter()
+        };
+        let attempts = AtomicU32::new(0);
+        let start = tokio::time::Instant::now();
+
+        let res: Result<()> = cfg
+            .retry("test", || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(err_msg("unreachable"))
+            })
+            .await;
+
+        assert!(res.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        // We back off after the first and second attempt, but not after the last one.
+        assert_eq!(start.elapsed(), Duration::from_millis(300));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_returns_first_success() {
+        let cfg = config_without_jitter();
+        let attempts = AtomicU32::new(0);
+
+        let res = cfg
+            .retry("test", || async {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0..=4 => Err(err_msg("unreachable")),
+                    n => Ok(n),
+                }
+            })
+            .await;
+
+        assert_eq!(res.unwrap(), 5);
+    }
+
+    /// Reconnects after the given connection lifetime and returns the delay until the connection
+    /// attempt.
+    async fn reconnect_after(
+        reconnector: &mut Reconnector,
+        lifetime: Duration,
+        msg_out: &Sender<Result<MonitoringItem>>,
+    ) -> Duration {
+        tokio::time::sleep(lifetime).await;
+        let disconnected_at = Instant::now();
+        let attempted_at = reconnector
+            .reconnect(ConsumerExit::ConsumerClosed, msg_out, || async {
+                Ok((Instant::now(), false))
+            })
+            .await
+            .unwrap();
+        attempted_at - disconnected_at
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_backs_off_after_short_lived_connections() {
+        let (msg_out, mut msg_in) = tokio::sync::mpsc::channel(10);
+        let mut reconnector = Reconnector::new("test", Some(config_without_jitter()));
+
+        let short = Duration::from_millis(10);
+        let mut delays = Vec::new();
+        for _ in 0..3 {
+            delays.push(reconnect_after(&mut reconnector, short, &msg_out).await);
+        }
+        assert_eq!(
+            delays,
+            vec![
+                Duration::from_millis(100),
+                Duration::from_millis(200),
+                Duration::from_millis(400)
+            ]
+        );
+
+        // A stable connection resets the backoff.
+        let stable = Duration::from_millis(1000);
+        assert_eq!(
+            reconnect_after(&mut reconnector, stable, &msg_out).await,
+            Duration::ZERO
+        );
+        assert_eq!(
+            reconnect_after(&mut reconnector, short, &msg_out).await,
+            Duration::from_millis(100)
+        );
+
+        // Each reconnection is reported as a gap.
+        for _ in 0..5 {
+            assert!(matches!(
+                msg_in.try_recv().unwrap().unwrap(),
+                MonitoringItem::Gap(_)
+            ));
         }
+        assert!(msg_in.try_recv().is_err());
     }
-}
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub enum EventType {
-    #[serde(rename = "bitswap_message")]
-    BitswapMessage(BitswapMessage),
-    #[serde(rename = "connection_event")]
-    ConnectionEvent(ConnectionEvent),
-}
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_without_gap_when_resumed() {
+        let (msg_out, mut msg_in) = tokio::sync::mpsc::channel(10);
+        let mut reconnector = Reconnector::new("test", Some(config_without_jitter()));
 
-/// A Bitswap message received by the monitor and subsequently pushed to us via TCP.
-/// This contains both "requests" (updates to the wantlist) as well as "responses" (blocks
-/// and block presences).
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct BitswapMessage {
-    pub wantlist_entries: Vec<JSONWantlistEntry>,
-    pub full_wantlist: bool,
-    pub blocks: Vec<JsonCID>,
-    pub block_presences: Vec<BlockPresence>,
-    pub connected_addresses: Vec<String>,
-}
+        let res = reconnector
+            .reconnect(ConsumerExit::ConsumerClosed, &msg_out, || async {
+                Ok(((), true))
+            })
+            .await;
+        assert!(res.is_some());
+        assert!(msg_in.try_recv().is_err());
+    }
 
-/// Block presence indication, as contained in a Bitswap message.
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct BlockPresence {
-    pub cid: JsonCID,
-    pub block_presence_type: BlockPresenceType,
-}
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_passes_on_errors() {
+        let (msg_out, mut msg_in) = tokio::sync::mpsc::channel(10);
+        let connect = || async { Ok(((), false)) };
 
-/// Block presence type constants for monitoring events.
-#[derive(Serialize_repr, Deserialize_repr, Debug, Copy, Clone)]
-#[repr(u8)]
-pub enum BlockPresenceType {
-    Have = 0,
-    DontHave = 1,
-}
+        // Without reconnection, failures are passed on and closed connections are not.
+        let mut reconnector = Reconnector::new("test", None);
+        let exit = ConsumerExit::Failed(err_msg("broken"));
+        assert!(reconnector
+            .reconnect(exit, &msg_out, connect)
+            .await
+            .is_none());
+        assert!(msg_in.try_recv().unwrap().is_err());
+        let exit = ConsumerExit::ConsumerClosed;
+        assert!(reconnector
+            .reconnect(exit, &msg_out, connect)
+            .await
+            .is_none());
+        assert!(msg_in.try_recv().is_err());
 
-/// A connection event, as reported by IPFS.
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct ConnectionEvent {
-    pub remote: String,
-    pub connection_event_type: ConnectionEventType,
-}
+        // Giving up on reconnecting is passed on as well.
+        let cfg = ReconnectConfig {
+            max_attempts: Some(2),
+            ..config_without_jitter()
+        };
+        let mut reconnector = Reconnector::new("test", Some(cfg));
+        let res: Option<()> = reconnector
+            .reconnect(ConsumerExit::ConsumerClosed, &msg_out, || async {
+                Err(err_msg("unreachable"))
+            })
+            .await;
+        assert!(res.is_none());
+        assert!(msg_in.try_recv().unwrap().is_err());
+
+        // Nothing is reconnected once the subscriber is gone.
+        drop(msg_in);
+        let mut reconnector = Reconnector::new("test", Some(config_without_jitter()));
+        let exit = ConsumerExit::ConsumerClosed;
+        assert!(reconnector
+            .reconnect(exit, &msg_out, connect)
+            .await
+            .is_none());
+    }
 
-/// Connection event type constants for monitoring events.
-#[derive(Serialize_repr, Deserialize_repr, Debug, Copy, Clone)]
-#[repr(u8)]
-pub enum ConnectionEventType {
-    Connected = 0,
-    Disconnected = 1,
+    #[test]
+    fn connection_gap_duration() {
+        let disconnected_at = chrono::Utc::now();
+        let gap = ConnectionGap {
+            disconnected_at,
+            reconnected_at: disconnected_at + chrono::Duration::milliseconds(1500),
+        };
+        assert_eq!(gap.duration(), chrono::Duration::milliseconds(1500));
+    }
 }
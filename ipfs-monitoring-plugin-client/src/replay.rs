@@ -0,0 +1,442 @@
+use crate::file::{open_event_file, read_events};
+use crate::monitoring::{MonitoringItem, PushedEvent, RoutingKeyInformation};
+use failure::ResultExt;
+use futures::prelude::*;
+use ipfs_resolver_common::Result;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::time::Instant;
+
+/// The maximum number of events to group into one batch.
+pub(crate) const MAX_REPLAY_BATCH_SIZE: usize = 1000;
+
+/// How far in the future to schedule events whose deadline can not be represented, e.g., for
+/// tiny speed factors.
+const FAR_FUTURE: Duration = Duration::from_secs(86400 * 365 * 30);
+
+/// Controls how fast recorded events are replayed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplaySpeed {
+    /// Replay events at the pace at which they were recorded.
+    #[default]
+    RealTime,
+    /// Replay events the given factor faster than they were recorded.
+    /// A factor of 2.0 replays one hour of recordings in 30 minutes.
+    Factor(f64),
+    /// Replay events as fast as they can be read and decoded.
+    Unlimited,
+}
+
+/// A replay source for traces written to disk by the bitswap-monitoring-client.
+///
+/// Input files are newline-delimited JSON-encoded `PushedEvent`s, optionally gzipped.
+/// They are read in the order given by the expansion of the input globs, and the events are paced
+/// by their `timestamp` according to the configured `ReplaySpeed`.
+/// The stream produces the same items as a `MonitoringClient`, with routing keys derived from the
+/// configured monitor name and the type of each event.
+/// Gaps are not reported, since we cannot tell them apart from periods without traffic.
+/// Files which can not be read, e.g., because they are truncated, are skipped from the first
+/// unreadable event on, with a warning.
+/// The stream ends once all input files have been replayed.
+#[derive(Debug)]
+pub struct ReplayClient {
+    pub remote: String,
+    msg_in: Receiver<Result<MonitoringItem>>,
+}
+
+impl Stream for ReplayClient {
+    type Item = Result<MonitoringItem>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.msg_in.poll_recv(cx)
+    }
+}
+
+impl ReplayClient {
+    /// Creates a new replay source for the given monitor name, reading from the files matched by
+    /// the given globs.
+    /// This spawns a thread to read and decode the input files, and a task to pace the events.
+    pub fn new(monitor_name: &str, input_globs: &[String], speed: ReplaySpeed) -> Result<Self> {
+        if let ReplaySpeed::Factor(f) = speed {
+            failure::ensure!(
+                f.is_finite() && f > 0_f64,
+                "replay speed factor must be finite and >0"
+            );
+        }
+        let paths = ipfs_resolver_common::expand_globs(&input_globs.to_vec())
+            .context("unable to expand globs")?;
+        debug!(
+            "replaying {} files for monitor {}: {:?}",
+            paths.len(),
+            monitor_name,
+            paths
+        );
+
+        let (event_sender, event_receiver) = tokio::sync::mpsc::channel(MAX_REPLAY_BATCH_SIZE);
+        let (msg_sender, msg_receiver) = tokio::sync::mpsc::channel(1);
+
+        let thread_monitor_name = monitor_name.to_string();
+        std::thread::Builder::new()
+            .name(format!("replay-{}", monitor_name))
+            .spawn(move || Self::read_input_files(thread_monitor_name, paths, event_sender))
+            .context("unable to spawn reader thread")?;
+
+        tokio::spawn(pace_events(
+            monitor_name.to_string(),
+            speed,
+            event_receiver,
+            msg_sender,
+        ));
+
+        Ok(ReplayClient {
+            remote: format!("replay of {:?}", input_globs),
+            msg_in: msg_receiver,
+        })
+    }
+
+    fn read_input_files(
+        monitor_name: String,
+        paths: Vec<PathBuf>,
+        events_out: Sender<Result<PushedEvent>>,
+    ) {
+        for path in paths {
+            debug!("{} replay: now reading {}", monitor_name, path.display());
+            if let Err(err) =
+                open_event_file(&path).and_then(|reader| read_events(reader, &events_out))
+            {
+                warn!(
+                    "{} replay: skipping the rest of {}: {:?}",
+                    monitor_name,
+                    path.display(),
+                    err
+                );
+            }
+            if events_out.is_closed() {
+                debug!("{} replay: receiver closed, quitting", monitor_name);
+                return;
+            }
+        }
+        debug!("{} replay: read all input files", monitor_name);
+    }
+}
+
+/// Paces incoming events according to the given speed and groups consecutive events of the same
+/// type which are due into batches.
+pub(crate) async fn pace_events(
+    monitor_name: String,
+    speed: ReplaySpeed,
+    mut events_in: Receiver<Result<PushedEvent>>,
+    msg_out: Sender<Result<MonitoringItem>>,
+) {
+    // The timestamp of the first event and the instant we replayed it at.
+    let mut start: Option<(chrono::DateTime<chrono::Utc>, Instant)> = None;
+    // An event we received but did not fit into the previous batch.
+    let mut pending: Option<PushedEvent> = None;
+
+    loop {
+        let first = match pending.take() {
+            Some(event) => event,
+            None => match events_in.recv().await {
+                None => break,
+                Some(Err(err)) => {
+                    // We ignore this error because we return immediately.
+                    let _ = msg_out.send(Err(err)).await;
+                    return;
+                }
+                Some(Ok(event)) => event,
+            },
+        };
+
+        let (start_ts, start_instant) = *start.get_or_insert((first.timestamp, Instant::now()));
+        if let Some(deadline) = deadline(speed, start_ts, start_instant, &first) {
+            tokio::time::sleep_until(deadline).await;
+        }
+
+        // Group events of the same type which are due already.
+        let key = RoutingKeyInformation::for_event(&monitor_name, &first);
+        let mut batch = vec![first];
+        while batch.len() < MAX_REPLAY_BATCH_SIZE {
+            match events_in.try_recv() {
+                Ok(Ok(event)) => {
+                    let due = deadline(speed, start_ts, start_instant, &event)
+                        .is_none_or(|deadline| deadline <= Instant::now());
+                    if due && RoutingKeyInformation::for_event(&monitor_name, &event) == key {
+                        batch.push(event);
+                    } else {
+                        pending = Some(event);
+                        break;
+                    }
+                }
+                Ok(Err(err)) => {
+                    // Pass on what we have, then the error.
+                    if msg_out
+                        .send(Ok(MonitoringItem::Events(key, batch)))
+                        .await
+                        .is_ok()
+                    {
+                        let _ = msg_out.send(Err(err)).await;
+                    }
+                    return;
+                }
+                Err(_) => break,
+            }
+        }
+
+        if msg_out
+            .send(Ok(MonitoringItem::Events(key, batch)))
+            .await
+            .is_err()
+        {
+            debug!(
+                "{} replay: unable to pass on events, quitting",
+                monitor_name
+            );
+            return;
+        }
+    }
+
+    debug!("{} replay: done", monitor_name);
+}
+
+/// Computes the instant at which the given event is due, or `None` if events are not paced.
+fn deadline(
+    speed: ReplaySpeed,
+    start_ts: chrono::DateTime<chrono::Utc>,
+    start_instant: Instant,
+    event: &PushedEvent,
+) -> Option<Instant> {
+    let factor = match speed {
+        ReplaySpeed::RealTime => 1_f64,
+        ReplaySpeed::Factor(f) => f,
+        ReplaySpeed::Unlimited => return None,
+    };
+    // Events that are out of order are replayed immediately.
+    let offset = (event.timestamp - start_ts).to_std().unwrap_or_default();
+    // Deadlines which can not be represented are clamped, which only happens for tiny factors.
+    let offset = Duration::try_from_secs_f64(offset.as_secs_f64() / factor)
+        .unwrap_or(FAR_FUTURE)
+        .min(FAR_FUTURE);
+
+    Some(start_instant + offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitoring::{BitswapMessage, ConnectionEvent, ConnectionEventType, EventType};
+    use std::io::Write;
+
+    const MONITOR_NAME: &str = "test";
+
+    fn connection_event(offset_secs: i64) -> PushedEvent {
+        PushedEvent {
+            timestamp: chrono::DateTime::from_timestamp(1_700_000_000 + offset_secs, 0).unwrap(),
+            peer: "12D3KooWSoLzampfxc4t3sy9z7yq1Cgzbi7zGXpV7nvt5hfeKUhR".to_string(),
+            inner: EventType::ConnectionEvent(ConnectionEvent {
+                remote: "/ip4/1.2.3.4/tcp/4001".to_string(),
+                connection_event_type: ConnectionEventType::Connected,
+            }),
+        }
+    }
+
+    fn bitswap_message(offset_secs: i64) -> PushedEvent {
+        PushedEvent {
+            inner: EventType::BitswapMessage(BitswapMessage {
+                wantlist_entries: vec![],
+                full_wantlist: false,
+                blocks: vec![],
+                block_presences: vec![],
+                connected_addresses: vec!["/ip4/1.2.3.4/tcp/4001".to_string()],
+            }),
+            ..connection_event(offset_secs)
+        }
+    }
+
+    /// Paces the given events and returns the batches produced, together with the time at which
+    /// they were produced, relative to the start.
+    async fn pace(
+        speed: ReplaySpeed,
+        events: Vec<PushedEvent>,
+    ) -> Vec<(Duration, RoutingKeyInformation, usize)> {
+        let (event_sender, event_receiver) = tokio::sync::mpsc::channel(events.len());
+        let (msg_sender, mut msg_receiver) = tokio::sync::mpsc::channel(1);
+        for event in events {
+            event_sender.send(Ok(event)).await.unwrap();
+        }
+        drop(event_sender);
+
+        let start = Instant::now();
+        tokio::spawn(pace_events(
+            MONITOR_NAME.to_string(),
+            speed,
+            event_receiver,
+            msg_sender,
+        ));
+
+        let mut batches = Vec::new();
+        while let Some(item) = msg_receiver.recv().await {
+            match item.unwrap() {
+                MonitoringItem::Events(key, events) => {
+                    batches.push((start.elapsed(), key, events.len()))
+                }
+                MonitoringItem::Gap(_) => panic!("replay reported a gap"),
+            }
+        }
+        batches
+    }
+
+    fn elapsed_secs(batches: &[(Duration, RoutingKeyInformation, usize)]) -> Vec<u64> {
+        batches.iter().map(|(d, _, _)| d.as_secs()).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paces_in_real_time() {
+        let batches = pace(
+            ReplaySpeed::RealTime,
+            vec![
+                connection_event(0),
+                connection_event(10),
+                connection_event(20),
+            ],
+        )
+        .await;
+
+        assert_eq!(elapsed_secs(&batches), vec![0, 10, 20]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paces_by_factor() {
+        let batches = pace(
+            ReplaySpeed::Factor(2.0),
+            vec![
+                connection_event(0),
+                connection_event(10),
+                connection_event(20),
+            ],
+        )
+        .await;
+
+        assert_eq!(elapsed_secs(&batches), vec![0, 5, 10]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replays_unlimited_immediately() {
+        let batches = pace(
+            ReplaySpeed::Unlimited,
+            vec![
+                connection_event(0),
+                connection_event(10),
+                connection_event(20),
+            ],
+        )
+        .await;
+
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].0, Duration::ZERO);
+        assert_eq!(batches[0].2, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn batches_by_routing_key() {
+        let batches = pace(
+            ReplaySpeed::Unlimited,
+            vec![
+                connection_event(0),
+                connection_event(1),
+                bitswap_message(2),
+                bitswap_message(3),
+                connection_event(4),
+            ],
+        )
+        .await;
+
+        let connection_events = RoutingKeyInformation::ConnectionEvents {
+            monitor_name: MONITOR_NAME.to_string(),
+        };
+        let bitswap_messages = RoutingKeyInformation::BitswapMessages {
+            monitor_name: MONITOR_NAME.to_string(),
+        };
+        let keys_and_sizes: Vec<_> = batches.into_iter().map(|(_, k, n)| (k, n)).collect();
+        assert_eq!(
+            keys_and_sizes,
+            vec![
+                (connection_events.clone(), 2),
+                (bitswap_messages, 2),
+                (connection_events, 1)
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clamps_deadlines_of_tiny_factors() {
+        let start = Instant::now();
+        let start_ts = connection_event(0).timestamp;
+        let deadline = |speed| deadline(speed, start_ts, start, &connection_event(3600)).unwrap();
+
+        assert_eq!(deadline(ReplaySpeed::Factor(1e-300)), start + FAR_FUTURE);
+        assert_eq!(
+            deadline(ReplaySpeed::Factor(f64::MIN_POSITIVE)),
+            start + FAR_FUTURE
+        );
+        assert_eq!(
+            deadline(ReplaySpeed::Factor(0.5)),
+            start + Duration::from_secs(7200)
+        );
+        assert!(ReplayClient::new(MONITOR_NAME, &[], ReplaySpeed::Factor(f64::INFINITY)).is_err());
+    }
+
+    #[tokio::test]
+    async fn skips_unreadable_files() {
+        let path = |name: &str| {
+            std::env::temp_dir().join(format!(
+                "ipfs-monitoring-plugin-client-replay-test-{}-{}",
+                std::process::id(),
+                name
+            ))
+        };
+        let corrupt = path("corrupt.json.gz");
+        std::fs::write(&corrupt, [0x1f, 0x8b, 0xde, 0xad, 0xbe, 0xef, 0x00, 0x01]).unwrap();
+        let truncated = path("truncated.json");
+        let mut f = std::fs::File::create(&truncated).unwrap();
+        writeln!(
+            f,
+            "{}",
+            serde_json::to_string(&connection_event(0)).unwrap()
+        )
+        .unwrap();
+        write!(f, "{{\"timestamp\":").unwrap();
+        drop(f);
+        let valid = path("valid.json");
+        std::fs::write(
+            &valid,
+            serde_json::to_string(&connection_event(1)).unwrap() + "\n",
+        )
+        .unwrap();
+
+        let client = ReplayClient::new(
+            MONITOR_NAME,
+            &[&corrupt, &truncated, &valid].map(|p| p.to_str().unwrap().to_string()),
+            ReplaySpeed::Unlimited,
+        )
+        .unwrap();
+        let items = client.collect::<Vec<_>>().await;
+        for p in [corrupt, truncated, valid] {
+            std::fs::remove_file(p).unwrap();
+        }
+
+        // The events before the truncation and those of the valid file are replayed.
+        let num_events: usize = items
+            .into_iter()
+            .map(|item| match item.unwrap() {
+                MonitoringItem::Events(_, events) => events.len(),
+                MonitoringItem::Gap(_) => panic!("replay reported a gap"),
+            })
+            .sum();
+        assert_eq!(num_events, 2);
+    }
+}
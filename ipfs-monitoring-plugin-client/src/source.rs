@@ -0,0 +1,58 @@
+use crate::file::FileMonitoringSource;
+use crate::monitoring::{MonitoringClient, MonitoringItem, PushedEvent, RoutingKeyInformation};
+use crate::replay::ReplayClient;
+use crate::tcp::TCPMonitoringClient;
+use futures::prelude::*;
+use ipfs_resolver_common::Result;
+
+/// A source of monitoring events, e.g., an AMQP subscription, a TCP connection, or a file.
+///
+/// Sources produce batches of events, tagged with the routing key they were published under (or
+/// would have been, for sources without routing), as well as gaps in the data, if they can detect
+/// them.
+pub trait MonitoringSource: Stream<Item = Result<MonitoringItem>> + Unpin + Send {
+    /// Returns a human-readable description of where events come from.
+    fn remote(&self) -> &str;
+}
+
+impl MonitoringSource for MonitoringClient {
+    fn remote(&self) -> &str {
+        &self.remote
+    }
+}
+
+impl MonitoringSource for TCPMonitoringClient {
+    fn remote(&self) -> &str {
+        &self.remote
+    }
+}
+
+impl MonitoringSource for FileMonitoringSource {
+    fn remote(&self) -> &str {
+        &self.remote
+    }
+}
+
+impl MonitoringSource for ReplayClient {
+    fn remote(&self) -> &str {
+        &self.remote
+    }
+}
+
+/// Splits a batch of events into runs of consecutive events with the same routing key.
+pub(crate) fn split_by_routing_key(
+    monitor_name: &str,
+    events: Vec<PushedEvent>,
+) -> Vec<(RoutingKeyInformation, Vec<PushedEvent>)> {
+    let mut batches: Vec<(RoutingKeyInformation, Vec<PushedEvent>)> = Vec::new();
+
+    for event in events {
+        let key = RoutingKeyInformation::for_event(monitor_name, &event);
+        match batches.last_mut() {
+            Some((last_key, batch)) if *last_key == key => batch.push(event),
+            _ => batches.push((key, vec![event])),
+        }
+    }
+
+    batches
+}
@@ -0,0 +1,332 @@
+use crate::monitoring::{
+    ConsumerExit, MonitoringClientOptions, MonitoringItem, PushedEvent, Reconnector,
+};
+use crate::source::split_by_routing_key;
+use failure::ResultExt;
+use futures::prelude::*;
+use ipfs_resolver_common::Result;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio_serde::formats::SymmetricalJson;
+use tokio_serde::SymmetricallyFramed;
+use tokio_util::codec::{FramedRead, LengthDelimitedCodec};
+
+/// A stream of length-delimited frames, each containing a JSON-encoded array of events.
+type EventFrames = SymmetricallyFramed<
+    FramedRead<TcpStream, LengthDelimitedCodec>,
+    Vec<PushedEvent>,
+    SymmetricalJson<Vec<PushedEvent>>,
+>;
+
+/// A client for monitors pushing events over plain TCP.
+///
+/// The monitor sends frames prefixed with their length as a 32-bit big-endian integer.
+/// Each frame contains a JSON-encoded array of `PushedEvent`s, i.e., the same payload as published
+/// via AMQP, without compression.
+/// Since there is no routing, events are attributed to the configured monitor name.
+#[derive(Debug)]
+pub struct TCPMonitoringClient {
+    pub remote: String,
+    msg_in: Receiver<Result<MonitoringItem>>,
+}
+
+impl Stream for TCPMonitoringClient {
+    type Item = Result<MonitoringItem>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.msg_in.poll_recv(cx)
+    }
+}
+
+impl TCPMonitoringClient {
+    /// Connects to the monitor at the given address.
+    /// The connection is not re-established if it fails.
+    pub async fn new(addr: &str, monitor_name: &str) -> Result<TCPMonitoringClient> {
+        Self::new_with_options(addr, monitor_name, MonitoringClientOptions::default()).await
+    }
+
+    /// Connects to the monitor at the given address.
+    /// If reconnection is configured, the initial connection is also attempted with backoff, and
+    /// the connection is re-established whenever it fails or is closed by the monitor.
+    pub async fn new_with_options(
+        addr: &str,
+        monitor_name: &str,
+        options: MonitoringClientOptions,
+    ) -> Result<TCPMonitoringClient> {
+        let frames = match &options.reconnect {
+            None => Self::connect(addr).await?,
+            Some(cfg) => cfg.retry(addr, || Self::connect(addr)).await?,
+        };
+
+        let (msg_sender, msg_receiver) = tokio::sync::mpsc::channel(1);
+
+        tokio::spawn(Self::run(
+            addr.to_string(),
+            monitor_name.to_string(),
+            options,
+            frames,
+            msg_sender,
+        ));
+
+        Ok(TCPMonitoringClient {
+            remote: addr.to_string(),
+            msg_in: msg_receiver,
+        })
+    }
+
+    async fn connect(addr: &str) -> Result<EventFrames> {
+        let stream = TcpStream::connect(addr)
+            .await
+            .context("unable to connect")?;
+
+        Ok(SymmetricallyFramed::new(
+            FramedRead::new(stream, LengthDelimitedCodec::new()),
+            SymmetricalJson::default(),
+        ))
+    }
+
+    async fn run(
+        addr: String,
+        monitor_name: String,
+        options: MonitoringClientOptions,
+        mut frames: EventFrames,
+        msg_out: Sender<Result<MonitoringItem>>,
+    ) {
+        let mut reconnector = Reconnector::new(&addr, options.reconnect);
+        loop {
+            let exit = Self::process_incoming_frames(&monitor_name, &mut frames, &msg_out).await;
+            let connect = || Self::connect(&addr).map_ok(|frames| (frames, false));
+            match reconnector.reconnect(exit, &msg_out, connect).await {
+                None => return,
+                Some(new_frames) => frames = new_frames,
+            }
+        }
+    }
+
+    /// Decodes and passes on frames until the connection or the subscriber goes away.
+    async fn process_incoming_frames(
+        monitor_name: &str,
+        frames: &mut EventFrames,
+        msg_out: &Sender<Result<MonitoringItem>>,
+    ) -> ConsumerExit {
+        while let Some(frame) = frames.next().await {
+            match frame {
+                Err(err) => return ConsumerExit::Failed(err.into()),
+                Ok(events) => {
+                    for (key, events) in split_by_routing_key(monitor_name, events) {
+                        if msg_out
+                            .send(Ok(MonitoringItem::Events(key, events)))
+                            .await
+                            .is_err()
+                        {
+                            return ConsumerExit::SubscriberGone;
+                        }
+                    }
+                }
+            }
+        }
+
+        ConsumerExit::ConsumerClosed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitoring::{
+        BitswapMessage, ConnectionEvent, ConnectionEventType, EventType, ReconnectConfig,
+        RoutingKeyInformation,
+    };
+    use bytes::Bytes;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio_util::codec::FramedWrite;
+
+    const MONITOR_NAME: &str = "test";
+
+    fn connection_event() -> PushedEvent {
+        PushedEvent {
+            timestamp: chrono::Utc::now(),
+            peer: "12D3KooWSoLzampfxc4t3sy9z7yq1Cgzbi7zGXpV7nvt5hfeKUhR".to_string(),
+            inner: EventType::ConnectionEvent(ConnectionEvent {
+                remote: "/ip4/1.2.3.4/tcp/4001".to_string(),
+                connection_event_type: ConnectionEventType::Connected,
+            }),
+        }
+    }
+
+    /// Accepts a connection on the given listener and sends the given events as one frame.
+    async fn accept_and_send(
+        listener: &TcpListener,
+        events: &[PushedEvent],
+    ) -> FramedWrite<TcpStream, LengthDelimitedCodec> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut frames = FramedWrite::new(stream, LengthDelimitedCodec::new());
+        frames
+            .send(Bytes::from(serde_json::to_vec(events).unwrap()))
+            .await
+            .unwrap();
+        frames
+    }
+
+    fn unwrap_events(item: Option<Result<MonitoringItem>>) -> Vec<PushedEvent> {
+        match item.unwrap().unwrap() {
+            MonitoringItem::Events(key, events) => {
+                assert_eq!(
+                    key,
+                    RoutingKeyInformation::ConnectionEvents {
+                        monitor_name: MONITOR_NAME.to_string()
+                    }
+                );
+                events
+            }
+            MonitoringItem::Gap(gap) => panic!("unexpected gap {:?}", gap),
+        }
+    }
+
+    #[tokio::test]
+    async fn splits_frames_by_routing_key() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let message = PushedEvent {
+            inner: EventType::BitswapMessage(BitswapMessage {
+                wantlist_entries: vec![],
+                full_wantlist: false,
+                blocks: vec![],
+                block_presences: vec![],
+                connected_addresses: vec![],
+            }),
+            ..connection_event()
+        };
+        let events = vec![connection_event(), message.clone(), message];
+        let monitor = tokio::spawn(async move {
+            // The connection is closed once the frame is sent.
+            drop(accept_and_send(&listener, &events).await);
+        });
+
+        let mut client = TCPMonitoringClient::new(&addr, MONITOR_NAME).await.unwrap();
+
+        assert_eq!(unwrap_events(client.next().await).len(), 1);
+        match client.next().await.unwrap().unwrap() {
+            MonitoringItem::Events(key, events) => {
+                assert_eq!(
+                    key,
+                    RoutingKeyInformation::BitswapMessages {
+                        monitor_name: MONITOR_NAME.to_string()
+                    }
+                );
+                assert_eq!(events.len(), 2);
+            }
+            MonitoringItem::Gap(gap) => panic!("unexpected gap {:?}", gap),
+        }
+        // Without reconnection, the stream ends when the monitor closes the connection.
+        assert!(client.next().await.is_none());
+
+        monitor.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_on_invalid_frame() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let monitor = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut frames = FramedWrite::new(stream, LengthDelimitedCodec::new());
+            frames.send(Bytes::from_static(b"not JSON")).await.unwrap();
+            frames
+        });
+
+        let mut client = TCPMonitoringClient::new(&addr, MONITOR_NAME).await.unwrap();
+
+        assert!(client.next().await.unwrap().is_err());
+        assert!(client.next().await.is_none());
+
+        drop(monitor.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn reports_gap_after_reconnecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let options = MonitoringClientOptions {
+            reconnect: Some(ReconnectConfig {
+                initial_backoff_millis: 10,
+                jitter: 0_f64,
+                max_attempts: Some(10),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let monitor = tokio::spawn(async move {
+            // The first connection is closed by the monitor after one frame.
+            drop(accept_and_send(&listener, &[connection_event()]).await);
+            accept_and_send(&listener, &[connection_event(), connection_event()]).await
+        });
+
+        let mut client = TCPMonitoringClient::new_with_options(&addr, MONITOR_NAME, options)
+            .await
+            .unwrap();
+
+        assert_eq!(unwrap_events(client.next().await).len(), 1);
+        match client.next().await.unwrap().unwrap() {
+            MonitoringItem::Gap(gap) => assert!(gap.duration() >= chrono::Duration::zero()),
+            MonitoringItem::Events(_, _) => panic!("expected a gap"),
+        }
+        assert_eq!(unwrap_events(client.next().await).len(), 2);
+
+        drop(monitor.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn backs_off_from_monitor_dropping_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let options = MonitoringClientOptions {
+            reconnect: Some(ReconnectConfig {
+                initial_backoff_millis: 50,
+                jitter: 0_f64,
+                max_attempts: Some(10),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let monitor = tokio::spawn(async move {
+            // The monitor accepts connections but drops them right away, twice.
+            let mut accepted_at = Vec::new();
+            drop(accept_and_send(&listener, &[connection_event()]).await);
+            for _ in 0..2 {
+                drop(listener.accept().await.unwrap());
+                accepted_at.push(tokio::time::Instant::now());
+            }
+            let frames = accept_and_send(&listener, &[connection_event()]).await;
+            accepted_at.push(tokio::time::Instant::now());
+            (accepted_at, frames)
+        });
+
+        let mut client = TCPMonitoringClient::new_with_options(&addr, MONITOR_NAME, options)
+            .await
+            .unwrap();
+
+        assert_eq!(unwrap_events(client.next().await).len(), 1);
+        // Each dropped connection is reported as a gap.
+        for _ in 0..3 {
+            match client.next().await.unwrap().unwrap() {
+                MonitoringItem::Gap(_) => {}
+                MonitoringItem::Events(_, _) => panic!("expected a gap"),
+            }
+        }
+        // The client resumes receiving events once the connection is kept.
+        assert_eq!(unwrap_events(client.next().await).len(), 1);
+
+        let (accepted_at, _frames) = monitor.await.unwrap();
+        // Reconnection attempts are delayed by a growing backoff.
+        let waited = accepted_at
+            .windows(2)
+            .map(|w| w[1] - w[0])
+            .collect::<Vec<_>>();
+        assert!(waited[0] >= Duration::from_millis(100));
+        assert!(waited[1] >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,380 @@
+use failure::ResultExt;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use ipfs_monitoring_plugin_client::http::{
+    BroadcastBitswapCancelResponse, BroadcastBitswapWantCancelResponse,
+    BroadcastBitswapWantResponse, JSONResponse, MonitoringAddressesResponse, PingResponse,
+    SamplePeerMetadataResponse, API_BASE_PATH, API_PATH_BROADCAST_CANCEL, API_PATH_BROADCAST_WANT,
+    API_PATH_BROADCAST_WANT_CANCEL, API_PATH_MONITORING_ADDRESSES, API_PATH_PING,
+    API_PATH_SAMPLE_PEER_METADATA,
+};
+use ipfs_resolver_common::Result;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// An endpoint of the plugin API.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Ping,
+    MonitoringAddresses,
+    SamplePeerMetadata,
+    BroadcastWant,
+    BroadcastCancel,
+    BroadcastWantCancel,
+}
+
+impl Endpoint {
+    pub const ALL: [Endpoint; 6] = [
+        Endpoint::Ping,
+        Endpoint::MonitoringAddresses,
+        Endpoint::SamplePeerMetadata,
+        Endpoint::BroadcastWant,
+        Endpoint::BroadcastCancel,
+        Endpoint::BroadcastWantCancel,
+    ];
+
+    /// Returns the path of this endpoint, relative to the API base path.
+    pub fn path(&self) -> &'static str {
+        match self {
+            Endpoint::Ping => API_PATH_PING,
+            Endpoint::MonitoringAddresses => API_PATH_MONITORING_ADDRESSES,
+            Endpoint::SamplePeerMetadata => API_PATH_SAMPLE_PEER_METADATA,
+            Endpoint::BroadcastWant => API_PATH_BROADCAST_WANT,
+            Endpoint::BroadcastCancel => API_PATH_BROADCAST_CANCEL,
+            Endpoint::BroadcastWantCancel => API_PATH_BROADCAST_WANT_CANCEL,
+        }
+    }
+
+    /// Returns the HTTP method used to query this endpoint.
+    pub fn method(&self) -> Method {
+        match self {
+            Endpoint::Ping | Endpoint::MonitoringAddresses | Endpoint::SamplePeerMetadata => {
+                Method::GET
+            }
+            Endpoint::BroadcastWant | Endpoint::BroadcastCancel | Endpoint::BroadcastWantCancel => {
+                Method::POST
+            }
+        }
+    }
+
+    fn from_request(method: &Method, path: &str) -> Option<Endpoint> {
+        let path = path.strip_prefix(API_BASE_PATH)?;
+        Endpoint::ALL
+            .into_iter()
+            .find(|e| e.path() == path && e.method() == method)
+    }
+}
+
+/// A scripted response of the mock API.
+#[derive(Clone, Debug)]
+pub enum ScriptedResponse {
+    /// A successful response with the given result.
+    Result(serde_json::Value),
+
+    /// An error reported by the plugin, sent with the given status as both the HTTP status and
+    /// the `status` field of the response.
+    PluginError { status: u16, error: String },
+
+    /// A response with the given HTTP status and raw body.
+    Status { status: u16, body: String },
+
+    /// The given response, sent after a delay.
+    Delayed(Duration, Box<ScriptedResponse>),
+}
+
+impl ScriptedResponse {
+    /// Creates a successful response with the given result.
+    pub fn result<T: Serialize>(result: &T) -> ScriptedResponse {
+        ScriptedResponse::Result(serde_json::to_value(result).expect("unable to serialize result"))
+    }
+
+    fn default_for(endpoint: Endpoint) -> ScriptedResponse {
+        match endpoint {
+            Endpoint::Ping => Self::result(&PingResponse {}),
+            Endpoint::MonitoringAddresses => Self::result(&MonitoringAddressesResponse {
+                addresses: Vec::new(),
+            }),
+            Endpoint::SamplePeerMetadata => Self::result(&SamplePeerMetadataResponse {
+                timestamp: chrono::Utc::now(),
+                num_connections: 0,
+                peer_metadata: Vec::new(),
+            }),
+            Endpoint::BroadcastWant => {
+                Self::result(&BroadcastBitswapWantResponse { peers: Vec::new() })
+            }
+            Endpoint::BroadcastCancel => {
+                Self::result(&BroadcastBitswapCancelResponse { peers: Vec::new() })
+            }
+            Endpoint::BroadcastWantCancel => {
+                Self::result(&BroadcastBitswapWantCancelResponse { peers: Vec::new() })
+            }
+        }
+    }
+
+    async fn into_response(self) -> Response<Body> {
+        let mut resp = self;
+        while let ScriptedResponse::Delayed(delay, inner) = resp {
+            tokio::time::sleep(delay).await;
+            resp = *inner;
+        }
+
+        let (status, body) = match resp {
+            ScriptedResponse::Result(result) => (
+                200,
+                serde_json::to_string(&JSONResponse {
+                    status: 200,
+                    result: Some(result),
+                    error: None,
+                })
+                .unwrap(),
+            ),
+            ScriptedResponse::PluginError { status, error } => (
+                status,
+                serde_json::to_string(&JSONResponse::<()> {
+                    status: status as i32,
+                    result: None,
+                    error: Some(error),
+                })
+                .unwrap(),
+            ),
+            ScriptedResponse::Status { status, body } => (status, body),
+            ScriptedResponse::Delayed(..) => unreachable!(),
+        };
+
+        Response::builder()
+            .status(status)
+            .body(Body::from(body))
+            .unwrap()
+    }
+}
+
+/// A request received by the mock API.
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    pub endpoint: Endpoint,
+    pub query: Option<String>,
+    /// The JSON body of the request, or null if there was none.
+    pub body: serde_json::Value,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    scripts: HashMap<Endpoint, VecDeque<ScriptedResponse>>,
+    defaults: HashMap<Endpoint, ScriptedResponse>,
+    requests: Vec<RecordedRequest>,
+}
+
+impl State {
+    fn next_response(&mut self, endpoint: Endpoint) -> ScriptedResponse {
+        if let Some(resp) = self
+            .scripts
+            .get_mut(&endpoint)
+            .and_then(|script| script.pop_front())
+        {
+            return resp;
+        }
+
+        self.defaults
+            .get(&endpoint)
+            .cloned()
+            .unwrap_or_else(|| ScriptedResponse::default_for(endpoint))
+    }
+}
+
+/// A mock of the HTTP API of the monitoring plugin, listening on localhost.
+///
+/// Each endpoint answers with the scripted responses pushed for it, in order.
+/// Once these are used up, the endpoint answers with its default response, which is a successful,
+/// empty response unless set otherwise.
+/// The server shuts down when this is dropped.
+#[derive(Debug)]
+pub struct MockPluginAPI {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockPluginAPI {
+    /// Starts a mock API on a random port on localhost.
+    /// This must be called from within a Tokio runtime.
+    pub fn start() -> Result<MockPluginAPI> {
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let svc_state = state.clone();
+        let make_svc = make_service_fn(move |_| {
+            let state = svc_state.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| Self::handle(state.clone(), req))) }
+        });
+
+        let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .context("unable to bind mock API")?
+            .serve(make_svc);
+        let addr = server.local_addr();
+
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let server = server.with_graceful_shutdown(async {
+                // This fails if the sender is dropped, which is also a reason to shut down.
+                let _ = shutdown_rx.await;
+            });
+            if let Err(err) = server.await {
+                error!("mock API failed: {}", err)
+            }
+        });
+        debug!("started mock API on {}", addr);
+
+        Ok(MockPluginAPI {
+            addr,
+            state,
+            shutdown: Some(shutdown_tx),
+        })
+    }
+
+    /// Returns the base URL to pass to an `APIClient`.
+    pub fn base_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Appends a response to the script of the given endpoint.
+    pub fn push_response(&self, endpoint: Endpoint, response: ScriptedResponse) {
+        self.state
+            .lock()
+            .unwrap()
+            .scripts
+            .entry(endpoint)
+            .or_default()
+            .push_back(response)
+    }
+
+    /// Sets the response used for the given endpoint once its script is used up.
+    pub fn set_default_response(&self, endpoint: Endpoint, response: ScriptedResponse) {
+        self.state
+            .lock()
+            .unwrap()
+            .defaults
+            .insert(endpoint, response);
+    }
+
+    /// Returns all requests received so far, in order.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Returns the requests received so far for the given endpoint, in order.
+    pub fn requests_to(&self, endpoint: Endpoint) -> Vec<RecordedRequest> {
+        self.state
+            .lock()
+            .unwrap()
+            .requests
+            .iter()
+            .filter(|r| r.endpoint == endpoint)
+            .cloned()
+            .collect()
+    }
+
+    async fn handle(
+        state: Arc<Mutex<State>>,
+        req: Request<Body>,
+    ) -> std::result::Result<Response<Body>, Infallible> {
+        let endpoint = match Endpoint::from_request(req.method(), req.uri().path()) {
+            Some(endpoint) => endpoint,
+            None => {
+                warn!("mock API: no such endpoint: {} {}", req.method(), req.uri());
+                return Ok(Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::from("not found"))
+                    .unwrap());
+            }
+        };
+        debug!("mock API: {:?} {}", endpoint, req.uri());
+
+        let query = req.uri().query().map(|q| q.to_string());
+        let body = match hyper::body::to_bytes(req.into_body()).await {
+            Ok(body) if body.is_empty() => serde_json::Value::Null,
+            Ok(body) => match serde_json::from_slice(&body) {
+                Ok(body) => body,
+                Err(err) => {
+                    return Ok(Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(Body::from(format!("invalid body: {}", err)))
+                        .unwrap())
+                }
+            },
+            Err(err) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from(format!("unable to read body: {}", err)))
+                    .unwrap())
+            }
+        };
+
+        let resp = {
+            let mut state = state.lock().unwrap();
+            state.requests.push(RecordedRequest {
+                endpoint,
+                query,
+                body,
+            });
+            state.next_response(endpoint)
+        };
+
+        Ok(resp.into_response().await)
+    }
+}
+
+impl Drop for MockPluginAPI {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            // This fails if the server is already gone, which is fine.
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipfs_monitoring_plugin_client::http::{APIClient, APIError};
+
+    #[tokio::test]
+    async fn scripted_responses() {
+        let api = MockPluginAPI::start().unwrap();
+        let client = APIClient::new(&api.base_url()).unwrap();
+
+        api.push_response(
+            Endpoint::MonitoringAddresses,
+            ScriptedResponse::result(&MonitoringAddressesResponse {
+                addresses: vec!["amqp://localhost".to_string()],
+            }),
+        );
+        api.push_response(
+            Endpoint::BroadcastWant,
+            ScriptedResponse::PluginError {
+                status: 400,
+                error: "no CIDs".to_string(),
+            },
+        );
+
+        client.ping().await.unwrap();
+        assert_eq!(
+            client.monitoring_addresses().await.unwrap(),
+            vec!["amqp://localhost".to_string()]
+        );
+        // The script is used up, so this returns the default.
+        assert!(client.monitoring_addresses().await.unwrap().is_empty());
+        assert!(matches!(
+            client.broadcast_bitswap_want(vec![]).await,
+            Err(APIError::Plugin { status: 400, .. })
+        ));
+
+        let requests = api.requests();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[3].endpoint, Endpoint::BroadcastWant);
+        assert_eq!(requests[3].body, serde_json::json!({ "cids": [] }));
+    }
+}
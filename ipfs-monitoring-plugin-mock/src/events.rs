@@ -0,0 +1,86 @@
+//! Helpers to construct events for scripts.
+
+use ipfs_monitoring_plugin_client::monitoring::{
+    BitswapMessage, BlockPresence, BlockPresenceType, ConnectionEvent, ConnectionEventType,
+    EventType, PushedEvent,
+};
+use ipfs_resolver_common::wantlist::{JSONWantType, JSONWantlistEntry, JsonCID};
+
+/// Creates a connection event for the given peer, timestamped now.
+pub fn connection_event(
+    peer: &str,
+    remote: &str,
+    connection_event_type: ConnectionEventType,
+) -> PushedEvent {
+    PushedEvent {
+        timestamp: chrono::Utc::now(),
+        peer: peer.to_string(),
+        inner: EventType::ConnectionEvent(ConnectionEvent {
+            remote: remote.to_string(),
+            connection_event_type,
+        }),
+    }
+}
+
+/// Creates an empty Bitswap message, received via the given addresses.
+pub fn bitswap_message(connected_addresses: &[&str]) -> BitswapMessage {
+    BitswapMessage {
+        wantlist_entries: Vec::new(),
+        full_wantlist: false,
+        blocks: Vec::new(),
+        block_presences: Vec::new(),
+        connected_addresses: connected_addresses.iter().map(|a| a.to_string()).collect(),
+    }
+}
+
+/// Wraps a Bitswap message from the given peer into an event, timestamped now.
+pub fn bitswap_event(peer: &str, msg: BitswapMessage) -> PushedEvent {
+    PushedEvent {
+        timestamp: chrono::Utc::now(),
+        peer: peer.to_string(),
+        inner: EventType::BitswapMessage(msg),
+    }
+}
+
+/// Creates an event for a Bitswap message containing a single wantlist entry.
+pub fn want(peer: &str, cid: &str, want_type: JSONWantType, cancel: bool) -> PushedEvent {
+    let mut msg = bitswap_message(&[]);
+    msg.wantlist_entries.push(JSONWantlistEntry {
+        priority: 1,
+        cancel,
+        send_dont_have: false,
+        cid: JsonCID {
+            path: cid.to_string(),
+        },
+        want_type,
+    });
+
+    bitswap_event(peer, msg)
+}
+
+/// Creates an event for a Bitswap message containing a single block.
+pub fn block(peer: &str, cid: &str) -> PushedEvent {
+    let mut msg = bitswap_message(&[]);
+    msg.blocks.push(JsonCID {
+        path: cid.to_string(),
+    });
+
+    bitswap_event(peer, msg)
+}
+
+/// Creates an event for a Bitswap message containing a single block presence.
+pub fn block_presence(
+    peer: &str,
+    cid: &str,
+    block_presence_type: BlockPresenceType,
+) -> PushedEvent {
+    let mut msg = bitswap_message(&[]);
+    msg.block_presences.push(BlockPresence {
+        cid: JsonCID {
+            path: cid.to_string(),
+        },
+        block_presence_type,
+    });
+
+    bitswap_event(peer, msg)
+}
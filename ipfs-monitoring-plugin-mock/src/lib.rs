@@ -0,0 +1,14 @@
+//! In-process stand-ins for a monitoring node, for testing tools without an IPFS node or an AMQP
+//! broker.
+//!
+//! `api::MockPluginAPI` serves the HTTP API of the plugin from scripted responses, and
+//! `publisher::MockPublisher` pushes scripted batches of events to consumers connected via a
+//! `TCPMonitoringClient`.
+//! The `events` module contains helpers to construct events.
+
+#[macro_use]
+extern crate log;
+
+pub mod api;
+pub mod events;
+pub mod publisher;
@@ -0,0 +1,210 @@
+use bytes::Bytes;
+use failure::ResultExt;
+use futures::prelude::*;
+use ipfs_monitoring_plugin_client::monitoring::PushedEvent;
+use ipfs_resolver_common::Result;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio_util::codec::{FramedWrite, LengthDelimitedCodec};
+
+/// A step of a script played by a `MockPublisher`.
+#[derive(Clone, Debug)]
+pub enum ScriptStep {
+    /// Publishes a batch of events to all connected consumers.
+    Publish(Vec<PushedEvent>),
+
+    /// Waits for the given duration.
+    Sleep(Duration),
+
+    /// Waits until at least the given number of consumers are connected.
+    WaitForConsumers(usize),
+
+    /// Disconnects all consumers.
+    DisconnectConsumers,
+}
+
+/// A monitor pushing events to consumers via TCP, listening on localhost.
+///
+/// Consumers connect using a `TCPMonitoringClient`.
+/// Each batch of events is sent to every consumer connected at the time it is published, as one
+/// length-delimited frame containing a JSON array, i.e., consumers connecting later miss it.
+/// The listener and all connections are shut down when this is dropped.
+#[derive(Debug)]
+pub struct MockPublisher {
+    addr: SocketAddr,
+    consumers: Arc<Mutex<Vec<mpsc::UnboundedSender<Bytes>>>>,
+    num_consumers: watch::Receiver<usize>,
+    accept_task: JoinHandle<()>,
+}
+
+impl MockPublisher {
+    /// Starts a publisher on a random port on localhost.
+    pub async fn start() -> Result<MockPublisher> {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .context("unable to bind mock publisher")?;
+        let addr = listener
+            .local_addr()
+            .context("unable to get local address")?;
+
+        let consumers = Arc::new(Mutex::new(Vec::new()));
+        let (num_consumers_tx, num_consumers_rx) = watch::channel(0);
+
+        let accept_task = tokio::spawn(Self::accept(listener, consumers.clone(), num_consumers_tx));
+        debug!("started mock publisher on {}", addr);
+
+        Ok(MockPublisher {
+            addr,
+            consumers,
+            num_consumers: num_consumers_rx,
+            accept_task,
+        })
+    }
+
+    /// Returns the address to pass to a `TCPMonitoringClient`.
+    pub fn address(&self) -> String {
+        self.addr.to_string()
+    }
+
+    /// Returns the number of consumers currently connected.
+    pub fn num_consumers(&self) -> usize {
+        let mut consumers = self.consumers.lock().unwrap();
+        consumers.retain(|c| !c.is_closed());
+        consumers.len()
+    }
+
+    /// Waits until at least the given number of consumers have connected.
+    pub async fn wait_for_consumers(&self, n: usize) {
+        let mut num_consumers = self.num_consumers.clone();
+        while self.num_consumers() < n {
+            if num_consumers.changed().await.is_err() {
+                // The listener is gone, no more consumers will connect.
+                return;
+            }
+        }
+    }
+
+    /// Publishes a batch of events to all connected consumers.
+    /// Returns the number of consumers the batch was sent to.
+    pub fn publish(&self, events: &[PushedEvent]) -> Result<usize> {
+        let frame = Bytes::from(serde_json::to_vec(events).context("unable to serialize events")?);
+
+        let mut consumers = self.consumers.lock().unwrap();
+        consumers.retain(|c| c.send(frame.clone()).is_ok());
+
+        Ok(consumers.len())
+    }
+
+    /// Disconnects all consumers.
+    pub fn disconnect_consumers(&self) {
+        self.consumers.lock().unwrap().clear()
+    }
+
+    /// Plays the given script.
+    pub async fn play(&self, script: Vec<ScriptStep>) -> Result<()> {
+        for step in script {
+            match step {
+                ScriptStep::Publish(events) => {
+                    self.publish(&events)?;
+                }
+                ScriptStep::Sleep(duration) => tokio::time::sleep(duration).await,
+                ScriptStep::WaitForConsumers(n) => self.wait_for_consumers(n).await,
+                ScriptStep::DisconnectConsumers => self.disconnect_consumers(),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn accept(
+        listener: TcpListener,
+        consumers: Arc<Mutex<Vec<mpsc::UnboundedSender<Bytes>>>>,
+        num_consumers: watch::Sender<usize>,
+    ) {
+        loop {
+            let (stream, remote) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    error!("mock publisher: unable to accept connection: {}", err);
+                    return;
+                }
+            };
+            debug!("mock publisher: consumer connected from {}", remote);
+
+            let (frames_tx, frames_rx) = mpsc::unbounded_channel();
+            tokio::spawn(Self::serve(stream, frames_rx));
+
+            let n = {
+                let mut consumers = consumers.lock().unwrap();
+                consumers.push(frames_tx);
+                consumers.len()
+            };
+            // This fails if the publisher is dropped, in which case this task is aborted anyway.
+            let _ = num_consumers.send(n);
+        }
+    }
+
+    async fn serve(stream: TcpStream, mut frames_in: mpsc::UnboundedReceiver<Bytes>) {
+        let mut frames_out = FramedWrite::new(stream, LengthDelimitedCodec::new());
+
+        while let Some(frame) = frames_in.recv().await {
+            if let Err(err) = frames_out.send(frame).await {
+                debug!("mock publisher: consumer went away: {}", err);
+                return;
+            }
+        }
+        debug!("mock publisher: disconnecting consumer")
+    }
+}
+
+impl Drop for MockPublisher {
+    fn drop(&mut self) {
+        self.accept_task.abort()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events;
+    use ipfs_monitoring_plugin_client::monitoring::{ConnectionEventType, MonitoringItem};
+    use ipfs_monitoring_plugin_client::tcp::TCPMonitoringClient;
+
+    #[tokio::test]
+    async fn publish_to_tcp_client() {
+        let publisher = MockPublisher::start().await.unwrap();
+        let mut client = TCPMonitoringClient::new(&publisher.address(), "mock")
+            .await
+            .unwrap();
+
+        publisher
+            .play(vec![
+                ScriptStep::WaitForConsumers(1),
+                ScriptStep::Publish(vec![
+                    events::connection_event(
+                        "peer",
+                        "/ip4/1.2.3.4",
+                        ConnectionEventType::Connected,
+                    ),
+                    events::block("peer", "QmPZ9gcCEpqKTo6aq61g2nXGUhM4iCL3ewB6LDXZCtioEB"),
+                ]),
+                ScriptStep::DisconnectConsumers,
+            ])
+            .await
+            .unwrap();
+
+        let mut received = Vec::new();
+        while let Some(item) = client.next().await {
+            match item.unwrap() {
+                MonitoringItem::Events(_, events) => received.extend(events),
+                MonitoringItem::Gap(_) => panic!("unexpected gap"),
+            }
+        }
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1].peer, "peer");
+    }
+}
@@ -464,3 +464,73 @@ impl Monitor {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipfs_monitoring_plugin_client::http::SamplePeerMetadataResponse;
+    use ipfs_monitoring_plugin_mock::api::{Endpoint, MockPluginAPI, ScriptedResponse};
+
+    fn peer_metadata(peers: &[&str]) -> ScriptedResponse {
+        ScriptedResponse::result(&SamplePeerMetadataResponse {
+            timestamp: chrono::Utc::now(),
+            num_connections: peers.len() as u32,
+            peer_metadata: peers
+                .iter()
+                .map(|p| PeerMetadataEntry {
+                    peer_id: p.to_string(),
+                    connectedness: PeerMetadataConnectedness::Connected,
+                    multiaddresses: vec![],
+                    protocols: Some(vec!["/ipfs/bitswap/1.2.0".to_string()]),
+                    agent_version: Some("kubo".to_string()),
+                    latency_ewma_ns: None,
+                    connected_multiaddresses: None,
+                })
+                .collect(),
+        })
+    }
+
+    #[tokio::test]
+    async fn estimates_against_mock_monitors() {
+        let api_1 = MockPluginAPI::start().unwrap();
+        api_1.push_response(
+            Endpoint::SamplePeerMetadata,
+            peer_metadata(&["a", "b", "c", "d"]),
+        );
+        let api_2 = MockPluginAPI::start().unwrap();
+        api_2.push_response(
+            Endpoint::SamplePeerMetadata,
+            peer_metadata(&["c", "d", "e", "f"]),
+        );
+
+        let monitors = vec![
+            Monitor::new("m1", &api_1.base_url()).await.unwrap(),
+            Monitor::new("m2", &api_2.base_url()).await.unwrap(),
+        ];
+        compute_estimates(&monitors).await.unwrap();
+
+        // 4 * 4 / 2 overlapping peers.
+        let hypergeom = |protocol: &str| {
+            prom::HYPERGEOM_SIZE_ESTIMATE
+                .get_metric_with_label_values(&[protocol, "", "m1 with m2"])
+                .unwrap()
+                .get()
+        };
+        assert_eq!(hypergeom(""), 8);
+        assert_eq!(hypergeom("/ipfs/bitswap/1.2.0"), 8);
+        assert!(
+            prom::COUPON_SIZE_ESTIMATE
+                .get_metric_with_label_values(&["", ""])
+                .unwrap()
+                .get()
+                >= 6
+        );
+
+        assert_eq!(
+            api_1.requests_to(Endpoint::SamplePeerMetadata)[0]
+                .query
+                .as_deref(),
+            Some("only_connected=true")
+        );
+    }
+}
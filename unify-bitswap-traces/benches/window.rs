@@ -0,0 +1,38 @@
+//! Compares the indexed per-peer windows to a linear scan, for a chatty peer.
+//! Run with `cargo bench -p unify-bitswap-traces`.
+
+#[macro_use]
+extern crate log;
+
+// The crate is a binary, so we include the modules needed directly.
+#[allow(dead_code)]
+#[path = "../src/config.rs"]
+mod config;
+// The tests of the window are compiled, but not run, so their imports are unused.
+#[allow(unused_imports)]
+#[path = "../src/window.rs"]
+mod window;
+#[path = "../src/window_testing.rs"]
+mod window_testing;
+
+use crate::window::PeerWindow;
+use crate::window_testing::{configs, run, synthetic_trace, LinearWindow};
+use ipfs_resolver_common::Result;
+
+fn main() {
+    let trace = synthetic_trace(2_000, 5_000, 20);
+    let num_entries: usize = trace.iter().map(|(_, _, e)| e.len()).sum();
+    for cfg in configs() {
+        let (expected, linear_time) = run::<LinearWindow>(&trace, &cfg);
+        let (results, indexed_time) = run::<PeerWindow>(&trace, &cfg);
+        assert_eq!(results, expected);
+        println!(
+            "{} entries, {:?}: linear scan {:.2}s, indexed {:.2}s => {:.1}x speed-up",
+            num_entries,
+            cfg,
+            linear_time.as_secs_f64(),
+            indexed_time.as_secs_f64(),
+            linear_time.as_secs_f64() / indexed_time.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use serde::{Deserialize, Serialize};
+
+/// The z-score for approximate 95% confidence intervals.
+const Z_95: f64 = 1.96;
+
+/// Milliseconds per hour, the unit of time for drift.
+const MILLIS_PER_HOUR: f64 = 3_600_000.0;
+
+/// A linear correction of the clock of a monitor.
+/// The offset of the clock at some time `ts` is `offset_ms + drift_ms_per_hour * (ts - reference_ts)`,
+/// which is subtracted from the timestamps of the monitor.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ClockCorrection {
+    /// The offset of the clock at `reference_ts`, in milliseconds.
+    pub(crate) offset_ms: f64,
+    /// The drift of the clock, in milliseconds per hour.
+    pub(crate) drift_ms_per_hour: f64,
+    pub(crate) reference_ts: chrono::DateTime<chrono::Utc>,
+}
+
+impl ClockCorrection {
+    /// Constructs a correction by a constant offset.
+    pub(crate) fn fixed(offset_ms: i64) -> ClockCorrection {
+        ClockCorrection {
+            offset_ms: offset_ms as f64,
+            drift_ms_per_hour: 0_f64,
+            reference_ts: chrono::DateTime::UNIX_EPOCH,
+        }
+    }
+
+    /// Returns the offset of the clock at the given time, in milliseconds.
+    fn offset_at(&self, ts: chrono::DateTime<chrono::Utc>) -> f64 {
+        let millis_since_reference = (ts - self.reference_ts).num_milliseconds() as f64;
+        self.offset_ms + self.drift_ms_per_hour * millis_since_reference / MILLIS_PER_HOUR
+    }
+
+    /// Corrects the given timestamp.
+    pub(crate) fn apply(&self, ts: chrono::DateTime<chrono::Utc>) -> chrono::DateTime<chrono::Utc> {
+        ts - chrono::Duration::milliseconds(self.offset_at(ts).round() as i64)
+    }
+
+    /// Combines this correction with one estimated on timestamps already corrected by this one.
+    pub(crate) fn combine(&self, other: &ClockCorrection) -> ClockCorrection {
+        ClockCorrection {
+            offset_ms: self.offset_at(other.reference_ts) + other.offset_ms,
+            drift_ms_per_hour: self.drift_ms_per_hour + other.drift_ms_per_hour,
+            reference_ts: other.reference_ts,
+        }
+    }
+}
+
+/// An estimate of the clock offset and drift of a monitor, relative to the first monitor.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ClockOffsetEstimate {
+    /// The number of matches the estimate is based on.
+    pub(crate) num_samples: u64,
+    /// The estimated correction.
+    pub(crate) correction: ClockCorrection,
+    /// The half-width of the approximate 95% confidence interval of the offset, in milliseconds.
+    pub(crate) offset_ci_ms: f64,
+    /// The half-width of the approximate 95% confidence interval of the drift, in milliseconds per
+    /// hour.
+    pub(crate) drift_ci_ms_per_hour: f64,
+}
+
+/// Running sums for a linear regression of the time difference between matched entries of a
+/// monitor and the first monitor over time.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct OffsetSamples {
+    /// The timestamp of the first sample, to which times are relative.
+    reference_ts: Option<chrono::DateTime<chrono::Utc>>,
+    n: u64,
+    sum_t: f64,
+    sum_d: f64,
+    sum_tt: f64,
+    sum_td: f64,
+    sum_dd: f64,
+}
+
+impl OffsetSamples {
+    fn record(&mut self, ts: chrono::DateTime<chrono::Utc>, diff_ms: f64) {
+        let reference_ts = *self.reference_ts.get_or_insert(ts);
+        let t = (ts - reference_ts).num_milliseconds() as f64 / MILLIS_PER_HOUR;
+
+        self.n += 1;
+        self.sum_t += t;
+        self.sum_d += diff_ms;
+        self.sum_tt += t * t;
+        self.sum_td += t * diff_ms;
+        self.sum_dd += diff_ms * diff_ms;
+    }
+
+    /// Fits offset and drift via least squares.
+    /// Drift is assumed to be zero if all samples were taken at the same time.
+    fn estimate(&self) -> Option<ClockOffsetEstimate> {
+        let reference_ts = self.reference_ts?;
+        if self.n < 3 {
+            return None;
+        }
+        let n = self.n as f64;
+        let mean_t = self.sum_t / n;
+        let mean_d = self.sum_d / n;
+        let s_tt = self.sum_tt - n * mean_t * mean_t;
+        let s_td = self.sum_td - n * mean_t * mean_d;
+        let s_dd = self.sum_dd - n * mean_d * mean_d;
+
+        let (offset_ms, drift_ms_per_hour, offset_se, drift_se) = if s_tt > f64::EPSILON {
+            let drift = s_td / s_tt;
+            let offset = mean_d - drift * mean_t;
+            let residual_variance = (s_dd - drift * s_td).max(0_f64) / (n - 2_f64);
+            (
+                offset,
+                drift,
+                (residual_variance * (1_f64 / n + mean_t * mean_t / s_tt)).sqrt(),
+                (residual_variance / s_tt).sqrt(),
+            )
+        } else {
+            let variance = s_dd.max(0_f64) / (n - 1_f64);
+            (mean_d, 0_f64, (variance / n).sqrt(), 0_f64)
+        };
+
+        Some(ClockOffsetEstimate {
+            num_samples: self.n,
+            correction: ClockCorrection {
+                offset_ms,
+                drift_ms_per_hour,
+                reference_ts,
+            },
+            offset_ci_ms: Z_95 * offset_se,
+            drift_ci_ms_per_hour: Z_95 * drift_se,
+        })
+    }
+}
+
+/// Estimates the clock offsets of monitors relative to the first monitor, from the time
+/// differences of high-confidence matches with it.
+/// This assumes that requests reach all monitors with the same delay, on average.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ClockOffsetEstimator {
+    /// Samples per monitor, indexed by monitor ID.
+    samples: Vec<OffsetSamples>,
+}
+
+impl ClockOffsetEstimator {
+    /// Records a match of an entry of `monitor_id` at `ts` to an entry of `matched_monitor_id`
+    /// which is `diff_ms` older.
+    pub(crate) fn record_match(
+        &mut self,
+        monitor_id: usize,
+        matched_monitor_id: usize,
+        ts: chrono::DateTime<chrono::Utc>,
+        diff_ms: i64,
+    ) {
+        // We only track offsets relative to the first monitor.
+        let (monitor_id, diff_ms) = match (monitor_id, matched_monitor_id) {
+            (0, other) => (other, -diff_ms),
+            (other, 0) => (other, diff_ms),
+            _ => return,
+        };
+        if self.samples.len() <= monitor_id {
+            self.samples.resize_with(monitor_id + 1, Default::default);
+        }
+        self.samples[monitor_id].record(ts, diff_ms as f64);
+    }
+
+    /// Returns the current estimate for each monitor, indexed by monitor ID.
+    /// The estimate is `None` for the first monitor and monitors with fewer than three samples.
+    pub(crate) fn estimates(&self, num_monitors: usize) -> Vec<Option<ClockOffsetEstimate>> {
+        (0..num_monitors)
+            .map(|i| self.samples.get(i).and_then(|s| s.estimate()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_offset_and_drift() {
+        let start = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut estimator = ClockOffsetEstimator::default();
+        // Monitor 1 is 200ms ahead, drifting by 10ms per hour, with symmetric delays of 50ms.
+        for i in 0..100 {
+            let ts = start + chrono::Duration::minutes(i * 6);
+            let offset = 200 + i;
+            estimator.record_match(1, 0, ts, offset + 50);
+            estimator.record_match(0, 1, ts, 50 - offset);
+        }
+
+        let estimates = estimator.estimates(2);
+        assert!(estimates[0].is_none());
+        let estimate = estimates[1].unwrap();
+        assert_eq!(estimate.num_samples, 200);
+        assert!((estimate.correction.offset_ms - 200_f64).abs() < estimate.offset_ci_ms + 1.0);
+        assert!((estimate.correction.drift_ms_per_hour - 10_f64).abs() < 0.5);
+
+        let corrected = estimate
+            .correction
+            .apply(start + chrono::Duration::hours(2));
+        assert_eq!(
+            corrected,
+            start + chrono::Duration::hours(2) - chrono::Duration::milliseconds(220)
+        );
+    }
+}
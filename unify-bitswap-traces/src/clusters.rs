@@ -0,0 +1,195 @@
+use ipfs_resolver_common::output::{Column, ColumnType, Record};
+use ipfs_resolver_common::wantlist::CSVWantlistEntry;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A summary of a match cluster, i.e., a group of matched entries from different monitors which
+/// represent one logical request.
+/// To be serialized to CSV.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct CSVMatchCluster {
+    /// The ID of the cluster, as referenced by `match_cluster_id` of the wantlist entries.
+    pub cluster_id: i64,
+
+    /// The ID of the requesting peer.
+    pub peer_id: String,
+    /// The CID of the first entry of the cluster.
+    pub cid: String,
+    /// The entry type of the first entry of the cluster.
+    pub entry_type: i32,
+
+    /// The number of entries in the cluster, which is also the number of monitors involved.
+    pub num_entries: u32,
+    /// A bitfield of the monitors involved, with bit `i` set for the monitor with ID `i`.
+    /// Monitors with IDs of 64 and above are not recorded.
+    pub monitors: u64,
+
+    /// The monitor that saw the request first.
+    pub first_monitor_id: u64,
+    /// The ID of the message of the first entry of the cluster.
+    pub first_message_id: i64,
+    pub first_timestamp_seconds: i64,
+    pub first_timestamp_subsec_milliseconds: u32,
+    /// The monitor that saw the request last.
+    pub last_monitor_id: u64,
+    pub last_timestamp_seconds: i64,
+    pub last_timestamp_subsec_milliseconds: u32,
+    /// The time between the first and the last entry of the cluster, in milliseconds.
+    pub spread_ms: u64,
+}
+
+impl Record for CSVMatchCluster {
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::new("cluster_id", ColumnType::Int64),
+            Column::new("peer_id", ColumnType::Dictionary),
+            Column::new("cid", ColumnType::Dictionary),
+            Column::new("entry_type", ColumnType::Int32),
+            Column::new("num_entries", ColumnType::UInt32),
+            Column::new("monitors", ColumnType::UInt64),
+            Column::new("first_monitor_id", ColumnType::UInt64),
+            Column::new("first_message_id", ColumnType::Int64),
+            Column::new("first_timestamp_seconds", ColumnType::Int64),
+            Column::new("first_timestamp_subsec_milliseconds", ColumnType::UInt32),
+            Column::new("last_monitor_id", ColumnType::UInt64),
+            Column::new("last_timestamp_seconds", ColumnType::Int64),
+            Column::new("last_timestamp_subsec_milliseconds", ColumnType::UInt32),
+            Column::new("spread_ms", ColumnType::UInt64),
+        ]
+    }
+}
+
+impl CSVMatchCluster {
+    fn new(cluster_id: i64, monitor_id: usize, entry: &CSVWantlistEntry) -> CSVMatchCluster {
+        CSVMatchCluster {
+            cluster_id,
+            peer_id: entry.peer_id.clone(),
+            cid: entry.cid.clone(),
+            entry_type: entry.entry_type,
+            num_entries: 1,
+            monitors: monitor_bit(monitor_id),
+            first_monitor_id: monitor_id as u64,
+            first_message_id: entry.message_id,
+            first_timestamp_seconds: entry.timestamp_seconds,
+            first_timestamp_subsec_milliseconds: entry.timestamp_subsec_milliseconds,
+            last_monitor_id: monitor_id as u64,
+            last_timestamp_seconds: entry.timestamp_seconds,
+            last_timestamp_subsec_milliseconds: entry.timestamp_subsec_milliseconds,
+            spread_ms: 0,
+        }
+    }
+
+    /// Adds an entry, which must not be older than the entries already in the cluster.
+    fn add(&mut self, monitor_id: usize, entry: &CSVWantlistEntry) {
+        self.num_entries += 1;
+        self.monitors |= monitor_bit(monitor_id);
+        self.last_monitor_id = monitor_id as u64;
+        self.last_timestamp_seconds = entry.timestamp_seconds;
+        self.last_timestamp_subsec_milliseconds = entry.timestamp_subsec_milliseconds;
+        self.spread_ms = ((self.last_timestamp_seconds - self.first_timestamp_seconds) * 1000
+            + (self.last_timestamp_subsec_milliseconds as i64
+                - self.first_timestamp_subsec_milliseconds as i64)) as u64;
+    }
+}
+
+fn monitor_bit(monitor_id: usize) -> u64 {
+    1_u64.checked_shl(monitor_id as u32).unwrap_or(0)
+}
+
+/// A cluster which can still grow, together with the number of references to it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct OpenCluster {
+    summary: CSVMatchCluster,
+    /// The monitors of the cluster, which can be more than fit into the summary.
+    monitor_ids: Vec<usize>,
+    /// The number of entries in the per-peer queues, or currently being processed, which belong
+    /// to this cluster.
+    /// The cluster is finished once this drops to zero, as no entries can be matched to it
+    /// anymore.
+    references: usize,
+}
+
+/// Keeps track of match clusters.
+///
+/// Every non-synthetic entry starts a new cluster, unless it is matched to an entry of a cluster
+/// which does not yet contain an entry of the same monitor.
+/// All clusters are reported, including those of unmatched entries, such that every
+/// `match_cluster_id` of the output refers to a reported cluster.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct MatchClusters {
+    next_cluster_id: i64,
+    open_clusters: HashMap<i64, OpenCluster>,
+    /// Clusters which were finished but not yet taken.
+    finished_clusters: Vec<CSVMatchCluster>,
+}
+
+impl MatchClusters {
+    /// Assigns an entry to a cluster, either the one of the entry it was matched to, or a new one.
+    /// The entry holds a reference to the cluster, which must be released after processing it.
+    pub(crate) fn assign(
+        &mut self,
+        monitor_id: usize,
+        entry: &CSVWantlistEntry,
+        matched_cluster_id: Option<i64>,
+    ) -> i64 {
+        if let Some(cluster) = matched_cluster_id.and_then(|id| self.open_clusters.get_mut(&id)) {
+            if !cluster.monitor_ids.contains(&monitor_id) {
+                cluster.summary.add(monitor_id, entry);
+                cluster.monitor_ids.push(monitor_id);
+                cluster.references += 1;
+                return cluster.summary.cluster_id;
+            }
+        }
+
+        let cluster_id = self.next_cluster_id;
+        self.next_cluster_id += 1;
+        self.open_clusters.insert(
+            cluster_id,
+            OpenCluster {
+                summary: CSVMatchCluster::new(cluster_id, monitor_id, entry),
+                monitor_ids: vec![monitor_id],
+                references: 1,
+            },
+        );
+        cluster_id
+    }
+
+    /// Adds a reference to a cluster.
+    pub(crate) fn acquire(&mut self, cluster_id: i64) {
+        if let Some(cluster) = self.open_clusters.get_mut(&cluster_id) {
+            cluster.references += 1;
+        }
+    }
+
+    /// Releases a reference to a cluster, finishing the cluster if it was the last one.
+    pub(crate) fn release(&mut self, cluster_id: i64) {
+        let finished = match self.open_clusters.get_mut(&cluster_id) {
+            Some(cluster) => {
+                cluster.references -= 1;
+                cluster.references == 0
+            }
+            None => false,
+        };
+        if finished {
+            self.finish(cluster_id);
+        }
+    }
+
+    fn finish(&mut self, cluster_id: i64) {
+        if let Some(cluster) = self.open_clusters.remove(&cluster_id) {
+            self.finished_clusters.push(cluster.summary);
+        }
+    }
+
+    /// Finishes all open clusters, in order of their IDs.
+    pub(crate) fn finish_all(&mut self) {
+        let mut cluster_ids = self.open_clusters.keys().copied().collect::<Vec<_>>();
+        cluster_ids.sort_unstable();
+        cluster_ids.into_iter().for_each(|id| self.finish(id));
+    }
+
+    /// Takes the clusters finished since the last call.
+    pub(crate) fn take_finished(&mut self) -> Vec<CSVMatchCluster> {
+        std::mem::take(&mut self.finished_clusters)
+    }
+}
@@ -1,5 +1,9 @@
 use crate::Result;
 use failure::ResultExt;
+use ipfs_monitoring_plugin_client::monitoring::ReconnectConfig;
+use ipfs_resolver_common::events::TraceFormat;
+use ipfs_resolver_common::geoip::GeoIPConfig;
+use ipfs_resolver_common::output::OutputConfig;
 use ipfs_resolver_common::wantlist;
 use serde::{Deserialize, Serialize};
 use std::fs::File;
@@ -12,35 +16,89 @@ pub(crate) struct Config {
     ///
     /// The monitors configured here will be given numeric IDs in the order they are provided in
     /// the config file.
+    /// This must be empty for live unification.
+    #[serde(default)]
     pub(crate) monitors: Vec<MonitorSourceConfig>,
 
     /// The size of the sliding window (in number of JSON messages) to use for sorting the streams
     /// of per-monitor messages.
+    /// Defaults to 1000.
+    #[serde(default = "default_message_sorting_window_size")]
     pub(crate) message_sorting_window_size: usize,
 
+    /// Configures live unification of events received from monitors via AMQP, instead of
+    /// unifying traces.
+    /// Defaults to unifying traces.
+    #[serde(default)]
+    pub(crate) live: Option<LiveConfig>,
+
     /// A pattern for output file paths.
     /// The pattern must contain "$id$", which will be replaced by the ID (number) of the first
     /// message in this file, formatted in such a way that the paths are lexicographically ordered.
     /// This is used in order not to produce one gigantic output file, but rather a bunch of smaller
     /// ones.
     ///
-    /// The output file will be gzipped CSV.
+    /// The output file will be in the format configured by `output`.
+    /// For columnar formats, only the file for ID zero is created, with one row group or record
+    /// batch per rotation.
     ///
     /// Example: output/part-$id$.csv.gz
     pub(crate) wantlist_output_file_pattern: String,
 
-    /// Currently unused.
-    ///
-    /// The path for a file to record something about ledgers in.
+    /// The path for a file to record the number of ledgers and missing ledgers in, for each
+    /// monitor and in total.
+    /// A record is written whenever the output is rotated, and at the end.
     ///
-    /// The file will be gzipped CSV.
+    /// The output file will be in the format configured by `output`.
     pub(crate) ledger_count_output_file: String,
 
     /// Configuration for the global matching and deduplication algorithm.
     pub(crate) matching_config: MatchingConfig,
 
+    /// Configuration for the estimation of clock offsets between monitors.
+    /// If set, the offsets are estimated in a pass over the traces before the unification, and
+    /// corrected for in addition to the offsets configured per monitor.
+    /// Defaults to no estimation.
+    #[serde(default)]
+    pub(crate) clock_offset_estimation: Option<ClockOffsetEstimationConfig>,
+
     /// Configuration for the single-monitor bitswap simulations.
     pub(crate) simulation_config: wantlist::EngineSimulationConfig,
+
+    /// The path of a file to write the lifetimes of requests to, as tracked by the engine
+    /// simulation of each monitor.
+    /// Defaults to not writing request lifetimes.
+    #[serde(default)]
+    pub(crate) request_lifetimes_output_file: Option<String>,
+
+    /// The path of a file to write a summary of each match cluster to, i.e., of each group of
+    /// matched entries from different monitors which represent the same request.
+    /// Clusters of a single, unmatched entry are written as well.
+    /// Defaults to not writing match clusters.
+    #[serde(default)]
+    pub(crate) match_clusters_output_file: Option<String>,
+
+    /// The format to write output in.
+    /// Defaults to gzipped CSV.
+    #[serde(default)]
+    pub(crate) output: OutputConfig,
+
+    /// Configures enrichment of the wantlist output with the origin of the address of each entry,
+    /// i.e., its country and, optionally, autonomous system and city.
+    /// Defaults to no enrichment, leaving the `origin_` columns empty.
+    #[serde(default)]
+    pub(crate) geoip: Option<GeoIPConfig>,
+
+    /// The file to write a snapshot of the unification state to whenever the output is rotated,
+    /// to resume from with `--resume`.
+    /// This requires gzipped CSV output.
+    /// Defaults to no snapshots.
+    #[serde(default)]
+    pub(crate) snapshot_file: Option<String>,
+}
+
+fn default_message_sorting_window_size() -> usize {
+    1000
 }
 
 impl Config {
@@ -69,10 +127,98 @@ pub(crate) struct MonitorSourceConfig {
     /// In our setup, files are named in such a way that they order lexicographically over time,
     /// i.e., ordered by date and time of day.
     pub(crate) input_globs: Vec<String>,
+
+    /// The format of the input files, either legacy wantlist messages as logged by our modified
+    /// IPFS, or events as logged by the `bitswap-monitoring-client`.
+    /// Defaults to detecting the format of each file.
+    #[serde(default)]
+    pub(crate) input_format: TraceFormat,
+
+    /// The offset of the clock of this monitor, in milliseconds, which is subtracted from its
+    /// timestamps.
+    /// Defaults to zero.
+    #[serde(default)]
+    pub(crate) clock_offset_milliseconds: i64,
+}
+
+/// Configuration for the live unification of events received via AMQP.
+///
+/// Messages of all monitors are merged by timestamp in a reorder buffer.
+/// The buffer keeps a watermark per monitor, which trails the newest timestamp received from
+/// the monitor by `allowed_lateness_milliseconds`.
+/// Messages are released once they are older than the watermarks of all monitors, except for
+/// monitors which did not deliver messages for `idle_timeout_milliseconds`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct LiveConfig {
+    /// The monitors to subscribe to.
+    /// These are given numeric IDs in the order they are provided, as for traces.
+    pub(crate) monitors: Vec<LiveMonitorConfig>,
+
+    /// How much older than the newest message of a monitor its messages may be, in
+    /// milliseconds, to still be released in order.
+    /// This delays the output by the same amount.
+    /// Defaults to 2000.
+    #[serde(default = "default_allowed_lateness_milliseconds")]
+    pub(crate) allowed_lateness_milliseconds: u64,
+
+    /// The time after which a monitor which did not deliver any messages is not waited for
+    /// anymore, in milliseconds.
+    /// Defaults to 10000.
+    #[serde(default = "default_idle_timeout_milliseconds")]
+    pub(crate) idle_timeout_milliseconds: u64,
+
+    /// Specifies on what address a prometheus endpoint will be created.
+    pub(crate) prometheus_address: String,
+}
+
+fn default_allowed_lateness_milliseconds() -> u64 {
+    2000
+}
+
+fn default_idle_timeout_milliseconds() -> u64 {
+    10_000
+}
+
+/// Configuration for a single monitor to subscribe to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct LiveMonitorConfig {
+    /// The name of the monitor, which is used in the routing keys of its events.
+    pub(crate) monitor_name: String,
+
+    /// The address of the AMQP server, including the amqp:// or amqps:// scheme.
+    pub(crate) amqp_server_address: String,
+
+    /// Configures backoff for re-establishing lost connections.
+    /// Defaults to retrying indefinitely, starting at 500ms and backing off to at most one minute.
+    #[serde(default)]
+    pub(crate) reconnect: ReconnectConfig,
+
+    /// The offset of the clock of this monitor, in milliseconds, which is subtracted from its
+    /// timestamps.
+    /// Defaults to zero.
+    #[serde(default)]
+    pub(crate) clock_offset_milliseconds: i64,
+}
+
+/// Configuration for the estimation of clock offsets between monitors.
+///
+/// The offsets and drifts of the clocks of all monitors are estimated relative to the first
+/// monitor, from the time differences of high-confidence matches with it.
+/// These are matches of entries which are neither synthetic nor per-monitor duplicates.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ClockOffsetEstimationConfig {
+    /// The number of messages, from the start of the traces, to estimate the offsets on.
+    /// Defaults to all messages.
+    #[serde(default)]
+    pub(crate) num_messages: Option<i64>,
+
+    /// The minimum number of matches with the first monitor required to correct the clock of a
+    /// monitor.
+    pub(crate) min_samples: u64,
 }
 
 /// Configuration for the global matching algorithms.
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) struct MatchingConfig {
     /// The window size, in milliseconds, in which entries can be matched between monitors.
     pub(crate) inter_monitor_matching_window_milliseconds: u64,
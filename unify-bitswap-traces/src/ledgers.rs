@@ -0,0 +1,135 @@
+use crate::Result;
+use failure::ResultExt;
+use ipfs_resolver_common::output::{Column, ColumnType, OutputSink, Record};
+use serde::Serialize;
+
+/// The number of ledgers of one or all monitors at some point in time, to be serialized as CSV.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct CSVLedgerCount {
+    pub ts_secs: i64,
+    /// The monitor this count is for, or `None` for the sum over all monitors.
+    pub monitor_id: Option<usize>,
+    /// The number of messages for which no ledger existed since the previous count.
+    pub missing_ledgers: usize,
+    /// The number of ledgers currently tracked by the engine simulation.
+    pub total_ledgers: usize,
+}
+
+impl Record for CSVLedgerCount {
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::new("ts_secs", ColumnType::Int64),
+            Column::nullable("monitor_id", ColumnType::UInt64),
+            Column::new("missing_ledgers", ColumnType::UInt64),
+            Column::new("total_ledgers", ColumnType::UInt64),
+        ]
+    }
+}
+
+/// Counts missing ledgers per monitor, between writes of ledger counts.
+#[derive(Clone, Debug)]
+pub(crate) struct LedgerCounter {
+    missing_ledgers: Vec<usize>,
+}
+
+impl LedgerCounter {
+    pub(crate) fn new(num_monitors: usize) -> LedgerCounter {
+        LedgerCounter {
+            missing_ledgers: vec![0; num_monitors],
+        }
+    }
+
+    /// Records a message of the given monitor for which no ledger existed.
+    pub(crate) fn record_missing_ledger(&mut self, monitor_id: usize) {
+        self.missing_ledgers[monitor_id] += 1;
+    }
+
+    /// Writes the ledger counts of each monitor and their sum, and resets the counts of missing
+    /// ledgers.
+    pub(crate) fn write_counts(
+        &mut self,
+        ts: chrono::DateTime<chrono::Utc>,
+        num_ledgers: &[usize],
+        sink: &mut OutputSink<CSVLedgerCount>,
+    ) -> Result<()> {
+        for (monitor_id, (missing_ledgers, total_ledgers)) in self
+            .missing_ledgers
+            .iter()
+            .zip(num_ledgers.iter())
+            .enumerate()
+        {
+            sink.write(&CSVLedgerCount {
+                ts_secs: ts.timestamp(),
+                monitor_id: Some(monitor_id),
+                missing_ledgers: *missing_ledgers,
+                total_ledgers: *total_ledgers,
+            })
+            .context("unable to write ledger count")?;
+        }
+
+        let missing_ledgers: usize = self.missing_ledgers.iter().sum();
+        let total_ledgers: usize = num_ledgers.iter().sum();
+        info!(
+            "{} missing ledgers, {} ledgers total",
+            missing_ledgers, total_ledgers
+        );
+        sink.write(&CSVLedgerCount {
+            ts_secs: ts.timestamp(),
+            monitor_id: None,
+            missing_ledgers,
+            total_ledgers,
+        })
+        .context("unable to write total ledger count")?;
+
+        self.missing_ledgers.iter_mut().for_each(|c| *c = 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipfs_resolver_common::output::OutputConfig;
+
+    fn read_counts(path: &std::path::Path) -> Vec<(i64, Option<usize>, usize, usize)> {
+        let file = std::fs::File::open(path).unwrap();
+        csv::Reader::from_reader(flate2::read::GzDecoder::new(file))
+            .deserialize()
+            .map(|r| r.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn writes_per_monitor_and_total_counts() {
+        let path =
+            std::env::temp_dir().join(format!("ledger-counts-test-{}.csv.gz", std::process::id()));
+        let mut sink = OutputSink::create(&OutputConfig::default(), &path).unwrap();
+        let ts = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+
+        let mut counter = LedgerCounter::new(2);
+        counter.record_missing_ledger(0);
+        counter.record_missing_ledger(1);
+        counter.record_missing_ledger(1);
+        counter.write_counts(ts, &[3, 5], &mut sink).unwrap();
+        counter.record_missing_ledger(0);
+        counter
+            .write_counts(ts + chrono::Duration::seconds(60), &[4, 5], &mut sink)
+            .unwrap();
+        sink.finish().unwrap();
+
+        let counts = read_counts(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            counts,
+            vec![
+                (1_700_000_000, Some(0), 1, 3),
+                (1_700_000_000, Some(1), 2, 5),
+                (1_700_000_000, None, 3, 8),
+                // Missing ledgers are counted since the previous write.
+                (1_700_000_060, Some(0), 1, 4),
+                (1_700_000_060, Some(1), 0, 5),
+                (1_700_000_060, None, 1, 9),
+            ]
+        );
+    }
+}
@@ -0,0 +1,62 @@
+use ipfs_resolver_common::output::{Column, ColumnType, Record};
+use ipfs_resolver_common::wantlist::CSVRequestLifetime;
+use serde::Serialize;
+
+// TODO keep this in sync with `wantlist::CSVRequestLifetime`.
+/// The lifetime of a request as seen by one monitor, to be serialized as CSV.
+/// See `wantlist::CSVRequestLifetime` for a description of the fields.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct OutputCSVRequestLifetime {
+    /// The monitor whose engine simulation produced this lifetime.
+    pub monitor_id: usize,
+
+    pub peer_id: String,
+    pub cid: String,
+
+    pub start_message_id: i64,
+    pub start_timestamp_seconds: i64,
+    pub start_timestamp_subsec_milliseconds: u32,
+    pub start_entry_type: i32,
+    pub end_entry_type: i32,
+    pub upgraded: bool,
+    pub num_resends: u32,
+
+    pub end_message_id: i64,
+    pub end_timestamp_seconds: i64,
+    pub end_timestamp_subsec_milliseconds: u32,
+    pub end_reason: i32,
+    pub duration_ms: u64,
+
+    pub normalized_cid: Option<String>,
+}
+
+impl Record for OutputCSVRequestLifetime {
+    fn columns() -> Vec<Column> {
+        let mut columns = vec![Column::new("monitor_id", ColumnType::UInt64)];
+        columns.extend(CSVRequestLifetime::columns());
+        columns
+    }
+}
+
+impl OutputCSVRequestLifetime {
+    pub(crate) fn new(monitor_id: usize, l: CSVRequestLifetime) -> OutputCSVRequestLifetime {
+        OutputCSVRequestLifetime {
+            monitor_id,
+            peer_id: l.peer_id,
+            cid: l.cid,
+            normalized_cid: l.normalized_cid,
+            start_message_id: l.start_message_id,
+            start_timestamp_seconds: l.start_timestamp_seconds,
+            start_timestamp_subsec_milliseconds: l.start_timestamp_subsec_milliseconds,
+            start_entry_type: l.start_entry_type,
+            end_entry_type: l.end_entry_type,
+            upgraded: l.upgraded,
+            num_resends: l.num_resends,
+            end_message_id: l.end_message_id,
+            end_timestamp_seconds: l.end_timestamp_seconds,
+            end_timestamp_subsec_milliseconds: l.end_timestamp_subsec_milliseconds,
+            end_reason: l.end_reason,
+            duration_ms: l.duration_ms,
+        }
+    }
+}
@@ -0,0 +1,485 @@
+use crate::clock::ClockCorrection;
+use crate::config::{Config, LiveConfig, LiveMonitorConfig};
+use crate::matcher::{InterMonitorMatcher, OutputCSVWantlistEntry};
+use crate::prom;
+use crate::reorder::WatermarkReorderBuffer;
+use crate::source::MultiSourceIngestResult;
+use crate::unifier::Unifier;
+use crate::Result;
+use failure::{ensure, ResultExt};
+use futures_util::StreamExt;
+use ipfs_monitoring_plugin_client::monitoring::{
+    MonitoringClient, MonitoringClientOptions, MonitoringItem, RoutingKeyInformation,
+};
+use ipfs_monitoring_plugin_client::source::MonitoringSource;
+use ipfs_resolver_common::wantlist;
+use ipfs_resolver_common::wantlist::{EngineSimulation, JSONMessage};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+/// The interval at which buffered messages are released while no new messages arrive.
+const RELEASE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The number of items received from all monitors which can be queued for buffering.
+const ITEM_BUFFER_SIZE: usize = 1024;
+
+/// An item received from a monitor, or the error which ended its subscription.
+type ReceivedItem = (usize, Result<MonitoringItem>);
+
+/// Unifies events received live from the configured monitors until shut down, i.e., until
+/// SIGINT or SIGTERM is received or all subscriptions end.
+pub(crate) fn run(cfg: Config, live_cfg: LiveConfig) -> Result<()> {
+    ensure!(
+        cfg.monitors.is_empty(),
+        "monitors must be configured either for traces or live"
+    );
+    ensure!(
+        !live_cfg.monitors.is_empty(),
+        "live unification requires at least one monitor"
+    );
+    ensure!(
+        cfg.snapshot_file.is_none(),
+        "snapshots are not supported for live unification"
+    );
+    ensure!(
+        cfg.clock_offset_estimation.is_none(),
+        "clock offset estimation is not supported for live unification"
+    );
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("unable to set up runtime")?;
+    runtime.block_on(run_async(cfg, live_cfg))
+}
+
+async fn run_async(cfg: Config, live_cfg: LiveConfig) -> Result<()> {
+    let prometheus_address = live_cfg
+        .prometheus_address
+        .parse::<SocketAddr>()
+        .context("invalid prometheus_address")?;
+    debug!("starting prometheus server");
+    prom::run_prometheus(prometheus_address)?;
+    info!("started prometheus server");
+
+    let mut unification = LiveUnification::new(&cfg, &live_cfg)?;
+    info!("unifying monitors {:?}", unification.source_names);
+
+    // Subscribe to all monitors.
+    // The subscriptions end once the receiver is dropped, or are aborted when the set is dropped.
+    let (items_tx, items_rx) = mpsc::channel(ITEM_BUFFER_SIZE);
+    let mut subscriptions = JoinSet::new();
+    for (monitor_id, monitor_cfg) in live_cfg.monitors.into_iter().enumerate() {
+        subscriptions.spawn(subscribe(monitor_id, monitor_cfg, items_tx.clone()));
+    }
+    drop(items_tx);
+
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .context("unable to set up signal handling")?;
+    let shutdown = async move {
+        select! {
+            _ = tokio::signal::ctrl_c() => info!("received shutdown signal, shutting down..."),
+            _ = sigterm.recv() => info!("received SIGTERM, shutting down...")
+        }
+    };
+
+    let before = std::time::Instant::now();
+    info!("unifying live, try Ctrl+C to exit");
+    let failure = unification.unify_until(items_rx, shutdown).await?;
+
+    // Stop receiving, then unify whatever is still buffered.
+    subscriptions.shutdown().await;
+    let source_names = unification.source_names.clone();
+    let msg_id = unification.message_id;
+    let matcher = unification.finish()?;
+    let time_diff = before.elapsed();
+
+    info!(
+        "processed {} messages in {:.1}s => {:.1}msg/s",
+        msg_id,
+        time_diff.as_secs_f32(),
+        (msg_id as f64) / time_diff.as_secs_f64()
+    );
+    crate::log_matching_stats(&source_names, &matcher);
+
+    match failure {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Subscribes to the events of a monitor via AMQP and passes them on, until the subscription
+/// fails or the receiver is dropped.
+async fn subscribe(monitor_id: usize, cfg: LiveMonitorConfig, items: mpsc::Sender<ReceivedItem>) {
+    let routing_keys = vec![
+        RoutingKeyInformation::BitswapMessages {
+            monitor_name: cfg.monitor_name.clone(),
+        },
+        RoutingKeyInformation::ConnectionEvents {
+            monitor_name: cfg.monitor_name.clone(),
+        },
+    ];
+    let options = MonitoringClientOptions {
+        reconnect: Some(cfg.reconnect.clone()),
+        skip_undecodable: true,
+        ..Default::default()
+    };
+
+    debug!(
+        "connecting to AMQP server at {} and subscribing to events for monitor {}...",
+        cfg.amqp_server_address, cfg.monitor_name
+    );
+    match MonitoringClient::new_with_options(&cfg.amqp_server_address, &routing_keys, options).await
+    {
+        Ok(client) => {
+            info!(
+                "connected for monitor {} at {}",
+                cfg.monitor_name, cfg.amqp_server_address
+            );
+            forward(monitor_id, &cfg.monitor_name, client, items).await
+        }
+        Err(err) => {
+            // We ignore this error because we return immediately.
+            let _ = items.send((monitor_id, Err(err))).await;
+        }
+    }
+}
+
+/// Passes on the items of a source, until it ends or the receiver is dropped.
+async fn forward<S: MonitoringSource>(
+    monitor_id: usize,
+    monitor_name: &str,
+    mut source: S,
+    items: mpsc::Sender<ReceivedItem>,
+) {
+    while let Some(item) = source.next().await {
+        if items.send((monitor_id, item)).await.is_err() {
+            debug!("monitor {}: receiver gone, quitting", monitor_name);
+            return;
+        }
+    }
+    info!("monitor {}: subscription ended", monitor_name);
+}
+
+/// The state of a live unification: the engine simulations of the monitors, the buffer merging
+/// their messages, and the unifier.
+struct LiveUnification {
+    source_names: Vec<String>,
+    engine_states: Vec<EngineSimulation>,
+    clock_corrections: Vec<ClockCorrection>,
+    buffer: WatermarkReorderBuffer,
+    unifier: Unifier,
+    /// The ID of the last message unified.
+    message_id: i64,
+}
+
+impl LiveUnification {
+    fn new(cfg: &Config, live_cfg: &LiveConfig) -> Result<LiveUnification> {
+        let source_names = live_cfg
+            .monitors
+            .iter()
+            .map(|m| m.monitor_name.clone())
+            .collect::<Vec<_>>();
+        let clock_corrections = live_cfg
+            .monitors
+            .iter()
+            .map(|m| ClockCorrection::fixed(m.clock_offset_milliseconds))
+            .collect();
+
+        let state = EngineSimulation::new(cfg.simulation_config.clone())
+            .context("unable to set up engine simulation")?;
+        let engine_states = vec![state; source_names.len()];
+
+        let buffer = WatermarkReorderBuffer::new(
+            source_names.len(),
+            Duration::from_millis(live_cfg.allowed_lateness_milliseconds),
+            Duration::from_millis(live_cfg.idle_timeout_milliseconds),
+            Instant::now(),
+        );
+        let unifier = Unifier::create(cfg, source_names.len(), 0)?;
+
+        Ok(LiveUnification {
+            source_names,
+            engine_states,
+            clock_corrections,
+            buffer,
+            unifier,
+            message_id: 0,
+        })
+    }
+
+    /// Unifies the items received until all subscriptions end, one of them fails, or the given
+    /// future completes.
+    /// Messages still buffered are unified afterwards.
+    /// Returns the error of the subscription which failed, if any.
+    async fn unify_until<F: Future<Output = ()>>(
+        &mut self,
+        mut items: mpsc::Receiver<ReceivedItem>,
+        shutdown: F,
+    ) -> Result<Option<failure::Error>> {
+        // Output is written synchronously, which only blocks this loop, as the subscriptions run
+        // on the worker threads of the runtime.
+        let mut release_interval = tokio::time::interval(RELEASE_INTERVAL);
+        let mut failure = None;
+        tokio::pin!(shutdown);
+        loop {
+            select! {
+                item = items.recv() => match item {
+                    Some((monitor_id, Ok(item))) => self.handle_item(monitor_id, item),
+                    Some((monitor_id, Err(err))) => {
+                        error!(
+                            "monitor {}: subscription failed, shutting down: {:?}",
+                            self.source_names[monitor_id], err
+                        );
+                        failure = Some(err);
+                        break;
+                    }
+                    None => {
+                        info!("all subscriptions ended, shutting down...");
+                        break;
+                    }
+                },
+                _ = release_interval.tick() => {},
+                _ = &mut shutdown => break,
+            }
+
+            self.release_ready(Instant::now())
+                .context("unable to unify messages")?;
+        }
+
+        info!("flushing {} buffered messages...", self.buffer.len());
+        self.release_all().context("unable to unify messages")?;
+
+        Ok(failure)
+    }
+
+    /// Buffers the events of an item received from a monitor, or records a gap.
+    fn handle_item(&mut self, monitor_id: usize, item: MonitoringItem) {
+        let monitor_name = self.source_names[monitor_id].as_str();
+        match item {
+            MonitoringItem::Events(_, events) => {
+                let now = Instant::now();
+                for event in events {
+                    let mut msg = JSONMessage::from(event);
+                    msg.timestamp = self.clock_corrections[monitor_id].apply(msg.timestamp);
+                    prom::MESSAGES_RECEIVED
+                        .with_label_values(&[monitor_name])
+                        .inc();
+                    if self.buffer.push(monitor_id, msg, now) {
+                        debug!("monitor {}: dropping late message", monitor_name);
+                        prom::MESSAGES_LATE.with_label_values(&[monitor_name]).inc();
+                    }
+                }
+            }
+            MonitoringItem::Gap(gap) => {
+                warn!(
+                    "monitor {}: missed data from {} to {}",
+                    monitor_name, gap.disconnected_at, gap.reconnected_at
+                );
+                prom::MONITORING_GAPS
+                    .with_label_values(&[monitor_name])
+                    .inc();
+                prom::MONITORING_GAP_SECONDS
+                    .with_label_values(&[monitor_name])
+                    .inc_by(gap.duration().num_milliseconds() as f64 / 1000_f64);
+            }
+        }
+    }
+
+    /// Unifies all messages which can be released at `now`.
+    fn release_ready(&mut self, now: Instant) -> Result<()> {
+        while let Some((monitor_id, msg)) = self.buffer.pop_ready(now) {
+            self.unify_message(monitor_id, msg)?;
+        }
+
+        prom::REORDER_BUFFER_MESSAGES.set(self.buffer.len() as i64);
+        if let Some(watermark) = self.buffer.watermark(now) {
+            if watermark > chrono::DateTime::<chrono::Utc>::MIN_UTC {
+                prom::WATERMARK_LAG_SECONDS
+                    .set((chrono::Utc::now() - watermark).num_milliseconds() as f64 / 1000_f64);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unifies all buffered messages.
+    fn release_all(&mut self) -> Result<()> {
+        while let Some((monitor_id, msg)) = self.buffer.pop() {
+            self.unify_message(monitor_id, msg)?;
+        }
+        prom::REORDER_BUFFER_MESSAGES.set(0);
+        Ok(())
+    }
+
+    fn unify_message(&mut self, monitor_id: usize, msg: JSONMessage) -> Result<()> {
+        // Rotate output file if necessary, such that the new file is named after this message.
+        if self.unifier.needs_rotation() {
+            let num_ledgers = self
+                .engine_states
+                .iter()
+                .map(|e| e.num_ledgers())
+                .collect::<Vec<_>>();
+            self.unifier.rotate(self.message_id + 1, &num_ledgers)?;
+        }
+
+        self.message_id += 1;
+        let simulation_result = self.engine_states[monitor_id]
+            .ingest(&msg, self.message_id)
+            .context("unable to update engine simulation state with new message")?;
+        let output_entries = self.unifier.handle_ingest_result(MultiSourceIngestResult {
+            monitor_id,
+            timestamp: msg.timestamp,
+            peer_id: msg.peer,
+            simulation_result,
+        })?;
+
+        output_entries.iter().for_each(|e| self.record_metrics(e));
+        Ok(())
+    }
+
+    fn record_metrics(&self, entry: &OutputCSVWantlistEntry) {
+        if entry.message_type == wantlist::CSV_MESSAGE_TYPE_SYNTHETIC {
+            return;
+        }
+        let monitor_name = self.source_names[entry.monitor_id as usize].as_str();
+        prom::ENTRIES_UNIFIED
+            .with_label_values(&[monitor_name])
+            .inc();
+
+        if let (Some(matched_to_monitor_id), Some(diff_ms)) =
+            (entry.matched_to_monitor_id, entry.match_time_diff_ms)
+        {
+            let labels = [
+                monitor_name,
+                self.source_names[matched_to_monitor_id as usize].as_str(),
+            ];
+            prom::ENTRIES_MATCHED.with_label_values(&labels).inc();
+            prom::INTER_MONITOR_DELAY_SECONDS
+                .with_label_values(&labels)
+                .observe(diff_ms as f64 / 1000_f64);
+        }
+        if entry.global_duplicate_time_diff_ms.is_some() {
+            prom::ENTRIES_GLOBAL_DUPLICATES
+                .with_label_values(&[monitor_name])
+                .inc();
+        }
+    }
+
+    /// Emits end-of-simulation entries and finishes the outputs.
+    /// Returns the matcher, e.g., to get statistics.
+    fn finish(self) -> Result<InterMonitorMatcher> {
+        self.unifier
+            .finish(self.engine_states, self.message_id)
+            .context("unable to finish unification")
+            .map_err(|e| e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use ipfs_monitoring_plugin_client::tcp::TCPMonitoringClient;
+    use ipfs_monitoring_plugin_mock::events;
+    use ipfs_monitoring_plugin_mock::publisher::MockPublisher;
+    use ipfs_resolver_common::wantlist::JSONWantType;
+    use std::fs::File;
+
+    const CID: &str = "QmPZ9gcCEpqKTo6aq61g2nXGUhM4iCL3ewB6LDXZCtioEB";
+    const OTHER_CID: &str = "QmUNLLsPACCz1vLxQVkXqqLX5R1X345qqfHbsf67hvA3Nn";
+
+    #[tokio::test]
+    async fn unifies_events_of_mock_monitors() {
+        let dir = std::env::temp_dir().join(format!("unify-live-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cfg: Config = serde_yaml::from_str(&format!(
+            r#"
+wantlist_output_file_pattern: "{dir}/wl-$id$.csv.gz"
+ledger_count_output_file: "{dir}/ledgers.csv.gz"
+matching_config:
+  inter_monitor_matching_window_milliseconds: 5000
+  global_duplicate_window_seconds: 31
+  match_newest_first: false
+  allow_multiple_match: false
+  match_exact_entry_type: false
+simulation_config:
+  allow_empty_full_wantlist: false
+  allow_empty_connection_event: false
+  insert_full_wantlist_synth_cancels: true
+  insert_disconnect_synth_cancels: true
+  reconnect_duplicate_duration_secs: 5
+  sliding_window_lengths: [1,9,11,29,31,601,3601,604801]
+live:
+  monitors:
+    - monitor_name: "a"
+      amqp_server_address: "amqp://unused"
+    - monitor_name: "b"
+      amqp_server_address: "amqp://unused"
+  prometheus_address: "127.0.0.1:0"
+"#,
+            dir = dir.display()
+        ))
+        .unwrap();
+        let mut unification = LiveUnification::new(&cfg, cfg.live.as_ref().unwrap()).unwrap();
+
+        let publishers = [
+            MockPublisher::start().await.unwrap(),
+            MockPublisher::start().await.unwrap(),
+        ];
+        let (items_tx, items_rx) = mpsc::channel(ITEM_BUFFER_SIZE);
+        for (monitor_id, publisher) in publishers.iter().enumerate() {
+            let name = unification.source_names[monitor_id].clone();
+            let client = TCPMonitoringClient::new(&publisher.address(), &name)
+                .await
+                .unwrap();
+            publisher.wait_for_consumers(1).await;
+            tokio::spawn({
+                let items_tx = items_tx.clone();
+                async move { forward(monitor_id, &name, client, items_tx).await }
+            });
+        }
+        drop(items_tx);
+
+        // Both monitors see the same request, only the first one sees another one.
+        let want = events::want("peer", CID, JSONWantType::Block, false);
+        publishers[0]
+            .publish(&[
+                want.clone(),
+                events::want("peer", OTHER_CID, JSONWantType::Have, false),
+            ])
+            .unwrap();
+        publishers[1].publish(&[want]).unwrap();
+        publishers.iter().for_each(|p| p.disconnect_consumers());
+
+        let failure = tokio::time::timeout(
+            Duration::from_secs(10),
+            unification.unify_until(items_rx, std::future::pending()),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(failure.is_none());
+        assert_eq!(unification.message_id, 3);
+        unification.finish().unwrap();
+
+        let mut reader = csv::Reader::from_reader(GzDecoder::new(
+            File::open(dir.join("wl-000000000.csv.gz")).unwrap(),
+        ));
+        let headers = reader.headers().unwrap().clone();
+        let column = |name: &str| headers.iter().position(|h| h == name).unwrap();
+        let (matched_to, message_type) = (column("matched_to_monitor_id"), column("message_type"));
+        let rows = reader
+            .records()
+            .map(|r| r.unwrap())
+            .filter(|r| r[message_type] != wantlist::CSV_MESSAGE_TYPE_SYNTHETIC.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows.iter().filter(|r| !r[matched_to].is_empty()).count(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,18 +1,47 @@
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate prometheus;
 
+mod clock;
+mod clusters;
 mod config;
+mod ledgers;
+mod lifetimes;
+mod live;
 mod matcher;
+mod prom;
+mod reorder;
 mod source;
+mod unifier;
+mod window;
+#[cfg(test)]
+mod window_testing;
 
-use crate::config::Config;
+use crate::clock::{ClockCorrection, ClockOffsetEstimate};
+use crate::config::{ClockOffsetEstimationConfig, Config};
 use crate::matcher::InterMonitorMatcher;
-use crate::source::{MultiSourceIngestResult, MultiSourceIngester};
+use crate::source::{IngesterState, MultiSourceIngester};
+use crate::unifier::{Unifier, UnifierState};
 use clap::{App, Arg};
-use failure::{err_msg, ResultExt};
-use flate2::write::GzEncoder;
-use ipfs_resolver_common::{logging, Result};
-use std::io;
+use failure::{ensure, err_msg, ResultExt};
+use ipfs_resolver_common::output::OutputFormat;
+use ipfs_resolver_common::{logging, snapshot, Result};
+use serde::{Deserialize, Serialize};
+
+/// The kind of snapshots written by this tool.
+const SNAPSHOT_KIND: &str = "unify-bitswap-traces";
+
+/// A snapshot of the unification, taken whenever the output is rotated.
+#[derive(Serialize, Deserialize)]
+struct UnifySnapshot {
+    /// The ID of the first message of the output file to write next.
+    output_file_id: i64,
+    ingester: IngesterState,
+    unifier: UnifierState,
+}
 
 fn main() -> Result<()> {
     logging::set_up_logging()?;
@@ -29,6 +58,12 @@ fn main() -> Result<()> {
                 .help("the config file to load")
                 .required(true),
         )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .help("resume unification from the configured snapshot file"),
+        )
+        .after_help("If the config contains a `live` section, events are unified live as they are received via AMQP, until Ctrl+C is pressed.")
         .get_matches();
 
     if !matches.is_present("cfg") {
@@ -39,8 +74,22 @@ fn main() -> Result<()> {
 
     // Read config
     info!("attempting to load config file '{}'", cfg);
-    let cfg = Config::open(cfg).context("unable to load config")?;
+    let mut cfg = Config::open(cfg).context("unable to load config")?;
+    cfg.simulation_config.emit_request_lifetimes = cfg.request_lifetimes_output_file.is_some();
     debug!("read config {:?}", cfg);
+    ensure!(
+        cfg.snapshot_file.is_none() || cfg.output.format == OutputFormat::CsvGz,
+        "snapshots require gzipped CSV output"
+    );
+
+    if let Some(live_cfg) = cfg.live.clone() {
+        ensure!(
+            !matches.is_present("resume"),
+            "resuming is not supported for live unification"
+        );
+        return live::run(cfg, live_cfg);
+    }
+    ensure!(!cfg.monitors.is_empty(), "no monitors configured");
 
     // Construct merged source
     let mut multi_source =
@@ -48,68 +97,75 @@ fn main() -> Result<()> {
     let source_names = multi_source.source_names();
     info!("unifying sources {:?}", source_names);
 
-    // Construct the thing that keeps track of inter-monitor duplicates and matches entries
-    let mut dup_marker = InterMonitorMatcher::new_from_config(&cfg.matching_config)
-        .context("unable to construct inter-monitor duplicate marker and matcher")?;
+    // Construct the thing that keeps track of inter-monitor duplicates and matches entries, and
+    // writes the output
+    let mut unifier = if matches.is_present("resume") {
+        let snapshot_file = cfg
+            .snapshot_file
+            .as_ref()
+            .ok_or_else(|| err_msg("resuming requires a snapshot file"))?;
+        info!("resuming from snapshot {}", snapshot_file);
+        let snapshot: UnifySnapshot = snapshot::read_snapshot(snapshot_file, SNAPSHOT_KIND)
+            .context("unable to read snapshot")?;
 
-    let mut num_messages_in_current_output_file = 0;
-    let messages_per_file = 100_000;
-    let mut current_output_file =
-        create_output_writer_from_pattern(cfg.wantlist_output_file_pattern.clone(), 0)
-            .context("unable to create output file")?;
+        let unifier = Unifier::resume(
+            &cfg,
+            source_names.len(),
+            snapshot.output_file_id,
+            snapshot.unifier,
+        )?;
+        multi_source
+            .resume(snapshot.ingester)
+            .context("unable to resume sources")?;
+        info!("resuming after {} messages", multi_source.last_message_id());
+        unifier
+    } else {
+        if let Some(estimation_cfg) = cfg.clock_offset_estimation.as_ref() {
+            let corrections = estimate_clock_offsets(&cfg, estimation_cfg)
+                .context("unable to estimate clock offsets")?;
+            multi_source.correct_clocks(&corrections);
+        }
+        Unifier::create(&cfg, source_names.len(), 0)?
+    };
 
     // Iterate through entries produced by the merged source iterator
     let before = std::time::Instant::now();
-    while let Some(res) = multi_source.next() {
+    loop {
+        // Rotate output file if necessary.
+        // This is done before advancing the sources, such that the new file is named after the
+        // next message and we can take a snapshot here.
+        if unifier.needs_rotation() {
+            let first_message_id = multi_source.last_message_id() + 1;
+            unifier.rotate(first_message_id, &multi_source.num_ledgers())?;
+
+            if let Some(snapshot_file) = cfg.snapshot_file.as_ref() {
+                let snapshot = UnifySnapshot {
+                    output_file_id: first_message_id,
+                    ingester: multi_source.state(),
+                    unifier: unifier.state()?,
+                };
+                snapshot::write_snapshot(snapshot_file, SNAPSHOT_KIND, &snapshot)
+                    .context("unable to write snapshot")?;
+            }
+        }
+
+        let res = match multi_source.next() {
+            Some(res) => res,
+            None => break,
+        };
         match res {
             Err(e) => {
                 return Err(e.context("unable to advance sources").into());
             }
-            Ok(MultiSourceIngestResult {
-                monitor_id,
-                timestamp,
-                peer_id,
-                simulation_result,
-            }) => {
-                debug!(
-                    "got entry {:?} from monitor {}",
-                    simulation_result, monitor_id
-                );
-
-                // Feed that into the matching engine
-                let output_entries = dup_marker
-                    .handle_ingest_result(monitor_id, timestamp, peer_id, simulation_result)
-                    .context("unable to handle ingest result")?;
-
-                // Rotate output file if necessary
-                if num_messages_in_current_output_file > messages_per_file {
-                    current_output_file = create_output_writer_from_pattern(
-                        cfg.wantlist_output_file_pattern.clone(),
-                        multi_source.last_message_id() + 1,
-                    )
-                    .context("unable to create output file")?;
-                    num_messages_in_current_output_file = 0;
-                }
-
-                // Write entries to output file
-                output_entries
-                    .into_iter()
-                    .try_for_each(|e| current_output_file.serialize(e))
-                    .context("unable to write output")?;
-                num_messages_in_current_output_file += 1;
+            Ok(res) => {
+                unifier.handle_ingest_result(res)?;
             }
         }
     }
 
-    // TODO emit end-of-simulation synthetic cancels?
-    // TODO keep track of missing ledgers, maybe per-source?
-    // TODO also keep track of ledger count per-source and in sum?
-
-    let time_diff = before.elapsed();
-
     let msg_id = multi_source.last_message_id();
-    let _engine_states = multi_source.into_engine_states();
-    let matching_stats = dup_marker.stats();
+    let matcher = unifier.finish(multi_source.into_engine_states(), msg_id)?;
+    let time_diff = before.elapsed();
 
     info!(
         "processed {} messages in {:.1}s => {:.1}msg/s",
@@ -117,6 +173,14 @@ fn main() -> Result<()> {
         time_diff.as_secs_f32(),
         (msg_id as f64) / time_diff.as_secs_f64()
     );
+    log_matching_stats(&source_names, &matcher);
+
+    Ok(())
+}
+
+/// Logs statistics about the matches found, and the remaining clock offsets.
+fn log_matching_stats(source_names: &[String], matcher: &InterMonitorMatcher) {
+    let matching_stats = matcher.stats();
     info!(
         "{} entries in total, of which {} were matched between monitors",
         matching_stats.total_entries, matching_stats.matched_entries
@@ -127,19 +191,85 @@ fn main() -> Result<()> {
         (matching_stats.match_diff_sum / matching_stats.matched_entries as f64) * 1000.0,
         matching_stats.max_match_diff
     );
+    info!("remaining clock offsets relative to the first monitor:");
+    log_clock_offset_estimates(
+        source_names,
+        &matcher.clock_offset_estimates(source_names.len()),
+    );
+}
 
-    Ok(())
+/// Estimates the clock offsets of the monitors relative to the first monitor, by matching entries
+/// of a prefix of the traces.
+/// Returns a correction for each monitor, which is zero for monitors without enough matches.
+fn estimate_clock_offsets(
+    cfg: &Config,
+    estimation_cfg: &ClockOffsetEstimationConfig,
+) -> Result<Vec<ClockCorrection>> {
+    let mut multi_source =
+        MultiSourceIngester::from_config(cfg).context("unable to set up sources")?;
+    let source_names = multi_source.source_names();
+    let mut matcher = InterMonitorMatcher::new_from_config(&cfg.matching_config)
+        .context("unable to construct inter-monitor matcher")?;
+    info!("estimating clock offsets...");
+
+    let num_messages = estimation_cfg.num_messages.unwrap_or(i64::MAX);
+    while multi_source.last_message_id() < num_messages {
+        let res = match multi_source.next() {
+            Some(res) => res.context("unable to advance sources")?,
+            None => break,
+        };
+        matcher
+            .handle_ingest_result(
+                res.monitor_id,
+                res.timestamp,
+                res.peer_id,
+                res.simulation_result,
+            )
+            .context("unable to handle ingest result")?;
+        matcher.take_finished_clusters();
+    }
+
+    let estimates = matcher.clock_offset_estimates(source_names.len());
+    info!(
+        "estimated clock offsets relative to the first monitor from {} messages:",
+        multi_source.last_message_id()
+    );
+    log_clock_offset_estimates(&source_names, &estimates);
+
+    Ok(estimates
+        .iter()
+        .enumerate()
+        .map(|(monitor_id, estimate)| match estimate {
+            Some(estimate) if estimate.num_samples >= estimation_cfg.min_samples => {
+                estimate.correction
+            }
+            _ => {
+                if monitor_id != 0 {
+                    warn!(
+                        "not enough matches to correct the clock of monitor {}",
+                        source_names[monitor_id]
+                    );
+                }
+                ClockCorrection::fixed(0)
+            }
+        })
+        .collect())
 }
 
-fn create_output_writer_from_pattern(
-    pattern: String,
-    current_message_id: i64,
-) -> Result<csv::Writer<GzEncoder<io::BufWriter<std::fs::File>>>> {
-    Ok(csv::Writer::from_writer(GzEncoder::new(
-        io::BufWriter::new(
-            std::fs::File::create(pattern.replace("$id$", &format!("{:09}", current_message_id)))
-                .context("unable to open output file for writing")?,
-        ),
-        flate2::Compression::default(),
-    )))
+fn log_clock_offset_estimates(source_names: &[String], estimates: &[Option<ClockOffsetEstimate>]) {
+    for (name, estimate) in source_names.iter().zip(estimates.iter()).skip(1) {
+        match estimate {
+            Some(estimate) => info!(
+                "{}: offset {:.1}±{:.1}ms at {}, drift {:.2}±{:.2}ms/h, from {} matches",
+                name,
+                estimate.correction.offset_ms,
+                estimate.offset_ci_ms,
+                estimate.correction.reference_ts,
+                estimate.correction.drift_ms_per_hour,
+                estimate.drift_ci_ms_per_hour,
+                estimate.num_samples
+            ),
+            None => info!("{}: not enough matches", name),
+        }
+    }
 }
@@ -1,44 +1,15 @@
+use crate::clock::{ClockOffsetEstimate, ClockOffsetEstimator};
+use crate::clusters::{CSVMatchCluster, MatchClusters};
 use crate::config::MatchingConfig;
+use crate::window::{PeerWindow, SourcedCSVWantlistEntry};
 use crate::Result;
 use failure::ResultExt;
+use ipfs_resolver_common::geoip::{AddressOrigin, WithOrigin};
+use ipfs_resolver_common::output::{Column, ColumnType, Record};
 use ipfs_resolver_common::wantlist;
 use ipfs_resolver_common::wantlist::{CSVWantlistEntry, IngestResult};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, VecDeque};
-
-/// An entry in a per-peer queue.
-/// These queues hold entries from multiple monitors.
-/// We need to keep track of where they came from and whether they've been matched with.
-#[derive(Debug, Clone)]
-struct SourcedCSVWantlistEntry {
-    entry: CSVWantlistEntry,
-    monitor_id: usize,
-    matched: bool,
-}
-
-/// Functions to calculate time differences.
-/// Note that they always assume the receiver (the `SourcedCSVWantlistEntry`) to be further in the
-/// past than the thing compared to.
-impl SourcedCSVWantlistEntry {
-    fn millis_to_timestamp(&self, ts: chrono::DateTime<chrono::Utc>) -> i64 {
-        (ts.timestamp() - self.entry.timestamp_seconds) * 1000
-            + (ts.timestamp_subsec_millis() as i64
-                - self.entry.timestamp_subsec_milliseconds as i64)
-    }
-
-    fn millis_to_csv_entry(&self, other: &CSVWantlistEntry) -> i64 {
-        debug!(
-            "self.ts: {}, other.ts: {}, self.millis: {}, other.millis: {}",
-            self.entry.timestamp_seconds,
-            other.timestamp_seconds,
-            self.entry.timestamp_subsec_milliseconds,
-            other.timestamp_subsec_milliseconds
-        );
-        (other.timestamp_seconds - self.entry.timestamp_seconds) * 1000
-            + (other.timestamp_subsec_milliseconds as i64
-                - self.entry.timestamp_subsec_milliseconds as i64)
-    }
-}
+use std::collections::HashMap;
 
 /// A CSV entry augmented with information about the source and whether it has been matched to
 /// another entry.
@@ -48,6 +19,9 @@ struct MatchedCSVWantlistEntry {
     monitor_id: u64,
 
     inter_source_match: Option<InterSourceMatching>,
+    /// The match cluster of this entry, or of the entry it was matched to before clustering.
+    /// This is `None` for synthetic entries.
+    cluster_id: Option<i64>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -81,6 +55,10 @@ pub(crate) struct OutputCSVWantlistEntry {
     pub matched_to_monitor_id: Option<u64>,
     /// The time difference between the matches, in milliseconds.
     pub match_time_diff_ms: Option<u64>,
+    /// The match cluster this entry belongs to, i.e., the group of matched entries from different
+    /// monitors which represent the same request.
+    /// This is not set for synthetic entries.
+    pub match_cluster_id: Option<i64>,
 
     /// Whether this entry was seen at another monitor, within the global duplicate window.
     /// If yes: What's the time difference between the duplicates, in milliseconds?
@@ -132,6 +110,61 @@ pub(crate) struct OutputCSVWantlistEntry {
     /// This is only valid for requests of type `WANT_BLOCK`, as they can upgrade earlier
     /// `WANT_HAVE` requests.
     pub upgrades_earlier_request: bool,
+
+    /// The ISO 3166-1 alpha-2 code of the country of the address, if enriched, see `geoip`.
+    pub origin_country: Option<String>,
+    /// The number of the autonomous system of the address, if enriched.
+    pub origin_asn: Option<u32>,
+    /// The organization operating the autonomous system of the address, if enriched.
+    pub origin_as_organization: Option<String>,
+    /// The city of the address, if enriched.
+    pub origin_city: Option<String>,
+
+    /// The normalized CID, if CID normalization is enabled.
+    pub normalized_cid: Option<String>,
+}
+
+impl Record for OutputCSVWantlistEntry {
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::new("monitor_id", ColumnType::UInt64),
+            Column::nullable("matched_to_monitor_id", ColumnType::UInt64),
+            Column::nullable("match_time_diff_ms", ColumnType::UInt64),
+            Column::nullable("match_cluster_id", ColumnType::Int64),
+            Column::nullable("global_duplicate_time_diff_ms", ColumnType::UInt64),
+            Column::new("message_id", ColumnType::Int64),
+            Column::new("message_type", ColumnType::Int32),
+            Column::new("timestamp_seconds", ColumnType::Int64),
+            Column::new("timestamp_subsec_milliseconds", ColumnType::UInt32),
+            Column::new("peer_id", ColumnType::Dictionary),
+            Column::new("address", ColumnType::Dictionary),
+            Column::new("priority", ColumnType::Int32),
+            Column::new("entry_type", ColumnType::Int32),
+            Column::new("cid", ColumnType::Dictionary),
+            Column::new("duplicate_status", ColumnType::UInt32),
+            Column::new("sliding_window_smallest_match", ColumnType::UInt32),
+            Column::new("secs_since_earlier_message", ColumnType::UInt32),
+            Column::new("upgrades_earlier_request", ColumnType::Boolean),
+            Column::nullable("origin_country", ColumnType::Dictionary),
+            Column::nullable("origin_asn", ColumnType::UInt32),
+            Column::nullable("origin_as_organization", ColumnType::Dictionary),
+            Column::nullable("origin_city", ColumnType::Dictionary),
+            Column::nullable("normalized_cid", ColumnType::Dictionary),
+        ]
+    }
+}
+
+impl WithOrigin for OutputCSVWantlistEntry {
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn set_origin(&mut self, origin: AddressOrigin) {
+        self.origin_country = origin.country;
+        self.origin_asn = origin.asn;
+        self.origin_as_organization = origin.as_organization;
+        self.origin_city = origin.city;
+    }
 }
 
 impl From<GloballyDupedMatchedCSVWantlistEntry> for OutputCSVWantlistEntry {
@@ -146,20 +179,27 @@ impl From<GloballyDupedMatchedCSVWantlistEntry> for OutputCSVWantlistEntry {
             priority: e.entry.entry.priority,
             entry_type: e.entry.entry.entry_type,
             cid: e.entry.entry.cid,
+            normalized_cid: e.entry.entry.normalized_cid,
             duplicate_status: e.entry.entry.duplicate_status,
             sliding_window_smallest_match: e.entry.entry.sliding_window_smallest_match,
             secs_since_earlier_message: e.entry.entry.secs_since_earlier_message,
             monitor_id: e.entry.monitor_id,
             matched_to_monitor_id: e.entry.inter_source_match.map(|m| m.matched_to_monitor_id),
             match_time_diff_ms: e.entry.inter_source_match.map(|m| m.match_time_diff_ms),
+            match_cluster_id: e.entry.cluster_id,
             global_duplicate_time_diff_ms: e.global_dup.map(|m| m.time_since_dup_ms),
             upgrades_earlier_request: false,
+            origin_country: e.entry.entry.origin_country,
+            origin_asn: e.entry.entry.origin_asn,
+            origin_as_organization: e.entry.entry.origin_as_organization,
+            origin_city: e.entry.entry.origin_city,
         }
     }
 }
 
 /// The algorithm keeping track of global duplicates and matches between monitors.
-#[derive(Debug, Clone)]
+/// Its state can be serialized, to resume matching later.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct InterMonitorMatcher {
     /// Configuration
     cfg: MatchingConfig,
@@ -167,16 +207,22 @@ pub(crate) struct InterMonitorMatcher {
     /// Statistics
     stats: MatcherStatistics,
 
-    /// Maps Peer IDs to Queues of CSV entries.
-    /// The queues contain past entries for that peer ID, for both monitors, ordered by timestamp.
-    /// The queues do not contain synthetic entries.
-    /// The queue is truncated to the appropriate window size whenever a new entry for that peer ID
+    /// Estimates clock offsets between monitors from the matches found.
+    clock_offsets: ClockOffsetEstimator,
+
+    /// Groups matched entries into clusters.
+    clusters: MatchClusters,
+
+    /// Maps Peer IDs to windows of CSV entries.
+    /// The windows contain past entries for that peer ID, for both monitors, ordered by timestamp.
+    /// The windows do not contain synthetic entries.
+    /// The window is truncated to the appropriate size whenever a new entry for that peer ID
     /// is processed.
-    peer_queues: HashMap<String, VecDeque<SourcedCSVWantlistEntry>>,
+    peer_queues: HashMap<String, PeerWindow>,
 }
 
 /// Some statistics about entry matches between monitors.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub(crate) struct MatcherStatistics {
     pub(crate) total_entries: usize,
     pub(crate) matched_entries: usize,
@@ -202,15 +248,44 @@ impl InterMonitorMatcher {
         Ok(InterMonitorMatcher {
             cfg: cfg.clone(),
             stats: Default::default(),
+            clock_offsets: Default::default(),
+            clusters: Default::default(),
             peer_queues: Default::default(),
         })
     }
 
+    /// Returns the configuration of this matcher.
+    pub(crate) fn config(&self) -> &MatchingConfig {
+        &self.cfg
+    }
+
     /// Returns stats about how entries were matched.
     pub(crate) fn stats(&self) -> MatcherStatistics {
         self.stats.clone()
     }
 
+    /// Returns the current estimates of the clock offsets of the monitors, relative to the first
+    /// monitor.
+    pub(crate) fn clock_offset_estimates(
+        &self,
+        num_monitors: usize,
+    ) -> Vec<Option<ClockOffsetEstimate>> {
+        self.clock_offsets.estimates(num_monitors)
+    }
+
+    /// Takes the match clusters which were finished since the last call, i.e., those which can not
+    /// grow anymore.
+    /// This includes clusters of a single entry.
+    pub(crate) fn take_finished_clusters(&mut self) -> Vec<CSVMatchCluster> {
+        self.clusters.take_finished()
+    }
+
+    /// Finishes all match clusters, to be taken via `take_finished_clusters`.
+    /// This is to be called after all entries have been processed.
+    pub(crate) fn finish_clusters(&mut self) {
+        self.clusters.finish_all()
+    }
+
     /// Drives the matching and duplicate detection algorithm with the given entries.
     /// Returns the entries (minus connection events) augmented with matching and duplicate
     /// detection information.
@@ -230,9 +305,12 @@ impl InterMonitorMatcher {
 
         let output_entries = if let Some(entries) = ingest_result.wantlist_entries {
             self.stats.total_entries += entries.len();
-            let queue = self.peer_queues.entry(peer_id).or_default();
-            Self::handle_entries(queue, monitor_id, entries, ts, &self.cfg, &mut self.stats)
-                .context("unable to handle entries")?
+            // We take the queue out of the map, to be able to borrow the rest of the state.
+            let mut queue = self.peer_queues.remove(&peer_id).unwrap_or_default();
+            let res = self.handle_entries(&mut queue, monitor_id, entries, ts);
+            self.peer_queues.insert(peer_id, queue);
+
+            res.context("unable to handle entries")?
                 .into_iter()
                 .map(|entry| OutputCSVWantlistEntry::from(entry))
                 .collect()
@@ -243,22 +321,55 @@ impl InterMonitorMatcher {
         Ok(output_entries)
     }
 
+    /// Drives the matching and duplicate detection algorithm with the end-of-simulation synthetic
+    /// cancels of one monitor, which must be sorted by peer.
+    pub(crate) fn handle_end_of_simulation_entries(
+        &mut self,
+        monitor_id: usize,
+        ts: chrono::DateTime<chrono::Utc>,
+        entries: Vec<CSVWantlistEntry>,
+    ) -> Result<Vec<OutputCSVWantlistEntry>> {
+        let mut output_entries = Vec::with_capacity(entries.len());
+        let mut entries = entries.into_iter().peekable();
+        while let Some(entry) = entries.next() {
+            let peer_id = entry.peer_id.clone();
+            let mut peer_entries = vec![entry];
+            while let Some(entry) = entries.next_if(|e| e.peer_id == peer_id) {
+                peer_entries.push(entry);
+            }
+
+            output_entries.extend(self.handle_ingest_result(
+                monitor_id,
+                ts,
+                peer_id,
+                IngestResult {
+                    wantlist_entries: Some(peer_entries),
+                    ..Default::default()
+                },
+            )?);
+        }
+
+        Ok(output_entries)
+    }
+
     fn handle_entries(
-        queue: &mut VecDeque<SourcedCSVWantlistEntry>,
+        &mut self,
+        queue: &mut PeerWindow,
         monitor_id: usize,
         entries: Vec<CSVWantlistEntry>,
         ts: chrono::DateTime<chrono::Utc>,
-        cfg: &MatchingConfig,
-        stats: &mut MatcherStatistics,
     ) -> Result<Vec<GloballyDupedMatchedCSVWantlistEntry>> {
+        let cfg = &self.cfg;
+
         // Clear entries in peer queue older than max window size
         let max_window_size = cfg
             .inter_monitor_matching_window_milliseconds
             .max(cfg.global_duplicate_window_seconds * 1000) as i64;
-        queue.retain(|e| {
-            let diff_ms = e.millis_to_timestamp(ts);
-            diff_ms <= max_window_size
-        });
+        queue
+            .evict(ts, max_window_size)
+            .into_iter()
+            .filter_map(|e| e.cluster_id)
+            .for_each(|id| self.clusters.release(id));
         debug!("peer message queue is {:?}", queue);
 
         // Try to find and mark matches between monitors.
@@ -266,74 +377,83 @@ impl InterMonitorMatcher {
         // The number of entries does not change.
         let matched_entries: Vec<_> = entries
             .into_iter()
-            .map(|entry| Self::match_single_entry(queue, monitor_id, cfg, stats, entry))
+            .map(|entry| {
+                Self::match_single_entry(
+                    queue,
+                    monitor_id,
+                    ts,
+                    cfg,
+                    &mut self.stats,
+                    &mut self.clock_offsets,
+                    entry,
+                )
+            })
             .collect();
         debug!("generated matched entries {:?}", matched_entries);
 
+        // Assign the entries to match clusters.
+        // Each entry holds a reference to its cluster until it is added to the queue.
+        let matched_entries: Vec<_> = matched_entries
+            .into_iter()
+            .map(|mut entry| {
+                if entry.entry.message_type != wantlist::CSV_MESSAGE_TYPE_SYNTHETIC {
+                    entry.cluster_id = Some(self.clusters.assign(
+                        monitor_id,
+                        &entry.entry,
+                        entry.cluster_id,
+                    ));
+                } else {
+                    entry.cluster_id = None;
+                }
+                entry
+            })
+            .collect();
+
         // Find and mark global duplicates, i.e., duplicates in some window calculated over all
         // monitors.
         // The number of entries does not change.
         let duped_entries: Vec<_> = matched_entries
             .into_iter()
-            .map(|entry| Self::search_dup_single_entry(queue, cfg, entry))
+            .map(|entry| Self::search_dup_single_entry(queue, &self.cfg, entry))
             .collect();
         debug!("generated duped entries {:?}", duped_entries);
 
         // Append entries to peer queue
         // We use the output entries for this so we can mark which ones were matched.
         // We filter synthetic entries, which are not to be included in the per-peer windows.
-        queue.extend(
-            duped_entries
-                .iter()
-                .filter(|&e| e.entry.entry.entry_type != wantlist::CSV_MESSAGE_TYPE_SYNTHETIC)
-                .map(|e| SourcedCSVWantlistEntry {
+        // The entries in the queue hold references to their clusters.
+        duped_entries
+            .iter()
+            .filter(|&e| e.entry.entry.entry_type != wantlist::CSV_MESSAGE_TYPE_SYNTHETIC)
+            .for_each(|e| {
+                if let Some(id) = e.entry.cluster_id {
+                    self.clusters.acquire(id);
+                }
+                queue.push(SourcedCSVWantlistEntry {
                     entry: e.entry.entry.clone(),
                     monitor_id,
                     matched: e.entry.inter_source_match.is_some(),
-                }),
-        );
+                    cluster_id: e.entry.cluster_id,
+                })
+            });
         debug!("peer message queue after update is {:?}", queue);
 
+        // Release the references of the entries being processed.
+        duped_entries
+            .iter()
+            .filter_map(|e| e.entry.cluster_id)
+            .for_each(|id| self.clusters.release(id));
+
         Ok(duped_entries)
     }
 
     fn search_dup_single_entry(
-        queue: &mut VecDeque<SourcedCSVWantlistEntry>,
+        queue: &PeerWindow,
         cfg: &MatchingConfig,
         entry: MatchedCSVWantlistEntry,
     ) -> GloballyDupedMatchedCSVWantlistEntry {
         debug!("searching for global duplicates for entry {:?}", entry);
-        // Oldest entries are first in the queue.
-        let dup = queue
-            .iter()
-            // Newest entries are first in the queue.
-            .rev()
-            // Find entries that reference the same CID.
-            .filter(|&dup| dup.entry.cid == entry.entry.cid)
-            // Find entries with the correct entry type.
-            .filter(|&dup| {
-                if cfg.match_exact_entry_type {
-                    // We do not check whether the _message_type_ is the same, as there are no
-                    // synthetic messages in the queue.
-                    // We do not differentiate full wantlists from incremental ones.
-                    dup.entry.entry_type == entry.entry.entry_type
-                } else {
-                    // If we don't match the exact entry type, just check whether both of them were
-                    // requests or both of them were CANCELs...
-                    wantlist::csv_entry_type_is_request(dup.entry.entry_type)
-                        == wantlist::csv_entry_type_is_request(entry.entry.entry_type)
-                }
-            })
-            // Calculate the time difference between entries in the queue and the new entry.
-            .map(|dup| {
-                let diff_ms = dup.millis_to_csv_entry(&entry.entry);
-                assert!(diff_ms >= 0);
-                (dup, diff_ms)
-            })
-            // Find the first one that fits into our window.
-            // This will be the most recent matching entry because we reversed the iterator at some
-            // point.
-            .find(|&(_, diff_ms)| diff_ms <= cfg.global_duplicate_window_seconds as i64 * 1000);
+        let dup = queue.find_duplicate(&entry.entry, cfg);
 
         // Create output based on whether we found a duplicate
         GloballyDupedMatchedCSVWantlistEntry {
@@ -354,52 +474,40 @@ impl InterMonitorMatcher {
     }
 
     fn match_single_entry(
-        queue: &mut VecDeque<SourcedCSVWantlistEntry>,
+        queue: &mut PeerWindow,
         monitor_id: usize,
+        ts: chrono::DateTime<chrono::Utc>,
         cfg: &MatchingConfig,
         stats: &mut MatcherStatistics,
+        clock_offsets: &mut ClockOffsetEstimator,
         entry: CSVWantlistEntry,
     ) -> MatchedCSVWantlistEntry {
         debug!("searching for matches for entry {:?}", entry);
 
         // Search peer queue for matches from another monitor
-        // (oldest are first in queue, queue only contains non-synthetic messages)
-        // We will match with the first candidate while iterating.
-        // If we want to match newest-first, we need to reverse the iterator.
-        let it: Box<dyn Iterator<Item = &mut SourcedCSVWantlistEntry>> = if cfg.match_newest_first {
-            Box::new(queue.iter_mut().rev())
-        } else {
-            Box::new(queue.iter_mut())
-        };
-
-        let mut matched_entry = it
-            .filter(|ref dup| {
-                // Do we allow matching the same entry multiple times?
-                cfg.allow_multiple_match || !dup.matched
-            })
-            .filter(|ref dup| {
-                dup.monitor_id != monitor_id
-                    && dup.entry.cid == entry.cid
-                    && dup.entry.message_type == entry.message_type
-                    && dup.entry.entry_type == entry.entry_type
-            })
-            .map(|dup| {
-                let diff_ms = dup.millis_to_csv_entry(&entry);
-                assert!(diff_ms >= 0);
-
-                (dup, diff_ms)
-            })
-            .find(|&(_, diff_ms)| diff_ms <= cfg.inter_monitor_matching_window_milliseconds as i64);
+        // (queue only contains non-synthetic messages)
+        let mut matched_entry = queue.find_match(monitor_id, &entry, cfg);
 
         // Mark the entry as matched
-        if let Some((entry, _)) = &mut matched_entry {
-            entry.matched = true
+        if let Some((matched_entry, diff_ms)) = &mut matched_entry {
+            matched_entry.matched = true;
+
+            // Only use high-confidence matches to estimate clock offsets.
+            if entry.message_type != wantlist::CSV_MESSAGE_TYPE_SYNTHETIC
+                && entry.duplicate_status == wantlist::CSV_DUPLICATE_STATUS_NO_DUP
+                && matched_entry.entry.duplicate_status == wantlist::CSV_DUPLICATE_STATUS_NO_DUP
+            {
+                clock_offsets.record_match(monitor_id, matched_entry.monitor_id, ts, *diff_ms);
+            }
         }
 
         // Construct output based on our findings...
         MatchedCSVWantlistEntry {
             entry,
             monitor_id: monitor_id as u64,
+            cluster_id: matched_entry
+                .as_ref()
+                .and_then(|(matched_entry, _)| matched_entry.cluster_id),
             inter_source_match: matched_entry.map_or_else(
                 || {
                     debug!("found no matching entry");
@@ -429,3 +537,198 @@ impl InterMonitorMatcher {
         stats.match_diff_sum += (diff_ms as f64) / 1000.0;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matching_config() -> MatchingConfig {
+        MatchingConfig {
+            inter_monitor_matching_window_milliseconds: 1000,
+            global_duplicate_window_seconds: 5,
+            match_newest_first: false,
+            allow_multiple_match: false,
+            match_exact_entry_type: false,
+        }
+    }
+
+    fn entry(
+        peer_id: &str,
+        cid: &str,
+        message_type: i32,
+        entry_type: i32,
+        ts: chrono::DateTime<chrono::Utc>,
+    ) -> CSVWantlistEntry {
+        CSVWantlistEntry {
+            message_id: 0,
+            message_type,
+            timestamp_seconds: ts.timestamp(),
+            timestamp_subsec_milliseconds: ts.timestamp_subsec_millis(),
+            peer_id: peer_id.to_string(),
+            address: "".to_string(),
+            priority: 1,
+            entry_type,
+            cid: cid.to_string(),
+            normalized_cid: None,
+            duplicate_status: wantlist::CSV_DUPLICATE_STATUS_NO_DUP,
+            sliding_window_smallest_match: 0,
+            secs_since_earlier_message: 0,
+            upgrades_earlier_request: false,
+            origin_country: None,
+            origin_asn: None,
+            origin_as_organization: None,
+            origin_city: None,
+        }
+    }
+
+    fn want_have(
+        matcher: &mut InterMonitorMatcher,
+        monitor_id: usize,
+        cid: &str,
+        ts: chrono::DateTime<chrono::Utc>,
+    ) -> OutputCSVWantlistEntry {
+        let want = entry(
+            "QmPeer",
+            cid,
+            wantlist::CSV_MESSAGE_TYPE_INCREMENTAL,
+            wantlist::CSV_ENTRY_TYPE_WANT_HAVE,
+            ts,
+        );
+        let mut output = matcher
+            .handle_ingest_result(
+                monitor_id,
+                ts,
+                "QmPeer".to_string(),
+                IngestResult {
+                    wantlist_entries: Some(vec![want]),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(output.len(), 1);
+        output.remove(0)
+    }
+
+    #[test]
+    fn clusters_entries_of_three_monitors() {
+        let mut matcher = InterMonitorMatcher::new_from_config(&MatchingConfig {
+            allow_multiple_match: true,
+            ..matching_config()
+        })
+        .unwrap();
+        let ts = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+
+        let output = (0..3)
+            .map(|monitor_id| {
+                let ts = ts + chrono::Duration::milliseconds(10 * monitor_id as i64);
+                want_have(&mut matcher, monitor_id, "Qmcid", ts)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            output
+                .iter()
+                .map(|e| (e.matched_to_monitor_id, e.match_cluster_id))
+                .collect::<Vec<_>>(),
+            vec![(None, Some(0)), (Some(0), Some(0)), (Some(0), Some(0))]
+        );
+        // The cluster is still referenced by the entries in the window.
+        assert!(matcher.take_finished_clusters().is_empty());
+
+        // A later entry evicts the entries of the cluster, which finishes it.
+        let later = want_have(
+            &mut matcher,
+            0,
+            "Qmothercid",
+            ts + chrono::Duration::seconds(10),
+        );
+        assert_eq!(later.match_cluster_id, Some(1));
+        let clusters = matcher.take_finished_clusters();
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].cluster_id, 0);
+        assert_eq!(clusters[0].num_entries, 3);
+        assert_eq!(clusters[0].monitors, 0b111);
+        assert_eq!(clusters[0].first_monitor_id, 0);
+        assert_eq!(clusters[0].last_monitor_id, 2);
+        assert_eq!(clusters[0].spread_ms, 20);
+
+        // The cluster of the unmatched entry is reported as well.
+        matcher.finish_clusters();
+        let clusters = matcher.take_finished_clusters();
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].cluster_id, 1);
+        assert_eq!(clusters[0].num_entries, 1);
+    }
+
+    #[test]
+    fn end_of_simulation_entries_are_handled_per_peer() {
+        let mut matcher = InterMonitorMatcher::new_from_config(&matching_config()).unwrap();
+        let ts = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+
+        // A CANCEL of peer A, seen by the first monitor.
+        let cancel = entry(
+            "QmPeerA",
+            "Qmcid",
+            wantlist::CSV_MESSAGE_TYPE_INCREMENTAL,
+            wantlist::CSV_ENTRY_TYPE_CANCEL,
+            ts,
+        );
+        matcher
+            .handle_ingest_result(
+                0,
+                ts,
+                "QmPeerA".to_string(),
+                IngestResult {
+                    wantlist_entries: Some(vec![cancel]),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        // End-of-simulation cancels of the second monitor, for the same CID at both peers.
+        let ts = ts + chrono::Duration::seconds(1);
+        let synthetic_cancel = |peer_id: &str, cid: &str| {
+            entry(
+                peer_id,
+                cid,
+                wantlist::CSV_MESSAGE_TYPE_SYNTHETIC,
+                wantlist::CSV_ENTRY_TYPE_SYNTHETIC_CANCEL_END_OF_SIMULATION,
+                ts,
+            )
+        };
+        let output = matcher
+            .handle_end_of_simulation_entries(
+                1,
+                ts,
+                vec![
+                    synthetic_cancel("QmPeerA", "Qmcid"),
+                    synthetic_cancel("QmPeerA", "Qmothercid"),
+                    synthetic_cancel("QmPeerB", "Qmcid"),
+                ],
+            )
+            .unwrap();
+
+        assert_eq!(
+            output
+                .iter()
+                .map(|e| (
+                    e.monitor_id,
+                    e.peer_id.as_str(),
+                    e.cid.as_str(),
+                    e.global_duplicate_time_diff_ms
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                // Only the cancel of peer A duplicates the earlier CANCEL.
+                (1, "QmPeerA", "Qmcid", Some(1000)),
+                (1, "QmPeerA", "Qmothercid", None),
+                (1, "QmPeerB", "Qmcid", None),
+            ]
+        );
+        assert!(output
+            .iter()
+            .all(|e| e.matched_to_monitor_id.is_none() && e.match_cluster_id.is_none()));
+        assert_eq!(matcher.stats().total_entries, 4);
+        assert!(matcher.peer_queues.contains_key("QmPeerA"));
+        assert!(matcher.peer_queues.contains_key("QmPeerB"));
+    }
+}
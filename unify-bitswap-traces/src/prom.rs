@@ -0,0 +1,81 @@
+use crate::Result;
+use failure::ResultExt;
+use prometheus::{CounterVec, Gauge, HistogramVec, IntCounterVec, IntGauge};
+use std::net::SocketAddr;
+
+lazy_static! {
+    pub static ref MESSAGES_RECEIVED: IntCounterVec = register_int_counter_vec!(
+        "unify_messages_received",
+        "number of messages received for live unification, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+
+    pub static ref MESSAGES_LATE: IntCounterVec = register_int_counter_vec!(
+        "unify_messages_late",
+        "number of messages dropped because newer messages were already unified, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+
+    pub static ref REORDER_BUFFER_MESSAGES: IntGauge = register_int_gauge!(
+        "unify_reorder_buffer_messages",
+        "number of messages buffered to be merged by timestamp"
+    )
+    .unwrap();
+
+    pub static ref WATERMARK_LAG_SECONDS: Gauge = register_gauge!(
+        "unify_watermark_lag_seconds",
+        "time by which the timestamp up to which messages are unified trails the current time"
+    )
+    .unwrap();
+
+    pub static ref ENTRIES_UNIFIED: IntCounterVec = register_int_counter_vec!(
+        "unify_entries",
+        "number of non-synthetic wantlist entries unified, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+
+    pub static ref ENTRIES_MATCHED: IntCounterVec = register_int_counter_vec!(
+        "unify_entries_matched",
+        "number of wantlist entries matched to an earlier entry of another monitor, by monitor and the monitor matched to",
+        &["monitor","matched_to_monitor"]
+    )
+    .unwrap();
+
+    pub static ref ENTRIES_GLOBAL_DUPLICATES: IntCounterVec = register_int_counter_vec!(
+        "unify_entries_global_duplicates",
+        "number of wantlist entries which are duplicates of an earlier entry of any monitor, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+
+    pub static ref INTER_MONITOR_DELAY_SECONDS: HistogramVec = register_histogram_vec!(
+        "unify_inter_monitor_delay_seconds",
+        "time between matched entries of two monitors, by monitor and the (earlier) monitor matched to",
+        &["monitor","matched_to_monitor"],
+        vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]
+    )
+    .unwrap();
+
+    pub static ref MONITORING_GAPS: IntCounterVec = register_int_counter_vec!(
+        "unify_monitoring_gaps",
+        "number of times the connection to the AMQP server was lost and re-established, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+
+    pub static ref MONITORING_GAP_SECONDS: CounterVec = register_counter_vec!(
+        "unify_monitoring_gap_seconds",
+        "total time during which no data was received due to connection loss, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+}
+
+pub(crate) fn run_prometheus(addr: SocketAddr) -> Result<()> {
+    prometheus_exporter::start(addr).context("can not start exporter")?;
+
+    Ok(())
+}
@@ -0,0 +1,232 @@
+use ipfs_resolver_common::wantlist::JSONMessage;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant};
+
+/// A message buffered for reordering, ordered by timestamp and then by arrival.
+#[derive(Debug)]
+struct BufferedMessage {
+    seq: u64,
+    monitor_id: usize,
+    msg: JSONMessage,
+}
+
+impl Ord for BufferedMessage {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.msg
+            .timestamp
+            .cmp(&other.msg.timestamp)
+            .then(self.seq.cmp(&other.seq))
+    }
+}
+
+impl PartialOrd for BufferedMessage {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for BufferedMessage {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for BufferedMessage {}
+
+/// What the buffer knows about the progress of a monitor.
+#[derive(Debug, Clone, Copy)]
+struct MonitorProgress {
+    /// The newest timestamp received from the monitor.
+    newest_ts: Option<chrono::DateTime<chrono::Utc>>,
+    /// When the monitor last delivered a message, or when the buffer was created.
+    last_arrival: Instant,
+}
+
+/// A buffer which merges the messages of multiple monitors, arriving in real time, by timestamp.
+/// This is the live analogue of the `WindowedJSONMessageSorter`, which sorts within a window of
+/// a fixed number of messages.
+///
+/// The watermark of each monitor trails the newest timestamp received from it by the allowed
+/// lateness.
+/// Messages are released once they are not newer than the watermarks of all monitors which
+/// delivered messages within the idle timeout.
+/// Monitors which did not deliver any messages yet are waited for, for the idle timeout after the
+/// buffer was created.
+/// Of messages with equal timestamps, those which arrived first are released first.
+///
+/// Messages older than a message which was already released are late.
+/// They are dropped, as releasing them out of order would break the per-peer windows of the
+/// matcher, which expect entries ordered by timestamp.
+#[derive(Debug)]
+pub(crate) struct WatermarkReorderBuffer {
+    heap: BinaryHeap<Reverse<BufferedMessage>>,
+    monitors: Vec<MonitorProgress>,
+    allowed_lateness: chrono::Duration,
+    idle_timeout: Duration,
+    next_seq: u64,
+    /// The timestamp of the newest message released.
+    released_until: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl WatermarkReorderBuffer {
+    pub(crate) fn new(
+        num_monitors: usize,
+        allowed_lateness: Duration,
+        idle_timeout: Duration,
+        now: Instant,
+    ) -> WatermarkReorderBuffer {
+        WatermarkReorderBuffer {
+            heap: BinaryHeap::new(),
+            monitors: vec![
+                MonitorProgress {
+                    newest_ts: None,
+                    last_arrival: now,
+                };
+                num_monitors
+            ],
+            allowed_lateness: chrono::Duration::from_std(allowed_lateness)
+                .unwrap_or(chrono::Duration::MAX),
+            idle_timeout,
+            next_seq: 0,
+            released_until: None,
+        }
+    }
+
+    /// Returns the number of buffered messages.
+    pub(crate) fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    /// Buffers a message of the given monitor, which arrived at `now`.
+    /// Returns whether the message is late, i.e., older than a message already released, in which
+    /// case it is dropped.
+    pub(crate) fn push(&mut self, monitor_id: usize, msg: JSONMessage, now: Instant) -> bool {
+        let progress = &mut self.monitors[monitor_id];
+        progress.last_arrival = now;
+        progress.newest_ts = progress.newest_ts.max(Some(msg.timestamp));
+
+        if self.released_until.is_some_and(|ts| msg.timestamp < ts) {
+            return true;
+        }
+        self.heap.push(Reverse(BufferedMessage {
+            seq: self.next_seq,
+            monitor_id,
+            msg,
+        }));
+        self.next_seq += 1;
+
+        false
+    }
+
+    /// Returns the watermark at `now`, i.e., the timestamp up to which messages are released.
+    /// This is `None` if all monitors are idle, in which case all messages are released.
+    pub(crate) fn watermark(&self, now: Instant) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.monitors
+            .iter()
+            .filter(|p| now.saturating_duration_since(p.last_arrival) < self.idle_timeout)
+            .map(|p| match p.newest_ts {
+                Some(ts) => ts
+                    .checked_sub_signed(self.allowed_lateness)
+                    .unwrap_or(chrono::DateTime::<chrono::Utc>::MIN_UTC),
+                None => chrono::DateTime::<chrono::Utc>::MIN_UTC,
+            })
+            .min()
+    }
+
+    /// Pops the oldest message, together with the ID of its monitor, if it can be released at
+    /// `now`.
+    pub(crate) fn pop_ready(&mut self, now: Instant) -> Option<(usize, JSONMessage)> {
+        let watermark = self.watermark(now);
+        let oldest = self.heap.peek()?;
+        if watermark.is_some_and(|w| oldest.0.msg.timestamp > w) {
+            return None;
+        }
+        self.pop()
+    }
+
+    /// Pops the oldest message, regardless of watermarks, e.g., to flush the buffer on shutdown.
+    pub(crate) fn pop(&mut self) -> Option<(usize, JSONMessage)> {
+        let Reverse(BufferedMessage {
+            monitor_id, msg, ..
+        }) = self.heap.pop()?;
+        self.released_until = self.released_until.max(Some(msg.timestamp));
+        Some((monitor_id, msg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(peer: &str, secs: i64) -> JSONMessage {
+        JSONMessage {
+            timestamp: chrono::DateTime::from_timestamp(1_700_000_000 + secs, 0).unwrap(),
+            peer: peer.to_string(),
+            address: None,
+            received_entries: None,
+            full_want_list: None,
+            peer_connected: None,
+            peer_disconnected: None,
+            connect_event_peer_found: None,
+            connected_addresses: None,
+            blocks: None,
+            block_presences: None,
+        }
+    }
+
+    fn pop_all_ready(buffer: &mut WatermarkReorderBuffer, now: Instant) -> Vec<(usize, String)> {
+        std::iter::from_fn(|| buffer.pop_ready(now))
+            .map(|(monitor_id, msg)| (monitor_id, msg.peer))
+            .collect()
+    }
+
+    #[test]
+    fn releases_messages_by_watermark() {
+        let start = Instant::now();
+        let mut buffer =
+            WatermarkReorderBuffer::new(2, Duration::from_secs(2), Duration::from_secs(10), start);
+
+        // Nothing is released before the second monitor delivered anything.
+        assert!(!buffer.push(0, message("a", 0), start));
+        assert!(!buffer.push(0, message("c", 5), start));
+        assert_eq!(pop_all_ready(&mut buffer, start), vec![]);
+
+        // Messages up to three seconds are released.
+        assert!(!buffer.push(1, message("b", 1), start));
+        assert!(!buffer.push(1, message("d", 5), start));
+        assert_eq!(
+            pop_all_ready(&mut buffer, start),
+            vec![(0, "a".to_string()), (1, "b".to_string())]
+        );
+
+        // This is late, and dropped.
+        assert!(buffer.push(1, message("late", 0), start));
+        assert_eq!(pop_all_ready(&mut buffer, start), vec![]);
+        assert_eq!(buffer.len(), 2);
+        // Messages as old as the newest one released are not late.
+        assert!(!buffer.push(1, message("f", 1), start));
+        assert_eq!(
+            pop_all_ready(&mut buffer, start),
+            vec![(1, "f".to_string())]
+        );
+
+        // Once the second monitor is idle, only the first one is waited for.
+        assert!(!buffer.push(0, message("e", 8), start + Duration::from_secs(5)));
+        assert_eq!(
+            pop_all_ready(&mut buffer, start + Duration::from_secs(5)),
+            vec![]
+        );
+        assert_eq!(
+            pop_all_ready(&mut buffer, start + Duration::from_secs(11)),
+            vec![(0, "c".to_string()), (1, "d".to_string())]
+        );
+
+        // Once all monitors are idle, everything is released.
+        assert_eq!(
+            pop_all_ready(&mut buffer, start + Duration::from_secs(20)),
+            vec![(0, "e".to_string())]
+        );
+        assert_eq!(buffer.len(), 0);
+    }
+}
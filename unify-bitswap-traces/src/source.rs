@@ -1,9 +1,12 @@
+use crate::clock::ClockCorrection;
 use crate::config::{Config, MonitorSourceConfig};
 use crate::Result;
-use failure::{Fail, ResultExt};
+use failure::{ensure, Fail, ResultExt};
 use flate2::read::GzDecoder;
+use ipfs_resolver_common::events::TraceDecoder;
 use ipfs_resolver_common::wantlist;
 use ipfs_resolver_common::wantlist::{EngineSimulation, JSONMessage};
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 use std::fs::File;
@@ -18,8 +21,22 @@ use std::path::PathBuf;
 struct MonitorSource {
     monitor_name: String,
     input_paths: Vec<PathBuf>,
+    /// The index of the next input file to open.
+    next_file: usize,
     current_file: Option<BufReader<GzDecoder<File>>>,
+    /// The number of lines read from the current file.
+    lines_read: usize,
     input_buffer: String,
+    decoder: TraceDecoder,
+}
+
+/// The position of a `MonitorSource` within its input files.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct SourcePosition {
+    /// The input files which have been opened, including the current one.
+    files: Vec<PathBuf>,
+    /// The number of lines read from the last of `files`, if it is still being read.
+    lines_read: Option<usize>,
 }
 
 impl MonitorSource {
@@ -31,22 +48,64 @@ impl MonitorSource {
         Ok(MonitorSource {
             monitor_name: cfg.monitor_name,
             input_paths: paths,
+            next_file: 0,
             current_file: None,
+            lines_read: 0,
             input_buffer: String::new(),
+            decoder: TraceDecoder::new(cfg.input_format),
         })
     }
 
     fn open_next_input_file(&mut self) -> Result<Option<BufReader<GzDecoder<File>>>> {
-        if self.input_paths.is_empty() {
-            return Ok(None);
-        }
-
-        // Popping off the front of this vector is not fast, but this is not performance critical...
-        let p = self.input_paths.remove(0);
+        let p = match self.input_paths.get(self.next_file) {
+            Some(p) => p,
+            None => return Ok(None),
+        };
         let f = File::open(p).context("unable to open input file for reading")?;
+        self.next_file += 1;
+        self.lines_read = 0;
+        self.decoder.start_file();
 
         Ok(Some(BufReader::new(GzDecoder::new(f))))
     }
+
+    fn position(&self) -> SourcePosition {
+        SourcePosition {
+            files: self.input_paths[..self.next_file].to_vec(),
+            lines_read: self.current_file.as_ref().map(|_| self.lines_read),
+        }
+    }
+
+    /// Skips input up to the given position.
+    /// The files opened before must be the first input files of this source.
+    fn seek(&mut self, pos: &SourcePosition) -> Result<()> {
+        ensure!(
+            self.input_paths.starts_with(&pos.files),
+            "input files of monitor {} do not match the snapshot",
+            self.monitor_name
+        );
+        self.next_file = pos.files.len();
+
+        if let Some(lines) = pos.lines_read {
+            ensure!(self.next_file > 0, "invalid source position");
+            self.next_file -= 1;
+            self.current_file = self.open_next_input_file()?;
+            let f = self
+                .current_file
+                .as_mut()
+                .ok_or_else(|| failure::err_msg("missing input file"))?;
+            for _ in 0..lines {
+                self.input_buffer.clear();
+                let n = f
+                    .read_line(&mut self.input_buffer)
+                    .context("unable to read input file")?;
+                ensure!(n > 0, "input file is shorter than in the snapshot");
+            }
+            self.lines_read = lines;
+        }
+
+        Ok(())
+    }
 }
 
 impl Iterator for MonitorSource {
@@ -76,8 +135,9 @@ impl Iterator for MonitorSource {
                         self.current_file = None;
                         continue;
                     }
+                    self.lines_read += 1;
 
-                    let message: JSONMessage = match serde_json::from_str(&self.input_buffer) {
+                    let message = match self.decoder.decode(&self.input_buffer) {
                         Ok(msg) => msg,
                         Err(e) => {
                             break Some(Err(e.context("unable to deserialize message").into()));
@@ -145,6 +205,28 @@ impl WindowedJSONMessageSorter {
             window_size,
         }
     }
+
+    /// Returns the buffered messages, in the internal order of the heap.
+    /// Messages with equal timestamps are popped in an order that depends on this, which is why
+    /// it is preserved in snapshots.
+    fn buffered_messages(&self) -> Vec<JSONMessage> {
+        self.heap
+            .as_slice()
+            .iter()
+            .map(|m| m.0.msg.clone())
+            .collect()
+    }
+
+    /// Replaces the buffered messages with the given ones, as returned by `buffered_messages`.
+    fn restore_buffered_messages(&mut self, msgs: Vec<JSONMessage>) {
+        // Building a heap from a vector which already satisfies the heap property does not
+        // reorder it.
+        self.heap = BinaryHeap::from(
+            msgs.into_iter()
+                .map(|msg| std::cmp::Reverse(TimestampOrderedJSONMessage { msg }))
+                .collect::<Vec<_>>(),
+        );
+    }
 }
 
 impl Iterator for WindowedJSONMessageSorter {
@@ -186,11 +268,40 @@ impl Iterator for WindowedJSONMessageSorter {
 
 /// This reads traces from multiple monitors, drives distinct bitswap engine simulations with them,
 /// and produces an ordered iterator of the messages emitted.
+///
+/// Messages of the monitors are merged by timestamp.
+/// Of multiple messages with equal timestamps, those of the monitor which produced the previous
+/// message are emitted first, followed by those of the monitors in the order of the config.
 pub(crate) struct MultiSourceIngester {
     source_names: Vec<String>,
     engine_states: Vec<EngineSimulation>,
-    merged_sources: Box<dyn Iterator<Item = Result<(usize, JSONMessage)>>>,
+    sources: Vec<WindowedJSONMessageSorter>,
+    /// The next message of each source, to be merged.
+    heads: Vec<Option<JSONMessage>>,
+    /// The source of the previous message.
+    last_monitor_id: Option<usize>,
+    message_id: i64,
+    /// The corrections of the clock of each monitor, applied to its messages before merging.
+    clock_corrections: Vec<ClockCorrection>,
+}
+
+/// The state of a `MultiSourceIngester`, from which ingestion can be resumed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct IngesterState {
     message_id: i64,
+    last_monitor_id: Option<usize>,
+    engine_states: Vec<EngineSimulation>,
+    sources: Vec<SourceState>,
+    clock_corrections: Vec<ClockCorrection>,
+}
+
+/// The state of a single source.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SourceState {
+    position: SourcePosition,
+    /// The messages buffered for sorting, in the internal order of the heap.
+    buffered_messages: Vec<JSONMessage>,
+    head: Option<JSONMessage>,
 }
 
 impl MultiSourceIngester {
@@ -208,6 +319,11 @@ impl MultiSourceIngester {
         let sources = Self::construct_sources(cfg).context("unable to construct sources")?;
         debug!("constructed sources {:?}", sources);
         let source_names: Vec<_> = sources.iter().map(|s| s.monitor_name.clone()).collect();
+        let clock_corrections = cfg
+            .monitors
+            .iter()
+            .map(|m| ClockCorrection::fixed(m.clock_offset_milliseconds))
+            .collect();
 
         // Construct engine states
         let state = EngineSimulation::new(cfg.simulation_config.clone())
@@ -218,51 +334,104 @@ impl MultiSourceIngester {
 
         // Make it so we can pop messages in order
         // Also sort them in windows, sheesh... (see journal on Nov 29th for an explanation).
-        // This turns the sources into iterators that produce (source-index, message) tuples.
-        // Pretty sick!
         let sources = sources
             .into_iter()
             .map(|s| WindowedJSONMessageSorter::new(s, cfg.message_sorting_window_size))
-            .enumerate()
-            .map(|(k, s)| s.map(move |msg| msg.map(|msg| (k, msg))))
             .collect::<Vec<_>>();
-        // And now we k-way merge them by timestamp.
-        let merged_sources = itertools::kmerge_by(
-            sources.into_iter(),
-            |a: &Result<(usize, JSONMessage)>, b: &Result<(usize, JSONMessage)>| {
-                // This is pretty hacky, but:
-                // We see if any of the two is an error, and if yes sort it towards the front of
-                // the queue.
-                // This is dirty because of many things, but it should be okay because we will
-                // cancel the entire unification if there's an error reading from disk anyway, so
-                // it should be fine.
-                match &a {
-                    Ok((_, msg1)) => {
-                        match &b {
-                            Ok((_, msg2)) => {
-                                // TODO what if they are equal?
-                                msg1.timestamp < msg2.timestamp
-                            }
-                            Err(_) => false,
-                        }
-                    }
-                    Err(_) => true,
-                }
-            },
-        );
+        let heads = vec![None; sources.len()];
 
         Ok(MultiSourceIngester {
             source_names,
             engine_states,
-            merged_sources: Box::new(merged_sources),
+            sources,
+            heads,
+            last_monitor_id: None,
             message_id: 0,
+            clock_corrections,
         })
     }
 
+    /// Returns the current state of ingestion.
+    pub(crate) fn state(&self) -> IngesterState {
+        IngesterState {
+            message_id: self.message_id,
+            last_monitor_id: self.last_monitor_id,
+            engine_states: self.engine_states.clone(),
+            sources: self
+                .sources
+                .iter()
+                .zip(self.heads.iter())
+                .map(|(s, head)| SourceState {
+                    position: s.input.position(),
+                    buffered_messages: s.buffered_messages(),
+                    head: head.clone(),
+                })
+                .collect(),
+            clock_corrections: self.clock_corrections.clone(),
+        }
+    }
+
+    /// Resumes ingestion from the given state.
+    /// This must be called before any messages are produced, on an ingester constructed from the
+    /// same config as the one the state was taken from.
+    pub(crate) fn resume(&mut self, state: IngesterState) -> Result<()> {
+        let IngesterState {
+            message_id,
+            last_monitor_id,
+            engine_states,
+            sources,
+            clock_corrections,
+        } = state;
+        ensure!(
+            sources.len() == self.sources.len() && engine_states.len() == self.sources.len(),
+            "number of monitors does not match the snapshot"
+        );
+        ensure!(
+            engine_states
+                .iter()
+                .zip(self.engine_states.iter())
+                .all(|(e1, e2)| e1.config() == e2.config()),
+            "simulation config does not match the snapshot"
+        );
+
+        for (i, state) in sources.into_iter().enumerate() {
+            let source = &mut self.sources[i];
+            source
+                .input
+                .seek(&state.position)
+                .context(format!("unable to resume monitor {}", self.source_names[i]))?;
+            source.restore_buffered_messages(state.buffered_messages);
+            self.heads[i] = state.head;
+        }
+        self.engine_states = engine_states;
+        self.clock_corrections = clock_corrections;
+        self.last_monitor_id = last_monitor_id;
+        self.message_id = message_id;
+
+        Ok(())
+    }
+
+    /// Applies the given corrections to the clocks of the monitors, in addition to the configured
+    /// offsets.
+    /// This must be called before any messages are produced.
+    pub(crate) fn correct_clocks(&mut self, corrections: &[ClockCorrection]) {
+        self.clock_corrections = self
+            .clock_corrections
+            .iter()
+            .zip(corrections.iter())
+            .map(|(c1, c2)| c1.combine(c2))
+            .collect();
+    }
+
     pub(crate) fn source_names(&self) -> Vec<String> {
         self.source_names.clone()
     }
 
+    /// Returns the number of ledgers tracked by the engine simulation of each monitor.
+    pub(crate) fn num_ledgers(&self) -> Vec<usize> {
+        self.engine_states.iter().map(|e| e.num_ledgers()).collect()
+    }
+
     pub(crate) fn into_engine_states(self) -> Vec<EngineSimulation> {
         self.engine_states
     }
@@ -271,13 +440,59 @@ impl MultiSourceIngester {
     pub(crate) fn last_message_id(&self) -> i64 {
         self.message_id
     }
+
+    /// Pops the oldest message of all sources, together with the index of its source.
+    fn next_message(&mut self) -> Option<Result<(usize, JSONMessage)>> {
+        // Fill up heads, if possible.
+        for ((source, head), clock_correction) in self
+            .sources
+            .iter_mut()
+            .zip(self.heads.iter_mut())
+            .zip(self.clock_corrections.iter())
+        {
+            if head.is_none() {
+                match source.next() {
+                    Some(Ok(mut msg)) => {
+                        msg.timestamp = clock_correction.apply(msg.timestamp);
+                        *head = Some(msg)
+                    }
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => {}
+                }
+            }
+        }
+
+        // Find the oldest head, preferring the previous source, then sources configured first.
+        let mut oldest: Option<(usize, &JSONMessage)> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            if let Some(msg) = head {
+                let is_older = match oldest {
+                    None => true,
+                    Some((_, oldest_msg)) => {
+                        msg.timestamp < oldest_msg.timestamp
+                            || (msg.timestamp == oldest_msg.timestamp
+                                && self.last_monitor_id == Some(i))
+                    }
+                };
+                if is_older {
+                    oldest = Some((i, msg));
+                }
+            }
+        }
+        let monitor_id = oldest?.0;
+        self.last_monitor_id = Some(monitor_id);
+
+        self.heads[monitor_id]
+            .take()
+            .map(|msg| Ok((monitor_id, msg)))
+    }
 }
 
 impl Iterator for MultiSourceIngester {
     type Item = Result<MultiSourceIngestResult>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.merged_sources.next() {
+        match self.next_message() {
             Some(elem) => {
                 match elem {
                     Ok((monitor_id, msg)) => {
@@ -0,0 +1,357 @@
+use crate::clusters::CSVMatchCluster;
+use crate::config::Config;
+use crate::ledgers::{CSVLedgerCount, LedgerCounter};
+use crate::lifetimes::OutputCSVRequestLifetime;
+use crate::matcher::{InterMonitorMatcher, OutputCSVWantlistEntry};
+use crate::source::MultiSourceIngestResult;
+use crate::Result;
+use failure::{ensure, err_msg, ResultExt};
+use ipfs_resolver_common::geoip::OriginLookup;
+use ipfs_resolver_common::output::{OutputCheckpoint, OutputConfig, OutputSink, Record};
+use ipfs_resolver_common::wantlist::EngineSimulation;
+use serde::{Deserialize, Serialize};
+
+/// The number of messages after which the wantlist output is rotated.
+const MESSAGES_PER_FILE: usize = 100_000;
+
+/// Matches the results of the engine simulations of all monitors and writes the outputs of the
+/// unification.
+/// This is driven by messages merged by timestamp, either from traces or live.
+pub(crate) struct Unifier {
+    matcher: InterMonitorMatcher,
+    ledger_counter: LedgerCounter,
+    output_sink: OutputSink<OutputCSVWantlistEntry>,
+    ledger_count_output_sink: OutputSink<CSVLedgerCount>,
+    request_lifetimes_output_sink: Option<OutputSink<OutputCSVRequestLifetime>>,
+    match_clusters_output_sink: Option<OutputSink<CSVMatchCluster>>,
+    /// Looks up the origin of addresses, if enrichment is configured.
+    origin_lookup: Option<OriginLookup>,
+    num_messages_in_current_output_file: usize,
+    /// The timestamp of the last message handled.
+    final_ts: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// The state of a `Unifier` at the time the output was rotated, from which it can be resumed.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct UnifierState {
+    matcher: InterMonitorMatcher,
+    ledger_count_output: OutputCheckpoint,
+    request_lifetimes_output: Option<OutputCheckpoint>,
+    match_clusters_output: Option<OutputCheckpoint>,
+}
+
+impl Unifier {
+    /// Creates the outputs of a new unification.
+    /// The first wantlist output file is named after the given message ID.
+    pub(crate) fn create(
+        cfg: &Config,
+        num_monitors: usize,
+        first_output_file_id: i64,
+    ) -> Result<Unifier> {
+        let matcher = InterMonitorMatcher::new_from_config(&cfg.matching_config)
+            .context("unable to construct inter-monitor duplicate marker and matcher")?;
+        let ledger_count_output_sink =
+            OutputSink::create(&cfg.output, &cfg.ledger_count_output_file)
+                .context("unable to create ledger count output file")?;
+        let request_lifetimes_output_sink = cfg
+            .request_lifetimes_output_file
+            .as_ref()
+            .map(|f| OutputSink::create(&cfg.output, f))
+            .transpose()
+            .context("unable to create request lifetimes output file")?;
+        let match_clusters_output_sink = cfg
+            .match_clusters_output_file
+            .as_ref()
+            .map(|f| OutputSink::create(&cfg.output, f))
+            .transpose()
+            .context("unable to create match clusters output file")?;
+
+        Self::with_outputs(
+            cfg,
+            num_monitors,
+            first_output_file_id,
+            matcher,
+            ledger_count_output_sink,
+            request_lifetimes_output_sink,
+            match_clusters_output_sink,
+        )
+    }
+
+    /// Resumes a unification from the given state, taken when the wantlist output file named after
+    /// the given message ID was started.
+    pub(crate) fn resume(
+        cfg: &Config,
+        num_monitors: usize,
+        output_file_id: i64,
+        state: UnifierState,
+    ) -> Result<Unifier> {
+        ensure!(
+            *state.matcher.config() == cfg.matching_config,
+            "matching config does not match the snapshot"
+        );
+        let ledger_count_output_sink = OutputSink::resume(&cfg.output, &state.ledger_count_output)
+            .context("unable to resume ledger count output")?;
+        let request_lifetimes_output_sink = resume_optional_output(
+            &cfg.output,
+            cfg.request_lifetimes_output_file.as_ref(),
+            state.request_lifetimes_output.as_ref(),
+        )
+        .context("unable to resume request lifetimes output")?;
+        let match_clusters_output_sink = resume_optional_output(
+            &cfg.output,
+            cfg.match_clusters_output_file.as_ref(),
+            state.match_clusters_output.as_ref(),
+        )
+        .context("unable to resume match clusters output")?;
+
+        Self::with_outputs(
+            cfg,
+            num_monitors,
+            output_file_id,
+            state.matcher,
+            ledger_count_output_sink,
+            request_lifetimes_output_sink,
+            match_clusters_output_sink,
+        )
+    }
+
+    fn with_outputs(
+        cfg: &Config,
+        num_monitors: usize,
+        output_file_id: i64,
+        matcher: InterMonitorMatcher,
+        ledger_count_output_sink: OutputSink<CSVLedgerCount>,
+        request_lifetimes_output_sink: Option<OutputSink<OutputCSVRequestLifetime>>,
+        match_clusters_output_sink: Option<OutputSink<CSVMatchCluster>>,
+    ) -> Result<Unifier> {
+        let output_sink = OutputSink::create_from_pattern(
+            &cfg.output,
+            &cfg.wantlist_output_file_pattern,
+            output_file_id,
+        )
+        .context("unable to create output file")?;
+        let origin_lookup = cfg
+            .geoip
+            .as_ref()
+            .map(OriginLookup::open)
+            .transpose()
+            .context("unable to open GeoIP databases")?;
+
+        Ok(Unifier {
+            matcher,
+            ledger_counter: LedgerCounter::new(num_monitors),
+            output_sink,
+            ledger_count_output_sink,
+            request_lifetimes_output_sink,
+            match_clusters_output_sink,
+            origin_lookup,
+            num_messages_in_current_output_file: 0,
+            final_ts: None,
+        })
+    }
+
+    /// Returns whether the output should be rotated before handling the next message.
+    pub(crate) fn needs_rotation(&self) -> bool {
+        self.num_messages_in_current_output_file > MESSAGES_PER_FILE
+    }
+
+    /// Rotates the wantlist output, naming the next file after the ID of the next message, and
+    /// writes the ledger counts of the monitors.
+    pub(crate) fn rotate(&mut self, first_message_id: i64, num_ledgers: &[usize]) -> Result<()> {
+        self.output_sink
+            .rotate(first_message_id)
+            .context("unable to rotate output file")?;
+        self.num_messages_in_current_output_file = 0;
+
+        if let Some(ts) = self.final_ts {
+            self.ledger_counter.write_counts(
+                ts,
+                num_ledgers,
+                &mut self.ledger_count_output_sink,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the current state, to be resumed from after the output was rotated.
+    pub(crate) fn state(&mut self) -> Result<UnifierState> {
+        Ok(UnifierState {
+            matcher: self.matcher.clone(),
+            ledger_count_output: self.ledger_count_output_sink.checkpoint()?,
+            request_lifetimes_output: self
+                .request_lifetimes_output_sink
+                .as_mut()
+                .map(|s| s.checkpoint())
+                .transpose()?,
+            match_clusters_output: self
+                .match_clusters_output_sink
+                .as_mut()
+                .map(|s| s.checkpoint())
+                .transpose()?,
+        })
+    }
+
+    /// Matches the result of ingesting a message and writes the outputs.
+    /// Returns the wantlist entries written.
+    pub(crate) fn handle_ingest_result(
+        &mut self,
+        res: MultiSourceIngestResult,
+    ) -> Result<Vec<OutputCSVWantlistEntry>> {
+        let MultiSourceIngestResult {
+            monitor_id,
+            timestamp,
+            peer_id,
+            mut simulation_result,
+        } = res;
+        debug!(
+            "got entry {:?} from monitor {}",
+            simulation_result, monitor_id
+        );
+        self.final_ts = Some(timestamp);
+        if simulation_result.missing_ledger {
+            self.ledger_counter.record_missing_ledger(monitor_id);
+        }
+
+        if let (Some(lifetimes), Some(sink)) = (
+            simulation_result.request_lifetimes.take(),
+            self.request_lifetimes_output_sink.as_mut(),
+        ) {
+            lifetimes
+                .into_iter()
+                .try_for_each(|l| sink.write(&OutputCSVRequestLifetime::new(monitor_id, l)))
+                .context("unable to write request lifetimes")?;
+        }
+
+        // Feed that into the matching engine
+        let mut output_entries = self
+            .matcher
+            .handle_ingest_result(monitor_id, timestamp, peer_id, simulation_result)
+            .context("unable to handle ingest result")?;
+
+        self.enrich(&mut output_entries);
+
+        // Write entries to output file
+        output_entries
+            .iter()
+            .try_for_each(|e| self.output_sink.write(e))
+            .context("unable to write output")?;
+        self.write_match_clusters()?;
+        self.num_messages_in_current_output_file += 1;
+
+        Ok(output_entries)
+    }
+
+    /// Emits end-of-simulation lifetimes and synthetic cancels for the given final states of the
+    /// engine simulations and finishes all outputs.
+    /// The end-of-simulation entries are assigned the message ID following the given one.
+    /// Returns the matcher, e.g., to get statistics.
+    pub(crate) fn finish(
+        mut self,
+        engine_states: Vec<EngineSimulation>,
+        last_message_id: i64,
+    ) -> Result<InterMonitorMatcher> {
+        let msg_id = last_message_id;
+        if let Some(ts) = self.final_ts {
+            let num_ledgers = engine_states
+                .iter()
+                .map(|e| e.num_ledgers())
+                .collect::<Vec<_>>();
+            self.ledger_counter.write_counts(
+                ts,
+                &num_ledgers,
+                &mut self.ledger_count_output_sink,
+            )?;
+        }
+
+        if let (Some(ts), Some(mut sink)) =
+            (self.final_ts, self.request_lifetimes_output_sink.take())
+        {
+            for (monitor_id, engine) in engine_states.iter().enumerate() {
+                let mut lifetimes = engine.generate_end_of_simulation_lifetimes(ts, msg_id + 1);
+                // Sort by peer, to make the output deterministic.
+                // This is stable, so lifetimes are still sorted by CID within each peer.
+                lifetimes.sort_by(|l1, l2| l1.peer_id.cmp(&l2.peer_id));
+                lifetimes
+                    .into_iter()
+                    .try_for_each(|l| sink.write(&OutputCSVRequestLifetime::new(monitor_id, l)))
+                    .context("unable to write end-of-simulation request lifetimes")?;
+            }
+            sink.finish()
+                .context("unable to write request lifetimes output")?;
+        }
+
+        if let Some(ts) = self.final_ts {
+            for (monitor_id, engine) in engine_states.into_iter().enumerate() {
+                let mut end_of_simulation_cancels =
+                    engine.generate_end_of_simulation_entries(ts, msg_id + 1);
+                // Sort by peer, to make the output deterministic and group entries by peer.
+                // This is stable, so entries are still sorted by CID within each peer.
+                end_of_simulation_cancels.sort_by(|e1, e2| e1.peer_id.cmp(&e2.peer_id));
+
+                let mut output_entries = self
+                    .matcher
+                    .handle_end_of_simulation_entries(monitor_id, ts, end_of_simulation_cancels)
+                    .context("unable to handle end-of-simulation synthetic cancels")?;
+                self.enrich(&mut output_entries);
+                output_entries
+                    .iter()
+                    .try_for_each(|e| self.output_sink.write(e))
+                    .context("unable to write end-of-simulation synthetic cancels")?;
+            }
+        } else {
+            warn!("missing final timestamp, unable to finalize")
+        }
+
+        self.matcher.finish_clusters();
+        self.write_match_clusters()?;
+        if let Some(sink) = self.match_clusters_output_sink {
+            sink.finish()
+                .context("unable to write match clusters output")?;
+        }
+
+        self.output_sink
+            .finish()
+            .context("unable to write output")?;
+        self.ledger_count_output_sink
+            .finish()
+            .context("unable to write ledger count output")?;
+
+        Ok(self.matcher)
+    }
+
+    /// Sets the origin of the given entries, if enrichment is configured.
+    fn enrich(&mut self, entries: &mut [OutputCSVWantlistEntry]) {
+        if let Some(origin_lookup) = self.origin_lookup.as_mut() {
+            entries.iter_mut().for_each(|e| origin_lookup.enrich(e))
+        }
+    }
+
+    /// Writes the match clusters finished since the last call, if configured.
+    fn write_match_clusters(&mut self) -> Result<()> {
+        let clusters = self.matcher.take_finished_clusters();
+        if let Some(sink) = self.match_clusters_output_sink.as_mut() {
+            clusters
+                .iter()
+                .try_for_each(|c| sink.write(c))
+                .context("unable to write match clusters")?;
+        }
+        Ok(())
+    }
+}
+
+/// Resumes an optional output, which must be configured if and only if it was configured when
+/// the snapshot was taken.
+fn resume_optional_output<T: Record>(
+    config: &OutputConfig,
+    file: Option<&String>,
+    checkpoint: Option<&OutputCheckpoint>,
+) -> Result<Option<OutputSink<T>>> {
+    match (file, checkpoint) {
+        (Some(_), Some(checkpoint)) => Ok(Some(OutputSink::resume(config, checkpoint)?)),
+        (None, None) => Ok(None),
+        _ => Err(err_msg(
+            "output must be configured both for the snapshot and now, or neither",
+        )),
+    }
+}
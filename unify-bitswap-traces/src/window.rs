@@ -0,0 +1,263 @@
+use crate::config::MatchingConfig;
+use ipfs_resolver_common::wantlist;
+use ipfs_resolver_common::wantlist::CSVWantlistEntry;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+
+/// An entry in a per-peer window.
+/// These windows hold entries from multiple monitors.
+/// We need to keep track of where they came from and whether they've been matched with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SourcedCSVWantlistEntry {
+    pub(crate) entry: CSVWantlistEntry,
+    pub(crate) monitor_id: usize,
+    pub(crate) matched: bool,
+    pub(crate) cluster_id: Option<i64>,
+}
+
+/// Functions to calculate time differences.
+/// Note that they always assume the receiver (the `SourcedCSVWantlistEntry`) to be further in the
+/// past than the thing compared to.
+/// If it is not, e.g., for late messages or skewed clocks, the difference is negative.
+impl SourcedCSVWantlistEntry {
+    pub(crate) fn millis_to_csv_entry(&self, other: &CSVWantlistEntry) -> i64 {
+        debug!(
+            "self.ts: {}, other.ts: {}, self.millis: {}, other.millis: {}",
+            self.entry.timestamp_seconds,
+            other.timestamp_seconds,
+            self.entry.timestamp_subsec_milliseconds,
+            other.timestamp_subsec_milliseconds
+        );
+        (other.timestamp_seconds - self.entry.timestamp_seconds) * 1000
+            + (other.timestamp_subsec_milliseconds as i64
+                - self.entry.timestamp_subsec_milliseconds as i64)
+    }
+
+    pub(crate) fn timestamp_millis(&self) -> i64 {
+        self.entry.timestamp_seconds * 1000 + self.entry.timestamp_subsec_milliseconds as i64
+    }
+}
+
+/// The key by which entries are indexed: the CID, and whether the entry is a request.
+type IndexKey = (String, bool);
+
+fn index_key(entry: &CSVWantlistEntry) -> IndexKey {
+    (
+        entry.cid_key().to_string(),
+        wantlist::csv_entry_type_is_request(entry.entry_type),
+    )
+}
+
+/// The window of past entries of one peer, for both monitors, in the order they were added.
+///
+/// Entries are indexed by CID and entry type class, such that searching for matches and
+/// duplicates does not need to scan the entire window.
+/// The results are the same as those of a scan in order of insertion.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(
+    from = "Vec<SourcedCSVWantlistEntry>",
+    into = "Vec<SourcedCSVWantlistEntry>"
+)]
+pub(crate) struct PeerWindow {
+    next_seq: u64,
+    /// The entries, by sequence number, i.e., in the order they were added.
+    entries: HashMap<u64, SourcedCSVWantlistEntry>,
+    /// Sequence numbers by timestamp in milliseconds, for eviction.
+    by_time: BTreeSet<(i64, u64)>,
+    /// Sequence numbers by CID and entry type class.
+    by_key: HashMap<IndexKey, BTreeSet<u64>>,
+}
+
+impl From<Vec<SourcedCSVWantlistEntry>> for PeerWindow {
+    fn from(entries: Vec<SourcedCSVWantlistEntry>) -> Self {
+        let mut window = PeerWindow::default();
+        entries.into_iter().for_each(|e| window.push(e));
+        window
+    }
+}
+
+impl From<PeerWindow> for Vec<SourcedCSVWantlistEntry> {
+    fn from(mut window: PeerWindow) -> Self {
+        let mut seqs = window.entries.keys().copied().collect::<Vec<_>>();
+        seqs.sort_unstable();
+        seqs.into_iter()
+            .filter_map(|seq| window.entries.remove(&seq))
+            .collect()
+    }
+}
+
+impl PeerWindow {
+    /// Adds an entry to the window.
+    pub(crate) fn push(&mut self, entry: SourcedCSVWantlistEntry) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        self.by_time.insert((entry.timestamp_millis(), seq));
+        self.by_key
+            .entry(index_key(&entry.entry))
+            .or_default()
+            .insert(seq);
+        self.entries.insert(seq, entry);
+    }
+
+    /// Removes and returns all entries more than `max_window_ms` older than `ts`.
+    pub(crate) fn evict(
+        &mut self,
+        ts: chrono::DateTime<chrono::Utc>,
+        max_window_ms: i64,
+    ) -> Vec<SourcedCSVWantlistEntry> {
+        let threshold = ts.timestamp() * 1000 + ts.timestamp_subsec_millis() as i64 - max_window_ms;
+        let retained = self.by_time.split_off(&(threshold, 0));
+        let evicted = std::mem::replace(&mut self.by_time, retained);
+
+        evicted
+            .into_iter()
+            .filter_map(|(_, seq)| {
+                let entry = self.entries.remove(&seq)?;
+                let key = index_key(&entry.entry);
+                if let Some(seqs) = self.by_key.get_mut(&key) {
+                    seqs.remove(&seq);
+                    if seqs.is_empty() {
+                        self.by_key.remove(&key);
+                    }
+                }
+                Some(entry)
+            })
+            .collect()
+    }
+
+    /// Returns the sequence numbers of entries with the same CID and entry type class as the given
+    /// entry, oldest first.
+    fn candidates<'a>(
+        &'a self,
+        entry: &CSVWantlistEntry,
+    ) -> impl DoubleEndedIterator<Item = u64> + 'a {
+        self.by_key
+            .get(&index_key(entry))
+            .into_iter()
+            .flat_map(|seqs| seqs.iter().copied())
+    }
+
+    /// Finds an entry of another monitor to match the given entry of `monitor_id` to, together with
+    /// the time difference between them.
+    /// Entries are matched if they reference the same CID with the same message and entry type,
+    /// within the matching window.
+    pub(crate) fn find_match(
+        &mut self,
+        monitor_id: usize,
+        entry: &CSVWantlistEntry,
+        cfg: &MatchingConfig,
+    ) -> Option<(&mut SourcedCSVWantlistEntry, i64)> {
+        // We will match with the first candidate while iterating.
+        // If we want to match newest-first, we need to reverse the iterator.
+        let candidates: Box<dyn Iterator<Item = u64>> = if cfg.match_newest_first {
+            Box::new(self.candidates(entry).rev())
+        } else {
+            Box::new(self.candidates(entry))
+        };
+
+        let (seq, diff_ms) = candidates
+            .map(|seq| (seq, &self.entries[&seq]))
+            .filter(|(_, dup)| {
+                // Do we allow matching the same entry multiple times?
+                cfg.allow_multiple_match || !dup.matched
+            })
+            .filter(|(_, dup)| {
+                dup.monitor_id != monitor_id
+                    && dup.entry.cid_key() == entry.cid_key()
+                    && dup.entry.message_type == entry.message_type
+                    && dup.entry.entry_type == entry.entry_type
+            })
+            .map(|(seq, dup)| (seq, dup.millis_to_csv_entry(entry)))
+            // Entries newer than the given one, e.g., of a monitor with a clock running ahead, are
+            // not matched.
+            .find(|&(_, diff_ms)| {
+                (0..=cfg.inter_monitor_matching_window_milliseconds as i64).contains(&diff_ms)
+            })?;
+
+        self.entries.get_mut(&seq).map(|e| (e, diff_ms))
+    }
+
+    /// Finds the most recent entry which the given entry duplicates, from any monitor, together
+    /// with the time difference between them.
+    pub(crate) fn find_duplicate(
+        &self,
+        entry: &CSVWantlistEntry,
+        cfg: &MatchingConfig,
+    ) -> Option<(&SourcedCSVWantlistEntry, i64)> {
+        self.candidates(entry)
+            // Newest entries first.
+            .rev()
+            .map(|seq| &self.entries[&seq])
+            // Find entries that reference the same CID.
+            .filter(|&dup| dup.entry.cid_key() == entry.cid_key())
+            // Find entries with the correct entry type.
+            .filter(|&dup| {
+                if cfg.match_exact_entry_type {
+                    // We do not check whether the _message_type_ is the same, as there are no
+                    // synthetic messages in the queue.
+                    // We do not differentiate full wantlists from incremental ones.
+                    dup.entry.entry_type == entry.entry_type
+                } else {
+                    // If we don't match the exact entry type, just check whether both of them were
+                    // requests or both of them were CANCELs...
+                    // This is guaranteed by the index.
+                    true
+                }
+            })
+            // Calculate the time difference between entries in the queue and the new entry.
+            .map(|dup| (dup, dup.millis_to_csv_entry(entry)))
+            // Find the first one that fits into our window, ignoring newer entries.
+            // This will be the most recent matching entry because we reversed the iterator.
+            .find(|&(_, diff_ms)| {
+                (0..=cfg.global_duplicate_window_seconds as i64 * 1000).contains(&diff_ms)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::window_testing::{configs, run, synthetic_trace, LinearWindow};
+
+    #[test]
+    fn indexed_window_matches_linear_scan() {
+        let trace = synthetic_trace(400, 200, 200);
+        for cfg in configs() {
+            let (expected, _) = run::<LinearWindow>(&trace, &cfg);
+            let (results, _) = run::<PeerWindow>(&trace, &cfg);
+            assert!(expected.iter().any(|(m, d)| m.is_some() && d.is_some()));
+            assert_eq!(results, expected, "results differ for {:?}", cfg);
+        }
+    }
+
+    #[test]
+    fn ignores_newer_entries() {
+        let trace = synthetic_trace(1, 1, 1);
+        let (_, _, entries) = &trace[0];
+        let mut window = PeerWindow::default();
+        window.push(SourcedCSVWantlistEntry {
+            entry: entries[0].clone(),
+            monitor_id: 0,
+            matched: false,
+            cluster_id: None,
+        });
+
+        // An entry of the same CID, but older than the one in the window.
+        let mut older = entries[0].clone();
+        older.timestamp_seconds -= 1;
+        for cfg in configs() {
+            assert!(window.find_match(1, &older, &cfg).is_none());
+            assert!(window.find_duplicate(&older, &cfg).is_none());
+        }
+
+        // The entry in the window is still found for entries at the same time.
+        let cfg = configs()[0];
+        assert_eq!(
+            window
+                .find_match(1, &entries[0], &cfg)
+                .map(|(e, diff_ms)| (e.monitor_id, diff_ms)),
+            Some((0, 0))
+        );
+    }
+}
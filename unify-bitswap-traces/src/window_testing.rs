@@ -0,0 +1,221 @@
+//! Utilities to compare the indexed per-peer windows to a linear scan over all entries, shared
+//! by the tests and the benchmark of the windows.
+
+use crate::config::MatchingConfig;
+use crate::window::{PeerWindow, SourcedCSVWantlistEntry};
+use ipfs_resolver_common::wantlist;
+use ipfs_resolver_common::wantlist::CSVWantlistEntry;
+use std::collections::VecDeque;
+
+/// The linear scan over a queue of entries, as used before the windows were indexed.
+#[derive(Default)]
+pub(crate) struct LinearWindow {
+    queue: VecDeque<SourcedCSVWantlistEntry>,
+}
+
+/// A match or duplicate found in a window: the message ID and monitor of the earlier entry,
+/// and the time difference.
+pub(crate) type Found = Option<(i64, usize, i64)>;
+
+pub(crate) trait Window {
+    fn push(&mut self, entry: SourcedCSVWantlistEntry);
+    fn evict(&mut self, ts: chrono::DateTime<chrono::Utc>, max_window_ms: i64);
+    fn find_match(
+        &mut self,
+        monitor_id: usize,
+        entry: &CSVWantlistEntry,
+        cfg: &MatchingConfig,
+    ) -> Found;
+    fn find_duplicate(&self, entry: &CSVWantlistEntry, cfg: &MatchingConfig) -> Found;
+}
+
+impl Window for LinearWindow {
+    fn push(&mut self, entry: SourcedCSVWantlistEntry) {
+        self.queue.push_back(entry)
+    }
+
+    fn evict(&mut self, ts: chrono::DateTime<chrono::Utc>, max_window_ms: i64) {
+        let ts_ms = ts.timestamp_millis();
+        self.queue
+            .retain(|e| ts_ms - e.timestamp_millis() <= max_window_ms)
+    }
+
+    fn find_match(
+        &mut self,
+        monitor_id: usize,
+        entry: &CSVWantlistEntry,
+        cfg: &MatchingConfig,
+    ) -> Found {
+        let it: Box<dyn Iterator<Item = &mut SourcedCSVWantlistEntry>> = if cfg.match_newest_first {
+            Box::new(self.queue.iter_mut().rev())
+        } else {
+            Box::new(self.queue.iter_mut())
+        };
+        let (dup, diff_ms) = it
+            .filter(|dup| cfg.allow_multiple_match || !dup.matched)
+            .filter(|dup| {
+                dup.monitor_id != monitor_id
+                    && dup.entry.cid_key() == entry.cid_key()
+                    && dup.entry.message_type == entry.message_type
+                    && dup.entry.entry_type == entry.entry_type
+            })
+            .map(|dup| {
+                let diff_ms = dup.millis_to_csv_entry(entry);
+                (dup, diff_ms)
+            })
+            .find(|&(_, diff_ms)| {
+                (0..=cfg.inter_monitor_matching_window_milliseconds as i64).contains(&diff_ms)
+            })?;
+        dup.matched = true;
+        Some((dup.entry.message_id, dup.monitor_id, diff_ms))
+    }
+
+    fn find_duplicate(&self, entry: &CSVWantlistEntry, cfg: &MatchingConfig) -> Found {
+        self.queue
+            .iter()
+            .rev()
+            .filter(|&dup| dup.entry.cid_key() == entry.cid_key())
+            .filter(|&dup| {
+                if cfg.match_exact_entry_type {
+                    dup.entry.entry_type == entry.entry_type
+                } else {
+                    wantlist::csv_entry_type_is_request(dup.entry.entry_type)
+                        == wantlist::csv_entry_type_is_request(entry.entry_type)
+                }
+            })
+            .map(|dup| (dup, dup.millis_to_csv_entry(entry)))
+            .find(|&(_, diff_ms)| {
+                (0..=cfg.global_duplicate_window_seconds as i64 * 1000).contains(&diff_ms)
+            })
+            .map(|(dup, diff_ms)| (dup.entry.message_id, dup.monitor_id, diff_ms))
+    }
+}
+
+impl Window for PeerWindow {
+    fn push(&mut self, entry: SourcedCSVWantlistEntry) {
+        PeerWindow::push(self, entry)
+    }
+
+    fn evict(&mut self, ts: chrono::DateTime<chrono::Utc>, max_window_ms: i64) {
+        PeerWindow::evict(self, ts, max_window_ms);
+    }
+
+    fn find_match(
+        &mut self,
+        monitor_id: usize,
+        entry: &CSVWantlistEntry,
+        cfg: &MatchingConfig,
+    ) -> Found {
+        let (dup, diff_ms) = PeerWindow::find_match(self, monitor_id, entry, cfg)?;
+        dup.matched = true;
+        Some((dup.entry.message_id, dup.monitor_id, diff_ms))
+    }
+
+    fn find_duplicate(&self, entry: &CSVWantlistEntry, cfg: &MatchingConfig) -> Found {
+        PeerWindow::find_duplicate(self, entry, cfg)
+            .map(|(dup, diff_ms)| (dup.entry.message_id, dup.monitor_id, diff_ms))
+    }
+}
+
+/// Generates a synthetic trace of a single chatty peer, as seen by three monitors.
+/// Returns messages as (monitor ID, timestamp, entries).
+pub(crate) fn synthetic_trace(
+    num_messages: usize,
+    num_cids: u64,
+    max_message_interval_ms: u64,
+) -> Vec<(usize, chrono::DateTime<chrono::Utc>, Vec<CSVWantlistEntry>)> {
+    // A simple xorshift generator, to be deterministic without further dependencies.
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut next = move |n: u64| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state % n
+    };
+
+    let mut ts = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    (0..num_messages)
+        .map(|i| {
+            ts += chrono::Duration::milliseconds(next(max_message_interval_ms) as i64);
+            let monitor_id = next(3) as usize;
+            let entries = (0..1 + next(50))
+                .map(|_| CSVWantlistEntry {
+                    message_id: i as i64,
+                    message_type: wantlist::CSV_MESSAGE_TYPE_INCREMENTAL,
+                    timestamp_seconds: ts.timestamp(),
+                    timestamp_subsec_milliseconds: ts.timestamp_subsec_millis(),
+                    peer_id: "QmPeer".to_string(),
+                    address: "".to_string(),
+                    priority: 1,
+                    entry_type: [
+                        wantlist::CSV_ENTRY_TYPE_CANCEL,
+                        wantlist::CSV_ENTRY_TYPE_WANT_BLOCK,
+                        wantlist::CSV_ENTRY_TYPE_WANT_HAVE,
+                    ][next(3) as usize],
+                    cid: format!("Qmcid{}", next(num_cids)),
+                    normalized_cid: None,
+                    duplicate_status: wantlist::CSV_DUPLICATE_STATUS_NO_DUP,
+                    sliding_window_smallest_match: 0,
+                    secs_since_earlier_message: 0,
+                    upgrades_earlier_request: false,
+                    origin_country: None,
+                    origin_asn: None,
+                    origin_as_organization: None,
+                    origin_city: None,
+                })
+                .collect();
+            (monitor_id, ts, entries)
+        })
+        .collect()
+}
+
+/// Runs matching and duplicate detection on a window, like the matcher does.
+/// Returns the matches and duplicates found, and the time it took.
+pub(crate) fn run<W: Window + Default>(
+    trace: &[(usize, chrono::DateTime<chrono::Utc>, Vec<CSVWantlistEntry>)],
+    cfg: &MatchingConfig,
+) -> (Vec<(Found, Found)>, std::time::Duration) {
+    let max_window_ms = cfg
+        .inter_monitor_matching_window_milliseconds
+        .max(cfg.global_duplicate_window_seconds * 1000) as i64;
+    let mut window = W::default();
+    let mut results = Vec::new();
+
+    let before = std::time::Instant::now();
+    for (monitor_id, ts, entries) in trace {
+        window.evict(*ts, max_window_ms);
+        let matches = entries
+            .iter()
+            .map(|e| window.find_match(*monitor_id, e, cfg))
+            .collect::<Vec<_>>();
+        for (entry, m) in entries.iter().zip(matches) {
+            results.push((m, window.find_duplicate(entry, cfg)));
+        }
+        for (entry, (m, _)) in entries.iter().zip(results.iter().rev()) {
+            window.push(SourcedCSVWantlistEntry {
+                entry: entry.clone(),
+                monitor_id: *monitor_id,
+                matched: m.is_some(),
+                cluster_id: None,
+            });
+        }
+    }
+
+    (results, before.elapsed())
+}
+
+pub(crate) fn configs() -> Vec<MatchingConfig> {
+    let mut configs = Vec::new();
+    for match_newest_first in [false, true] {
+        for allow_multiple_match in [false, true] {
+            configs.push(MatchingConfig {
+                inter_monitor_matching_window_milliseconds: 5000,
+                global_duplicate_window_seconds: 31,
+                match_newest_first,
+                allow_multiple_match,
+                match_exact_entry_type: allow_multiple_match,
+            });
+        }
+    }
+    configs
+}